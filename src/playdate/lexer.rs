@@ -0,0 +1,386 @@
+//! Tokens of the text formats of mursten models and skeleton schemas, which share their names,
+//! quoted strings, punctuation and `//` comments.
+//!
+//! Quoted strings escape `"` and `\` with a backslash, and write line breaks, tabs and other
+//! control characters as `\n`, `\r`, `\t` or `\u{1b}`. `quoted` writes them the way `tokenize`
+//! reads them back.
+
+use std::{
+    fmt::{Display, Write},
+    iter::Peekable,
+    str::Chars,
+};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Name(String),
+    Quoted(String),
+    Number(f32),
+    Colon,
+    Comma,
+    Bang,
+    Equals,
+    Arrow,
+    OpenBrace,
+    CloseBrace,
+    OpenParen,
+    CloseParen,
+    End,
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Name(name) => write!(f, "`{}`", name),
+            Token::Quoted(string) => f.write_str(&quoted(string)),
+            Token::Number(number) => write!(f, "`{}`", number),
+            Token::Colon => f.write_str("`:`"),
+            Token::Comma => f.write_str("`,`"),
+            Token::Bang => f.write_str("`!`"),
+            Token::Equals => f.write_str("`=`"),
+            Token::Arrow => f.write_str("`=>`"),
+            Token::OpenBrace => f.write_str("`{`"),
+            Token::CloseBrace => f.write_str("`}`"),
+            Token::OpenParen => f.write_str("`(`"),
+            Token::CloseParen => f.write_str("`)`"),
+            Token::End => f.write_str("end of file"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Located<T> {
+    pub value: T,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl<T> Located<T> {
+    pub fn error<S: Into<String>>(&self, message: S) -> ParseError {
+        ParseError {
+            line: self.line,
+            column: self.column,
+            message: message.into(),
+        }
+    }
+
+    pub fn map<U>(&self, value: U) -> Located<U> {
+        Located {
+            value,
+            line: self.line,
+            column: self.column,
+        }
+    }
+}
+
+/// Whether `tokenize`, with the same `numbers`, reads `name` back as a name without quotes.
+pub fn is_bare_name(name: &str, numbers: bool) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if numbers && c.is_ascii_digit() => false,
+        Some(c) if c.is_alphanumeric() || c == '_' => {
+            chars.all(|c| c.is_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+/// `string` between double quotes, escaped so that `tokenize` reads it back as it is.
+pub fn quoted(string: &str) -> String {
+    let mut text = String::from('"');
+    for c in string.chars() {
+        match c {
+            '"' => text.push_str("\\\""),
+            '\\' => text.push_str("\\\\"),
+            '\n' => text.push_str("\\n"),
+            '\r' => text.push_str("\\r"),
+            '\t' => text.push_str("\\t"),
+            c if c.is_control() => write!(text, "\\u{{{:x}}}", c as u32).unwrap(),
+            c => text.push(c),
+        }
+    }
+    text.push('"');
+    text
+}
+
+/// Splits `text` into tokens, ending with `Token::End`. With `numbers`, words starting with a
+/// digit, `-` or `.` are numbers; otherwise digits start names and the others are not allowed.
+pub fn tokenize(text: &str, numbers: bool) -> Result<Vec<Located<Token>>, ParseError> {
+    let mut tokens = vec![];
+    let mut chars = text.chars().peekable();
+    let (mut line, mut column) = (1, 1);
+
+    while let Some(c) = chars.next() {
+        let start = Located {
+            value: (),
+            line,
+            column,
+        };
+        column += 1;
+        let token = match c {
+            '\n' => {
+                line += 1;
+                column = 1;
+                continue;
+            }
+            c if c.is_whitespace() => continue,
+            '/' if chars.peek() == Some(&'/') => {
+                while chars.peek().map_or(false, |c| *c != '\n') {
+                    chars.next();
+                }
+                continue;
+            }
+            ':' => Token::Colon,
+            ',' => Token::Comma,
+            '!' => Token::Bang,
+            '{' => Token::OpenBrace,
+            '}' => Token::CloseBrace,
+            '(' => Token::OpenParen,
+            ')' => Token::CloseParen,
+            '=' if chars.peek() == Some(&'>') => {
+                chars.next();
+                column += 1;
+                Token::Arrow
+            }
+            '=' => Token::Equals,
+            '"' => {
+                let mut string = String::new();
+                loop {
+                    column += 1;
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            column += 1;
+                            match chars.next() {
+                                Some('n') => string.push('\n'),
+                                Some('r') => string.push('\r'),
+                                Some('t') => string.push('\t'),
+                                Some('u') => match unicode_escape(&mut chars, &mut column) {
+                                    Some(c) => string.push(c),
+                                    None => return Err(start.error("Invalid unicode escape")),
+                                },
+                                Some('\n') | None => return Err(start.error("Unterminated string")),
+                                Some(c) => string.push(c),
+                            }
+                        }
+                        Some('\n') | None => return Err(start.error("Unterminated string")),
+                        Some(c) => string.push(c),
+                    }
+                }
+                Token::Quoted(string)
+            }
+            c if numbers && (c.is_ascii_digit() || c == '-' || c == '.') => {
+                let mut number = c.to_string();
+                while let Some(c) = chars.peek().copied() {
+                    let exponent_sign = (c == '-' || c == '+') && number.ends_with('e');
+                    if !(c.is_ascii_digit() || c == '.' || c == 'e' || exponent_sign) {
+                        break;
+                    }
+                    number.push(c);
+                    chars.next();
+                    column += 1;
+                }
+                match number.parse() {
+                    Ok(number) => Token::Number(number),
+                    Err(_) => return Err(start.error(format!("Invalid number `{}`", number))),
+                }
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut name = c.to_string();
+                while let Some(c) = chars.peek().copied() {
+                    if !(c.is_alphanumeric() || c == '_') {
+                        break;
+                    }
+                    name.push(c);
+                    chars.next();
+                    column += 1;
+                }
+                Token::Name(name)
+            }
+            c => return Err(start.error(format!("Unexpected character {:?}", c))),
+        };
+        tokens.push(start.map(token));
+    }
+
+    tokens.push(Located {
+        value: Token::End,
+        line,
+        column,
+    });
+    Ok(tokens)
+}
+
+/// The tokens of a text, read one at a time by the parsers of each format.
+pub struct Tokens {
+    tokens: Vec<Located<Token>>,
+    position: usize,
+}
+
+impl Tokens {
+    /// The tokens of `text`, read as `tokenize` does.
+    pub fn new(text: &str, numbers: bool) -> Result<Self, ParseError> {
+        Ok(Self {
+            tokens: tokenize(text, numbers)?,
+            position: 0,
+        })
+    }
+
+    pub fn peek(&self) -> &Located<Token> {
+        &self.tokens[self.position]
+    }
+
+    /// The token after the one `peek` returns.
+    pub fn peek_after(&self) -> &Token {
+        &self.tokens[(self.position + 1).min(self.tokens.len() - 1)].value
+    }
+
+    /// Consumes a token. Once at `Token::End`, it keeps returning it.
+    pub fn next(&mut self) -> Located<Token> {
+        let token = self.tokens[self.position].clone();
+        if token.value != Token::End {
+            self.position += 1;
+        }
+        token
+    }
+
+    pub fn expect(&mut self, expected: Token) -> Result<(), ParseError> {
+        let token = self.next();
+        if token.value == expected {
+            Ok(())
+        } else {
+            Err(token.error(format!("Expected {}, found {}", expected, token.value)))
+        }
+    }
+
+    /// Consumes the comma separating two entries. Returns true if there are no more entries.
+    pub fn end_of_entry(&mut self) -> Result<bool, ParseError> {
+        match self.peek().value {
+            Token::Comma => {
+                self.next();
+                Ok(self.peek().value == Token::CloseBrace)
+            }
+            Token::CloseBrace => Ok(true),
+            ref other => Err(self
+                .peek()
+                .error(format!("Expected `,` or `}}`, found {}", other))),
+        }
+    }
+}
+
+/// The character of a `\u{...}` escape, read after its `u`.
+fn unicode_escape(chars: &mut Peekable<Chars>, column: &mut usize) -> Option<char> {
+    let mut hex = String::new();
+    if chars.next()? != '{' {
+        return None;
+    }
+    *column += 1;
+    loop {
+        let c = chars.next()?;
+        *column += 1;
+        if c == '}' {
+            break;
+        }
+        hex.push(c);
+    }
+    std::char::from_u32(u32::from_str_radix(&hex, 16).ok()?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn values(text: &str) -> Vec<Token> {
+        tokenize(text, true)
+            .unwrap()
+            .into_iter()
+            .map(|token| token.value)
+            .collect()
+    }
+
+    #[test]
+    fn quoted_strings_read_back_as_they_were() {
+        for string in &[
+            "plain",
+            "with \"quotes\" and \\",
+            "line\nbreak, carriage\rreturn and\ttab",
+            "it's",
+            "escape \u{1b} and nul \u{0}",
+            "zero\u{200b}width and ñandú",
+        ] {
+            assert_eq!(
+                values(&quoted(string)),
+                vec![Token::Quoted(string.to_string()), Token::End]
+            );
+        }
+        assert_eq!(quoted("a\u{7f}\r"), "\"a\\u{7f}\\r\"");
+    }
+
+    #[test]
+    fn columns_count_every_character_of_escapes() {
+        let tokens = tokenize("\"a\\\"b\\u{1b}\" x", false).unwrap();
+        assert_eq!((tokens[1].line, tokens[1].column), (1, 14));
+
+        let err = tokenize("\"\\u{110000}\"", false).unwrap_err();
+        assert_eq!(err.message, "Invalid unicode escape");
+    }
+
+    #[test]
+    fn bare_names_read_back_as_names() {
+        for (name, numbers) in &[("x2", true), ("_x", true), ("4", false), ("ñandú", true)] {
+            assert!(is_bare_name(name, *numbers));
+            assert_eq!(
+                tokenize(name, *numbers).unwrap()[0].value,
+                Token::Name(name.to_string())
+            );
+        }
+        for (name, numbers) in &[("4", true), ("", false), ("a b", false), ("-x", false)] {
+            assert!(!is_bare_name(name, *numbers));
+        }
+    }
+
+    #[test]
+    fn tokens_are_read_one_at_a_time() {
+        let mut tokens = Tokens::new("a, }", false).unwrap();
+        assert_eq!(tokens.peek_after(), &Token::Comma);
+        tokens.expect(Token::Name("a".into())).unwrap();
+        assert_eq!(tokens.end_of_entry(), Ok(true));
+        tokens.expect(Token::CloseBrace).unwrap();
+        assert_eq!(tokens.next().value, Token::End);
+        assert_eq!(tokens.next().value, Token::End);
+        let err = tokens.expect(Token::Comma).unwrap_err();
+        assert_eq!(err.message, "Expected `,`, found end of file");
+    }
+
+    #[test]
+    fn numbers_are_only_read_when_asked_for() {
+        assert_eq!(
+            values("-0.5 1e-3 x2"),
+            vec![
+                Token::Number(-0.5),
+                Token::Number(1e-3),
+                Token::Name("x2".into()),
+                Token::End
+            ]
+        );
+        let tokens = tokenize("4", false).unwrap();
+        assert_eq!(tokens[0].value, Token::Name("4".into()));
+        assert!(tokenize("-1", false).is_err());
+    }
+}
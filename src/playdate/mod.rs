@@ -2,17 +2,38 @@ use crate::root_ui::*;
 use bevy::{prelude::*, utils::HashMap};
 
 use self::{
-    mursten::InMemoryModel, mursten_bevy_plugin::CurrentModel, mursten_egui_editor::ModelEditor,
+    mursten::InMemoryModel,
+    mursten_bevy_plugin::{
+        publish_model_changes, reload_current_model, update_instances, BlockFactories,
+        CurrentModel, ModelChange,
+    },
+    mursten_egui_editor::ModelEditor,
+    skeleton_animation::{Clip, Interpolation, Playback, PlaybackMode},
+    skeleton_instance::{
+        play_animations, update_poses, AnimationPlayer, CreateInstance, LinkInstance, UpdatePose,
+    },
 };
 
+mod lexer;
 mod mursten;
 mod mursten_bevy_plugin;
+mod mursten_commands;
+mod mursten_directory;
 mod mursten_egui_editor;
+mod mursten_graph;
+mod mursten_persistence;
+#[cfg(test)]
+mod mursten_properties;
+mod mursten_query;
 mod skeleton;
+mod skeleton_animation;
 mod skeleton_editor;
 mod skeleton_instance;
+mod skeleton_library;
+mod skeleton_persistence;
+mod skeleton_schema;
 
-// use skeleton_editor::SkeletonEditor;
+use skeleton_editor::{LinkTypeEditor, SkeletonEditor};
 
 // #[derive(Clone, Copy, Debug)]
 // pub enum Pixel {
@@ -54,17 +75,52 @@ impl Plugin for PlaydateSkeletonsPlugin {
     fn build(&self, app: &mut AppBuilder) {
         app
             // .require(RootUiPlugin)
-            .insert_resource(CurrentModel(Box::new(mursten::example::and_one_more_is_five_model())))
+            .insert_resource(CurrentModel::new(initial_mursten_model()))
+            .init_resource::<BlockFactories>()
+            .add_event::<ModelChange>()
             .insert_resource(ModelEditor::default())
             .add_system(mursten_model_editor.system())
+            .add_system(reload_current_model.system())
+            .add_system(publish_model_changes.system())
+            .add_system(update_instances.exclusive_system())
             .add_startup_system(on_startup.system())
             .add_startup_system(create_menu_entry.system())
             .insert_resource(SkeletonDatabase::default())
-            .add_system(SkeletonDatabase::render_stuff.system());
-        //.add_system(SkeletonEditor::render_editors.system());
+            .add_system(SkeletonDatabase::render_stuff.system())
+            .add_system(SkeletonDatabase::render_files.system())
+            .add_system(SkeletonDatabase::render_library.system())
+            .add_system(play_animations.system().before("update_poses"))
+            .add_system(update_poses.system().label("update_poses"))
+            .add_system(show_skeleton_links.system())
+            .add_system(SkeletonEditor::render_editors.system())
+            .add_system(LinkTypeEditor::render_editors.system());
     }
 }
 
+fn initial_mursten_model() -> Box<dyn mursten::Model> {
+    let project_path = mursten_persistence::default_project_path();
+    if project_path.is_dir() {
+        match mursten_directory::DirectoryModel::open(project_path) {
+            Ok(model) => {
+                info!("Opened mursten project {}", project_path.display());
+                return Box::new(model);
+            }
+            Err(err) => warn!("Failed to open {}: {}", project_path.display(), err),
+        }
+    }
+    let path = mursten_persistence::default_model_path();
+    if path.exists() {
+        match mursten_persistence::load_model(path) {
+            Ok(model) => {
+                info!("Loaded mursten model from {}", path.display());
+                return Box::new(model);
+            }
+            Err(err) => warn!("Failed to load {}: {}", path.display(), err),
+        }
+    }
+    Box::new(mursten::example::and_one_more_is_five_model())
+}
+
 fn mursten_model_editor(
     mut model: ResMut<CurrentModel>,
     mut editor: ResMut<ModelEditor>,
@@ -82,6 +138,7 @@ fn on_startup(
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
 ) {
+    skeleton_db.load_recent_files();
     skeleton_db.load_from_disk();
     // commands.spawn_bundle(OrthographicCameraBundle::new_2d());
 
@@ -100,6 +157,23 @@ fn on_startup(
     });
 }
 
+/// Gives a body to the links of skeleton instances as they are spawned.
+fn show_skeleton_links(
+    mut commands: Commands,
+    links: Query<(Entity, &Transform), Added<LinkInstance>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    for (entity, transform) in links.iter() {
+        commands.entity(entity).insert_bundle(PbrBundle {
+            mesh: meshes.add(Mesh::from(shape::Cube { size: 0.3 })),
+            material: materials.add(Color::rgb(0.8, 0.7, 0.6).into()),
+            transform: *transform,
+            ..Default::default()
+        });
+    }
+}
+
 // TODO: This could be totally generated with a macro
 fn create_menu_entry(mut commands: Commands) {
     commands.spawn().insert(MenuEntry {
@@ -111,12 +185,36 @@ fn create_menu_entry(mut commands: Commands) {
                     cmd.add(SkeletonDatabaseCommand::FileIn);
                 },
             },
+            MenuEntryAction {
+                name: "File In Schemas".into(),
+                callback: &|cmd: &mut Commands| {
+                    cmd.add(SkeletonDatabaseCommand::FileInSchemas);
+                },
+            },
             MenuEntryAction {
                 name: "File Out".into(),
                 callback: &|cmd: &mut Commands| {
                     cmd.add(SkeletonDatabaseCommand::FileOut);
                 },
             },
+            MenuEntryAction {
+                name: "File Out Schemas".into(),
+                callback: &|cmd: &mut Commands| {
+                    cmd.add(SkeletonDatabaseCommand::FileOutSchemas);
+                },
+            },
+            MenuEntryAction {
+                name: "Files".into(),
+                callback: &|cmd: &mut Commands| {
+                    cmd.add(SkeletonDatabaseCommand::Files);
+                },
+            },
+            MenuEntryAction {
+                name: "Link Library".into(),
+                callback: &|cmd: &mut Commands| {
+                    cmd.add(SkeletonDatabaseCommand::LinkLibrary);
+                },
+            },
             MenuEntryAction {
                 name: "Open".into(),
                 callback: &|cmd: &mut Commands| {
@@ -141,7 +239,11 @@ fn create_menu_entry(mut commands: Commands) {
 
 enum SkeletonDatabaseCommand {
     FileIn,
+    FileInSchemas,
     FileOut,
+    FileOutSchemas,
+    Files,
+    LinkLibrary,
     Open,
     CreateNew,
     Preview,
@@ -152,7 +254,11 @@ impl bevy::ecs::system::Command for SkeletonDatabaseCommand {
         let mut skeleton_db = world.get_resource_mut::<SkeletonDatabase>().unwrap();
         match *self {
             SkeletonDatabaseCommand::FileIn => skeleton_db.load_from_disk(),
+            SkeletonDatabaseCommand::FileInSchemas => skeleton_db.import_schemas(),
             SkeletonDatabaseCommand::FileOut => skeleton_db.save_to_disk(),
+            SkeletonDatabaseCommand::FileOutSchemas => skeleton_db.export_schemas(),
+            SkeletonDatabaseCommand::Files => skeleton_db.files_prompt(),
+            SkeletonDatabaseCommand::LinkLibrary => skeleton_db.library_prompt(),
             SkeletonDatabaseCommand::Open => skeleton_db.open_prompt(),
             SkeletonDatabaseCommand::CreateNew => skeleton_db.create_prompt(),
             SkeletonDatabaseCommand::Preview => skeleton_db.preview_prompt(),
@@ -160,12 +266,33 @@ impl bevy::ecs::system::Command for SkeletonDatabaseCommand {
     }
 }
 
+/// How far from the preview time, in seconds, a keyframe can be and still be the one removed.
+const KEYFRAME_TOLERANCE: f32 = 0.05;
+
 #[derive(Debug, Default, Clone)]
 struct SkeletonDatabase {
     skeletons: HashMap<String, skeleton::Definition>,
+    /// Link types the skeletons are built from.
+    links: skeleton_library::LinkLibrary,
+    /// Animation clips of each skeleton, by name.
+    clips: HashMap<String, HashMap<String, Clip>>,
     new_name: Option<String>,
     open_prompt: bool,
     preview_prompt: Option<String>,
+    preview_pose: skeleton::Pose,
+    preview_targets: std::collections::HashMap<skeleton::Effector, Vec3>,
+    preview_instance: Option<(String, Entity)>,
+    preview_clip: Option<String>,
+    preview_playback: Playback,
+    /// Set when the user picks a skeletons file other than the most recent one.
+    project_path: Option<std::path::PathBuf>,
+    recent_files: skeleton_persistence::RecentFiles,
+    /// Path being typed in the files window, when it is open.
+    files_prompt: Option<String>,
+    /// Errors to show to the user until dismissed.
+    errors: Vec<String>,
+    /// Name for a new link type being typed in the library window, when it is open.
+    library_prompt: Option<String>,
 }
 
 impl SkeletonDatabase {
@@ -179,41 +306,448 @@ impl SkeletonDatabase {
         }
     }
 
-    fn file_path() -> &'static std::path::Path {
-        std::path::Path::new("skeletons.json")
+    /// The skeletons file being edited: the one set by the user, or else the most recent one.
+    fn project_path(&self) -> std::path::PathBuf {
+        self.project_path
+            .clone()
+            .or_else(|| self.recent_files.paths().first().cloned())
+            .unwrap_or_else(|| skeleton_persistence::default_database_path().to_path_buf())
+    }
+
+    /// The link library of the skeletons file being edited.
+    fn library_path(&self) -> std::path::PathBuf {
+        skeleton_library::library_path(&self.project_path())
+    }
+
+    fn report_error(&mut self, error: String) {
+        warn!("{}", error);
+        self.errors.push(error);
+    }
+
+    fn load_recent_files(&mut self) {
+        let path = skeleton_persistence::recent_files_path();
+        if !path.exists() {
+            return;
+        }
+        match skeleton_persistence::RecentFiles::load(path) {
+            Ok(recent_files) => self.recent_files = recent_files,
+            Err(err) => self.report_error(format!("Failed to load recent files: {}", err)),
+        }
+    }
+
+    fn remember_project_path(&mut self) {
+        let path = self.project_path();
+        self.recent_files.push(&path);
+        if let Err(err) = self
+            .recent_files
+            .save(skeleton_persistence::recent_files_path())
+        {
+            self.report_error(format!("Failed to save recent files: {}", err));
+        }
+    }
+
+    /// Switches to the skeletons file in `path` and loads it. The current project is kept if the
+    /// file cannot be loaded.
+    fn open_file(&mut self, path: std::path::PathBuf) {
+        if !path.exists() {
+            self.report_error(format!("No skeletons file at {}", path.display()));
+            return;
+        }
+        self.load_from(path);
     }
 
     fn load_from_disk(&mut self) {
-        if Self::file_path().exists() {
-            info!("File exists, loading!");
-            let f = std::fs::File::open(Self::file_path()).expect("Failed to read skeletons file");
-            self.skeletons = serde_json::from_reader(f).expect("Failed to parse skeletons file");
-            info!("Loaded {} skeletons", self.skeletons.len());
+        let path = self.project_path();
+        if !path.exists() {
+            info!("No skeletons file at {}", path.display());
+            return;
+        }
+        self.load_from(path);
+    }
+
+    /// Loads the skeletons file in `path` and its link library, and makes it the project. Nothing
+    /// changes if either of them fails to load.
+    fn load_from(&mut self, path: std::path::PathBuf) {
+        let file = match skeleton_persistence::load(&path) {
+            Ok(file) => file,
+            Err(err) => {
+                self.report_error(format!("Failed to load {}: {}", path.display(), err));
+                return;
+            }
+        };
+        let library_path = skeleton_library::library_path(&path);
+        let links = match Self::load_library(&library_path) {
+            Ok(links) => links,
+            Err(err) => {
+                let path = library_path.display();
+                self.report_error(format!("Failed to load {}: {}", path, err));
+                return;
+            }
+        };
+        self.replace_project(file, links, path);
+        info!(
+            "Loaded {} skeletons from {}",
+            self.skeletons.len(),
+            self.project_path().display()
+        );
+        self.remember_project_path();
+    }
+
+    /// Makes `file` and `links` the project in `path`. The preview is closed, since the skeleton
+    /// it shows might not be in the new file.
+    fn replace_project(
+        &mut self,
+        file: skeleton_persistence::SkeletonFile,
+        links: skeleton_library::LinkLibrary,
+        path: std::path::PathBuf,
+    ) {
+        self.skeletons = file.skeletons.into_iter().collect();
+        self.clips = file
+            .clips
+            .into_iter()
+            .map(|(name, clips)| (name, clips.into_iter().collect()))
+            .collect();
+        self.links = links;
+        self.project_path = Some(path);
+        self.preview_prompt = None;
+        self.preview_pose = skeleton::Pose::default();
+        self.preview_targets.clear();
+        self.preview_instance = None;
+        self.preview_clip = None;
+        self.preview_playback = Playback::default();
+        self.update_skeletons();
+    }
+
+    /// Loads the link library in `path`, or the presets if there is none.
+    fn load_library(
+        path: &std::path::Path,
+    ) -> Result<skeleton_library::LinkLibrary, skeleton_persistence::DatabaseError> {
+        if path.exists() {
+            skeleton_library::LinkLibrary::load(path)
         } else {
-            warn!("File does not exist");
+            Ok(skeleton_library::LinkLibrary::default())
+        }
+    }
+
+    /// Replaces the link type `name` in the library and in every skeleton using it. Nothing is
+    /// changed if any of the skeletons cannot take the new link type.
+    fn update_link_type(&mut self, name: String, link_type: skeleton_library::LinkType) {
+        let mut links = self.links.clone();
+        links.insert(name.clone(), link_type);
+        let mut skeletons = self.skeletons.clone();
+        let mut names: Vec<_> = skeletons.keys().cloned().collect();
+        names.sort();
+        let mut failed = false;
+        for skeleton_name in names {
+            if let Err(err) = links.update(skeletons.get_mut(&skeleton_name).unwrap()) {
+                failed = true;
+                self.report_error(format!(
+                    "Link type {} cannot be changed, skeleton {} would be broken: {:?}",
+                    name, skeleton_name, err
+                ));
+            }
+        }
+        if !failed {
+            self.links = links;
+            self.skeletons = skeletons;
         }
     }
+
+    /// Brings the links of every skeleton up to date with the link library.
+    fn update_skeletons(&mut self) {
+        let mut names: Vec<_> = self.skeletons.keys().cloned().collect();
+        names.sort();
+        for name in names {
+            let skeleton = self.skeletons.get_mut(&name).unwrap();
+            if let Err(err) = self.links.update(skeleton) {
+                self.report_error(format!(
+                    "Skeleton {} cannot take the changes to its link types: {:?}",
+                    name, err
+                ));
+            }
+        }
+    }
+
+    /// Directory with the `.schema` files imported by `import_schemas` and written by
+    /// `export_schemas`.
+    fn schemas_path() -> &'static std::path::Path {
+        std::path::Path::new("skeletons")
+    }
+
+    /// Adds a skeleton for every `.schema` file, named after the file. Skeletons with the same
+    /// name are replaced, keeping the variable constraints and effectors that schemas lack.
+    fn import_schemas(&mut self) {
+        let entries = match std::fs::read_dir(Self::schemas_path()) {
+            Ok(entries) => entries,
+            Err(err) => {
+                let path = Self::schemas_path().display();
+                self.report_error(format!("Failed to read {}: {}", path, err));
+                return;
+            }
+        };
+        for path in entries.filter_map(|entry| Some(entry.ok()?.path())) {
+            if path.extension().map_or(true, |ext| ext != "schema") {
+                continue;
+            }
+            let name = match path.file_stem() {
+                Some(name) => name.to_string_lossy().into_owned(),
+                None => continue,
+            };
+            let text = match std::fs::read_to_string(&path) {
+                Ok(text) => text,
+                Err(err) => {
+                    self.report_error(format!("Failed to read {}: {}", path.display(), err));
+                    continue;
+                }
+            };
+            match skeleton_schema::from_schema(&text, &self.links) {
+                Ok(mut definition) => {
+                    if let Some(previous) = self.skeletons.get(&name) {
+                        let dropped = skeleton_schema::keep_constraints_and_effectors(
+                            previous,
+                            &mut definition,
+                        );
+                        if !dropped.is_empty() {
+                            self.report_error(format!(
+                                "Skeleton {} lost what does not fit the schema in {}: {:?}",
+                                name,
+                                path.display(),
+                                dropped
+                            ));
+                        }
+                    }
+                    info!("Imported skeleton {} from {}", name, path.display());
+                    self.skeletons.insert(name, definition);
+                }
+                Err(err) => {
+                    self.report_error(format!("Invalid schema {}: {}", path.display(), err))
+                }
+            }
+        }
+    }
+
+    /// Writes a `.schema` file for every skeleton, named after it. Skeletons with links that are
+    /// not in the library cannot be written, and are reported. So are the variable constraints and
+    /// effectors left out of the schemas.
+    fn export_schemas(&mut self) {
+        let directory = Self::schemas_path();
+        if let Err(err) = std::fs::create_dir_all(directory) {
+            self.report_error(format!("Failed to create {}: {}", directory.display(), err));
+            return;
+        }
+        let mut names: Vec<_> = self.skeletons.keys().cloned().collect();
+        names.sort();
+        for name in names {
+            let path = directory.join(format!("{}.schema", name));
+            let text = match skeleton_schema::to_schema(&self.skeletons[&name], &self.links) {
+                Ok(text) => text,
+                Err(err) => {
+                    self.report_error(format!("Cannot export skeleton {}: {}", name, err));
+                    continue;
+                }
+            };
+            let skeleton = &self.skeletons[&name];
+            if !skeleton.variable_constraints.is_empty() || !skeleton.effectors.is_empty() {
+                self.report_error(format!(
+                    "Schema {} leaves out the variable constraints and effectors of skeleton {}",
+                    path.display(),
+                    name
+                ));
+            }
+            match skeleton_persistence::write_safely(&path, &text) {
+                Ok(()) => info!("Exported skeleton {} to {}", name, path.display()),
+                Err(err) => {
+                    self.report_error(format!("Failed to save {}: {}", path.display(), err))
+                }
+            }
+        }
+    }
+
     fn save_to_disk(&mut self) {
-        info!("Writing to file!");
-        let f =
-            std::fs::File::create(Self::file_path()).expect("Failed to write to skeletons file");
-        serde_json::to_writer_pretty(f, &self.skeletons)
-            .expect("Failed to rialize to skeletons files");
-        info!("Wrote {} skeletons", self.skeletons.len());
+        let path = self.project_path();
+        let file = skeleton_persistence::SkeletonFile {
+            skeletons: self
+                .skeletons
+                .iter()
+                .map(|(name, skeleton)| (name.clone(), skeleton.clone()))
+                .collect(),
+            clips: self
+                .clips
+                .iter()
+                .map(|(name, clips)| (name.clone(), clips.clone().into_iter().collect()))
+                .collect(),
+        };
+        match skeleton_persistence::save(&file, &path) {
+            Ok(()) => {
+                info!(
+                    "Wrote {} skeletons to {}",
+                    self.skeletons.len(),
+                    path.display()
+                );
+                self.remember_project_path();
+            }
+            Err(err) => self.report_error(format!("Failed to save {}: {}", path.display(), err)),
+        }
+        let path = self.library_path();
+        if let Err(err) = self.links.save(&path) {
+            self.report_error(format!("Failed to save {}: {}", path.display(), err));
+        }
     }
+
+    fn files_prompt(&mut self) {
+        self.files_prompt = Some(self.project_path().display().to_string());
+    }
+
     fn open_prompt(&mut self) {
         self.open_prompt = true;
     }
+
+    fn library_prompt(&mut self) {
+        self.library_prompt = Some("".into());
+    }
+    /// Windows to pick the skeletons file and to show what went wrong with it.
+    fn render_files(egui_context: ResMut<EguiContext>, mut db: ResMut<Self>) {
+        let db = &mut *db;
+        if !db.errors.is_empty() {
+            let mut dismissed = None;
+            egui::Window::new("Skeleton Errors").show(egui_context.ctx(), |ui| {
+                for (i, error) in db.errors.iter().enumerate() {
+                    ui.horizontal(|ui| {
+                        ui.label(error.as_str());
+                        if ui.small_button("dismiss").clicked() {
+                            dismissed = Some(i);
+                        }
+                    });
+                }
+            });
+            if let Some(i) = dismissed {
+                db.errors.remove(i);
+            }
+        }
+
+        enum FileAction {
+            Load(std::path::PathBuf),
+            Save(std::path::PathBuf),
+        }
+        let mut action = None;
+        let mut window_open = true;
+        let recent_paths = db.recent_files.paths().to_vec();
+        if let Some(ref mut path) = db.files_prompt {
+            egui::Window::new("Skeleton Files")
+                .open(&mut window_open)
+                .show(egui_context.ctx(), |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label("Path");
+                        ui.text_edit_singleline(path);
+                    });
+                    ui.horizontal(|ui| {
+                        if ui.button("Load").clicked() {
+                            action = Some(FileAction::Load(path.as_str().into()));
+                        }
+                        if ui.button("Save").clicked() {
+                            action = Some(FileAction::Save(path.as_str().into()));
+                        }
+                    });
+                    ui.separator();
+                    ui.label("Recent files");
+                    for recent_path in recent_paths {
+                        if ui.small_button(recent_path.display().to_string()).clicked() {
+                            *path = recent_path.display().to_string();
+                            action = Some(FileAction::Load(recent_path));
+                        }
+                    }
+                });
+        }
+        if !window_open {
+            db.files_prompt = None;
+        }
+        match action {
+            Some(FileAction::Load(path)) => db.open_file(path),
+            Some(FileAction::Save(path)) => {
+                db.project_path = Some(path);
+                db.save_to_disk();
+            }
+            None => (),
+        }
+    }
+
+    /// Window listing the link types of the library, to edit them or add new ones.
+    fn render_library(
+        mut commands: Commands,
+        egui_context: ResMut<EguiContext>,
+        mut db: ResMut<Self>,
+    ) {
+        let db = &mut *db;
+        let mut window_open = true;
+        let mut link_type_to_add = None;
+        let mut link_type_to_delete = None;
+        let links = &db.links;
+        if let Some(ref mut new_name) = db.library_prompt {
+            egui::Window::new("Link Library")
+                .open(&mut window_open)
+                .show(egui_context.ctx(), |ui| {
+                    for name in links.names() {
+                        ui.horizontal(|ui| {
+                            ui.label(name.as_str());
+                            ui.separator();
+                            if ui.small_button("open").clicked() {
+                                let link_type = links.get(name).unwrap().clone();
+                                commands
+                                    .spawn()
+                                    .insert(LinkTypeEditor::for_link_type(name.clone(), link_type));
+                            }
+                            if ui.small_button("delete").clicked() {
+                                link_type_to_delete = Some(name.clone());
+                            }
+                        });
+                    }
+                    ui.separator();
+                    ui.horizontal(|ui| {
+                        ui.text_edit_singleline(new_name);
+                        let valid = !new_name.is_empty() && links.get(new_name).is_none();
+                        if ui.add(egui::Button::new("New").enabled(valid)).clicked() {
+                            link_type_to_add = Some(std::mem::take(new_name));
+                        }
+                    });
+                });
+        }
+        if let Some(name) = link_type_to_add {
+            // Links need a slot to hang from
+            let link_type = skeleton_library::LinkType {
+                slots: vec![('p', skeleton::Slot::default())].into_iter().collect(),
+            };
+            db.links.insert(name.clone(), link_type.clone());
+            commands
+                .spawn()
+                .insert(LinkTypeEditor::for_link_type(name, link_type));
+        }
+        if let Some(name) = link_type_to_delete {
+            db.links.remove(&name);
+        }
+        if !window_open {
+            db.library_prompt = None;
+        }
+    }
+
     fn render_stuff(
         mut commands: Commands,
+        time: Res<Time>,
         egui_context: ResMut<EguiContext>,
         mut db: ResMut<Self>,
+        players: Query<&AnimationPlayer>,
     ) {
         let SkeletonDatabase {
             ref mut skeletons,
+            ref mut clips,
             ref mut new_name,
             ref mut open_prompt,
             ref mut preview_prompt,
+            ref mut preview_pose,
+            ref mut preview_targets,
+            ref mut preview_instance,
+            ref mut preview_clip,
+            ref mut preview_playback,
+            ..
         } = *db;
         let mut clear_prompt = false;
         if let Some(ref mut new_name) = new_name {
@@ -231,13 +765,16 @@ impl SkeletonDatabase {
                         ui.label("Already exists a skeleton with the given name");
                         return;
                     }
-                    // if ui.button("Create").clicked() {
-                    //     skeletons.insert(new_name.clone(), skeleton::Definition::default());
-                    //     commands
-                    //         .spawn()
-                    //         .insert(SkeletonEditor::for_skeleton(new_name.clone()));
-                    //     clear_prompt = true;
-                    // }
+                    if ui.button("Create").clicked() {
+                        skeletons.insert(
+                            new_name.clone(),
+                            skeleton::Definition::new(skeleton::link::arm_base()),
+                        );
+                        commands
+                            .spawn()
+                            .insert(SkeletonEditor::for_skeleton(new_name.clone()));
+                        clear_prompt = true;
+                    }
                 });
             });
         }
@@ -255,12 +792,12 @@ impl SkeletonDatabase {
                         ui.horizontal(|ui| {
                             ui.label(name);
                             ui.separator();
-                            // if ui.small_button("open").clicked() {
-                            //     commands
-                            //         .spawn()
-                            //         .insert(SkeletonEditor::for_skeleton(name.clone()));
-                            //     *open_prompt = false;
-                            // }
+                            if ui.small_button("open").clicked() {
+                                commands
+                                    .spawn()
+                                    .insert(SkeletonEditor::for_skeleton(name.clone()));
+                                *open_prompt = false;
+                            }
                             if ui.small_button("delete").clicked() {
                                 skeleton_to_delete = Some(name.clone());
                             }
@@ -287,12 +824,202 @@ impl SkeletonDatabase {
                         });
 
                     let md = skeletons.get_mut(name).unwrap();
+                    // The spawned instance of the skeleton being previewed, if there is one
+                    let instance = preview_instance
+                        .as_ref()
+                        .filter(|(instance_name, _)| instance_name == name)
+                        .map(|(_, target)| *target);
+                    let mut instance_plays = false;
+
+                    let clips = clips.entry(name.clone()).or_default();
+                    ui.horizontal(|ui| {
+                        egui::ComboBox::from_label("Clip")
+                            .selected_text(preview_clip.as_deref().unwrap_or("none"))
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(preview_clip, None, "none");
+                                let mut clip_names: Vec<_> = clips.keys().cloned().collect();
+                                clip_names.sort();
+                                for n in clip_names {
+                                    ui.selectable_value(preview_clip, Some(n.clone()), n);
+                                }
+                            });
+                        if ui.button("New clip").clicked() {
+                            let clip_name = (1..)
+                                .map(|i| format!("clip {}", i))
+                                .find(|clip_name| !clips.contains_key(clip_name))
+                                .unwrap();
+                            clips.insert(clip_name.clone(), Clip::default());
+                            *preview_clip = Some(clip_name);
+                            *preview_playback = Playback {
+                                playing: false,
+                                ..Playback::default()
+                            };
+                        }
+                    });
+                    if let Some(clip) = preview_clip.as_ref().and_then(|n| clips.get_mut(n)) {
+                        ui.horizontal(|ui| {
+                            ui.checkbox(&mut preview_playback.playing, "Play");
+                            egui::ComboBox::from_label("Mode")
+                                .selected_text(format!("{:?}", clip.mode))
+                                .show_ui(ui, |ui| {
+                                    for mode in [
+                                        PlaybackMode::Once,
+                                        PlaybackMode::Loop,
+                                        PlaybackMode::PingPong,
+                                    ]
+                                    .iter()
+                                    {
+                                        ui.selectable_value(
+                                            &mut clip.mode,
+                                            *mode,
+                                            format!("{:?}", mode),
+                                        );
+                                    }
+                                });
+                        });
+                        // Stopped clips can be scrubbed a bit past their end, to key new poses there
+                        let mut clip_time = if preview_playback.playing {
+                            clip.local_time(preview_playback.time)
+                        } else {
+                            preview_playback.time
+                        };
+                        let scrubbed = ui
+                            .add(
+                                egui::Slider::new(&mut clip_time, 0.0..=clip.duration() + 1.0)
+                                    .text("time"),
+                            )
+                            .changed();
+                        if scrubbed {
+                            preview_playback.time = clip_time;
+                            preview_playback.playing = false;
+                        }
+                        ui.horizontal(|ui| {
+                            if ui.button("Key").clicked() {
+                                clip.insert_keyframe(clip_time, preview_pose.clone());
+                            }
+                            let near = clip.keyframe_near(clip_time, KEYFRAME_TOLERANCE);
+                            if ui
+                                .add(egui::Button::new("Remove key").enabled(near.is_some()))
+                                .clicked()
+                            {
+                                if let Some(index) = near {
+                                    clip.remove_keyframe(index);
+                                }
+                            }
+                            ui.label(format!("{} keyframes", clip.keyframes().len()));
+                        });
+                        let mut vars: Vec<_> = clip.variables().into_iter().collect();
+                        vars.sort();
+                        for var in vars {
+                            let mut interpolation = clip.interpolation(&var);
+                            egui::ComboBox::from_label(format!("{} interpolation", var))
+                                .selected_text(format!("{:?}", interpolation))
+                                .show_ui(ui, |ui| {
+                                    for i in [
+                                        Interpolation::Step,
+                                        Interpolation::Linear,
+                                        Interpolation::EaseInOut,
+                                    ]
+                                    .iter()
+                                    {
+                                        ui.selectable_value(
+                                            &mut interpolation,
+                                            *i,
+                                            format!("{:?}", i),
+                                        );
+                                    }
+                                });
+                            if interpolation != clip.interpolation(&var) {
+                                clip.interpolations.insert(var, interpolation);
+                            }
+                        }
+
+                        if preview_playback.playing {
+                            // A spawned instance plays the clip itself, and the preview follows it
+                            match instance.and_then(|target| players.get(target).ok()) {
+                                Some(player) => *preview_playback = player.playback.clone(),
+                                None => preview_playback.advance(clip, time.delta_seconds()),
+                            }
+                        }
+                        if preview_playback.playing || scrubbed {
+                            *preview_pose = clip.sample(preview_playback.time);
+                        }
+                        if let Some(target) = instance {
+                            commands.entity(target).insert(AnimationPlayer {
+                                clip: clip.clone(),
+                                playback: preview_playback.clone(),
+                            });
+                            instance_plays = preview_playback.playing;
+                        }
+                    } else if let Some(target) = instance {
+                        commands.entity(target).remove::<AnimationPlayer>();
+                    }
 
                     let mut vars: Vec<_> = md.variables().into_iter().collect();
                     vars.sort();
 
+                    *preview_pose = md.clamp_pose(preview_pose);
                     for var in vars {
-                        ui.label(var);
+                        let constraint = md.constraint(&var);
+                        // Unbounded variables get a full turn around their default value
+                        let default = constraint.default_value();
+                        let min = constraint.min.unwrap_or(default - std::f32::consts::PI);
+                        let max = constraint.max.unwrap_or(default + std::f32::consts::PI);
+                        let value = preview_pose.valuation.get_mut(&var).unwrap();
+                        ui.add(egui::Slider::new(value, min..=max).text(var));
+                    }
+
+                    let mut effectors: Vec<_> = md.effectors.keys().cloned().collect();
+                    effectors.sort();
+                    preview_targets.retain(|effector, _| md.effectors.contains_key(effector));
+                    let mut moved = false;
+                    for effector in effectors {
+                        let position = md.effector_position(&effector, preview_pose);
+                        let target = preview_targets
+                            .entry(effector.clone())
+                            .or_insert_with(|| position.unwrap_or_default());
+                        ui.horizontal(|ui| {
+                            ui.label(&effector);
+                            moved |= ui
+                                .add(egui::DragValue::new(&mut target.x).speed(0.05))
+                                .changed();
+                            moved |= ui
+                                .add(egui::DragValue::new(&mut target.y).speed(0.05))
+                                .changed();
+                            moved |= ui
+                                .add(egui::DragValue::new(&mut target.z).speed(0.05))
+                                .changed();
+                        });
+                    }
+                    if moved {
+                        match md.solve_ik(preview_targets, preview_pose) {
+                            Ok(solution) => *preview_pose = solution.pose,
+                            Err(err) => warn!("Failed to reach effector targets: {:?}", err),
+                        }
+                    }
+
+                    if ui.button("Spawn").clicked() {
+                        // Skeletons grow along Z, so they are turned to stand up along Y
+                        let target = commands
+                            .spawn_bundle((
+                                Transform::from_rotation(Quat::from_rotation_x(
+                                    -std::f32::consts::FRAC_PI_2,
+                                )),
+                                GlobalTransform::identity(),
+                            ))
+                            .id();
+                        commands.add(CreateInstance {
+                            definition: md.clone(),
+                            target,
+                            pose: preview_pose.clone(),
+                        });
+                        *preview_instance = Some((name.clone(), target));
+                    }
+                    if let Some(target) = instance.filter(|_| !instance_plays) {
+                        commands.add(UpdatePose {
+                            target,
+                            new_pose: preview_pose.clone(),
+                        });
                     }
                 });
         }
@@ -301,3 +1028,96 @@ impl SkeletonDatabase {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn files_that_fail_to_load_do_not_replace_the_project() {
+        let dir = std::env::temp_dir().join(format!(
+            "skeleton_database_failed_load_{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let project = dir.join("project.json");
+        let broken = dir.join("broken.json");
+        std::fs::write(&broken, "{ not json").unwrap();
+        let newer = dir.join("newer.json");
+        std::fs::write(&newer, r#"{ "version": 99, "skeletons": {} }"#).unwrap();
+
+        let mut db = SkeletonDatabase {
+            project_path: Some(project.clone()),
+            ..SkeletonDatabase::default()
+        };
+        db.skeletons.insert(
+            "arm".into(),
+            skeleton::Definition::new(skeleton::link::arm_base()),
+        );
+        let skeletons = db.skeletons.clone();
+
+        db.open_file(broken);
+        db.open_file(newer);
+        db.open_file(dir.join("missing.json"));
+        assert_eq!(db.project_path(), project);
+        assert_eq!(db.skeletons, skeletons);
+        assert_eq!(db.errors.len(), 3);
+        assert!(db.recent_files.paths().is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn opening_another_project_closes_the_preview() {
+        let mut db = SkeletonDatabase::default();
+        db.skeletons.insert(
+            "arm".into(),
+            skeleton::Definition::new(skeleton::link::arm_base()),
+        );
+        db.preview_prompt();
+        db.preview_clip = Some("wave".into());
+        db.preview_instance = Some(("arm".into(), Entity::new(0)));
+        assert_eq!(db.preview_prompt, Some("arm".into()));
+
+        let mut file = skeleton_persistence::SkeletonFile::default();
+        file.skeletons.insert(
+            "leg".into(),
+            skeleton::Definition::new(skeleton::link::arm_base()),
+        );
+        db.replace_project(file, db.links.clone(), "legs.json".into());
+        assert_eq!(db.skeletons.keys().collect::<Vec<_>>(), vec!["leg"]);
+        assert_eq!(db.preview_prompt, None);
+        assert_eq!(db.preview_clip, None);
+        assert_eq!(db.preview_instance, None);
+    }
+
+    #[test]
+    fn link_types_that_would_break_a_skeleton_are_not_applied() {
+        let mut db = SkeletonDatabase::default();
+        let mut arm = skeleton::Definition::new(skeleton::link::arm_base());
+        arm.apply(skeleton::Change::Add {
+            link: skeleton::link::l_link(),
+            to_parent_slot: skeleton::SlotId(0, 'n'),
+            joint: skeleton::Joint::Fixed,
+            local_slot_name: 'n',
+        })
+        .unwrap();
+        db.skeletons.insert("arm".into(), arm.clone());
+        let links = db.links.clone();
+
+        let mut short_link = links.get("l_link").unwrap().clone();
+        short_link.slots.remove(&'n');
+        db.update_link_type("l_link".into(), short_link);
+        assert_eq!(db.links, links);
+        assert_eq!(db.skeletons["arm"], arm);
+        assert_eq!(db.errors.len(), 1);
+
+        let mut short_link = links.get("l_link").unwrap().clone();
+        short_link.slots.remove(&'p');
+        db.update_link_type("l_link".into(), short_link.clone());
+        assert_eq!(db.links.get("l_link"), Some(&short_link));
+        assert_eq!(db.skeletons["arm"].links[&1].slots, short_link.slots);
+        assert_eq!(db.errors.len(), 1);
+    }
+}
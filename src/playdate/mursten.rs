@@ -1,15 +1,24 @@
 #[macro_use]
 pub use maplit::hashmap;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fmt::{Display, Write},
 };
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+use super::mursten_query::{search, Query, QueryMatch};
+
+#[derive(
+    Debug, Clone, Eq, PartialEq, Hash, Ord, PartialOrd, serde::Serialize, serde::Deserialize,
+)]
 pub struct ArtifactReference(String);
 pub fn ar<T: Into<String>>(x: T) -> ArtifactReference {
     ArtifactReference(x.into())
 }
+impl ArtifactReference {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
 
 impl Display for ArtifactReference {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -18,11 +27,18 @@ impl Display for ArtifactReference {
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(
+    Debug, Clone, Eq, PartialEq, Hash, Ord, PartialOrd, serde::Serialize, serde::Deserialize,
+)]
 pub struct SlotName(String);
 pub fn sn<T: Into<String>>(x: T) -> SlotName {
     SlotName(x.into())
 }
+impl SlotName {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
 impl Display for SlotName {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str("'")?;
@@ -30,11 +46,18 @@ impl Display for SlotName {
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(
+    Debug, Clone, Eq, PartialEq, Hash, Ord, PartialOrd, serde::Serialize, serde::Deserialize,
+)]
 pub struct SlotKind(String);
 pub fn sk<T: Into<String>>(x: T) -> SlotKind {
     SlotKind(x.into())
 }
+impl SlotKind {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
 
 impl Display for SlotKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -44,42 +67,192 @@ impl Display for SlotKind {
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+/// Declared relations between slot kinds. A slot accepts artifacts of its own kind, and of any
+/// kind declared (directly or through others) to be a subkind of it.
+#[derive(Debug, Clone, Default, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct KindLattice(BTreeMap<SlotKind, BTreeSet<SlotKind>>);
+
+impl KindLattice {
+    /// Declares `kind` as a subkind of `superkind`. Declarations that would make a kind a subkind
+    /// of itself are rejected.
+    pub fn declare(&mut self, kind: SlotKind, superkind: SlotKind) -> bool {
+        if self.accepts(&kind, &superkind) {
+            return false;
+        }
+        self.0.entry(kind).or_default().insert(superkind);
+        true
+    }
+
+    pub fn undeclare(&mut self, kind: &SlotKind, superkind: &SlotKind) -> bool {
+        let superkinds = match self.0.get_mut(kind) {
+            Some(superkinds) => superkinds,
+            None => return false,
+        };
+        let removed = superkinds.remove(superkind);
+        if superkinds.is_empty() {
+            self.0.remove(kind);
+        }
+        removed
+    }
+
+    /// Every declaration as `(kind, superkind)`, sorted.
+    pub fn declarations(&self) -> Vec<(SlotKind, SlotKind)> {
+        self.0
+            .iter()
+            .flat_map(|(kind, superkinds)| {
+                superkinds
+                    .iter()
+                    .map(move |superkind| (kind.clone(), superkind.clone()))
+            })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Kinds declared directly as superkinds of `kind`.
+    pub fn superkinds(&self, kind: &SlotKind) -> Vec<SlotKind> {
+        self.0
+            .get(kind)
+            .map_or(vec![], |superkinds| superkinds.iter().cloned().collect())
+    }
+
+    /// Whether a slot of kind `expected` accepts an artifact of kind `found`.
+    pub fn accepts(&self, expected: &SlotKind, found: &SlotKind) -> bool {
+        let mut seen = BTreeSet::new();
+        let mut to_see = vec![found];
+        while let Some(kind) = to_see.pop() {
+            if kind == expected {
+                return true;
+            }
+            if seen.insert(kind) {
+                to_see.extend(self.0.get(kind).into_iter().flatten());
+            }
+        }
+        false
+    }
+
+    /// Adds every declaration of `other` that does not contradict the ones already here, and
+    /// returns the ones that do, as `(kind, superkind)`.
+    pub fn merge(&mut self, other: &KindLattice) -> Vec<(SlotKind, SlotKind)> {
+        other
+            .declarations()
+            .into_iter()
+            .filter(|(kind, superkind)| !self.declare(kind.clone(), superkind.clone()))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Block {
     pub main_slot_kind: SlotKind,
+    #[serde(serialize_with = "serialize_ordered")]
     pub slots: HashMap<SlotName, SlotKind>,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum Artifact {
     Block(Block),
     Structure(Structure),
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Structure {
     pub a_ref: ArtifactReference,
+    #[serde(serialize_with = "serialize_ordered")]
     pub c: HashMap<SlotName, Connection>,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum Connection {
     Structure(Structure),
     Slot(SlotName),
 }
 
-#[derive(Debug)]
-pub enum ModelValidationError {
-    Artifact(ArtifactReference, ArtifactValidationError),
+/// Keeps serialized maps in a stable order, so saved models can be diffed.
+fn serialize_ordered<S, K, V>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+    K: Ord + serde::Serialize,
+    V: serde::Serialize,
+{
+    serde::Serialize::serialize(&map.iter().collect::<BTreeMap<_, _>>(), serializer)
 }
 
+/// Invalid artifacts of a model, with only the diagnostics that make them invalid.
 #[derive(Debug)]
-pub enum ArtifactValidationError {
-    GettingKindOf(GettingKindOfError),
-    GettingSlotOf(GettingSlotOfError),
-}
+pub struct ModelValidationError(pub Vec<(ArtifactReference, ArtifactValidationError)>);
 
 #[derive(Debug)]
+pub struct ArtifactValidationError(pub Vec<Diagnostic>);
+
+/// A problem found in an artifact. Most of them make the artifact (and the model) invalid, but a
+/// disconnected slot only means that the structure is not complete yet.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Diagnostic {
+    UnexistentArtifact(Location, ArtifactReference),
+    RecursionDetected(Vec<ArtifactReference>, ArtifactReference),
+    InvalidDependency(Location, ArtifactReference),
+    UnexistentSlot(Location, ArtifactReference, SlotName),
+    KindMismatch {
+        at: Location,
+        expected: SlotKind,
+        found: SlotKind,
+    },
+    DisconnectedSlot(Location, SlotKind),
+}
+
+impl Diagnostic {
+    pub fn is_error(&self) -> bool {
+        !matches!(self, Diagnostic::DisconnectedSlot(_, _))
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Diagnostic::UnexistentArtifact(at, aref) => {
+                write!(f, "{}{} does not exist", at.prefix(), aref)
+            }
+            Diagnostic::RecursionDetected(breadcrumb, aref) => {
+                f.write_str("Recursion detected: ")?;
+                for step in breadcrumb {
+                    write!(f, "{} -> ", step)?;
+                }
+                write!(f, "{}", aref)
+            }
+            Diagnostic::InvalidDependency(at, aref) => {
+                write!(f, "{}{} is not valid", at.prefix(), aref)
+            }
+            Diagnostic::UnexistentSlot(at, aref, slot_name) => {
+                write!(
+                    f,
+                    "{}{} does not have a {} slot",
+                    at.prefix(),
+                    aref,
+                    slot_name
+                )
+            }
+            Diagnostic::KindMismatch {
+                at,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{}expected {} but found {}",
+                at.prefix(),
+                expected,
+                found
+            ),
+            Diagnostic::DisconnectedSlot(at, slot_kind) => {
+                write!(f, "{}{} is disconnected", at.prefix(), slot_kind)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum GettingKindOfError {
     Unexistent(Vec<ArtifactReference>, ArtifactReference),
     RecursionDetected(Vec<ArtifactReference>, ArtifactReference),
@@ -87,6 +260,102 @@ pub enum GettingKindOfError {
 #[derive(Debug)]
 pub struct GettingSlotOfError(String);
 
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RefactorOperation {
+    RemoveBlockSlot,
+    RemoveArtifact,
+    RenameSlot,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RefactorError {
+    InvalidModel(RefactorOperation),
+    UnexistentArtifact(RefactorOperation, ArtifactReference),
+    UnexistentSlot(RefactorOperation, ArtifactReference, SlotName),
+    SlotAlreadyExists(ArtifactReference, SlotName),
+    NotABlock(ArtifactReference),
+    SlotInUse {
+        aref: ArtifactReference,
+        slot_name: SlotName,
+        dependents: Vec<ArtifactReference>,
+    },
+    ArtifactInUse {
+        aref: ArtifactReference,
+        dependents: Vec<ArtifactReference>,
+    },
+}
+
+impl Display for RefactorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RefactorError::InvalidModel(RefactorOperation::RemoveBlockSlot) => {
+                f.write_str("Cannot safely remove slot in an invalid model")
+            }
+            RefactorError::InvalidModel(RefactorOperation::RemoveArtifact) => {
+                f.write_str("Cannot safely remove an artifact from an invalid model")
+            }
+            RefactorError::InvalidModel(RefactorOperation::RenameSlot) => {
+                f.write_str("Cannot safely rename slot in an invalid model")
+            }
+            RefactorError::UnexistentArtifact(RefactorOperation::RenameSlot, aref) => {
+                write!(f, "Artifact {} does not exists ", aref)
+            }
+            RefactorError::UnexistentArtifact(_, aref) => {
+                write!(f, "Artifact {} does not exist", aref)
+            }
+            RefactorError::UnexistentSlot(RefactorOperation::RemoveBlockSlot, aref, slot_name) => {
+                write!(f, "Block {} does not have a {} slot", aref, slot_name)
+            }
+            RefactorError::UnexistentSlot(_, aref, slot_name) => {
+                write!(f, "Slot {} does not exists in {}", slot_name, aref)
+            }
+            RefactorError::SlotAlreadyExists(aref, slot_name) => {
+                write!(
+                    f,
+                    "Artifact {} already has a slot named {}",
+                    aref, slot_name
+                )
+            }
+            RefactorError::NotABlock(_) => f.write_str("Cannot remove slot of a structure"),
+            RefactorError::SlotInUse {
+                aref,
+                slot_name,
+                dependents,
+            } => write!(
+                f,
+                "Cannot remove slot {} from {} because is used by {}",
+                slot_name,
+                aref,
+                enumerate(dependents)
+            ),
+            RefactorError::ArtifactInUse { aref, dependents } => write!(
+                f,
+                "Cannot safely remove {} because is referenced by {}",
+                aref,
+                enumerate(dependents)
+            ),
+        }
+    }
+}
+
+/// Lists things in plain english, like "a, b and c".
+fn enumerate<T: Display>(things: &[T]) -> String {
+    match things.split_last() {
+        None => String::new(),
+        Some((last, [])) => last.to_string(),
+        Some((last, all_but_last)) => {
+            let all_but_last: Vec<_> = all_but_last.iter().map(|t| t.to_string()).collect();
+            format!("{} and {}", all_but_last.join(", "), last)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ExpansionError {
+    Unexistent(Vec<ArtifactReference>, ArtifactReference),
+    RecursionDetected(Vec<ArtifactReference>, ArtifactReference),
+}
+
 // TODO: Indicate which methods are safe to use (do not invalidate model), and which ones can panic or
 // overflow if the model is not valid.
 pub trait Model: Send + Sync {
@@ -96,42 +365,220 @@ pub trait Model: Send + Sync {
 
     // TODO: This could be an iterator to easily support infinite models...
     fn list_artifacts(&self) -> Vec<ArtifactReference>;
+
+    fn kinds(&self) -> &KindLattice;
+    fn set_kinds(&mut self, kinds: KindLattice);
+
     // ... and expose something like this to hint users of what to expect of this.
     fn is_finite(&self) -> bool {
         true
     }
 
+    /// Picks up changes done to the model from outside, like files edited by hand, and returns the
+    /// artifacts that were affected. Models that only live in memory never change by themselves.
+    fn reload(&mut self) -> Vec<ArtifactReference> {
+        vec![]
+    }
+
+    /// Problems found since the last call while writing or reloading the model from wherever it
+    /// is stored, described for the user. Models that only live in memory never have any.
+    fn take_errors(&mut self) -> Vec<String> {
+        vec![]
+    }
+
     fn exists_artifact(&self, aref: &ArtifactReference) -> bool {
         self.get_artifact(aref).is_some()
     }
 
+    /// Whether a slot of kind `expected` accepts an artifact of kind `found`, according to the
+    /// kinds declared in this model.
+    fn accepts_kind(&self, expected: &SlotKind, found: &SlotKind) -> bool {
+        self.kinds().accepts(expected, found)
+    }
+
+    /// Artifacts matching `query`, sorted by reference, with the reasons why they matched.
+    fn query(&self, query: &Query) -> Vec<QueryMatch> {
+        search(self, query)
+    }
+
+    /// A model is valid if none of its artifacts have errors, even if some structures have
+    /// disconnected slots.
     fn is_all_valid(&self) -> bool {
         self.validate_model().is_ok()
     }
 
+    /// A model is connected if none of its artifacts have any kind of problem.
+    fn is_all_connected(&self) -> bool {
+        self.diagnose_model().is_empty()
+    }
+
     fn validate_model(&self) -> Result<(), ModelValidationError> {
+        let mut invalid = vec![];
         for aref in self.list_artifacts().into_iter() {
-            self.validate(&aref)
-                .map_err(|err| ModelValidationError::Artifact(aref, err))?;
+            if let Err(err) = self.validate(&aref) {
+                invalid.push((aref, err));
+            }
+        }
+        invalid.sort_by(|a, b| a.0.cmp(&b.0));
+        if invalid.is_empty() {
+            Ok(())
+        } else {
+            Err(ModelValidationError(invalid))
         }
-        Ok(())
     }
 
     fn validate(&self, aref: &ArtifactReference) -> Result<(), ArtifactValidationError> {
-        self.main_slot_kind_of(aref)
-            .map_err(ArtifactValidationError::GettingKindOf)?;
-        self.slots_of(aref)
-            .map_err(ArtifactValidationError::GettingSlotOf)?;
-        Ok(())
+        let errors: Vec<_> = self
+            .diagnose(aref)
+            .into_iter()
+            .filter(Diagnostic::is_error)
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ArtifactValidationError(errors))
+        }
+    }
+
+    /// Every artifact with problems, sorted by reference.
+    fn diagnose_model(&self) -> Vec<(ArtifactReference, Vec<Diagnostic>)> {
+        let mut diagnostics: Vec<_> = self
+            .list_artifacts()
+            .into_iter()
+            .map(|aref| {
+                let diagnostics = self.diagnose(&aref);
+                (aref, diagnostics)
+            })
+            .filter(|(_, diagnostics)| !diagnostics.is_empty())
+            .collect();
+        diagnostics.sort_by(|a, b| a.0.cmp(&b.0));
+        diagnostics
+    }
+
+    fn diagnose(&self, aref: &ArtifactReference) -> Vec<Diagnostic> {
+        match self.get_artifact(aref) {
+            None => vec![Diagnostic::UnexistentArtifact(
+                Location::root(),
+                aref.clone(),
+            )],
+            Some(Artifact::Block(_)) => vec![],
+            Some(Artifact::Structure(structure)) => {
+                // Recursive artifacts cannot be inspected further without looping forever.
+                if let Some((breadcrumb, repeated)) = self.find_recursion(aref) {
+                    return vec![Diagnostic::RecursionDetected(breadcrumb, repeated)];
+                }
+                let mut diagnostics = vec![];
+                self.diagnose_structure(structure, Location::root(), &mut diagnostics);
+                diagnostics
+            }
+        }
+    }
+
+    fn diagnose_structure(
+        &self,
+        structure: &Structure,
+        location: Location,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) {
+        if !self.exists_artifact(&structure.a_ref) {
+            diagnostics.push(Diagnostic::UnexistentArtifact(
+                location,
+                structure.a_ref.clone(),
+            ));
+            return;
+        }
+        let available_slots = match self.slots_of(&structure.a_ref) {
+            Ok(slots) => slots,
+            Err(_) => {
+                diagnostics.push(Diagnostic::InvalidDependency(
+                    location,
+                    structure.a_ref.clone(),
+                ));
+                return;
+            }
+        };
+
+        let mut connected: Vec<_> = structure.c.keys().collect();
+        connected.sort();
+        for slot_name in connected {
+            if !available_slots.contains_key(slot_name) {
+                diagnostics.push(Diagnostic::UnexistentSlot(
+                    location.clone(),
+                    structure.a_ref.clone(),
+                    slot_name.clone(),
+                ));
+            }
+        }
+
+        let mut available_slots: Vec<_> = available_slots.into_iter().collect();
+        available_slots.sort();
+        for (slot_name, slot_kind) in available_slots {
+            let slot_location = location.child(slot_name.clone());
+            match structure.c.get(&slot_name) {
+                None => diagnostics.push(Diagnostic::DisconnectedSlot(slot_location, slot_kind)),
+                Some(Connection::Slot(_)) => (),
+                Some(Connection::Structure(substruct)) => {
+                    if let Ok(found) = self.main_slot_kind_of(&substruct.a_ref) {
+                        if !self.accepts_kind(&slot_kind, &found) {
+                            diagnostics.push(Diagnostic::KindMismatch {
+                                at: slot_location.clone(),
+                                expected: slot_kind,
+                                found,
+                            });
+                        }
+                    }
+                    self.diagnose_structure(substruct, slot_location, diagnostics);
+                }
+            }
+        }
+    }
+
+    /// Looks for a loop in the dependencies of `aref`, which might not include `aref` itself.
+    fn find_recursion(
+        &self,
+        aref: &ArtifactReference,
+    ) -> Option<(Vec<ArtifactReference>, ArtifactReference)> {
+        self.find_recursion_with_breadcrumb(aref, &mut vec![], &mut HashSet::new())
+    }
+    /// Artifacts in `explored` were already looked into without finding a loop, so they are not
+    /// looked into again. Otherwise, artifacts depended on through many paths would be explored
+    /// once per path.
+    fn find_recursion_with_breadcrumb(
+        &self,
+        aref: &ArtifactReference,
+        breadcrumb: &mut Vec<ArtifactReference>,
+        explored: &mut HashSet<ArtifactReference>,
+    ) -> Option<(Vec<ArtifactReference>, ArtifactReference)> {
+        if breadcrumb.contains(aref) {
+            return Some((breadcrumb.clone(), aref.clone()));
+        }
+        if explored.contains(aref) || !self.exists_artifact(aref) {
+            return None;
+        }
+        breadcrumb.push(aref.clone());
+        let mut direct_dependencies = self.direct_dependencies(aref);
+        direct_dependencies.sort();
+        direct_dependencies.dedup();
+        for dref in direct_dependencies.iter() {
+            let found = self.find_recursion_with_breadcrumb(dref, breadcrumb, explored);
+            if found.is_some() {
+                return found;
+            }
+        }
+        breadcrumb.pop();
+        explored.insert(aref.clone());
+        None
     }
 
     fn safely_remove_block_slot(
         &mut self,
         aref: &ArtifactReference,
         slot_name: &SlotName,
-    ) -> Result<(), String> {
+    ) -> Result<(), RefactorError> {
         if !self.is_all_valid() {
-            return Err("Cannot safely remove slot in an invalid model".into());
+            return Err(RefactorError::InvalidModel(
+                RefactorOperation::RemoveBlockSlot,
+            ));
         }
 
         match self.get_artifact(aref) {
@@ -154,35 +601,26 @@ pub trait Model: Send + Sync {
                             self.set_artifact(aref.clone(), Artifact::Block(block));
                             Ok(())
                         }
-                        None => Err(format!("Block {} does not have a {} slot", aref, slot_name)),
+                        None => Err(RefactorError::UnexistentSlot(
+                            RefactorOperation::RemoveBlockSlot,
+                            aref.clone(),
+                            slot_name.clone(),
+                        )),
                     }
                 } else {
-                    // TODO: This is probably implemented in some lib. If not, is worth moving to a
-                    // prelude as it will be probably used in many places.
-                    let references: String = if deps.len() == 1 {
-                        deps.first().unwrap().0.clone()
-                    } else {
-                        deps.sort_by(|a, b| a.0.cmp(&b.0));
-                        let all_but_last = deps
-                            .iter()
-                            .rev()
-                            .skip(1)
-                            .rev()
-                            .cloned()
-                            .map(|dep_aref| dep_aref.0)
-                            .collect::<Vec<_>>()
-                            .join(", ");
-                        let last = deps.last().unwrap().0.clone();
-                        format!("{} and {}", all_but_last, last)
-                    };
-                    Err(format!(
-                        "Cannot remove slot {} from {} because is used by {}",
-                        slot_name, aref, references
-                    ))
+                    deps.sort();
+                    Err(RefactorError::SlotInUse {
+                        aref: aref.clone(),
+                        slot_name: slot_name.clone(),
+                        dependents: deps,
+                    })
                 }
             }
-            None => Err(format!("Artifact {} does not exist", aref)),
-            Some(Artifact::Structure(_)) => Err("Cannot remove slot of a structure".into()),
+            None => Err(RefactorError::UnexistentArtifact(
+                RefactorOperation::RemoveBlockSlot,
+                aref.clone(),
+            )),
+            Some(Artifact::Structure(_)) => Err(RefactorError::NotABlock(aref.clone())),
         }
     }
 
@@ -214,38 +652,23 @@ pub trait Model: Send + Sync {
         }
     }
 
-    fn safely_remove_artifact(&mut self, aref: &ArtifactReference) -> Result<(), String> {
+    fn safely_remove_artifact(&mut self, aref: &ArtifactReference) -> Result<(), RefactorError> {
         if self.is_all_valid() {
             let mut deps = self.direct_dependents(aref);
             if deps.is_empty() {
                 self.remove_artifact(&aref);
                 Ok(())
             } else {
-                // TODO: This is probably implemented in some lib. If not, is worth moving to a
-                // prelude as it will be probably used in many places.
-                let references: String = if deps.len() == 1 {
-                    deps.first().unwrap().0.clone()
-                } else {
-                    deps.sort_by(|a, b| a.0.cmp(&b.0));
-                    let all_but_last = deps
-                        .iter()
-                        .rev()
-                        .skip(1)
-                        .rev()
-                        .cloned()
-                        .map(|dep_aref| dep_aref.0)
-                        .collect::<Vec<_>>()
-                        .join(", ");
-                    let last = deps.last().unwrap().0.clone();
-                    format!("{} and {}", all_but_last, last)
-                };
-                Err(format!(
-                    "Cannot safely remove {} because is referenced by {}",
-                    aref, references
-                ))
+                deps.sort();
+                Err(RefactorError::ArtifactInUse {
+                    aref: aref.clone(),
+                    dependents: deps,
+                })
             }
         } else {
-            Err("Cannot safely remove an artifact from an invalid model".into())
+            Err(RefactorError::InvalidModel(
+                RefactorOperation::RemoveArtifact,
+            ))
         }
     }
 
@@ -254,11 +677,10 @@ pub trait Model: Send + Sync {
         aref: &ArtifactReference,
         old_slot_name: &SlotName,
         new_slot_name: SlotName,
-    ) -> Result<(), String> {
-        match self
-            .get_artifact(aref)
-            .ok_or_else(|| format!("Artifact {} does not exists ", aref))?
-        {
+    ) -> Result<(), RefactorError> {
+        match self.get_artifact(aref).ok_or_else(|| {
+            RefactorError::UnexistentArtifact(RefactorOperation::RenameSlot, aref.clone())
+        })? {
             Artifact::Block(ref block) => {
                 let mut block = block.clone();
                 if let Some(slot_kind) = block.slots.get(old_slot_name).cloned() {
@@ -268,19 +690,41 @@ pub trait Model: Send + Sync {
                         self.set_artifact(aref.clone(), Artifact::Block(block));
                         Ok(())
                     } else {
-                        Err(format!(
-                            "Artifact {} already has a slot named {}",
-                            aref, new_slot_name
+                        Err(RefactorError::SlotAlreadyExists(
+                            aref.clone(),
+                            new_slot_name.clone(),
                         ))
                     }
                 } else {
-                    Err(format!(
-                        "Slot {} does not exists in {}",
-                        old_slot_name, aref
+                    Err(RefactorError::UnexistentSlot(
+                        RefactorOperation::RenameSlot,
+                        aref.clone(),
+                        old_slot_name.clone(),
+                    ))
+                }
+            }
+            Artifact::Structure(ref structure) => {
+                let mut structure = structure.clone();
+                let slots = self
+                    .slots_of(aref)
+                    .map_err(|_| RefactorError::InvalidModel(RefactorOperation::RenameSlot))?;
+                if !slots.contains_key(old_slot_name) {
+                    Err(RefactorError::UnexistentSlot(
+                        RefactorOperation::RenameSlot,
+                        aref.clone(),
+                        old_slot_name.clone(),
                     ))
+                } else if slots.contains_key(&new_slot_name) {
+                    Err(RefactorError::SlotAlreadyExists(
+                        aref.clone(),
+                        new_slot_name.clone(),
+                    ))
+                } else {
+                    structure.rename_exposed_slot(old_slot_name, &new_slot_name);
+                    self.set_artifact(aref.clone(), Artifact::Structure(structure));
+                    Ok(())
                 }
             }
-            Artifact::Structure(_structure) => todo!(),
         }
         .and_then(|_| {
             for dref in self.direct_dependents(aref) {
@@ -309,8 +753,11 @@ pub trait Model: Send + Sync {
         new_slot_name: SlotName,
     ) {
         if structure.a_ref == *aref {
-            let connection = structure.disconnect(Location(vec![]), old_slot_name);
-            structure.connect(Location(vec![]), &new_slot_name, connection);
+            if let Ok(connection) = structure.disconnect(&Location::root(), old_slot_name) {
+                structure
+                    .connect(&Location::root(), &new_slot_name, connection)
+                    .unwrap();
+            }
         }
 
         for (_slot_name, connection) in structure.c.iter_mut() {
@@ -355,6 +802,21 @@ pub trait Model: Send + Sync {
         &self,
         aref: &ArtifactReference,
     ) -> Result<HashMap<SlotName, SlotKind>, GettingSlotOfError> {
+        self.slots_of_with_breadcrumb(aref, vec![])
+    }
+    fn slots_of_with_breadcrumb(
+        &self,
+        aref: &ArtifactReference,
+        mut breadcrumb: Vec<ArtifactReference>,
+    ) -> Result<HashMap<SlotName, SlotKind>, GettingSlotOfError> {
+        if breadcrumb.contains(aref) {
+            let steps: Vec<_> = breadcrumb.iter().map(ToString::to_string).collect();
+            return Err(GettingSlotOfError(format!(
+                "Recursion detected: {} -> {}",
+                steps.join(" -> "),
+                aref
+            )));
+        }
         match self.get_artifact(aref).ok_or(GettingSlotOfError(format!(
             "Could not get artifact {}",
             aref
@@ -367,7 +829,8 @@ pub trait Model: Send + Sync {
                         aref
                     )));
                 }
-                self.slots_of_structure(structure)
+                breadcrumb.push(aref.clone());
+                self.slots_of_structure_with_breadcrumb(structure, breadcrumb)
             }
         }
     }
@@ -375,7 +838,14 @@ pub trait Model: Send + Sync {
         &self,
         structure: &Structure,
     ) -> Result<HashMap<SlotName, SlotKind>, GettingSlotOfError> {
-        let inner_slots = self.slots_of(&structure.a_ref)?;
+        self.slots_of_structure_with_breadcrumb(structure, vec![])
+    }
+    fn slots_of_structure_with_breadcrumb(
+        &self,
+        structure: &Structure,
+        breadcrumb: Vec<ArtifactReference>,
+    ) -> Result<HashMap<SlotName, SlotKind>, GettingSlotOfError> {
+        let inner_slots = self.slots_of_with_breadcrumb(&structure.a_ref, breadcrumb.clone())?;
 
         let mut slots = hashmap! {};
 
@@ -386,7 +856,10 @@ pub trait Model: Send + Sync {
                         slots.insert(outer_slot_name.clone(), slot_kind.clone());
                     }
                     Connection::Structure(child_structure) => {
-                        for (slot_name, slot_kind) in self.slots_of_structure(child_structure)? {
+                        for (slot_name, slot_kind) in self.slots_of_structure_with_breadcrumb(
+                            child_structure,
+                            breadcrumb.clone(),
+                        )? {
                             slots.insert(slot_name, slot_kind);
                         }
                     }
@@ -456,69 +929,459 @@ pub trait Model: Send + Sync {
         ds
     }
 
-    fn union(&self, other_model: &dyn Model) -> InMemoryModel {
-        todo!()
+    /// Inlines recursively every structure referenced by `aref`, so the resulting structure only
+    /// references blocks. Exposed slots keep the names they have in `aref`, and disconnected
+    /// slots stay disconnected.
+    fn expand(&self, aref: &ArtifactReference) -> Result<Structure, ExpansionError> {
+        self.expand_with_breadcrumb(aref, vec![])
+    }
+    fn expand_with_breadcrumb(
+        &self,
+        aref: &ArtifactReference,
+        mut breadcrumb: Vec<ArtifactReference>,
+    ) -> Result<Structure, ExpansionError> {
+        if breadcrumb.contains(aref) {
+            return Err(ExpansionError::RecursionDetected(breadcrumb, aref.clone()));
+        }
+        match self
+            .get_artifact(aref)
+            .ok_or_else(|| ExpansionError::Unexistent(breadcrumb.clone(), aref.clone()))?
+        {
+            Artifact::Block(block) => Ok(Structure {
+                a_ref: aref.clone(),
+                c: block
+                    .slots
+                    .keys()
+                    .map(|slot_name| (slot_name.clone(), Connection::Slot(slot_name.clone())))
+                    .collect(),
+            }),
+            Artifact::Structure(structure) => {
+                breadcrumb.push(aref.clone());
+                self.expand_structure_with_breadcrumb(structure, breadcrumb)
+            }
+        }
+    }
+    fn expand_structure_with_breadcrumb(
+        &self,
+        structure: &Structure,
+        breadcrumb: Vec<ArtifactReference>,
+    ) -> Result<Structure, ExpansionError> {
+        let mut expanded_connections = HashMap::new();
+        for (slot_name, connection) in structure.c.iter() {
+            if let Connection::Structure(substruct) = connection {
+                let expanded =
+                    self.expand_structure_with_breadcrumb(substruct, breadcrumb.clone())?;
+                expanded_connections.insert(slot_name.clone(), Connection::Structure(expanded));
+            } else {
+                expanded_connections.insert(slot_name.clone(), connection.clone());
+            }
+        }
+        let mut expanded = self.expand_with_breadcrumb(&structure.a_ref, breadcrumb)?;
+        expanded.plug(&expanded_connections);
+        Ok(expanded)
     }
-}
 
-pub struct Location(Vec<SlotName>);
+    /// Merges this model with `other_model` into a new `InMemoryModel`. When both models define
+    /// the same artifact differently the one in `self` is kept, and the conflict is reported.
+    /// Subkind declarations of `other_model` that contradict the ones of `self` are left out and
+    /// reported too.
+    fn union(&self, other_model: &dyn Model) -> ModelUnion {
+        let mut model = InMemoryModel::default();
+        let mut kinds = self.kinds().clone();
+        let kind_conflicts = kinds
+            .merge(other_model.kinds())
+            .into_iter()
+            .map(|(kind, superkind)| KindConflict { kind, superkind })
+            .collect();
+        model.set_kinds(kinds);
+        for aref in self.list_artifacts() {
+            let artifact = self.get_artifact(&aref).unwrap().clone();
+            model.set_artifact(aref, artifact);
+        }
 
-impl Artifact {
-    fn composite(&self) -> bool {
-        if let Artifact::Structure(_) = self {
-            true
-        } else {
-            false
+        let mut conflicts = vec![];
+        for aref in other_model.list_artifacts() {
+            let theirs = other_model.get_artifact(&aref).unwrap().clone();
+            match model.get_artifact(&aref) {
+                None => model.set_artifact(aref, theirs),
+                Some(ours) if *ours == theirs => (),
+                Some(ours) => conflicts.push(UnionConflict {
+                    aref,
+                    ours: ours.clone(),
+                    theirs,
+                    taking_theirs_invalidates_model: false,
+                }),
+            }
+        }
+        conflicts.sort_by(|a, b| a.aref.0.cmp(&b.aref.0));
+
+        // A merged model that is already invalid cannot be invalidated by taking theirs
+        let was_valid = model.is_all_valid();
+        for conflict in conflicts.iter_mut() {
+            model.set_artifact(conflict.aref.clone(), conflict.theirs.clone());
+            conflict.taking_theirs_invalidates_model = was_valid && !model.is_all_valid();
+            model.set_artifact(conflict.aref.clone(), conflict.ours.clone());
+        }
+
+        let validation = model.validate_model();
+        ModelUnion {
+            model,
+            conflicts,
+            kind_conflicts,
+            validation,
         }
     }
 }
 
-impl Structure {
-    fn swap(&mut self, target: Location, new_aref: ArtifactReference) {
-        todo!();
-    }
+#[derive(Debug)]
+pub struct ModelUnion {
+    pub model: InMemoryModel,
+    pub conflicts: Vec<UnionConflict>,
+    pub kind_conflicts: Vec<KindConflict>,
+    pub validation: Result<(), ModelValidationError>,
+}
 
-    fn disconnect(&mut self, target: Location, slot_name: &SlotName) -> Connection {
-        self.c.remove(slot_name).unwrap()
+impl ModelUnion {
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty() && self.kind_conflicts.is_empty() && self.validation.is_ok()
     }
+}
+
+/// A subkind declaration of the other model in a union, which would make `kind` a subkind of
+/// itself given the declarations of the model it is merged into.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct KindConflict {
+    pub kind: SlotKind,
+    pub superkind: SlotKind,
+}
 
-    fn connect(&mut self, target: Location, slot_name: &SlotName, connection: Connection) {
-        self.c.insert(slot_name.clone(), connection);
+impl Display for KindConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} cannot be a subkind of {}, because it would be a subkind of itself",
+            self.kind, self.superkind
+        )
     }
 }
 
-#[derive(Debug, Default)]
-pub struct InMemoryModel(HashMap<ArtifactReference, Artifact>);
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UnionConflict {
+    pub aref: ArtifactReference,
+    pub ours: Artifact,
+    pub theirs: Artifact,
+    pub taking_theirs_invalidates_model: bool,
+}
 
-impl Model for InMemoryModel {
-    fn set_artifact(&mut self, aref: ArtifactReference, artifact: Artifact) {
-        self.0.insert(aref, artifact);
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum UnionConflictKind {
+    DifferentBlocks,
+    DifferentStructures,
+    BlockAgainstStructure,
+}
+
+impl UnionConflict {
+    pub fn kind(&self) -> UnionConflictKind {
+        match (&self.ours, &self.theirs) {
+            (Artifact::Block(_), Artifact::Block(_)) => UnionConflictKind::DifferentBlocks,
+            (Artifact::Structure(_), Artifact::Structure(_)) => {
+                UnionConflictKind::DifferentStructures
+            }
+            _ => UnionConflictKind::BlockAgainstStructure,
+        }
     }
+}
 
-    fn remove_artifact(&mut self, aref: &ArtifactReference) {
-        self.0.remove(aref);
+impl Display for UnionConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let what = match self.kind() {
+            UnionConflictKind::DifferentBlocks => "two different blocks",
+            UnionConflictKind::DifferentStructures => "two different structures",
+            UnionConflictKind::BlockAgainstStructure => "a block and a structure",
+        };
+        write!(f, "{} is defined as {}", self.aref, what)?;
+        if self.taking_theirs_invalidates_model {
+            f.write_str(" (taking the other one invalidates the model)")?;
+        }
+        Ok(())
     }
+}
 
-    fn get_artifact(&self, aref: &ArtifactReference) -> Option<&Artifact> {
-        self.0.get(aref)
+/// Path of slot names to follow from the root of a structure to reach one of its nested parts.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct Location(pub Vec<SlotName>);
+
+impl Location {
+    pub fn root() -> Self {
+        Self(vec![])
     }
 
-    fn list_artifacts(&self) -> Vec<ArtifactReference> {
-        self.0.keys().cloned().collect()
+    pub fn child(&self, slot_name: SlotName) -> Self {
+        let mut path = self.0.clone();
+        path.push(slot_name);
+        Self(path)
     }
-}
 
-pub mod example {
-    use super::*;
+    fn prefix(&self) -> String {
+        if self.0.is_empty() {
+            String::new()
+        } else {
+            format!("At {}: ", self)
+        }
+    }
+}
 
-    pub fn empty_test_model() -> InMemoryModel {
-        InMemoryModel(hashmap! {})
+impl Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return f.write_str("/");
+        }
+        for slot_name in self.0.iter() {
+            write!(f, "/{}", slot_name)?;
+        }
+        Ok(())
     }
+}
 
-    pub fn peano_model() -> InMemoryModel {
-        let mut model = empty_test_model();
-        model.set_artifact(
-            ar("successor"),
+impl Artifact {
+    fn composite(&self) -> bool {
+        if let Artifact::Structure(_) = self {
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum StructureEditError {
+    /// The location goes through a slot that is not connected to a structure.
+    UnexistentLocation(Location),
+    NotConnected(Location, SlotName),
+    /// The artifact of the structure at the location has no slot with that name.
+    UnexistentSlot(Location, SlotName),
+    /// The slots of the artifact of the structure at the location cannot be told.
+    Slots(Location, String),
+    Kind(GettingKindOfError),
+    KindMismatch {
+        at: Location,
+        expected: SlotKind,
+        found: SlotKind,
+    },
+}
+
+impl Display for StructureEditError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StructureEditError::UnexistentLocation(location) => {
+                write!(f, "There is no structure at {}", location)
+            }
+            StructureEditError::NotConnected(location, slot_name) => {
+                write!(f, "{}{} is not connected", location.prefix(), slot_name)
+            }
+            StructureEditError::UnexistentSlot(location, slot_name) => {
+                write!(f, "{}there is no slot {}", location.prefix(), slot_name)
+            }
+            StructureEditError::Slots(location, message) => {
+                write!(f, "{}{}", location.prefix(), message)
+            }
+            StructureEditError::Kind(GettingKindOfError::Unexistent(_, aref)) => {
+                write!(f, "Artifact {} does not exist", aref)
+            }
+            StructureEditError::Kind(GettingKindOfError::RecursionDetected(_, aref)) => {
+                write!(
+                    f,
+                    "Cannot tell the kind of {} because it depends on itself",
+                    aref
+                )
+            }
+            StructureEditError::KindMismatch {
+                at,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{}expected {} but found {}",
+                at.prefix(),
+                expected,
+                found
+            ),
+        }
+    }
+}
+
+impl Structure {
+    pub fn at(&self, target: &Location) -> Result<&Structure, StructureEditError> {
+        let mut structure = self;
+        for (depth, slot_name) in target.0.iter().enumerate() {
+            structure = match structure.c.get(slot_name) {
+                Some(Connection::Structure(substruct)) => substruct,
+                _ => {
+                    return Err(StructureEditError::UnexistentLocation(Location(
+                        target.0[..=depth].to_vec(),
+                    )))
+                }
+            };
+        }
+        Ok(structure)
+    }
+
+    pub fn at_mut(&mut self, target: &Location) -> Result<&mut Structure, StructureEditError> {
+        let mut structure = self;
+        for (depth, slot_name) in target.0.iter().enumerate() {
+            structure = match structure.c.get_mut(slot_name) {
+                Some(Connection::Structure(substruct)) => substruct,
+                _ => {
+                    return Err(StructureEditError::UnexistentLocation(Location(
+                        target.0[..=depth].to_vec(),
+                    )))
+                }
+            };
+        }
+        Ok(structure)
+    }
+
+    /// Changes the artifact of the structure at `target`, keeping its connections. The new
+    /// artifact must be accepted by the slot it is plugged in, or have the same main slot kind as
+    /// the one it replaces when swapping the root. Returns the replaced artifact reference.
+    pub fn swap(
+        &mut self,
+        target: &Location,
+        new_aref: ArtifactReference,
+        model: &dyn Model,
+    ) -> Result<ArtifactReference, StructureEditError> {
+        let slot_kind = match target.0.split_last() {
+            Some((slot_name, parent_path)) => {
+                let parent_location = Location(parent_path.to_vec());
+                let parent = self.at(&parent_location)?;
+                let slots = model
+                    .slots_of(&parent.a_ref)
+                    .map_err(|err| StructureEditError::Slots(parent_location.clone(), err.0))?;
+                let slot_kind = slots.get(slot_name).cloned().ok_or_else(|| {
+                    StructureEditError::UnexistentSlot(parent_location, slot_name.clone())
+                })?;
+                Some(slot_kind)
+            }
+            None => None,
+        };
+        let structure = self.at_mut(target)?;
+        let expected = match slot_kind {
+            Some(slot_kind) => slot_kind,
+            None => model
+                .main_slot_kind_of(&structure.a_ref)
+                .map_err(StructureEditError::Kind)?,
+        };
+        let found = model
+            .main_slot_kind_of(&new_aref)
+            .map_err(StructureEditError::Kind)?;
+        if !model.accepts_kind(&expected, &found) {
+            return Err(StructureEditError::KindMismatch {
+                at: target.clone(),
+                expected,
+                found,
+            });
+        }
+        Ok(std::mem::replace(&mut structure.a_ref, new_aref))
+    }
+
+    pub fn disconnect(
+        &mut self,
+        target: &Location,
+        slot_name: &SlotName,
+    ) -> Result<Connection, StructureEditError> {
+        self.at_mut(target)?
+            .c
+            .remove(slot_name)
+            .ok_or_else(|| StructureEditError::NotConnected(target.clone(), slot_name.clone()))
+    }
+
+    /// Connects `connection` to a slot of the structure at `target`, returning what was connected
+    /// there before.
+    pub fn connect(
+        &mut self,
+        target: &Location,
+        slot_name: &SlotName,
+        connection: Connection,
+    ) -> Result<Option<Connection>, StructureEditError> {
+        Ok(self.at_mut(target)?.c.insert(slot_name.clone(), connection))
+    }
+
+    /// Renames a slot this structure exposes, wherever it is exposed.
+    fn rename_exposed_slot(&mut self, old_slot_name: &SlotName, new_slot_name: &SlotName) {
+        for connection in self.c.values_mut() {
+            match connection {
+                Connection::Slot(exposed_slot_name) => {
+                    if exposed_slot_name == old_slot_name {
+                        *exposed_slot_name = new_slot_name.clone();
+                    }
+                }
+                Connection::Structure(substruct) => {
+                    substruct.rename_exposed_slot(old_slot_name, new_slot_name)
+                }
+            }
+        }
+    }
+
+    /// Replaces every exposed slot of this structure by the connection given for it, leaving it
+    /// disconnected if there is none.
+    fn plug(&mut self, connections: &HashMap<SlotName, Connection>) {
+        let c = std::mem::take(&mut self.c);
+        for (slot_name, connection) in c {
+            match connection {
+                Connection::Slot(exposed_slot_name) => {
+                    if let Some(connection) = connections.get(&exposed_slot_name) {
+                        self.c.insert(slot_name, connection.clone());
+                    }
+                }
+                Connection::Structure(mut substruct) => {
+                    substruct.plug(connections);
+                    self.c.insert(slot_name, Connection::Structure(substruct));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryModel(HashMap<ArtifactReference, Artifact>, KindLattice);
+
+impl Model for InMemoryModel {
+    fn set_artifact(&mut self, aref: ArtifactReference, artifact: Artifact) {
+        self.0.insert(aref, artifact);
+    }
+
+    fn remove_artifact(&mut self, aref: &ArtifactReference) {
+        self.0.remove(aref);
+    }
+
+    fn get_artifact(&self, aref: &ArtifactReference) -> Option<&Artifact> {
+        self.0.get(aref)
+    }
+
+    fn list_artifacts(&self) -> Vec<ArtifactReference> {
+        self.0.keys().cloned().collect()
+    }
+
+    fn kinds(&self) -> &KindLattice {
+        &self.1
+    }
+
+    fn set_kinds(&mut self, kinds: KindLattice) {
+        self.1 = kinds;
+    }
+}
+
+pub mod example {
+    use super::*;
+
+    pub fn empty_test_model() -> InMemoryModel {
+        InMemoryModel::default()
+    }
+
+    pub fn peano_model() -> InMemoryModel {
+        let mut model = empty_test_model();
+        model.set_artifact(
+            ar("successor"),
             Artifact::Block(Block {
                 main_slot_kind: sk("Natural"),
                 slots: hashmap! {
@@ -536,13 +1399,22 @@ pub mod example {
         model
     }
 
-    pub fn peano_eval<M: Model>(aref: &ArtifactReference, model: &M) -> usize {
-        if *aref == ar("number_2") {
-            2
-        } else if *aref == ar("number_4") {
-            4
+    /// The number `aref` stands for, or `None` if it cannot be expanded or is not a chain of
+    /// successors ending in zero, like a number with a disconnected slot.
+    pub fn peano_eval<M: Model>(aref: &ArtifactReference, model: &M) -> Option<usize> {
+        let mut structure = model.expand(aref).ok()?;
+        let mut n = 0;
+        while structure.a_ref == ar("successor") {
+            n += 1;
+            structure = match structure.c.remove(&sn("x")) {
+                Some(Connection::Structure(s)) => s,
+                _ => return None,
+            };
+        }
+        if structure.a_ref == ar("zero") {
+            Some(n)
         } else {
-            todo!("Not implemented")
+            None
         }
     }
 
@@ -604,7 +1476,7 @@ pub mod example {
         );
         model.validate_model().unwrap();
         assert!(model.slots_of(&ar("number_2")).unwrap().is_empty());
-        assert_eq!(peano_eval(&ar("number_2"), &model), 2);
+        assert_eq!(peano_eval(&ar("number_2"), &model), Some(2));
     }
 
     pub fn and_one_more_is_five_model() -> InMemoryModel {
@@ -794,7 +1666,9 @@ mod test {
         );
         assert!(model.slots_of(&ar("number_4")).unwrap().is_empty());
 
-        assert_eq!(peano_eval(&ar("number_4"), &model), 4);
+        assert_eq!(peano_eval(&ar("number_4"), &model), Some(4));
+        assert_eq!(peano_eval(&ar("plus_2"), &model), None);
+        assert_eq!(peano_eval(&ar("Natural"), &model), None);
     }
 
     #[test]
@@ -805,7 +1679,9 @@ mod test {
         let res = model.safely_remove_artifact(&ar("number_4"));
         assert_eq!(
             res,
-            Err("Cannot safely remove an artifact from an invalid model".into())
+            Err(RefactorError::InvalidModel(
+                RefactorOperation::RemoveArtifact
+            ))
         );
         assert!(model.list_artifacts().contains(&ar("number_4")));
     }
@@ -825,7 +1701,10 @@ mod test {
         let res = model.safely_remove_artifact(&ar("zero"));
         assert_eq!(
             res,
-            Err("Cannot safely remove zero because is referenced by number_4".into())
+            Err(RefactorError::ArtifactInUse {
+                aref: ar("zero"),
+                dependents: vec![ar("number_4")],
+            })
         );
         model.validate_model().unwrap();
         assert!(model.list_artifacts().contains(&ar("zero")));
@@ -838,10 +1717,10 @@ mod test {
         let res = model.safely_remove_artifact(&ar("successor"));
         assert_eq!(
             res,
-            Err(
-                "Cannot safely remove successor because is referenced by number_5 and plus_2"
-                    .into()
-            )
+            Err(RefactorError::ArtifactInUse {
+                aref: ar("successor"),
+                dependents: vec![ar("number_5"), ar("plus_2")],
+            })
         );
         model.validate_model().unwrap();
         assert!(model.list_artifacts().contains(&ar("zero")));
@@ -864,6 +1743,31 @@ mod test {
             .contains_key(&sn("y")));
     }
 
+    #[test]
+    fn can_rename_slot_exposed_by_a_structure() {
+        let mut model = and_one_more_is_five_model();
+        model
+            .rename_slot(&ar("plus_2"), &sn("x"), sn("n"))
+            .expect("Should be able to rename it");
+        model.validate_model().unwrap();
+        assert_eq!(
+            model.slots_of(&ar("plus_2")).unwrap(),
+            hashmap! { sn("n") => sk("Natural") }
+        );
+        assert_eq!(peano_eval(&ar("number_4"), &model), Some(4));
+        assert_eq!(peano_eval(&ar("number_5"), &model), Some(5));
+
+        let res = model.rename_slot(&ar("plus_2"), &sn("x"), sn("y"));
+        assert_eq!(
+            res,
+            Err(RefactorError::UnexistentSlot(
+                RefactorOperation::RenameSlot,
+                ar("plus_2"),
+                sn("x")
+            ))
+        );
+    }
+
     #[test]
     fn cannot_rename_slot_into_an_already_existent_name() {
         // This includes renaming a slot to the same name for now
@@ -872,7 +1776,7 @@ mod test {
 
         assert_eq!(
             res,
-            Err("Artifact successor already has a slot named 'x".into())
+            Err(RefactorError::SlotAlreadyExists(ar("successor"), sn("x")))
         );
         model.validate_model().unwrap();
         assert!(model
@@ -886,7 +1790,14 @@ mod test {
         let mut model = peano_model();
         let res = model.rename_slot(&ar("zero"), &sn("z"), sn("y"));
 
-        assert_eq!(res, Err("Slot 'z does not exists in zero".into()));
+        assert_eq!(
+            res,
+            Err(RefactorError::UnexistentSlot(
+                RefactorOperation::RenameSlot,
+                ar("zero"),
+                sn("z")
+            ))
+        );
         model.validate_model().unwrap();
         assert!(model.slots_of(&ar("zero")).unwrap().is_empty());
     }
@@ -927,7 +1838,14 @@ mod test {
     fn cannot_delete_an_unexisting_slot() {
         let mut model = peano_model();
         let res = model.safely_remove_block_slot(&ar("zero"), &sn("q"));
-        assert_eq!(res, Err("Block zero does not have a 'q slot".into()));
+        assert_eq!(
+            res,
+            Err(RefactorError::UnexistentSlot(
+                RefactorOperation::RemoveBlockSlot,
+                ar("zero"),
+                sn("q")
+            ))
+        );
         model.validate_model().unwrap();
         assert!(model
             .slots_of(&ar("successor"))
@@ -939,7 +1857,13 @@ mod test {
     fn cannot_delete_slot_from_an_unexistent_artifact() {
         let mut model = peano_model();
         let res = model.safely_remove_block_slot(&ar("wibblidy"), &sn("woob"));
-        assert_eq!(res, Err("Artifact wibblidy does not exist".into()));
+        assert_eq!(
+            res,
+            Err(RefactorError::UnexistentArtifact(
+                RefactorOperation::RemoveBlockSlot,
+                ar("wibblidy")
+            ))
+        );
         model.validate_model().unwrap();
     }
 
@@ -947,7 +1871,7 @@ mod test {
     fn cannot_delete_slot_from_a_structure() {
         let mut model = and_one_more_is_five_model();
         let res = model.safely_remove_block_slot(&ar("plus_2"), &sn("x"));
-        assert_eq!(res, Err("Cannot remove slot of a structure".into()));
+        assert_eq!(res, Err(RefactorError::NotABlock(ar("plus_2"))));
         model.validate_model().unwrap();
     }
 
@@ -957,10 +1881,11 @@ mod test {
         let res = model.safely_remove_block_slot(&ar("successor"), &sn("x"));
         assert_eq!(
             res,
-            Err(
-                "Cannot remove slot 'x from successor because is used by number_5 and plus_2"
-                    .into()
-            )
+            Err(RefactorError::SlotInUse {
+                aref: ar("successor"),
+                slot_name: sn("x"),
+                dependents: vec![ar("number_5"), ar("plus_2")],
+            })
         );
         model.validate_model().unwrap();
         assert!(model
@@ -976,7 +1901,9 @@ mod test {
         let res = model.safely_remove_block_slot(&ar("successor"), &sn("x"));
         assert_eq!(
             res,
-            Err("Cannot safely remove slot in an invalid model".into())
+            Err(RefactorError::InvalidModel(
+                RefactorOperation::RemoveBlockSlot
+            ))
         );
         assert!(model
             .slots_of(&ar("successor"))
@@ -997,7 +1924,8 @@ mod test {
                 },
             }),
         );
-        // TODO: Assert that model here is 'disconnected' but not that much invalid
+        assert!(model.is_all_valid());
+        assert!(!model.is_all_connected());
         model
             .safely_remove_block_slot(&ar("successor"), &sn("step"))
             .expect("Should be able to remove it");
@@ -1008,14 +1936,827 @@ mod test {
             .contains_key(&sn("x")));
         assert_eq!(model.slots_of(&ar("successor")).unwrap().len(), 1);
     }
-}
 
-#[test]
-#[ignore]
-fn roadmap() {
-    todo!("Separate concept of 'disconnected model' from the 'invalid model'");
-    todo!("Define commands and events (results of those commands)");
-    todo!("Check if a recursion can happen if the mutal recursion loop is longer (add breadcrumb to implementation and also improve errors)");
+    #[test]
+    fn a_structure_with_disconnected_slots_is_valid_but_not_connected() {
+        let mut model = peano_model();
+        model.set_artifact(
+            ar("something_more"),
+            Artifact::Structure(Structure {
+                a_ref: ar("successor"),
+                c: hashmap! {},
+            }),
+        );
+        model.validate_model().unwrap();
+        assert!(!model.is_all_connected());
+        assert_eq!(
+            model.diagnose_model(),
+            vec![(
+                ar("something_more"),
+                vec![Diagnostic::DisconnectedSlot(
+                    Location(vec![sn("x")]),
+                    sk("Natural")
+                )]
+            )]
+        );
+        assert_eq!(
+            model.diagnose(&ar("something_more"))[0].to_string(),
+            "At /'x: [Natural] is disconnected"
+        );
+
+        model
+            .safely_remove_artifact(&ar("something_more"))
+            .expect("Should be able to remove it from a disconnected model");
+        assert!(model.is_all_connected());
+    }
+
+    #[test]
+    fn connecting_an_artifact_of_another_kind_is_an_error() {
+        let mut model = peano_model();
+        model.set_artifact(
+            ar("letter_a"),
+            Artifact::Block(Block {
+                main_slot_kind: sk("Letter"),
+                slots: hashmap! {},
+            }),
+        );
+        model.set_artifact(
+            ar("a_plus_one"),
+            Artifact::Structure(Structure {
+                a_ref: ar("successor"),
+                c: hashmap! {
+                    sn("x") => Connection::Structure(Structure {
+                        a_ref: ar("letter_a"),
+                        c: hashmap! {},
+                    }),
+                },
+            }),
+        );
+        let err = model.validate_model().expect_err("Should fail");
+        assert_eq!(err.0.len(), 1);
+        assert_eq!(err.0[0].0, ar("a_plus_one"));
+        assert_eq!(
+            (err.0[0].1).0,
+            vec![Diagnostic::KindMismatch {
+                at: Location(vec![sn("x")]),
+                expected: sk("Natural"),
+                found: sk("Letter"),
+            }]
+        );
+    }
+
+    fn number_4(model: &dyn Model) -> Structure {
+        match model.get_artifact(&ar("number_4")) {
+            Some(Artifact::Structure(structure)) => structure.clone(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn nested_structures_are_reached_by_location() {
+        let model = two_and_two_is_four_model();
+        let mut structure = number_4(&model);
+
+        assert_eq!(structure.at(&Location::root()).unwrap().a_ref, ar("plus_2"));
+        let deepest = Location::root().child(sn("x")).child(sn("x"));
+        assert_eq!(structure.at(&deepest).unwrap().a_ref, ar("zero"));
+        assert_eq!(
+            structure.at(&deepest.child(sn("x"))),
+            Err(StructureEditError::UnexistentLocation(Location(vec![
+                sn("x"),
+                sn("x"),
+                sn("x")
+            ])))
+        );
+        assert_eq!(
+            structure
+                .at_mut(&Location(vec![sn("y"), sn("x")]))
+                .map(|s| s.a_ref.clone()),
+            Err(StructureEditError::UnexistentLocation(Location(vec![sn(
+                "y"
+            )])))
+        );
+    }
+
+    #[test]
+    fn connect_and_disconnect_deep_inside_a_structure() {
+        let mut model = two_and_two_is_four_model();
+        let mut structure = number_4(&model);
+        let inner = Location::root().child(sn("x"));
+
+        let zero = structure.disconnect(&inner, &sn("x")).unwrap();
+        assert_eq!(
+            structure.disconnect(&inner, &sn("x")),
+            Err(StructureEditError::NotConnected(inner.clone(), sn("x")))
+        );
+        model.set_artifact(ar("number_4"), Artifact::Structure(structure.clone()));
+        assert!(model.is_all_valid());
+        assert_eq!(
+            model.diagnose(&ar("number_4")),
+            vec![Diagnostic::DisconnectedSlot(
+                Location(vec![sn("x"), sn("x")]),
+                sk("Natural")
+            )]
+        );
+
+        let four = Connection::Structure(number_4(&two_and_two_is_four_model()));
+        assert_eq!(structure.connect(&inner, &sn("x"), four), Ok(None));
+        model.set_artifact(ar("number_4"), Artifact::Structure(structure.clone()));
+        assert_eq!(peano_eval(&ar("number_4"), &model), Some(8));
+
+        assert_eq!(
+            structure.connect(&inner, &sn("x"), zero.clone()),
+            Ok(Some(Connection::Structure(number_4(
+                &two_and_two_is_four_model()
+            ))))
+        );
+        model.set_artifact(ar("number_4"), Artifact::Structure(structure.clone()));
+        assert_eq!(peano_eval(&ar("number_4"), &model), Some(4));
+
+        assert_eq!(
+            structure.connect(&inner.child(sn("y")), &sn("x"), zero),
+            Err(StructureEditError::UnexistentLocation(Location(vec![
+                sn("x"),
+                sn("y")
+            ])))
+        );
+    }
+
+    #[test]
+    fn swapping_keeps_connections_and_checks_kinds() {
+        let mut model = and_one_more_is_five_model();
+        model.set_artifact(
+            ar("letter_a"),
+            Artifact::Block(Block {
+                main_slot_kind: sk("Letter"),
+                slots: hashmap! {},
+            }),
+        );
+        let mut structure = number_4(&model);
+        let inner = Location::root().child(sn("x"));
+
+        assert_eq!(
+            structure.swap(&inner, ar("successor"), &model),
+            Ok(ar("plus_2"))
+        );
+        model.set_artifact(ar("number_4"), Artifact::Structure(structure.clone()));
+        model.validate_model().unwrap();
+        assert_eq!(peano_eval(&ar("number_4"), &model), Some(3));
+
+        assert_eq!(
+            structure.swap(&inner, ar("letter_a"), &model),
+            Err(StructureEditError::KindMismatch {
+                at: inner.clone(),
+                expected: sk("Natural"),
+                found: sk("Letter"),
+            })
+        );
+        assert_eq!(
+            structure.swap(&inner, ar("wibblidy"), &model),
+            Err(StructureEditError::Kind(GettingKindOfError::Unexistent(
+                vec![],
+                ar("wibblidy")
+            )))
+        );
+        assert_eq!(
+            structure.swap(&Location::root(), ar("number_5"), &model),
+            Ok(ar("plus_2"))
+        );
+        assert_eq!(structure.at(&inner).unwrap().a_ref, ar("successor"));
+
+        // Only the root falls back to the main slot kind of what it replaces
+        let stray = Location::root().child(sn("y"));
+        structure
+            .connect(
+                &Location::root(),
+                &sn("y"),
+                Connection::Structure(Structure {
+                    a_ref: ar("zero"),
+                    c: hashmap! {},
+                }),
+            )
+            .unwrap();
+        assert_eq!(
+            structure.swap(&stray, ar("successor"), &model),
+            Err(StructureEditError::UnexistentSlot(
+                Location::root(),
+                sn("y")
+            ))
+        );
+        assert_eq!(structure.at(&stray).unwrap().a_ref, ar("zero"));
+
+        let mut orphan = Structure {
+            a_ref: ar("wibblidy"),
+            c: hashmap! {
+                sn("x") => Connection::Structure(Structure {
+                    a_ref: ar("zero"),
+                    c: hashmap! {},
+                }),
+            },
+        };
+        assert!(matches!(
+            orphan.swap(&inner, ar("successor"), &model),
+            Err(StructureEditError::Slots(location, _)) if location == Location::root()
+        ));
+    }
+
+    #[test]
+    fn structure_edit_errors_explain_what_happened() {
+        let at = Location(vec![sn("x"), sn("y")]);
+        assert_eq!(
+            StructureEditError::UnexistentLocation(at.clone()).to_string(),
+            "There is no structure at /'x/'y"
+        );
+        assert_eq!(
+            StructureEditError::NotConnected(at.clone(), sn("z")).to_string(),
+            "At /'x/'y: 'z is not connected"
+        );
+        assert_eq!(
+            StructureEditError::UnexistentSlot(at.clone(), sn("z")).to_string(),
+            "At /'x/'y: there is no slot 'z"
+        );
+        assert_eq!(
+            StructureEditError::KindMismatch {
+                at,
+                expected: sk("Natural"),
+                found: sk("Letter"),
+            }
+            .to_string(),
+            "At /'x/'y: expected [Natural] but found [Letter]"
+        );
+    }
+
+    #[test]
+    fn kinds_accept_their_declared_subkinds() {
+        let mut kinds = KindLattice::default();
+        assert!(kinds.accepts(&sk("Natural"), &sk("Natural")));
+        assert!(!kinds.accepts(&sk("Natural"), &sk("PositiveNatural")));
+
+        assert!(kinds.declare(sk("PositiveNatural"), sk("Natural")));
+        assert!(kinds.declare(sk("Prime"), sk("PositiveNatural")));
+        assert!(kinds.accepts(&sk("Natural"), &sk("Prime")));
+        assert!(!kinds.accepts(&sk("Prime"), &sk("Natural")));
+        assert_eq!(kinds.superkinds(&sk("Prime")), vec![sk("PositiveNatural")]);
+
+        assert!(!kinds.declare(sk("Natural"), sk("Prime")));
+        assert!(!kinds.declare(sk("Natural"), sk("Natural")));
+
+        assert!(kinds.undeclare(&sk("PositiveNatural"), &sk("Natural")));
+        assert!(!kinds.accepts(&sk("Natural"), &sk("Prime")));
+        assert_eq!(
+            kinds.declarations(),
+            vec![(sk("Prime"), sk("PositiveNatural"))]
+        );
+    }
+
+    #[test]
+    fn slots_accept_artifacts_of_subkinds() {
+        let mut model = peano_model();
+        model.set_artifact(
+            ar("one"),
+            Artifact::Block(Block {
+                main_slot_kind: sk("PositiveNatural"),
+                slots: hashmap! {},
+            }),
+        );
+        model.set_artifact(
+            ar("two"),
+            Artifact::Structure(Structure {
+                a_ref: ar("successor"),
+                c: hashmap! {
+                    sn("x") => Connection::Structure(Structure {
+                        a_ref: ar("one"),
+                        c: hashmap! {},
+                    }),
+                },
+            }),
+        );
+        assert_eq!(
+            model.diagnose(&ar("two")),
+            vec![Diagnostic::KindMismatch {
+                at: Location(vec![sn("x")]),
+                expected: sk("Natural"),
+                found: sk("PositiveNatural"),
+            }]
+        );
+
+        let mut kinds = KindLattice::default();
+        kinds.declare(sk("PositiveNatural"), sk("Natural"));
+        model.set_kinds(kinds);
+        model.validate_model().unwrap();
+
+        let mut structure = match model.get_artifact(&ar("two")) {
+            Some(Artifact::Structure(structure)) => structure.clone(),
+            _ => unreachable!(),
+        };
+        let x = Location::root().child(sn("x"));
+        assert_eq!(structure.swap(&x, ar("zero"), &model), Ok(ar("one")));
+        assert_eq!(structure.swap(&x, ar("one"), &model), Ok(ar("zero")));
+
+        model.set_kinds(KindLattice::default());
+        assert_eq!(structure.swap(&x, ar("zero"), &model), Ok(ar("one")));
+        assert_eq!(
+            structure.swap(&x, ar("one"), &model),
+            Err(StructureEditError::KindMismatch {
+                at: x,
+                expected: sk("Natural"),
+                found: sk("PositiveNatural"),
+            })
+        );
+    }
+
+    #[test]
+    fn union_merges_declared_kinds() {
+        let mut ours = peano_model();
+        let mut kinds = KindLattice::default();
+        kinds.declare(sk("PositiveNatural"), sk("Natural"));
+        ours.set_kinds(kinds);
+        let mut theirs = peano_model();
+        let mut kinds = KindLattice::default();
+        kinds.declare(sk("Natural"), sk("Number"));
+        kinds.declare(sk("Natural"), sk("PositiveNatural"));
+        theirs.set_kinds(kinds);
+
+        let union = ours.union(&theirs);
+        assert_eq!(
+            union.model.kinds().declarations(),
+            vec![
+                (sk("Natural"), sk("Number")),
+                (sk("PositiveNatural"), sk("Natural")),
+            ]
+        );
+        assert_eq!(
+            union.kind_conflicts,
+            vec![KindConflict {
+                kind: sk("Natural"),
+                superkind: sk("PositiveNatural"),
+            }]
+        );
+        assert!(!union.is_clean());
+        assert_eq!(
+            union.kind_conflicts[0].to_string(),
+            "[Natural] cannot be a subkind of [PositiveNatural], because it would be a subkind of itself"
+        );
+    }
+
+    #[test]
+    fn all_problems_of_an_artifact_are_reported() {
+        let mut model = peano_model();
+        model.set_artifact(
+            ar("a"),
+            Artifact::Structure(Structure {
+                a_ref: ar("successor"),
+                c: hashmap! {
+                    sn("y") => Connection::Slot(sn("y")),
+                    sn("x") => Connection::Structure(Structure {
+                        a_ref: ar("successor"),
+                        c: hashmap! {
+                            sn("x") => Connection::Structure(Structure {
+                                a_ref: ar("nothing"),
+                                c: hashmap! {},
+                            }),
+                        },
+                    }),
+                },
+            }),
+        );
+        model.set_artifact(
+            ar("b"),
+            Artifact::Structure(Structure {
+                a_ref: ar("successor"),
+                c: hashmap! {
+                    sn("x") => Connection::Structure(Structure {
+                        a_ref: ar("successor"),
+                        c: hashmap! {},
+                    }),
+                },
+            }),
+        );
+
+        assert_eq!(
+            model.diagnose(&ar("a")),
+            vec![
+                Diagnostic::UnexistentSlot(Location::root(), ar("successor"), sn("y")),
+                Diagnostic::UnexistentArtifact(Location(vec![sn("x"), sn("x")]), ar("nothing")),
+            ]
+        );
+        assert_eq!(
+            model.diagnose(&ar("b")),
+            vec![Diagnostic::DisconnectedSlot(
+                Location(vec![sn("x"), sn("x")]),
+                sk("Natural")
+            )]
+        );
+
+        let err = model.validate_model().expect_err("Should fail");
+        assert_eq!(err.0.len(), 1);
+        assert_eq!((err.0[0].1).0.len(), 2);
+    }
+
+    #[test]
+    fn shared_dependencies_are_looked_into_once() {
+        let mut model = peano_model();
+        model.set_artifact(
+            ar("pair"),
+            Artifact::Block(Block {
+                main_slot_kind: sk("Natural"),
+                slots: hashmap! { sn("x") => sk("Natural"), sn("y") => sk("Natural") },
+            }),
+        );
+        // Every level depends twice on the one below, through 2^40 paths in total
+        let mut below = ("zero".to_string(), "zero".to_string());
+        for level in 1..=40 {
+            let connect = |aref: &str| {
+                Connection::Structure(Structure {
+                    a_ref: ar(aref),
+                    c: hashmap! {},
+                })
+            };
+            let structure = Structure {
+                a_ref: ar("pair"),
+                c: hashmap! { sn("x") => connect(&below.0), sn("y") => connect(&below.1) },
+            };
+            below = (format!("left_{}", level), format!("right_{}", level));
+            model.set_artifact(ar(&*below.0), Artifact::Structure(structure.clone()));
+            model.set_artifact(ar(&*below.1), Artifact::Structure(structure));
+        }
+        assert_eq!(model.find_recursion(&ar("left_40")), None);
+    }
+
+    #[test]
+    fn longer_recursion_loops_are_detected() {
+        let mut model = peano_model();
+        for (aref, next) in vec![("a", "b"), ("b", "c"), ("c", "a")] {
+            model.set_artifact(
+                ar(aref),
+                Artifact::Structure(Structure {
+                    a_ref: ar("successor"),
+                    c: hashmap! {
+                        sn("x") => Connection::Structure(Structure {
+                            a_ref: ar(next),
+                            c: hashmap! {},
+                        }),
+                    },
+                }),
+            );
+        }
+        assert_eq!(
+            model.diagnose(&ar("a")),
+            vec![Diagnostic::RecursionDetected(
+                vec![ar("a"), ar("b"), ar("c")],
+                ar("a")
+            )]
+        );
+        assert_eq!(
+            model.diagnose(&ar("a"))[0].to_string(),
+            "Recursion detected: a -> b -> c -> a"
+        );
+        assert_eq!(model.validate_model().expect_err("Should fail").0.len(), 3);
+    }
+
+    fn depends_only_on_blocks<M: Model>(model: &M, structure: &Structure) -> bool {
+        matches!(
+            model.get_artifact(&structure.a_ref),
+            Some(Artifact::Block(_))
+        ) && structure.c.values().all(|c| match c {
+            Connection::Structure(s) => depends_only_on_blocks(model, s),
+            Connection::Slot(_) => true,
+        })
+    }
+
+    #[test]
+    fn expanding_a_block_exposes_all_its_slots() {
+        let model = peano_model();
+        assert_eq!(
+            model.expand(&ar("successor")).unwrap(),
+            Structure {
+                a_ref: ar("successor"),
+                c: hashmap! {
+                    sn("x") => Connection::Slot(sn("x")),
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn structure_expansion_only_depends_on_blocks() {
+        let model = and_one_more_is_five_model();
+        for aref in model.list_artifacts() {
+            let expanded = model.expand(&aref).unwrap();
+            assert!(depends_only_on_blocks(&model, &expanded));
+            assert_eq!(
+                model.slots_of_structure(&expanded).unwrap(),
+                model.slots_of(&aref).unwrap()
+            );
+        }
+
+        assert_eq!(peano_eval(&ar("number_5"), &model), Some(5));
+    }
+
+    #[test]
+    fn structure_expansion_equals_an_equivalent_structure_of_blocks() {
+        let model = two_and_two_is_four_model();
+        fn successor(x: Connection) -> Connection {
+            Connection::Structure(Structure {
+                a_ref: ar("successor"),
+                c: hashmap! { sn("x") => x },
+            })
+        }
+        let zero = Connection::Structure(Structure {
+            a_ref: ar("zero"),
+            c: hashmap! {},
+        });
+
+        let expected = successor(successor(Connection::Slot(sn("x"))));
+        assert_eq!(
+            Connection::Structure(model.expand(&ar("plus_2")).unwrap()),
+            expected
+        );
+
+        let expected = successor(successor(successor(successor(zero))));
+        assert_eq!(
+            Connection::Structure(model.expand(&ar("number_4")).unwrap()),
+            expected
+        );
+    }
+
+    #[test]
+    fn expansion_remaps_exposed_slots_and_keeps_disconnected_ones() {
+        let mut model = peano_model();
+        model.set_artifact(
+            ar("add"),
+            Artifact::Block(Block {
+                main_slot_kind: sk("Natural"),
+                slots: hashmap! {
+                    sn("a") => sk("Natural"),
+                    sn("b") => sk("Natural"),
+                },
+            }),
+        );
+        model.set_artifact(
+            ar("add_to_successor"),
+            Artifact::Structure(Structure {
+                a_ref: ar("add"),
+                c: hashmap! {
+                    sn("a") => Connection::Slot(sn("left")),
+                    sn("b") => Connection::Structure(Structure {
+                        a_ref: ar("successor"),
+                        c: hashmap! { sn("x") => Connection::Slot(sn("right")) },
+                    }),
+                },
+            }),
+        );
+        model.set_artifact(
+            ar("half_plugged"),
+            Artifact::Structure(Structure {
+                a_ref: ar("add_to_successor"),
+                c: hashmap! {
+                    sn("right") => Connection::Slot(sn("y")),
+                },
+            }),
+        );
+        model.validate_model().unwrap();
+
+        let expanded = model.expand(&ar("half_plugged")).unwrap();
+        assert_eq!(
+            expanded,
+            Structure {
+                a_ref: ar("add"),
+                c: hashmap! {
+                    sn("b") => Connection::Structure(Structure {
+                        a_ref: ar("successor"),
+                        c: hashmap! { sn("x") => Connection::Slot(sn("y")) },
+                    }),
+                },
+            }
+        );
+        assert_eq!(
+            model.slots_of_structure(&expanded).unwrap(),
+            model.slots_of(&ar("half_plugged")).unwrap()
+        );
+    }
+
+    #[test]
+    fn cannot_expand_recursive_or_unexistent_artifacts() {
+        let mut model = peano_model();
+        model.set_artifact(
+            ar("a"),
+            Artifact::Structure(Structure {
+                a_ref: ar("successor"),
+                c: hashmap! {
+                    sn("x") => Connection::Structure(Structure {
+                        a_ref: ar("b"),
+                        c: hashmap! {},
+                    }),
+                },
+            }),
+        );
+        model.set_artifact(
+            ar("b"),
+            Artifact::Structure(Structure {
+                a_ref: ar("a"),
+                c: hashmap! {},
+            }),
+        );
+        assert_eq!(
+            model.expand(&ar("a")),
+            Err(ExpansionError::RecursionDetected(
+                vec![ar("a"), ar("b")],
+                ar("a")
+            ))
+        );
+        assert_eq!(
+            model.expand(&ar("nothing")),
+            Err(ExpansionError::Unexistent(vec![], ar("nothing")))
+        );
+    }
+
+    #[test]
+    fn refactor_errors_explain_what_happened() {
+        let messages: Vec<_> = vec![
+            RefactorError::InvalidModel(RefactorOperation::RemoveBlockSlot),
+            RefactorError::InvalidModel(RefactorOperation::RemoveArtifact),
+            RefactorError::UnexistentArtifact(RefactorOperation::RemoveBlockSlot, ar("wibblidy")),
+            RefactorError::UnexistentArtifact(RefactorOperation::RenameSlot, ar("wibblidy")),
+            RefactorError::UnexistentSlot(RefactorOperation::RemoveBlockSlot, ar("zero"), sn("q")),
+            RefactorError::UnexistentSlot(RefactorOperation::RenameSlot, ar("zero"), sn("z")),
+            RefactorError::SlotAlreadyExists(ar("successor"), sn("x")),
+            RefactorError::NotABlock(ar("plus_2")),
+            RefactorError::SlotInUse {
+                aref: ar("successor"),
+                slot_name: sn("x"),
+                dependents: vec![ar("number_5"), ar("plus_2")],
+            },
+            RefactorError::ArtifactInUse {
+                aref: ar("zero"),
+                dependents: vec![ar("number_4")],
+            },
+            RefactorError::ArtifactInUse {
+                aref: ar("successor"),
+                dependents: vec![ar("a"), ar("b"), ar("c")],
+            },
+        ]
+        .into_iter()
+        .map(|err| err.to_string())
+        .collect();
+
+        assert_eq!(
+            messages,
+            vec![
+                "Cannot safely remove slot in an invalid model",
+                "Cannot safely remove an artifact from an invalid model",
+                "Artifact wibblidy does not exist",
+                "Artifact wibblidy does not exists ",
+                "Block zero does not have a 'q slot",
+                "Slot 'z does not exists in zero",
+                "Artifact successor already has a slot named 'x",
+                "Cannot remove slot of a structure",
+                "Cannot remove slot 'x from successor because is used by number_5 and plus_2",
+                "Cannot safely remove zero because is referenced by number_4",
+                "Cannot safely remove successor because is referenced by a, b and c",
+            ]
+        );
+    }
+
+    #[test]
+    fn union_of_disjoint_models_has_all_artifacts() {
+        let mut other = empty_test_model();
+        other.set_artifact(
+            ar("one"),
+            Artifact::Block(Block {
+                main_slot_kind: sk("Natural"),
+                slots: hashmap! {},
+            }),
+        );
+        let union = peano_model().union(&other);
+
+        assert!(union.is_clean());
+        let mut arefs = union.model.list_artifacts();
+        arefs.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(arefs, vec![ar("one"), ar("successor"), ar("zero")]);
+    }
+
+    #[test]
+    fn union_with_the_same_definitions_has_no_conflicts() {
+        let union = two_and_two_is_four_model().union(&and_one_more_is_five_model());
+        assert!(union.is_clean());
+        assert!(union.model.exists_artifact(&ar("number_5")));
+    }
+
+    #[test]
+    fn union_keeps_own_artifact_and_reports_conflicting_blocks() {
+        let mut other = peano_model();
+        other.set_artifact(
+            ar("successor"),
+            Artifact::Block(Block {
+                main_slot_kind: sk("Natural"),
+                slots: hashmap! {
+                    sn("y") => sk("Natural"),
+                },
+            }),
+        );
+        let model = two_and_two_is_four_model();
+        let union = model.union(&other);
+
+        union.validation.as_ref().unwrap();
+        assert_eq!(union.conflicts.len(), 1);
+        let conflict = &union.conflicts[0];
+        assert_eq!(conflict.aref, ar("successor"));
+        assert_eq!(conflict.kind(), UnionConflictKind::DifferentBlocks);
+        assert_eq!(
+            conflict.ours,
+            *model.get_artifact(&ar("successor")).unwrap()
+        );
+        assert_eq!(
+            union.model.get_artifact(&ar("successor")),
+            model.get_artifact(&ar("successor"))
+        );
+    }
+
+    #[test]
+    fn union_reports_conflicting_structures_that_do_not_invalidate_the_model() {
+        let mut other = two_and_two_is_four_model();
+        other.set_artifact(
+            ar("number_4"),
+            Artifact::Structure(Structure {
+                a_ref: ar("successor"),
+                c: hashmap! {},
+            }),
+        );
+        let union = two_and_two_is_four_model().union(&other);
+
+        assert_eq!(union.conflicts.len(), 1);
+        assert_eq!(
+            union.conflicts[0].kind(),
+            UnionConflictKind::DifferentStructures
+        );
+        assert!(!union.conflicts[0].taking_theirs_invalidates_model);
+        assert_eq!(
+            format!("{}", union.conflicts[0]),
+            "number_4 is defined as two different structures"
+        );
+    }
+
+    #[test]
+    fn union_reports_conflicts_that_would_invalidate_the_model() {
+        let mut other = empty_test_model();
+        other.set_artifact(
+            ar("zero"),
+            Artifact::Structure(Structure {
+                a_ref: ar("nothing"),
+                c: hashmap! {},
+            }),
+        );
+        let union = two_and_two_is_four_model().union(&other);
+
+        union.validation.as_ref().unwrap();
+        assert_eq!(union.conflicts.len(), 1);
+        assert_eq!(
+            union.conflicts[0].kind(),
+            UnionConflictKind::BlockAgainstStructure
+        );
+        assert!(union.conflicts[0].taking_theirs_invalidates_model);
+    }
+
+    #[test]
+    fn union_reports_when_merged_model_is_invalid() {
+        let mut other = empty_test_model();
+        other.set_artifact(
+            ar("number_1"),
+            Artifact::Structure(Structure {
+                a_ref: ar("one_more"),
+                c: hashmap! {},
+            }),
+        );
+        let union = peano_model().union(&other);
+
+        assert!(union.conflicts.is_empty());
+        assert!(union.validation.is_err());
+        assert!(!union.is_clean());
+    }
 
-    todo!("Assert that structure expansion equals to an equivalent structure depending only on blocks");
+    #[test]
+    fn conflicts_do_not_invalidate_a_merged_model_that_is_already_invalid() {
+        let mut other = two_and_two_is_four_model();
+        other.set_artifact(
+            ar("number_4"),
+            Artifact::Structure(Structure {
+                a_ref: ar("successor"),
+                c: hashmap! {},
+            }),
+        );
+        other.set_artifact(
+            ar("number_1"),
+            Artifact::Structure(Structure {
+                a_ref: ar("one_more"),
+                c: hashmap! {},
+            }),
+        );
+        let union = two_and_two_is_four_model().union(&other);
+
+        assert!(union.validation.is_err());
+        assert_eq!(union.conflicts.len(), 1);
+        assert_eq!(union.conflicts[0].aref, ar("number_4"));
+        assert!(!union.conflicts[0].taking_theirs_invalidates_model);
+    }
 }
@@ -1,143 +1,944 @@
 use bevy::{ecs::system::Command, prelude::*};
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use super::mursten::*;
+use super::mursten_commands::*;
 
-pub struct CurrentModel(pub Box<dyn Model>);
+/// The model being edited, shared as a resource. Changes done through it are published as
+/// `ModelChange` events by `publish_model_changes`.
+pub struct CurrentModel {
+    model: Box<dyn Model>,
+    history: ModelHistory,
+    revision: usize,
+    changes: Vec<ModelChange>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ModelChange {
+    pub revision: usize,
+    pub event: ModelEvent,
+}
+
+impl CurrentModel {
+    pub fn new(model: Box<dyn Model>) -> Self {
+        Self {
+            model,
+            history: ModelHistory::default(),
+            revision: 0,
+            changes: vec![],
+        }
+    }
+
+    /// Swaps the whole model, for example after loading one from disk. The history is lost.
+    pub fn replace(&mut self, model: Box<dyn Model>) {
+        let mut events: Vec<_> = self
+            .model
+            .list_artifacts()
+            .into_iter()
+            .map(ModelEvent::ArtifactRemoved)
+            .collect();
+        events.extend(
+            model
+                .list_artifacts()
+                .into_iter()
+                .map(ModelEvent::ArtifactAdded),
+        );
+        if self.model.kinds() != model.kinds() {
+            events.push(ModelEvent::KindsChanged);
+        }
+        self.model = model;
+        self.history = ModelHistory::default();
+        self.record(events);
+    }
 
-pub enum ModelChange {}
+    pub fn revision(&self) -> usize {
+        self.revision
+    }
 
+    pub fn can_undo(&self) -> bool {
+        self.history.can_undo()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        self.history.can_redo()
+    }
+
+    pub fn execute(&mut self, command: ModelCommand) -> Result<(), ModelCommandError> {
+        let events = self.history.execute(&mut *self.model, command)?;
+        self.record(events);
+        Ok(())
+    }
+
+    pub fn undo(&mut self) -> Result<(), ModelCommandError> {
+        let events = self.history.undo(&mut *self.model)?;
+        self.record(events);
+        Ok(())
+    }
+
+    pub fn redo(&mut self) -> Result<(), ModelCommandError> {
+        let events = self.history.redo(&mut *self.model)?;
+        self.record(events);
+        Ok(())
+    }
+
+    fn record(&mut self, events: Vec<ModelEvent>) {
+        if events.is_empty() {
+            return;
+        }
+        self.revision += 1;
+        let revision = self.revision;
+        self.changes.extend(
+            events
+                .into_iter()
+                .map(|event| ModelChange { revision, event }),
+        );
+    }
+}
+
+/// Changes done through the `Model` trait skip the history, so they forget it like `reload` does:
+/// the inverses in it might not make sense anymore.
 impl Model for CurrentModel {
     fn set_artifact(&mut self, aref: ArtifactReference, artifact: Artifact) {
-        self.0.set_artifact(aref, artifact)
+        let event = if self.model.exists_artifact(&aref) {
+            ModelEvent::ArtifactChanged(aref.clone())
+        } else {
+            ModelEvent::ArtifactAdded(aref.clone())
+        };
+        self.model.set_artifact(aref, artifact);
+        self.history = ModelHistory::default();
+        self.record(vec![event]);
     }
     fn remove_artifact(&mut self, aref: &ArtifactReference) {
-        self.0.remove_artifact(aref)
+        if self.model.exists_artifact(aref) {
+            self.model.remove_artifact(aref);
+            self.history = ModelHistory::default();
+            self.record(vec![ModelEvent::ArtifactRemoved(aref.clone())]);
+        }
     }
     fn get_artifact(&self, aref: &ArtifactReference) -> Option<&Artifact> {
-        self.0.get_artifact(aref)
+        self.model.get_artifact(aref)
     }
     fn list_artifacts(&self) -> Vec<ArtifactReference> {
-        self.0.list_artifacts()
+        self.model.list_artifacts()
+    }
+    fn kinds(&self) -> &KindLattice {
+        self.model.kinds()
+    }
+    fn set_kinds(&mut self, kinds: KindLattice) {
+        if *self.model.kinds() != kinds {
+            self.model.set_kinds(kinds);
+            self.history = ModelHistory::default();
+            self.record(vec![ModelEvent::KindsChanged]);
+        }
+    }
+    fn take_errors(&mut self) -> Vec<String> {
+        self.model.take_errors()
+    }
+    fn reload(&mut self) -> Vec<ArtifactReference> {
+        let existed: Vec<_> = self.model.list_artifacts();
+        let kinds = self.model.kinds().clone();
+        let changed = self.model.reload();
+        if changed.is_empty() {
+            return changed;
+        }
+        let mut events: Vec<_> = changed
+            .iter()
+            .cloned()
+            .map(|aref| {
+                if !self.model.exists_artifact(&aref) {
+                    ModelEvent::ArtifactRemoved(aref)
+                } else if existed.contains(&aref) {
+                    ModelEvent::ArtifactChanged(aref)
+                } else {
+                    ModelEvent::ArtifactAdded(aref)
+                }
+            })
+            .collect();
+        if *self.model.kinds() != kinds {
+            events.push(ModelEvent::KindsChanged);
+        }
+        // Inverses of the commands in the history might not make sense anymore.
+        self.history = ModelHistory::default();
+        self.record(events);
+        changed
+    }
+}
+
+/// Picks up external changes to the current model (like edited files) every now and then.
+pub fn reload_current_model(
+    time: Res<Time>,
+    mut last_reload: Local<f64>,
+    mut model: ResMut<CurrentModel>,
+) {
+    let now = time.seconds_since_startup();
+    if now - *last_reload < 1.0 {
+        return;
+    }
+    *last_reload = now;
+    model.reload();
+}
+
+pub fn publish_model_changes(
+    mut model: ResMut<CurrentModel>,
+    mut model_changes: EventWriter<ModelChange>,
+) {
+    if model.changes.is_empty() {
+        return;
+    }
+    for change in model.changes.drain(..) {
+        model_changes.send(change);
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BlockInstanceCreationError(pub String);
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum InstantiationError {
+    /// There is no `CurrentModel` resource to take the artifacts from.
+    NoCurrentModel,
+    UnexistentArtifact(ArtifactReference),
+    MissingFactory(ArtifactReference),
+    Block(ArtifactReference, BlockInstanceCreationError),
+    Expansion(ExpansionError),
+}
+
+impl std::fmt::Display for InstantiationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InstantiationError::NoCurrentModel => f.write_str("There is no current model"),
+            InstantiationError::UnexistentArtifact(aref) => {
+                write!(f, "Artifact {} does not exist", aref)
+            }
+            InstantiationError::MissingFactory(aref) => {
+                write!(f, "There is no factory registered for {}", aref)
+            }
+            InstantiationError::Block(aref, BlockInstanceCreationError(reason)) => {
+                write!(f, "Could not create an instance of {}: {}", aref, reason)
+            }
+            InstantiationError::Expansion(ExpansionError::Unexistent(_, aref)) => {
+                write!(f, "Artifact {} does not exist", aref)
+            }
+            InstantiationError::Expansion(ExpansionError::RecursionDetected(_, aref)) => {
+                write!(f, "Artifact {} depends on itself", aref)
+            }
+        }
     }
 }
 
+/// Marks the root entity of an instance of an artifact. It remembers what was built, and from
+/// which revision of the `CurrentModel`, so it can be patched when the model changes.
 #[derive(Debug, Clone, Eq, PartialEq)]
-struct BlockInstanceCreationError(String);
+pub struct ArtifactInstance {
+    pub aref: ArtifactReference,
+    pub revision: usize,
+    /// Entities of the slots exposed by the artifact.
+    pub slots: HashMap<SlotName, Entity>,
+    expansion: Structure,
+    // Nothing is built when the last attempt to patch the instance failed.
+    tree: Option<BuiltBlock>,
+}
 
-trait Instance {
-    fn of_artifact<'a>(&'a self) -> &'a str;
+/// The entities built for a block inside an instance.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BuiltBlock {
+    pub block: ArtifactReference,
+    pub entity: Entity,
+    pub slots: HashMap<SlotName, Entity>,
+    definition: Block,
+    children: HashMap<SlotName, BuiltBlock>,
 }
 
-struct CreateInstance {
-    aref: ArtifactReference,
-    root: Entity,
+impl BuiltBlock {
+    fn exposed_slots(&self, expansion: &Structure, exposed: &mut HashMap<SlotName, Entity>) {
+        for (slot_name, connection) in expansion.c.iter() {
+            match connection {
+                Connection::Slot(exposed_slot_name) => {
+                    if let Some(entity) = self.slots.get(slot_name) {
+                        exposed.insert(exposed_slot_name.clone(), *entity);
+                    }
+                }
+                Connection::Structure(substructure) => {
+                    if let Some(child) = self.children.get(slot_name) {
+                        child.exposed_slots(substructure, exposed);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Instantiates an artifact of the `CurrentModel` in `root`.
+pub struct CreateInstance {
+    pub aref: ArtifactReference,
+    pub root: Entity,
 }
 
 impl Command for CreateInstance {
     fn write(self: Box<Self>, world: &mut World) {
-        let a = world
-            .get_resource::<CurrentModel>()
-            .unwrap()
-            .get_artifact(&self.aref)
-            .unwrap();
-        world.build_artifact(&self.aref, self.root);
+        let existing = all_entities(world);
+        match world.build_artifact(&self.aref, self.root) {
+            Ok(instance) => {
+                world.entity_mut(self.root).insert(instance);
+            }
+            Err(err) => {
+                error!("{}", err);
+                // Entities that were in `root` before, like the ones of the caller, are kept
+                for entity in all_entities(world).difference(&existing) {
+                    if world.get_entity(*entity).is_some() {
+                        world.destroy(*entity);
+                    }
+                }
+            }
+        }
     }
 }
 
-struct BlockFactory {
-    create_instance: Box<
-        dyn Fn(Entity, &mut World) -> Result<HashMap<SlotName, Entity>, BlockInstanceCreationError>,
+fn all_entities(world: &mut World) -> HashSet<Entity> {
+    let mut query = world.query::<Entity>();
+    query.iter(world).collect()
+}
+
+/// Despawns an instance created with `CreateInstance`, with everything that was built for it.
+pub struct DestroyInstance {
+    pub root: Entity,
+}
+
+impl Command for DestroyInstance {
+    fn write(self: Box<Self>, world: &mut World) {
+        world.destroy(self.root);
+    }
+}
+
+/// Knows how to build instances of a block: it gets the root entity of the instance and returns
+/// an entity for each of the slots of the block, where the artifacts connected to them will be
+/// parented.
+#[derive(Clone)]
+pub struct BlockFactory {
+    create_instance: Arc<
+        dyn Fn(Entity, &mut World) -> Result<HashMap<SlotName, Entity>, BlockInstanceCreationError>
+            + Send
+            + Sync,
     >,
+    teardown: Option<Arc<dyn Fn(Entity, &mut World) + Send + Sync>>,
+}
+
+impl BlockFactory {
+    pub fn new<F>(create_instance: F) -> Self
+    where
+        F: Fn(Entity, &mut World) -> Result<HashMap<SlotName, Entity>, BlockInstanceCreationError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        Self {
+            create_instance: Arc::new(create_instance),
+            teardown: None,
+        }
+    }
+
+    /// Sets what undoes the changes done by the factory to the root entity of an instance (like
+    /// the components it inserted), which runs before another block is built in that entity.
+    pub fn with_teardown<F>(mut self, teardown: F) -> Self
+    where
+        F: Fn(Entity, &mut World) + Send + Sync + 'static,
+    {
+        self.teardown = Some(Arc::new(teardown));
+        self
+    }
 }
 
-trait ArtifactInstantiation {
-    fn get_factory(&self, aref: &ArtifactReference) -> BlockFactory;
-    fn register_factory(&self, aref: ArtifactReference, factory: BlockFactory);
+/// Registry of the factories of every block that can be instantiated, as a resource.
+#[derive(Default)]
+pub struct BlockFactories(HashMap<ArtifactReference, BlockFactory>);
+
+pub trait ArtifactInstantiation {
+    fn get_factory(&self, aref: &ArtifactReference) -> Option<BlockFactory>;
+    fn register_factory(&mut self, aref: ArtifactReference, factory: BlockFactory);
 
     fn alloc(&mut self) -> Entity;
+    /// Despawns `root` and everything below it.
     fn destroy(&mut self, root: Entity);
+    /// Despawns everything below `root`, leaving it empty to build something else in it.
+    /// Components that block factories inserted in `root` itself are kept, see
+    /// `BlockFactory::with_teardown`.
     fn free(&mut self, root: Entity);
 
+    /// Builds an instance of `aref` in `root`, returning the `ArtifactInstance` that describes it.
     fn build_artifact(
         &mut self,
         aref: &ArtifactReference,
         root: Entity,
-    ) -> HashMap<SlotName, Entity>;
-    fn build_structure(&mut self, structure: &Structure, root: Entity)
-        -> HashMap<SlotName, Entity>;
+    ) -> Result<ArtifactInstance, InstantiationError>;
+    /// Builds an expanded structure (see `Model::expand`) in `root`.
+    fn build_block(
+        &mut self,
+        expansion: &Structure,
+        root: Entity,
+    ) -> Result<BuiltBlock, InstantiationError>;
+    /// Updates the instance in `root` to the current revision of the model, rebuilding only the
+    /// blocks that changed.
+    fn patch_instance(&mut self, root: Entity) -> Result<(), InstantiationError>;
 }
 
 impl ArtifactInstantiation for World {
-    fn get_factory(&self, aref: &ArtifactReference) -> BlockFactory {
-        todo!()
+    fn get_factory(&self, aref: &ArtifactReference) -> Option<BlockFactory> {
+        self.get_resource::<BlockFactories>()?.0.get(aref).cloned()
     }
-    fn register_factory(&self, aref: ArtifactReference, factory: BlockFactory) {
-        todo!()
+    fn register_factory(&mut self, aref: ArtifactReference, factory: BlockFactory) {
+        if self.get_resource::<BlockFactories>().is_none() {
+            self.insert_resource(BlockFactories::default());
+        }
+        self.get_resource_mut::<BlockFactories>()
+            .unwrap()
+            .0
+            .insert(aref, factory);
     }
     fn alloc(&mut self) -> Entity {
-        todo!()
+        self.spawn().id()
     }
     fn destroy(&mut self, root: Entity) {
-        todo!()
+        self.free(root);
+        if let Some(parent) = self.get::<Parent>(root).map(|parent| parent.0) {
+            if let Some(children) = self.get::<Children>(parent) {
+                let siblings: Vec<Entity> = children
+                    .iter()
+                    .cloned()
+                    .filter(|child| *child != root)
+                    .collect();
+                self.entity_mut(parent).insert(Children::with(&siblings));
+            }
+        }
+        self.despawn(root);
     }
     fn free(&mut self, root: Entity) {
-        todo!()
+        if let Some(mut entity) = self.get_entity_mut(root) {
+            entity.remove::<ArtifactInstance>();
+        }
+        let children: Vec<Entity> = match self.get::<Children>(root) {
+            Some(children) => children.iter().cloned().collect(),
+            None => return,
+        };
+        self.entity_mut(root).remove::<Children>();
+        for child in children {
+            self.destroy(child);
+        }
     }
     fn build_artifact(
         &mut self,
         aref: &ArtifactReference,
         root: Entity,
-    ) -> HashMap<SlotName, Entity> {
-        let artifact: Artifact = (*self
+    ) -> Result<ArtifactInstance, InstantiationError> {
+        let model = self
             .get_resource::<CurrentModel>()
-            .unwrap()
-            .get_artifact(&aref)
-            .expect("Artifact not found in model"))
-        .clone();
+            .ok_or(InstantiationError::NoCurrentModel)?;
+        let revision = model.revision();
+        let expansion = model.expand(aref).map_err(InstantiationError::Expansion)?;
 
-        match artifact {
-            Artifact::Block(_block) => {
-                let factory = self.get_factory(aref);
-                (factory.create_instance)(root, self).expect("Block creation error")
-            }
-            Artifact::Structure(structure) => self.build_structure(&structure, root),
-        }
+        let tree = self.build_block(&expansion, root)?;
+        let mut slots = HashMap::new();
+        tree.exposed_slots(&expansion, &mut slots);
+        Ok(ArtifactInstance {
+            aref: aref.clone(),
+            revision,
+            slots,
+            expansion,
+            tree: Some(tree),
+        })
     }
 
-    fn build_structure(
+    fn build_block(
         &mut self,
-        structure: &Structure,
+        expansion: &Structure,
         root: Entity,
-    ) -> HashMap<SlotName, Entity> {
-        let main_slots = self.build_artifact(&structure.a_ref, root);
-
-        let mut exposed_slots = HashMap::new();
-        for (slot_name, entity) in main_slots {
-            match structure.c.get(&slot_name) {
-                Some(connection) => match connection {
-                    Connection::Structure(structure) => {
-                        self.build_structure(structure, root);
-                    }
-                    Connection::Slot(slot_name) => {
-                        exposed_slots.insert(slot_name.clone(), entity);
+    ) -> Result<BuiltBlock, InstantiationError> {
+        let definition = block_definition(self, &expansion.a_ref)?;
+        let factory = self
+            .get_factory(&expansion.a_ref)
+            .ok_or_else(|| InstantiationError::MissingFactory(expansion.a_ref.clone()))?;
+        let slots = (factory.create_instance)(root, self)
+            .map_err(|err| InstantiationError::Block(expansion.a_ref.clone(), err))?;
+
+        let mut children = HashMap::new();
+        for (slot_name, slot_entity) in slots.iter() {
+            // Disconnected slots are left empty.
+            if let Some(Connection::Structure(substructure)) = expansion.c.get(slot_name) {
+                let child = build_child(self, *slot_entity, substructure)?;
+                children.insert(slot_name.clone(), child);
+            }
+        }
+        Ok(BuiltBlock {
+            block: expansion.a_ref.clone(),
+            entity: root,
+            slots,
+            definition,
+            children,
+        })
+    }
+
+    fn patch_instance(&mut self, root: Entity) -> Result<(), InstantiationError> {
+        let mut instance = match self.get::<ArtifactInstance>(root) {
+            Some(instance) => instance.clone(),
+            None => return Ok(()),
+        };
+        let model = self
+            .get_resource::<CurrentModel>()
+            .ok_or(InstantiationError::NoCurrentModel)?;
+        let revision = model.revision();
+        if instance.revision == revision {
+            return Ok(());
+        }
+        let expansion = model.expand(&instance.aref);
+
+        // Until the model is fixed, the instance is left as it is.
+        instance.revision = revision;
+        let expansion = match expansion {
+            Ok(expansion) => expansion,
+            Err(err) => {
+                self.entity_mut(root).insert(instance);
+                return Err(InstantiationError::Expansion(err));
+            }
+        };
+
+        let tree = match instance.tree.take() {
+            Some(tree) if is_same_block(self, &tree, &expansion) => {
+                patch_block(self, tree, &expansion)
+            }
+            old_tree => {
+                if let Some(old_tree) = old_tree {
+                    tear_down(self, &old_tree);
+                }
+                self.free(root);
+                self.build_block(&expansion, root)
+            }
+        };
+        instance.slots.clear();
+        let result = match tree {
+            Ok(tree) => {
+                tree.exposed_slots(&expansion, &mut instance.slots);
+                instance.tree = Some(tree);
+                Ok(())
+            }
+            Err(err) => {
+                // Half patched instances are emptied, and built again on the next change.
+                self.free(root);
+                Err(err)
+            }
+        };
+        instance.expansion = expansion;
+        self.entity_mut(root).insert(instance);
+        result
+    }
+}
+
+fn block_definition(world: &World, aref: &ArtifactReference) -> Result<Block, InstantiationError> {
+    match world
+        .get_resource::<CurrentModel>()
+        .ok_or(InstantiationError::NoCurrentModel)?
+        .get_artifact(aref)
+    {
+        Some(Artifact::Block(block)) => Ok(block.clone()),
+        _ => Err(InstantiationError::UnexistentArtifact(aref.clone())),
+    }
+}
+
+fn build_child(
+    world: &mut World,
+    slot_entity: Entity,
+    expansion: &Structure,
+) -> Result<BuiltBlock, InstantiationError> {
+    let child = world.alloc();
+    world.entity_mut(slot_entity).push_children(&[child]);
+    world.build_block(expansion, child)
+}
+
+/// Undoes what the factory of `built` did to its entity, so another block can be built in it.
+fn tear_down(world: &mut World, built: &BuiltBlock) {
+    if let Some(teardown) = world
+        .get_factory(&built.block)
+        .and_then(|factory| factory.teardown)
+    {
+        teardown(built.entity, world);
+    }
+}
+
+fn is_same_block(world: &World, built: &BuiltBlock, expansion: &Structure) -> bool {
+    built.block == expansion.a_ref
+        && block_definition(world, &expansion.a_ref).as_ref() == Ok(&built.definition)
+}
+
+/// Makes what was built for a block match `expansion`, which must be of the same block. Children
+/// that are still the same block are patched, and the rest are rebuilt.
+fn patch_block(
+    world: &mut World,
+    mut built: BuiltBlock,
+    expansion: &Structure,
+) -> Result<BuiltBlock, InstantiationError> {
+    let mut old_children = std::mem::take(&mut built.children);
+    for (slot_name, slot_entity) in built.slots.clone() {
+        let old_child = old_children.remove(&slot_name);
+        let child = match (old_child, expansion.c.get(&slot_name)) {
+            (Some(child), Some(Connection::Structure(substructure)))
+                if is_same_block(world, &child, substructure) =>
+            {
+                Some(patch_block(world, child, substructure)?)
+            }
+            (old_child, connection) => {
+                if let Some(old_child) = old_child {
+                    world.destroy(old_child.entity);
+                }
+                match connection {
+                    Some(Connection::Structure(substructure)) => {
+                        Some(build_child(world, slot_entity, substructure)?)
                     }
-                },
-                None => panic!("Err(YCreateError::NoCforS(s))"),
+                    _ => None,
+                }
             }
+        };
+        if let Some(child) = child {
+            built.children.insert(slot_name, child);
+        }
+    }
+    Ok(built)
+}
+
+/// Brings every instance up to date with the `CurrentModel`.
+pub fn update_instances(world: &mut World) {
+    let revision = match world.get_resource::<CurrentModel>() {
+        Some(model) => model.revision(),
+        None => return,
+    };
+    let mut query = world.query::<(Entity, &ArtifactInstance)>();
+    let outdated: Vec<Entity> = query
+        .iter(world)
+        .filter(|(_, instance)| instance.revision != revision)
+        .map(|(entity, _)| entity)
+        .collect();
+    for root in outdated {
+        if let Err(err) = world.patch_instance(root) {
+            warn!("Could not update instance: {}", err);
         }
-        exposed_slots
     }
 }
 
-#[test]
-#[ignore]
-fn create_instance_of_basic_block() {
-    todo!(
-        "
-            - Define a factory for a basic block with one component.
-            - In an empty world instance one of those artifacts.
-            - Check if the component got created.
-            "
-    )
+#[cfg(test)]
+mod test {
+    use super::super::mursten::example::*;
+    use super::*;
+    use bevy::ecs::component::Component;
+
+    struct Zero;
+    struct Successor;
+
+    fn peano_world(aref: &str) -> (World, Entity) {
+        let mut model = peano_model();
+        model.set_artifact(
+            ar("number_2"),
+            Artifact::Structure(Structure {
+                a_ref: ar("successor"),
+                c: hashmap! {
+                    sn("x") => Connection::Structure(Structure {
+                        a_ref: ar("successor"),
+                        c: hashmap! {
+                            sn("x") => Connection::Slot(sn("n")),
+                        },
+                    }),
+                },
+            }),
+        );
+        let mut world = World::default();
+        world.insert_resource(CurrentModel::new(Box::new(model)));
+        world.register_factory(
+            ar("zero"),
+            BlockFactory::new(|root, world| {
+                world.entity_mut(root).insert(Zero);
+                Ok(hashmap! {})
+            })
+            .with_teardown(|root, world| {
+                world.entity_mut(root).remove::<Zero>();
+            }),
+        );
+        world.register_factory(
+            ar("successor"),
+            BlockFactory::new(|root, world| {
+                world.entity_mut(root).insert(Successor);
+                let x = world.spawn().id();
+                world.entity_mut(root).push_children(&[x]);
+                Ok(hashmap! { sn("x") => x })
+            })
+            .with_teardown(|root, world| {
+                world.entity_mut(root).remove::<Successor>();
+            }),
+        );
+        let root = world.alloc();
+        Box::new(CreateInstance {
+            aref: ar(aref),
+            root,
+        })
+        .write(&mut world);
+        (world, root)
+    }
+
+    fn count<T: Component>(world: &mut World) -> usize {
+        let mut query = world.query::<&T>();
+        query.iter(world).count()
+    }
+
+    #[test]
+    fn create_instance_of_basic_block() {
+        let (mut world, root) = peano_world("zero");
+        assert!(world.get::<Zero>(root).is_some());
+        let instance = world.get::<ArtifactInstance>(root).unwrap();
+        assert_eq!(instance.aref, ar("zero"));
+        assert_eq!(instance.revision, 0);
+        assert_eq!(instance.slots, hashmap! {});
+        assert_eq!(count::<Zero>(&mut world), 1);
+        assert_eq!(count::<Successor>(&mut world), 0);
+    }
+
+    #[test]
+    fn nested_structures_are_parented_to_slots() {
+        let (mut world, root) = peano_world("number_2");
+        assert_eq!(count::<Successor>(&mut world), 2);
+        assert_eq!(count::<Zero>(&mut world), 0);
+
+        let instance = world.get::<ArtifactInstance>(root).unwrap().clone();
+        assert_eq!(instance.aref, ar("number_2"));
+        let exposed = instance.slots[&sn("n")];
+
+        // root -> 'x -> inner successor -> 'x (exposed as 'n)
+        let x = world.get::<Children>(root).unwrap()[0];
+        let inner = world.get::<Children>(x).unwrap()[0];
+        assert!(world.get::<Successor>(inner).is_some());
+        assert_eq!(world.get::<Parent>(exposed).map(|p| p.0), Some(inner));
+    }
+
+    #[test]
+    fn destroying_an_instance_despawns_everything() {
+        let (mut world, root) = peano_world("number_2");
+        assert_eq!(world.entities().len(), 4);
+        Box::new(DestroyInstance { root }).write(&mut world);
+        assert_eq!(world.entities().len(), 0);
+    }
+
+    #[test]
+    fn artifacts_without_factory_are_not_instantiated() {
+        let mut world = World::default();
+        world.insert_resource(CurrentModel::new(Box::new(peano_model())));
+        let root = world.alloc();
+        assert_eq!(
+            world.build_artifact(&ar("zero"), root),
+            Err(InstantiationError::MissingFactory(ar("zero")))
+        );
+        assert_eq!(
+            world.build_artifact(&ar("one"), root),
+            Err(InstantiationError::Expansion(ExpansionError::Unexistent(
+                vec![],
+                ar("one")
+            )))
+        );
+    }
+
+    #[test]
+    fn failed_instances_only_despawn_what_they_built() {
+        let (mut world, _) = peano_world("zero");
+        {
+            let mut model = world.get_resource_mut::<CurrentModel>().unwrap();
+            model.set_artifact(
+                ar("one"),
+                Artifact::Block(Block {
+                    main_slot_kind: sk("Natural"),
+                    slots: hashmap! {},
+                }),
+            );
+            model.set_artifact(
+                ar("number_1"),
+                Artifact::Structure(Structure {
+                    a_ref: ar("successor"),
+                    c: hashmap! {
+                        sn("x") => Connection::Structure(Structure {
+                            a_ref: ar("one"),
+                            c: hashmap! {},
+                        }),
+                    },
+                }),
+            );
+        }
+        let root = world.alloc();
+        let attached = world.alloc();
+        world.entity_mut(root).push_children(&[attached]);
+        let entities = world.entities().len();
+
+        Box::new(CreateInstance {
+            aref: ar("number_1"),
+            root,
+        })
+        .write(&mut world);
+        assert!(world.get::<ArtifactInstance>(root).is_none());
+        assert_eq!(world.entities().len(), entities);
+        assert_eq!(
+            world
+                .get::<Children>(root)
+                .map(|children| children.to_vec()),
+            Some(vec![attached])
+        );
+
+        world.remove_resource::<CurrentModel>();
+        assert_eq!(
+            world.build_artifact(&ar("zero"), root),
+            Err(InstantiationError::NoCurrentModel)
+        );
+    }
+
+    fn execute(world: &mut World, command: ModelCommand) {
+        world
+            .get_resource_mut::<CurrentModel>()
+            .unwrap()
+            .execute(command)
+            .unwrap();
+        update_instances(world);
+    }
+
+    fn child_in_slot(world: &World, slot_entity: Entity) -> Option<Entity> {
+        world
+            .get::<Children>(slot_entity)
+            .and_then(|children| children.first().cloned())
+    }
+
+    #[test]
+    fn instances_are_patched_when_the_model_changes() {
+        let (mut world, root) = peano_world("number_2");
+        let outer_x = child_in_slot(&world, root).unwrap();
+        let inner = child_in_slot(&world, outer_x).unwrap();
+        let inner_x = child_in_slot(&world, inner).unwrap();
+        let inner_location = Location::root().child(sn("x"));
+
+        execute(
+            &mut world,
+            ModelCommand::Connect(
+                ar("number_2"),
+                inner_location.clone(),
+                sn("x"),
+                Connection::Structure(Structure {
+                    a_ref: ar("zero"),
+                    c: hashmap! {},
+                }),
+            ),
+        );
+        let instance = world.get::<ArtifactInstance>(root).unwrap().clone();
+        assert_eq!(instance.revision, 1);
+        assert!(instance.slots.is_empty());
+        // Untouched blocks keep their entities
+        assert_eq!(child_in_slot(&world, root), Some(outer_x));
+        assert_eq!(child_in_slot(&world, outer_x), Some(inner));
+        assert_eq!(child_in_slot(&world, inner), Some(inner_x));
+        let zero = child_in_slot(&world, inner_x).unwrap();
+        assert!(world.get::<Zero>(zero).is_some());
+        assert_eq!(world.entities().len(), 5);
+
+        execute(
+            &mut world,
+            ModelCommand::Disconnect(ar("number_2"), Location::root(), sn("x")),
+        );
+        assert_eq!(child_in_slot(&world, outer_x), None);
+        assert_eq!(count::<Successor>(&mut world), 1);
+        assert_eq!(count::<Zero>(&mut world), 0);
+        assert_eq!(world.entities().len(), 2);
+    }
+
+    #[test]
+    fn changes_outside_the_history_forget_it() {
+        let mut model = CurrentModel::new(Box::new(peano_model()));
+        model
+            .execute(ModelCommand::AddBlock(ar("one"), sk("Natural")))
+            .unwrap();
+        model
+            .execute(ModelCommand::AddBlock(ar("two"), sk("Natural")))
+            .unwrap();
+        model.undo().unwrap();
+        assert!(model.can_undo() && model.can_redo());
+
+        model.remove_artifact(&ar("one"));
+        assert!(!model.can_undo() && !model.can_redo());
+        assert_eq!(model.undo(), Err(ModelCommandError::NothingToUndo));
+
+        model
+            .execute(ModelCommand::DeclareSubkind(sk("Positive"), sk("Natural")))
+            .unwrap();
+        model.set_kinds(KindLattice::default());
+        assert!(!model.can_undo());
+        assert_eq!(model.revision(), 6);
+    }
+
+    #[test]
+    fn changed_blocks_are_rebuilt() {
+        let (mut world, root) = peano_world("number_2");
+        let outer_x = child_in_slot(&world, root).unwrap();
+        let inner = child_in_slot(&world, outer_x).unwrap();
+
+        execute(
+            &mut world,
+            ModelCommand::AddSlotToBlock(ar("successor"), sn("y"), sk("Natural")),
+        );
+        let new_outer_x = child_in_slot(&world, root).unwrap();
+        let new_inner = child_in_slot(&world, new_outer_x).unwrap();
+        assert_ne!(new_inner, inner);
+        assert!(world.get::<Successor>(new_inner).is_some());
+        assert_eq!(count::<Successor>(&mut world), 2);
+        assert_eq!(world.entities().len(), 4);
+
+        let instance = world.get::<ArtifactInstance>(root).unwrap().clone();
+        assert_eq!(
+            instance.slots,
+            hashmap! { sn("n") => child_in_slot(&world, new_inner).unwrap() }
+        );
+    }
+
+    #[test]
+    fn changing_the_root_block_tears_down_the_old_one() {
+        let (mut world, root) = peano_world("number_2");
+        world
+            .get_resource_mut::<CurrentModel>()
+            .unwrap()
+            .set_artifact(
+                ar("number_2"),
+                Artifact::Structure(Structure {
+                    a_ref: ar("zero"),
+                    c: hashmap! {},
+                }),
+            );
+        update_instances(&mut world);
+
+        assert!(world.get::<Zero>(root).is_some());
+        assert!(world.get::<Successor>(root).is_none());
+        assert!(world.get::<Children>(root).is_none());
+        assert_eq!(count::<Successor>(&mut world), 0);
+        assert_eq!(world.entities().len(), 1);
+        let instance = world.get::<ArtifactInstance>(root).unwrap();
+        assert_eq!(instance.revision, 1);
+        assert!(instance.slots.is_empty());
+    }
+
+    #[test]
+    fn instances_survive_invalid_models() {
+        let (mut world, root) = peano_world("number_2");
+        world
+            .get_resource_mut::<CurrentModel>()
+            .unwrap()
+            .remove_artifact(&ar("number_2"));
+        update_instances(&mut world);
+        let instance = world.get::<ArtifactInstance>(root).unwrap().clone();
+        assert_eq!(instance.revision, 1);
+        assert_eq!(count::<Successor>(&mut world), 2);
+
+        world.remove_resource::<CurrentModel>();
+        assert_eq!(
+            world.patch_instance(root),
+            Err(InstantiationError::NoCurrentModel)
+        );
+        assert_eq!(count::<Successor>(&mut world), 2);
+    }
 }
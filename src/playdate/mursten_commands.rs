@@ -0,0 +1,767 @@
+use std::fmt::Display;
+
+use super::mursten::*;
+
+/// Every change that tools can do to a model. Commands are applied atomically: if one fails, the
+/// model is left as it was before applying it.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ModelCommand {
+    AddBlock(ArtifactReference, SlotKind),
+    AddArtifact(ArtifactReference, Artifact),
+    ChangeBlockKind(ArtifactReference, SlotKind),
+    AddSlotToBlock(ArtifactReference, SlotName, SlotKind),
+    RenameSlot(ArtifactReference, SlotName, SlotName),
+    RemoveBlockSlot(ArtifactReference, SlotName),
+    RemoveArtifact(ArtifactReference),
+    Connect(ArtifactReference, Location, SlotName, Connection),
+    Disconnect(ArtifactReference, Location, SlotName),
+    Swap(ArtifactReference, Location, ArtifactReference),
+    DeclareSubkind(SlotKind, SlotKind),
+    UndeclareSubkind(SlotKind, SlotKind),
+    /// Puts an artifact back as it was, or removes it if it did not exist, without the checks of
+    /// the safe refactors. It reverts additions, which must be undoable in invalid models too.
+    RestoreArtifact(ArtifactReference, Option<Artifact>),
+}
+
+/// What happened to the model as a result of a command.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ModelEvent {
+    ArtifactAdded(ArtifactReference),
+    ArtifactChanged(ArtifactReference),
+    ArtifactRemoved(ArtifactReference),
+    KindsChanged,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ModelCommandError {
+    ArtifactAlreadyExists(ArtifactReference),
+    UnexistentArtifact(ArtifactReference),
+    NotABlock(ArtifactReference),
+    NotAStructure(ArtifactReference),
+    SlotAlreadyExists(ArtifactReference, SlotName),
+    UnexistentSlot(ArtifactReference, SlotName),
+    Edit(ArtifactReference, StructureEditError),
+    KindCycle(SlotKind, SlotKind),
+    UndeclaredSubkind(SlotKind, SlotKind),
+    Refactor(RefactorError),
+    InvalidatesModel,
+    NothingToUndo,
+    NothingToRedo,
+}
+
+impl Display for ModelCommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModelCommandError::ArtifactAlreadyExists(aref) => {
+                write!(f, "Artifact {} already exists", aref)
+            }
+            ModelCommandError::UnexistentArtifact(aref) => {
+                write!(f, "Artifact {} does not exists", aref)
+            }
+            ModelCommandError::NotABlock(aref) => write!(f, "Artifact {} is not a block", aref),
+            ModelCommandError::NotAStructure(aref) => {
+                write!(f, "Artifact {} is not a structure", aref)
+            }
+            ModelCommandError::SlotAlreadyExists(aref, slot_name) => {
+                write!(f, "Slot {} already exists in {}", slot_name, aref)
+            }
+            ModelCommandError::UnexistentSlot(aref, slot_name) => {
+                write!(f, "Slot {} does not exists in {}", slot_name, aref)
+            }
+            ModelCommandError::Edit(aref, err) => write!(f, "In {}: {}", aref, err),
+            ModelCommandError::KindCycle(kind, superkind) => write!(
+                f,
+                "{} cannot be a subkind of {} because {} already accepts {}",
+                kind, superkind, kind, superkind
+            ),
+            ModelCommandError::UndeclaredSubkind(kind, superkind) => {
+                write!(f, "{} is not declared as a subkind of {}", kind, superkind)
+            }
+            ModelCommandError::Refactor(err) => err.fmt(f),
+            ModelCommandError::InvalidatesModel => f.write_str("This would invalidate the model"),
+            ModelCommandError::NothingToUndo => f.write_str("Nothing to undo"),
+            ModelCommandError::NothingToRedo => f.write_str("Nothing to redo"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AppliedCommand {
+    pub command: ModelCommand,
+    pub inverse: ModelCommand,
+    pub events: Vec<ModelEvent>,
+}
+
+impl ModelCommand {
+    pub fn apply(self, model: &mut dyn Model) -> Result<AppliedCommand, ModelCommandError> {
+        let was_valid = model.is_all_valid();
+        let mut recorder = Recorder {
+            model,
+            touched: vec![],
+            kinds_before: None,
+        };
+
+        let result = self.run(&mut recorder).and_then(|inverse| {
+            if was_valid && !recorder.is_all_valid() {
+                Err(ModelCommandError::InvalidatesModel)
+            } else {
+                Ok(inverse)
+            }
+        });
+
+        match result {
+            Ok(inverse) => Ok(AppliedCommand {
+                events: recorder.events(),
+                command: self,
+                inverse,
+            }),
+            Err(err) => {
+                recorder.rollback();
+                Err(err)
+            }
+        }
+    }
+
+    /// Does the actual change and returns the command that reverts it.
+    fn run(&self, model: &mut dyn Model) -> Result<ModelCommand, ModelCommandError> {
+        match self {
+            ModelCommand::AddBlock(aref, slot_kind) => {
+                let block = Artifact::Block(Block {
+                    main_slot_kind: slot_kind.clone(),
+                    slots: Default::default(),
+                });
+                ModelCommand::AddArtifact(aref.clone(), block).run(model)
+            }
+            ModelCommand::AddArtifact(aref, artifact) => {
+                if model.exists_artifact(aref) {
+                    return Err(ModelCommandError::ArtifactAlreadyExists(aref.clone()));
+                }
+                model.set_artifact(aref.clone(), artifact.clone());
+                Ok(ModelCommand::RestoreArtifact(aref.clone(), None))
+            }
+            ModelCommand::ChangeBlockKind(aref, slot_kind) => {
+                let mut block = get_block(model, aref)?;
+                let old_slot_kind = std::mem::replace(&mut block.main_slot_kind, slot_kind.clone());
+                model.set_artifact(aref.clone(), Artifact::Block(block));
+                Ok(ModelCommand::ChangeBlockKind(aref.clone(), old_slot_kind))
+            }
+            ModelCommand::AddSlotToBlock(aref, slot_name, slot_kind) => {
+                let mut block = get_block(model, aref)?;
+                if block.slots.contains_key(slot_name) {
+                    return Err(ModelCommandError::SlotAlreadyExists(
+                        aref.clone(),
+                        slot_name.clone(),
+                    ));
+                }
+                let previous = Artifact::Block(block.clone());
+                block.slots.insert(slot_name.clone(), slot_kind.clone());
+                model.set_artifact(aref.clone(), Artifact::Block(block));
+                Ok(ModelCommand::RestoreArtifact(aref.clone(), Some(previous)))
+            }
+            ModelCommand::RenameSlot(aref, old_slot_name, new_slot_name) => {
+                model
+                    .rename_slot(aref, old_slot_name, new_slot_name.clone())
+                    .map_err(ModelCommandError::Refactor)?;
+                Ok(ModelCommand::RenameSlot(
+                    aref.clone(),
+                    new_slot_name.clone(),
+                    old_slot_name.clone(),
+                ))
+            }
+            ModelCommand::RemoveBlockSlot(aref, slot_name) => {
+                let slot_kind = match model.get_artifact(aref) {
+                    Some(Artifact::Block(block)) => block.slots.get(slot_name).cloned(),
+                    _ => None,
+                };
+                model
+                    .safely_remove_block_slot(aref, slot_name)
+                    .map_err(ModelCommandError::Refactor)?;
+                Ok(ModelCommand::AddSlotToBlock(
+                    aref.clone(),
+                    slot_name.clone(),
+                    slot_kind.expect("Removed slot should have had a kind"),
+                ))
+            }
+            ModelCommand::RemoveArtifact(aref) => {
+                let artifact = model
+                    .get_artifact(aref)
+                    .cloned()
+                    .ok_or_else(|| ModelCommandError::UnexistentArtifact(aref.clone()))?;
+                model
+                    .safely_remove_artifact(aref)
+                    .map_err(ModelCommandError::Refactor)?;
+                Ok(ModelCommand::AddArtifact(aref.clone(), artifact))
+            }
+            ModelCommand::Connect(aref, location, slot_name, connection) => {
+                let mut structure = get_structure(model, aref)?;
+                let target = structure
+                    .at(location)
+                    .map_err(|err| ModelCommandError::Edit(aref.clone(), err))?
+                    .a_ref
+                    .clone();
+                let available_slots = model.slots_of(&target).unwrap_or_default();
+                if !available_slots.contains_key(slot_name) {
+                    return Err(ModelCommandError::UnexistentSlot(target, slot_name.clone()));
+                }
+                let previous = structure
+                    .connect(location, slot_name, connection.clone())
+                    .map_err(|err| ModelCommandError::Edit(aref.clone(), err))?;
+                model.set_artifact(aref.clone(), Artifact::Structure(structure));
+                Ok(match previous {
+                    Some(previous) => ModelCommand::Connect(
+                        aref.clone(),
+                        location.clone(),
+                        slot_name.clone(),
+                        previous,
+                    ),
+                    None => {
+                        ModelCommand::Disconnect(aref.clone(), location.clone(), slot_name.clone())
+                    }
+                })
+            }
+            ModelCommand::Disconnect(aref, location, slot_name) => {
+                let mut structure = get_structure(model, aref)?;
+                let previous = structure
+                    .disconnect(location, slot_name)
+                    .map_err(|err| ModelCommandError::Edit(aref.clone(), err))?;
+                model.set_artifact(aref.clone(), Artifact::Structure(structure));
+                Ok(ModelCommand::Connect(
+                    aref.clone(),
+                    location.clone(),
+                    slot_name.clone(),
+                    previous,
+                ))
+            }
+            ModelCommand::Swap(aref, location, new_aref) => {
+                let mut structure = get_structure(model, aref)?;
+                let old_aref = structure
+                    .swap(location, new_aref.clone(), model)
+                    .map_err(|err| ModelCommandError::Edit(aref.clone(), err))?;
+                model.set_artifact(aref.clone(), Artifact::Structure(structure));
+                Ok(ModelCommand::Swap(aref.clone(), location.clone(), old_aref))
+            }
+            ModelCommand::DeclareSubkind(kind, superkind) => {
+                let mut kinds = model.kinds().clone();
+                if !kinds.declare(kind.clone(), superkind.clone()) {
+                    return Err(ModelCommandError::KindCycle(
+                        kind.clone(),
+                        superkind.clone(),
+                    ));
+                }
+                model.set_kinds(kinds);
+                Ok(ModelCommand::UndeclareSubkind(
+                    kind.clone(),
+                    superkind.clone(),
+                ))
+            }
+            ModelCommand::UndeclareSubkind(kind, superkind) => {
+                let mut kinds = model.kinds().clone();
+                if !kinds.undeclare(kind, superkind) {
+                    return Err(ModelCommandError::UndeclaredSubkind(
+                        kind.clone(),
+                        superkind.clone(),
+                    ));
+                }
+                model.set_kinds(kinds);
+                Ok(ModelCommand::DeclareSubkind(
+                    kind.clone(),
+                    superkind.clone(),
+                ))
+            }
+            ModelCommand::RestoreArtifact(aref, artifact) => {
+                let previous = model.get_artifact(aref).cloned();
+                match artifact {
+                    Some(artifact) => model.set_artifact(aref.clone(), artifact.clone()),
+                    None => model.remove_artifact(aref),
+                }
+                Ok(ModelCommand::RestoreArtifact(aref.clone(), previous))
+            }
+        }
+    }
+}
+
+fn get_block(model: &dyn Model, aref: &ArtifactReference) -> Result<Block, ModelCommandError> {
+    match model.get_artifact(aref) {
+        None => Err(ModelCommandError::UnexistentArtifact(aref.clone())),
+        Some(Artifact::Structure(_)) => Err(ModelCommandError::NotABlock(aref.clone())),
+        Some(Artifact::Block(block)) => Ok(block.clone()),
+    }
+}
+
+fn get_structure(
+    model: &dyn Model,
+    aref: &ArtifactReference,
+) -> Result<Structure, ModelCommandError> {
+    match model.get_artifact(aref) {
+        None => Err(ModelCommandError::UnexistentArtifact(aref.clone())),
+        Some(Artifact::Block(_)) => Err(ModelCommandError::NotAStructure(aref.clone())),
+        Some(Artifact::Structure(structure)) => Ok(structure.clone()),
+    }
+}
+
+/// Wraps a model remembering how every touched artifact was before, so changes can be reported
+/// as events or reverted.
+struct Recorder<'a> {
+    model: &'a mut dyn Model,
+    touched: Vec<(ArtifactReference, Option<Artifact>)>,
+    kinds_before: Option<KindLattice>,
+}
+
+impl<'a> Recorder<'a> {
+    fn touch(&mut self, aref: &ArtifactReference) {
+        if !self.touched.iter().any(|(touched, _)| touched == aref) {
+            let before = self.model.get_artifact(aref).cloned();
+            self.touched.push((aref.clone(), before));
+        }
+    }
+
+    fn events(&self) -> Vec<ModelEvent> {
+        let kinds_changed = self
+            .kinds_before
+            .as_ref()
+            .map_or(false, |before| before != self.model.kinds());
+        let artifact_events = self.touched.iter().filter_map(|(aref, before)| {
+            match (before, self.model.get_artifact(aref)) {
+                (None, Some(_)) => Some(ModelEvent::ArtifactAdded(aref.clone())),
+                (Some(_), None) => Some(ModelEvent::ArtifactRemoved(aref.clone())),
+                (Some(before), Some(after)) if before != after => {
+                    Some(ModelEvent::ArtifactChanged(aref.clone()))
+                }
+                _ => None,
+            }
+        });
+        kinds_changed
+            .then(|| ModelEvent::KindsChanged)
+            .into_iter()
+            .chain(artifact_events)
+            .collect()
+    }
+
+    fn rollback(&mut self) {
+        for (aref, before) in self.touched.drain(..).rev() {
+            match before {
+                Some(artifact) => self.model.set_artifact(aref, artifact),
+                None => self.model.remove_artifact(&aref),
+            }
+        }
+        if let Some(kinds) = self.kinds_before.take() {
+            self.model.set_kinds(kinds);
+        }
+    }
+}
+
+impl<'a> Model for Recorder<'a> {
+    fn set_artifact(&mut self, aref: ArtifactReference, artifact: Artifact) {
+        self.touch(&aref);
+        self.model.set_artifact(aref, artifact)
+    }
+    fn remove_artifact(&mut self, aref: &ArtifactReference) {
+        self.touch(aref);
+        self.model.remove_artifact(aref)
+    }
+    fn get_artifact(&self, aref: &ArtifactReference) -> Option<&Artifact> {
+        self.model.get_artifact(aref)
+    }
+    fn list_artifacts(&self) -> Vec<ArtifactReference> {
+        self.model.list_artifacts()
+    }
+    fn kinds(&self) -> &KindLattice {
+        self.model.kinds()
+    }
+    fn set_kinds(&mut self, kinds: KindLattice) {
+        if self.kinds_before.is_none() {
+            self.kinds_before = Some(self.model.kinds().clone());
+        }
+        self.model.set_kinds(kinds)
+    }
+}
+
+/// Applied commands, kept to be undone and redone.
+#[derive(Debug, Default)]
+pub struct ModelHistory {
+    done: Vec<AppliedCommand>,
+    undone: Vec<AppliedCommand>,
+}
+
+impl ModelHistory {
+    pub fn can_undo(&self) -> bool {
+        !self.done.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.undone.is_empty()
+    }
+
+    pub fn execute(
+        &mut self,
+        model: &mut dyn Model,
+        command: ModelCommand,
+    ) -> Result<Vec<ModelEvent>, ModelCommandError> {
+        let applied = command.apply(model)?;
+        let events = applied.events.clone();
+        self.done.push(applied);
+        self.undone.clear();
+        Ok(events)
+    }
+
+    pub fn undo(&mut self, model: &mut dyn Model) -> Result<Vec<ModelEvent>, ModelCommandError> {
+        let applied = self.done.pop().ok_or(ModelCommandError::NothingToUndo)?;
+        match applied.inverse.clone().apply(model) {
+            Ok(reverted) => {
+                self.undone.push(applied);
+                Ok(reverted.events)
+            }
+            Err(err) => {
+                self.done.push(applied);
+                Err(err)
+            }
+        }
+    }
+
+    pub fn redo(&mut self, model: &mut dyn Model) -> Result<Vec<ModelEvent>, ModelCommandError> {
+        let applied = self.undone.pop().ok_or(ModelCommandError::NothingToRedo)?;
+        match applied.command.clone().apply(model) {
+            Ok(reapplied) => {
+                let events = reapplied.events.clone();
+                self.done.push(reapplied);
+                Ok(events)
+            }
+            Err(err) => {
+                self.undone.push(applied);
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::mursten::example::*;
+    use super::*;
+
+    fn snapshot(model: &dyn Model) -> (Vec<(String, Artifact)>, KindLattice) {
+        let mut artifacts: Vec<_> = model
+            .list_artifacts()
+            .into_iter()
+            .map(|aref| (aref.to_string(), model.get_artifact(&aref).unwrap().clone()))
+            .collect();
+        artifacts.sort_by(|a, b| a.0.cmp(&b.0));
+        (artifacts, model.kinds().clone())
+    }
+
+    fn assert_inverse_restores_model(model: &mut dyn Model, command: ModelCommand) {
+        let before = snapshot(model);
+        let applied = command.apply(model).expect("Command should apply");
+        assert_ne!(snapshot(model), before);
+        applied.inverse.apply(model).expect("Inverse should apply");
+        assert_eq!(snapshot(model), before);
+    }
+
+    #[test]
+    fn every_command_has_an_inverse() {
+        let mut model = and_one_more_is_five_model();
+        let commands = vec![
+            ModelCommand::AddBlock(ar("one"), sk("Natural")),
+            ModelCommand::AddArtifact(
+                ar("number_1"),
+                Artifact::Structure(Structure {
+                    a_ref: ar("successor"),
+                    c: hashmap! {},
+                }),
+            ),
+            ModelCommand::AddSlotToBlock(ar("zero"), sn("y"), sk("Natural")),
+            ModelCommand::RenameSlot(ar("successor"), sn("x"), sn("y")),
+            ModelCommand::RemoveArtifact(ar("number_5")),
+            ModelCommand::Disconnect(ar("number_5"), Location::root(), sn("x")),
+            ModelCommand::Connect(
+                ar("number_5"),
+                Location::root(),
+                sn("x"),
+                Connection::Structure(Structure {
+                    a_ref: ar("zero"),
+                    c: hashmap! {},
+                }),
+            ),
+        ];
+        for command in commands {
+            assert_inverse_restores_model(&mut model, command);
+        }
+
+        let mut model = two_and_two_is_four_model();
+        let inner = Location::root().child(sn("x"));
+        assert_inverse_restores_model(
+            &mut model,
+            ModelCommand::Swap(ar("number_4"), inner.clone(), ar("successor")),
+        );
+        assert_inverse_restores_model(
+            &mut model,
+            ModelCommand::Disconnect(ar("number_4"), inner.clone(), sn("x")),
+        );
+        assert_inverse_restores_model(
+            &mut model,
+            ModelCommand::Connect(ar("number_4"), inner, sn("x"), Connection::Slot(sn("n"))),
+        );
+
+        let mut model = peano_model();
+        assert_inverse_restores_model(
+            &mut model,
+            ModelCommand::DeclareSubkind(sk("PositiveNatural"), sk("Natural")),
+        );
+        assert_inverse_restores_model(
+            &mut model,
+            ModelCommand::ChangeBlockKind(ar("zero"), sk("Zero")),
+        );
+        assert_inverse_restores_model(
+            &mut model,
+            ModelCommand::RemoveBlockSlot(ar("successor"), sn("x")),
+        );
+    }
+
+    #[test]
+    fn commands_report_what_changed() {
+        let mut model = two_and_two_is_four_model();
+        let applied = ModelCommand::RenameSlot(ar("successor"), sn("x"), sn("y"))
+            .apply(&mut model)
+            .unwrap();
+        assert_eq!(
+            applied.events,
+            vec![
+                ModelEvent::ArtifactChanged(ar("successor")),
+                ModelEvent::ArtifactChanged(ar("plus_2")),
+            ]
+        );
+
+        let applied = ModelCommand::AddBlock(ar("one"), sk("Natural"))
+            .apply(&mut model)
+            .unwrap();
+        assert_eq!(applied.events, vec![ModelEvent::ArtifactAdded(ar("one"))]);
+
+        let applied = ModelCommand::RemoveArtifact(ar("one"))
+            .apply(&mut model)
+            .unwrap();
+        assert_eq!(applied.events, vec![ModelEvent::ArtifactRemoved(ar("one"))]);
+    }
+
+    #[test]
+    fn failing_commands_do_not_change_the_model() {
+        let mut model = two_and_two_is_four_model();
+        let before = snapshot(&model);
+
+        let res = ModelCommand::AddBlock(ar("zero"), sk("Natural")).apply(&mut model);
+        assert_eq!(
+            res,
+            Err(ModelCommandError::ArtifactAlreadyExists(ar("zero")))
+        );
+
+        let res =
+            ModelCommand::AddSlotToBlock(ar("plus_2"), sn("y"), sk("Natural")).apply(&mut model);
+        assert_eq!(res, Err(ModelCommandError::NotABlock(ar("plus_2"))));
+
+        let res = ModelCommand::RemoveArtifact(ar("zero")).apply(&mut model);
+        assert_eq!(
+            res,
+            Err(ModelCommandError::Refactor(RefactorError::ArtifactInUse {
+                aref: ar("zero"),
+                dependents: vec![ar("number_4")],
+            }))
+        );
+
+        let res = ModelCommand::Connect(
+            ar("number_4"),
+            Location::root(),
+            sn("x"),
+            Connection::Structure(Structure {
+                a_ref: ar("nothing"),
+                c: hashmap! {},
+            }),
+        )
+        .apply(&mut model);
+        assert_eq!(res, Err(ModelCommandError::InvalidatesModel));
+
+        let res = ModelCommand::ChangeBlockKind(ar("zero"), sk("Zero")).apply(&mut model);
+        assert_eq!(res, Err(ModelCommandError::InvalidatesModel));
+
+        let res = ModelCommand::Connect(
+            ar("number_4"),
+            Location::root(),
+            sn("q"),
+            Connection::Slot(sn("q")),
+        )
+        .apply(&mut model);
+        assert_eq!(
+            res,
+            Err(ModelCommandError::UnexistentSlot(ar("plus_2"), sn("q")))
+        );
+
+        let deepest = Location(vec![sn("x"), sn("x")]);
+        let res = ModelCommand::Connect(
+            ar("number_4"),
+            deepest.clone(),
+            sn("x"),
+            Connection::Slot(sn("x")),
+        )
+        .apply(&mut model);
+        assert_eq!(
+            res,
+            Err(ModelCommandError::UnexistentSlot(ar("zero"), sn("x")))
+        );
+
+        let res =
+            ModelCommand::Disconnect(ar("number_4"), deepest.clone(), sn("x")).apply(&mut model);
+        assert_eq!(
+            res,
+            Err(ModelCommandError::Edit(
+                ar("number_4"),
+                StructureEditError::NotConnected(deepest, sn("x"))
+            ))
+        );
+
+        let res =
+            ModelCommand::Swap(ar("number_4"), Location::root(), ar("zero")).apply(&mut model);
+        assert_eq!(res, Err(ModelCommandError::InvalidatesModel));
+
+        let res = ModelCommand::Disconnect(ar("zero"), Location::root(), sn("x")).apply(&mut model);
+        assert_eq!(res, Err(ModelCommandError::NotAStructure(ar("zero"))));
+
+        let res =
+            ModelCommand::UndeclareSubkind(sk("PositiveNatural"), sk("Natural")).apply(&mut model);
+        assert_eq!(
+            res,
+            Err(ModelCommandError::UndeclaredSubkind(
+                sk("PositiveNatural"),
+                sk("Natural")
+            ))
+        );
+
+        assert_eq!(snapshot(&model), before);
+        model.validate_model().unwrap();
+    }
+
+    #[test]
+    fn undeclaring_a_used_subkind_is_rolled_back() {
+        let mut model = peano_model();
+        ModelCommand::DeclareSubkind(sk("PositiveNatural"), sk("Natural"))
+            .apply(&mut model)
+            .unwrap();
+        let applied = ModelCommand::AddBlock(ar("one"), sk("PositiveNatural"))
+            .apply(&mut model)
+            .unwrap();
+        assert_eq!(applied.events, vec![ModelEvent::ArtifactAdded(ar("one"))]);
+        ModelCommand::AddArtifact(
+            ar("two"),
+            Artifact::Structure(Structure {
+                a_ref: ar("successor"),
+                c: hashmap! {
+                    sn("x") => Connection::Structure(Structure {
+                        a_ref: ar("one"),
+                        c: hashmap! {},
+                    }),
+                },
+            }),
+        )
+        .apply(&mut model)
+        .unwrap();
+
+        let res =
+            ModelCommand::DeclareSubkind(sk("Natural"), sk("PositiveNatural")).apply(&mut model);
+        assert_eq!(
+            res,
+            Err(ModelCommandError::KindCycle(
+                sk("Natural"),
+                sk("PositiveNatural")
+            ))
+        );
+
+        let before = model.kinds().clone();
+        let res =
+            ModelCommand::UndeclareSubkind(sk("PositiveNatural"), sk("Natural")).apply(&mut model);
+        assert_eq!(res, Err(ModelCommandError::InvalidatesModel));
+        assert_eq!(model.kinds(), &before);
+
+        let applied = ModelCommand::DeclareSubkind(sk("Prime"), sk("PositiveNatural"))
+            .apply(&mut model)
+            .unwrap();
+        assert_eq!(applied.events, vec![ModelEvent::KindsChanged]);
+    }
+
+    #[test]
+    fn additions_can_be_undone_in_invalid_models() {
+        let mut model = peano_model();
+        model.set_artifact(
+            ar("broken"),
+            Artifact::Structure(Structure {
+                a_ref: ar("nothing"),
+                c: hashmap! {},
+            }),
+        );
+        assert!(!model.is_all_valid());
+        let initial = snapshot(&model);
+
+        let mut history = ModelHistory::default();
+        history
+            .execute(&mut model, ModelCommand::AddBlock(ar("one"), sk("Natural")))
+            .unwrap();
+        history
+            .execute(
+                &mut model,
+                ModelCommand::AddSlotToBlock(ar("zero"), sn("y"), sk("Natural")),
+            )
+            .unwrap();
+        let with_both = snapshot(&model);
+
+        let events = history.undo(&mut model).unwrap();
+        assert_eq!(events, vec![ModelEvent::ArtifactChanged(ar("zero"))]);
+        let events = history.undo(&mut model).unwrap();
+        assert_eq!(events, vec![ModelEvent::ArtifactRemoved(ar("one"))]);
+        assert_eq!(snapshot(&model), initial);
+
+        history.redo(&mut model).unwrap();
+        history.redo(&mut model).unwrap();
+        assert_eq!(snapshot(&model), with_both);
+    }
+
+    #[test]
+    fn history_undoes_and_redoes_commands() {
+        let mut model = peano_model();
+        let mut history = ModelHistory::default();
+        let initial = snapshot(&model);
+        assert!(!history.can_undo());
+        assert_eq!(
+            history.undo(&mut model),
+            Err(ModelCommandError::NothingToUndo)
+        );
+
+        history
+            .execute(&mut model, ModelCommand::AddBlock(ar("one"), sk("Natural")))
+            .unwrap();
+        history
+            .execute(
+                &mut model,
+                ModelCommand::AddSlotToBlock(ar("one"), sn("x"), sk("Natural")),
+            )
+            .unwrap();
+        let with_both = snapshot(&model);
+
+        let events = history.undo(&mut model).unwrap();
+        assert_eq!(events, vec![ModelEvent::ArtifactChanged(ar("one"))]);
+        let events = history.undo(&mut model).unwrap();
+        assert_eq!(events, vec![ModelEvent::ArtifactRemoved(ar("one"))]);
+        assert_eq!(snapshot(&model), initial);
+        assert!(history.can_redo());
+
+        history.redo(&mut model).unwrap();
+        history.redo(&mut model).unwrap();
+        assert_eq!(snapshot(&model), with_both);
+        assert_eq!(
+            history.redo(&mut model),
+            Err(ModelCommandError::NothingToRedo)
+        );
+
+        history.undo(&mut model).unwrap();
+        history
+            .execute(&mut model, ModelCommand::RemoveArtifact(ar("one")))
+            .unwrap();
+        assert!(!history.can_redo());
+        assert_eq!(snapshot(&model), initial);
+    }
+}
@@ -0,0 +1,513 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use super::mursten::*;
+use super::mursten_persistence::*;
+
+const EXTENSION: &str = "mursten";
+const KINDS_FILE: &str = "kinds.lattice";
+/// Files modified more recently than this could be modified again without their modification
+/// time changing, so they are read again on the next reload.
+const RECENT_MODIFICATION: Duration = Duration::from_secs(2);
+
+/// A model stored in a directory, with one file per artifact written in the text format of
+/// `mursten_persistence`. Every change is written to disk right away, and reads are served from
+/// memory. Files edited by someone else are picked up by `reload`. Declared kinds are kept
+/// together in their own file.
+#[derive(Debug)]
+pub struct DirectoryModel {
+    path: PathBuf,
+    artifacts: HashMap<ArtifactReference, Artifact>,
+    // What was last seen on disk for each artifact, to tell apart our writes from external edits.
+    files: HashMap<ArtifactReference, String>,
+    kinds: KindLattice,
+    kinds_file: Option<String>,
+    // Modification time of the files when they were last read, to skip the ones not modified since.
+    read_times: HashMap<PathBuf, SystemTime>,
+    // What was wrong with each file the last time it was reported, to report each problem once.
+    reported: HashMap<PathBuf, String>,
+    errors: Vec<PersistenceError>,
+}
+
+impl DirectoryModel {
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self, PersistenceError> {
+        let path = path.into();
+        std::fs::create_dir_all(&path).map_err(PersistenceError::Io)?;
+        let mut model = Self {
+            path,
+            artifacts: HashMap::new(),
+            files: HashMap::new(),
+            kinds: KindLattice::default(),
+            kinds_file: None,
+            read_times: HashMap::new(),
+            reported: HashMap::new(),
+            errors: vec![],
+        };
+        if let Some(contents) = model.read_kinds_file()? {
+            model.kinds = parse_kinds_file(&model.path.join(KINDS_FILE), &contents)?;
+            model.kinds_file = Some(contents);
+        }
+        for (aref, path) in model.list_files()? {
+            let contents = model.read(&path)?;
+            let artifact = parse_artifact_file(&path, &aref, &contents)?;
+            model.artifacts.insert(aref.clone(), artifact);
+            model.files.insert(aref, contents);
+        }
+        Ok(model)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn file_path(&self, aref: &ArtifactReference) -> PathBuf {
+        self.path
+            .join(format!("{}.{}", encode_file_stem(aref.as_str()), EXTENSION))
+    }
+
+    fn read_kinds_file(&mut self) -> Result<Option<String>, PersistenceError> {
+        let path = self.path.join(KINDS_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        self.read(&path).map(Some)
+    }
+
+    /// Reads a file, remembering when it was modified.
+    fn read(&mut self, path: &Path) -> Result<String, PersistenceError> {
+        let modified = modification_time(path);
+        let contents = std::fs::read_to_string(path).map_err(PersistenceError::Io)?;
+        match modified {
+            Some(modified) if !is_recent(modified) => {
+                self.read_times.insert(path.to_path_buf(), modified);
+            }
+            _ => {
+                self.read_times.remove(path);
+            }
+        }
+        Ok(contents)
+    }
+
+    fn modified_since_read(&self, path: &Path) -> bool {
+        modification_time(path).map_or(true, |modified| {
+            self.read_times.get(path) != Some(&modified)
+        })
+    }
+
+    /// Adds `error` to the errors unless it was already reported for the same `problem` in `path`,
+    /// like the same broken contents.
+    fn report_once(&mut self, path: &Path, problem: String, error: PersistenceError) {
+        if self.reported.get(path) != Some(&problem) {
+            self.reported.insert(path.to_path_buf(), problem);
+            self.errors.push(error);
+        }
+    }
+
+    fn reload_kinds(&mut self) -> bool {
+        let path = self.path.join(KINDS_FILE);
+        if path.exists() && !self.modified_since_read(&path) {
+            return false;
+        }
+        let contents = match self.read_kinds_file() {
+            Ok(contents) => contents,
+            Err(err) => {
+                self.report_once(&path, err.to_string(), err);
+                return false;
+            }
+        };
+        if contents == self.kinds_file {
+            return false;
+        }
+        let kinds = match contents {
+            Some(ref contents) => match parse_kinds_file(&path, contents) {
+                Ok(kinds) => kinds,
+                Err(err) => {
+                    self.report_once(&path, contents.clone(), err);
+                    return false;
+                }
+            },
+            None => KindLattice::default(),
+        };
+        self.reported.remove(&path);
+        self.kinds_file = contents;
+        if kinds == self.kinds {
+            return false;
+        }
+        self.kinds = kinds;
+        true
+    }
+
+    /// The artifact files in the directory, with the artifact each one defines.
+    fn list_files(&self) -> Result<Vec<(ArtifactReference, PathBuf)>, PersistenceError> {
+        let mut files = vec![];
+        for entry in std::fs::read_dir(&self.path).map_err(PersistenceError::Io)? {
+            let path = entry.map_err(PersistenceError::Io)?.path();
+            if path.extension().map_or(true, |ext| ext != EXTENSION) {
+                continue;
+            }
+            let aref = match path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(decode_file_stem)
+            {
+                Some(name) => ar(name),
+                None => {
+                    return Err(PersistenceError::ArtifactFile(
+                        path,
+                        "Cannot tell the artifact name from the file name".into(),
+                    ))
+                }
+            };
+            files.push((aref, path));
+        }
+        Ok(files)
+    }
+}
+
+fn modification_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).ok()?.modified().ok()
+}
+
+fn is_recent(time: SystemTime) -> bool {
+    SystemTime::now()
+        .duration_since(time)
+        .map_or(true, |age| age < RECENT_MODIFICATION)
+}
+
+fn parse_artifact_file(
+    path: &Path,
+    aref: &ArtifactReference,
+    contents: &str,
+) -> Result<Artifact, PersistenceError> {
+    let model = from_text(contents)
+        .map_err(|err| PersistenceError::ArtifactFile(path.into(), err.to_string()))?;
+    let arefs = model.list_artifacts();
+    if arefs != vec![aref.clone()] {
+        return Err(PersistenceError::ArtifactFile(
+            path.into(),
+            format!("Expected only a definition of {}", aref),
+        ));
+    }
+    Ok(model.get_artifact(aref).unwrap().clone())
+}
+
+fn parse_kinds_file(path: &Path, contents: &str) -> Result<KindLattice, PersistenceError> {
+    let model = from_text(contents)
+        .map_err(|err| PersistenceError::ArtifactFile(path.into(), err.to_string()))?;
+    if !model.list_artifacts().is_empty() {
+        return Err(PersistenceError::ArtifactFile(
+            path.into(),
+            "Expected only kind declarations".into(),
+        ));
+    }
+    Ok(model.kinds().clone())
+}
+
+fn encode_file_stem(name: &str) -> String {
+    let mut stem = String::new();
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-' {
+            stem.push(byte as char);
+        } else {
+            write!(stem, "%{:02X}", byte).unwrap();
+        }
+    }
+    stem
+}
+
+fn decode_file_stem(stem: &str) -> Option<String> {
+    let mut bytes = vec![];
+    let mut rest = stem.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+impl Model for DirectoryModel {
+    fn set_artifact(&mut self, aref: ArtifactReference, artifact: Artifact) {
+        let contents = artifact_to_text(&aref, &artifact);
+        if let Err(err) = std::fs::write(self.file_path(&aref), &contents) {
+            self.errors.push(PersistenceError::Io(err));
+        }
+        self.files.insert(aref.clone(), contents);
+        self.artifacts.insert(aref, artifact);
+    }
+
+    fn remove_artifact(&mut self, aref: &ArtifactReference) {
+        if self.artifacts.remove(aref).is_some() {
+            if let Err(err) = std::fs::remove_file(self.file_path(aref)) {
+                self.errors.push(PersistenceError::Io(err));
+            }
+        }
+        self.files.remove(aref);
+    }
+
+    fn get_artifact(&self, aref: &ArtifactReference) -> Option<&Artifact> {
+        self.artifacts.get(aref)
+    }
+
+    fn list_artifacts(&self) -> Vec<ArtifactReference> {
+        self.artifacts.keys().cloned().collect()
+    }
+
+    fn kinds(&self) -> &KindLattice {
+        &self.kinds
+    }
+
+    fn set_kinds(&mut self, kinds: KindLattice) {
+        let path = self.path.join(KINDS_FILE);
+        let result = if kinds.is_empty() {
+            self.kinds_file = None;
+            if path.exists() {
+                std::fs::remove_file(path)
+            } else {
+                Ok(())
+            }
+        } else {
+            let contents = kinds_to_text(&kinds);
+            self.kinds_file = Some(contents.clone());
+            std::fs::write(path, contents)
+        };
+        if let Err(err) = result {
+            self.errors.push(PersistenceError::Io(err));
+        }
+        self.kinds = kinds;
+    }
+
+    fn take_errors(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.errors)
+            .into_iter()
+            .map(|err| err.to_string())
+            .collect()
+    }
+
+    fn reload(&mut self) -> Vec<ArtifactReference> {
+        let files = match self.list_files() {
+            Ok(files) => {
+                self.reported.remove(&self.path);
+                files
+            }
+            Err(err) => {
+                let path = self.path.clone();
+                self.report_once(&path, err.to_string(), err);
+                return vec![];
+            }
+        };
+
+        let mut changed = vec![];
+        let mut seen = vec![];
+        for (aref, path) in files {
+            seen.push(aref.clone());
+            if !self.modified_since_read(&path) {
+                continue;
+            }
+            let contents = match self.read(&path) {
+                Ok(contents) => contents,
+                Err(err) => {
+                    self.report_once(&path, err.to_string(), err);
+                    continue;
+                }
+            };
+            if self.files.get(&aref) == Some(&contents) {
+                self.reported.remove(&path);
+                continue;
+            }
+            // Files that cannot be parsed (probably because someone is still editing them) keep
+            // their last known version until they are fixed.
+            match parse_artifact_file(&path, &aref, &contents) {
+                Ok(artifact) => {
+                    if self.artifacts.get(&aref) != Some(&artifact) {
+                        changed.push(aref.clone());
+                    }
+                    self.reported.remove(&path);
+                    self.artifacts.insert(aref.clone(), artifact);
+                    self.files.insert(aref, contents);
+                }
+                Err(err) => self.report_once(&path, contents, err),
+            }
+        }
+
+        let removed: Vec<_> = self
+            .artifacts
+            .keys()
+            .filter(|aref| !seen.contains(aref))
+            .cloned()
+            .collect();
+        for aref in removed {
+            self.artifacts.remove(&aref);
+            self.files.remove(&aref);
+            changed.push(aref);
+        }
+
+        // Any artifact could be affected by a change in the kinds.
+        if self.reload_kinds() {
+            for aref in self.artifacts.keys() {
+                if !changed.contains(aref) {
+                    changed.push(aref.clone());
+                }
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::mursten::example::*;
+    use super::*;
+
+    fn empty_directory(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "mursten_directory_model_{}_{}",
+            name,
+            std::process::id()
+        ));
+        if path.exists() {
+            std::fs::remove_dir_all(&path).unwrap();
+        }
+        path
+    }
+
+    fn copy_of(model: &dyn Model, path: &Path) -> DirectoryModel {
+        let mut directory_model = DirectoryModel::open(path).unwrap();
+        for aref in model.list_artifacts() {
+            directory_model.set_artifact(aref.clone(), model.get_artifact(&aref).unwrap().clone());
+        }
+        directory_model
+    }
+
+    #[test]
+    fn artifacts_are_written_one_per_file() {
+        let path = empty_directory("one_per_file");
+        let mut model = copy_of(&two_and_two_is_four_model(), &path);
+        assert!(model.take_errors().is_empty());
+
+        assert_eq!(std::fs::read_dir(&path).unwrap().count(), 4);
+        assert_eq!(
+            std::fs::read_to_string(path.join("zero.mursten")).unwrap(),
+            "block zero: Natural {}\n"
+        );
+
+        let reopened = DirectoryModel::open(&path).unwrap();
+        reopened.validate_model().unwrap();
+        assert_eq!(peano_eval(&ar("number_4"), &reopened), Some(4));
+
+        model.remove_artifact(&ar("number_4"));
+        assert!(!path.join("number_4.mursten").exists());
+        assert_eq!(
+            DirectoryModel::open(&path).unwrap().list_artifacts().len(),
+            3
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn weird_artifact_names_are_encoded_in_file_names() {
+        let path = empty_directory("weird_names");
+        let mut model = DirectoryModel::open(&path).unwrap();
+        let aref = ar("a/b.c d%");
+        model.set_artifact(
+            aref.clone(),
+            Artifact::Block(Block {
+                main_slot_kind: sk("A"),
+                slots: hashmap! {},
+            }),
+        );
+        assert!(path.join("a%2Fb%2Ec%20d%25.mursten").exists());
+
+        let reopened = DirectoryModel::open(&path).unwrap();
+        assert_eq!(reopened.list_artifacts(), vec![aref]);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn reload_detects_external_edits() {
+        let path = empty_directory("external_edits");
+        let mut model = copy_of(&peano_model(), &path);
+        assert!(model.reload().is_empty());
+
+        std::fs::write(
+            path.join("one.mursten"),
+            "structure one = successor! { \"x\" => zero! {} }\n",
+        )
+        .unwrap();
+        std::fs::write(path.join("zero.mursten"), "block zero: Zero {}\n").unwrap();
+        let mut changed = model.reload();
+        changed.sort();
+        assert_eq!(changed, vec![ar("one"), ar("zero")]);
+        assert_eq!(peano_eval(&ar("one"), &model), Some(1));
+        assert_eq!(model.main_slot_kind_of(&ar("zero")).unwrap(), sk("Zero"));
+
+        std::fs::remove_file(path.join("one.mursten")).unwrap();
+        assert_eq!(model.reload(), vec![ar("one")]);
+        assert!(!model.exists_artifact(&ar("one")));
+        assert!(model.reload().is_empty());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn kinds_are_kept_in_their_own_file() {
+        let path = empty_directory("kinds");
+        let mut model = copy_of(&peano_model(), &path);
+        let mut kinds = KindLattice::default();
+        kinds.declare(sk("PositiveNatural"), sk("Natural"));
+        model.set_kinds(kinds.clone());
+        assert_eq!(
+            std::fs::read_to_string(path.join("kinds.lattice")).unwrap(),
+            "kind PositiveNatural: Natural\n"
+        );
+        assert_eq!(DirectoryModel::open(&path).unwrap().kinds(), &kinds);
+        assert!(model.reload().is_empty());
+
+        std::fs::write(path.join("kinds.lattice"), "kind Natural: Number\n").unwrap();
+        let mut changed = model.reload();
+        changed.sort();
+        assert_eq!(changed, vec![ar("successor"), ar("zero")]);
+        assert!(model.accepts_kind(&sk("Number"), &sk("Natural")));
+
+        model.set_kinds(KindLattice::default());
+        assert!(!path.join("kinds.lattice").exists());
+        assert!(model.take_errors().is_empty());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn broken_files_keep_their_last_version() {
+        let path = empty_directory("broken_files");
+        let mut model = copy_of(&peano_model(), &path);
+
+        std::fs::write(path.join("zero.mursten"), "block zero: {}\n").unwrap();
+        assert!(model.reload().is_empty());
+        assert_eq!(model.take_errors().len(), 1);
+        assert_eq!(model.main_slot_kind_of(&ar("zero")).unwrap(), sk("Natural"));
+        assert!(DirectoryModel::open(&path).is_err());
+
+        // The same broken contents are only reported once
+        assert!(model.reload().is_empty());
+        assert!(model.take_errors().is_empty());
+
+        std::fs::write(path.join("zero.mursten"), "block other: Natural {}\n").unwrap();
+        assert!(model.reload().is_empty());
+        assert_eq!(model.take_errors().len(), 1);
+        assert!(model.reload().is_empty());
+        assert!(model.take_errors().is_empty());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -1,6 +1,11 @@
 use std::collections::HashMap;
 
 use super::mursten::*;
+use super::mursten_bevy_plugin::CurrentModel;
+use super::mursten_commands::*;
+use super::mursten_graph::*;
+use super::mursten_persistence::*;
+use super::mursten_query::*;
 use bevy_egui::egui::*;
 
 #[derive(Debug, Default)]
@@ -13,7 +18,16 @@ pub struct ModelEditor {
 pub struct EditorContext {
     focused: bool,
     log: Vec<EditorLog>,
+    /// Revision of the model that `model_is_valid`, `model_is_connected`, `graph` and
+    /// `diagnostics` were worked out for.
+    revision: Option<usize>,
     model_is_valid: bool,
+    model_is_connected: bool,
+    can_undo: bool,
+    can_redo: bool,
+    graph: DependencyGraph,
+    diagnostics: HashMap<ArtifactReference, Vec<Diagnostic>>,
+    search: String,
     pending_actions: Vec<EditorAction>,
 }
 
@@ -24,7 +38,11 @@ enum EditorState {
     AddingABlock(String, String),
     ChangingBlockKind(ArtifactReference, String),
     AddingSlotToBlock(ArtifactReference, String, String),
-    RenamingBlockSlot(ArtifactReference, SlotName, String),
+    RenamingSlot(ArtifactReference, SlotName, String),
+    CreatingStructure(ArtifactReference, String),
+    ConnectingSlot(ArtifactReference, Location, SlotName, SlotKind, String),
+    SwappingArtifact(ArtifactReference, Location, ArtifactReference),
+    DeclaringSubkind(String, String),
 }
 
 impl Default for EditorState {
@@ -43,9 +61,24 @@ pub enum EditorAction {
     OpenAddSlotToBlockPrompt(ArtifactReference),
     ConfirmChangeBlockKind(ArtifactReference, SlotKind),
     ConfirmAddSlotToBlock(ArtifactReference, SlotName, SlotKind),
-    OpenRenameBlockSlotPrompt(ArtifactReference, SlotName),
-    ConfirmRenameBlockSlot(ArtifactReference, SlotName, SlotName),
+    OpenRenameSlotPrompt(ArtifactReference, SlotName),
+    ConfirmRenameSlot(ArtifactReference, SlotName, SlotName),
     RemoveBlockSlot(ArtifactReference, SlotName),
+    OpenCreateStructurePrompt(ArtifactReference),
+    ConfirmCreateStructure(ArtifactReference, ArtifactReference),
+    OpenConnectSlotPrompt(ArtifactReference, Location, SlotName, SlotKind),
+    ConfirmConnectSlot(ArtifactReference, Location, SlotName, Connection),
+    DisconnectSlot(ArtifactReference, Location, SlotName),
+    OpenSwapArtifactPrompt(ArtifactReference, Location, ArtifactReference),
+    ConfirmSwapArtifact(ArtifactReference, Location, ArtifactReference),
+    OpenDeclareSubkindPrompt,
+    ConfirmDeclareSubkind(SlotKind, SlotKind),
+    UndeclareSubkind(SlotKind, SlotKind),
+    Undo,
+    Redo,
+    SaveModel,
+    LoadModel,
+    ExportGraph,
 }
 
 #[derive(Debug)]
@@ -66,23 +99,68 @@ impl EditorContext {
     fn should(&mut self, action: EditorAction) {
         self.pending_actions.push(action);
     }
+
+    /// Works out again what the editor shows about the model, if it changed since last time.
+    fn refresh(&mut self, model: &dyn Model, revision: usize) {
+        if self.revision == Some(revision) {
+            return;
+        }
+        self.revision = Some(revision);
+        self.model_is_valid = model.validate_model().is_ok();
+        self.model_is_connected = model.is_all_connected();
+        self.graph = DependencyGraph::of(model);
+        self.diagnostics = model
+            .list_artifacts()
+            .into_iter()
+            .map(|aref| {
+                let diagnostics = model.diagnose(&aref);
+                (aref, diagnostics)
+            })
+            .collect();
+    }
 }
 
 impl EditorState {
     pub fn show(&mut self, context: &mut EditorContext, model: &dyn Model, ui: &mut Ui) {
-        context.model_is_valid = model.validate_model().is_ok();
         match self {
             // TODO: EditorState might be a trait, but it needs to define a way of dealing with
             // nested editor states. This seems closely related to the idea of ui components, but
             // using egui in this case. For now, is better to wait and see which patterns appear in
             // the code.
             EditorState::Listing => {
-                if ui.button("Add block").clicked() {
-                    context.should(EditorAction::OpenAddBlockPrompt);
-                }
+                ui.horizontal(|ui| {
+                    if ui.button("Add block").clicked() {
+                        context.should(EditorAction::OpenAddBlockPrompt);
+                    }
+                    if ui.button("Save").clicked() {
+                        context.should(EditorAction::SaveModel);
+                    }
+                    if ui.button("Load").clicked() {
+                        context.should(EditorAction::LoadModel);
+                    }
+                    if ui.button("Export graph").clicked() {
+                        context.should(EditorAction::ExportGraph);
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Search:");
+                    ui.text_edit_singleline(&mut context.search);
+                });
                 ui.separator();
-                for aref in model.list_artifacts().iter() {
-                    self.list_item(context, model, ui, aref);
+                self.list_kinds(context, model, ui);
+                match Query::parse(&context.search) {
+                    Ok(query) => {
+                        let found = model.query(&query);
+                        if found.is_empty() {
+                            ui.label("Nothing found");
+                        }
+                        for found in found.iter() {
+                            self.list_item(context, model, ui, &found.aref, &found.reasons);
+                        }
+                    }
+                    Err(err) => {
+                        ui.colored_label(Color32::RED, format!("{}", err));
+                    }
                 }
             }
             EditorState::AddingABlock(ref mut aref, ref mut slot_kind) => {
@@ -132,7 +210,7 @@ impl EditorState {
                     ));
                 }
             }
-            EditorState::RenamingBlockSlot(ref aref, ref slot_name, ref mut slot_new_name) => {
+            EditorState::RenamingSlot(ref aref, ref slot_name, ref mut slot_new_name) => {
                 ui.label(format!("You are renaming {} slot in {}", slot_name, aref));
                 ui.label("New name for the slot:");
                 ui.text_edit_singleline(slot_new_name);
@@ -141,18 +219,146 @@ impl EditorState {
                     context.should(EditorAction::GoToListing);
                 }
                 if ui.button("Rename").clicked() {
-                    context.should(EditorAction::ConfirmRenameBlockSlot(
+                    context.should(EditorAction::ConfirmRenameSlot(
                         aref.clone(),
                         slot_name.clone(),
                         sn(&*slot_new_name),
                     ));
                 }
             }
+            EditorState::DeclaringSubkind(ref mut kind, ref mut superkind) => {
+                ui.label("Kind:");
+                ui.text_edit_singleline(kind);
+                ui.label("Is accepted by slots of kind:");
+                ui.text_edit_singleline(superkind);
+                ui.separator();
+                if ui.button("Cancel").clicked() {
+                    context.should(EditorAction::GoToListing);
+                }
+                if ui.button("Declare").clicked() {
+                    context.should(EditorAction::ConfirmDeclareSubkind(
+                        sk(&*kind),
+                        sk(&*superkind),
+                    ));
+                }
+            }
+            EditorState::CreatingStructure(ref base_aref, ref mut aref) => {
+                ui.label(format!(
+                    "Artifact reference for the new structure of {}:",
+                    base_aref
+                ));
+                ui.text_edit_singleline(aref);
+                ui.separator();
+                if ui.button("Cancel").clicked() {
+                    context.should(EditorAction::GoToListing);
+                }
+                if ui.button("Create").clicked() {
+                    context.should(EditorAction::ConfirmCreateStructure(
+                        base_aref.clone(),
+                        ar(&*aref),
+                    ));
+                }
+            }
+            EditorState::ConnectingSlot(
+                ref aref,
+                ref location,
+                ref slot_name,
+                ref slot_kind,
+                ref mut exposed_slot_name,
+            ) => {
+                ui.label(format!(
+                    "You are connecting {} at {} in {}",
+                    slot_name, location, aref
+                ));
+                ui.label(format!("Artifacts of kind {}:", slot_kind));
+                for candidate in artifacts_of_kind(context, model, slot_kind, aref) {
+                    if ui.button(format!("{}", candidate)).clicked() {
+                        context.should(EditorAction::ConfirmConnectSlot(
+                            aref.clone(),
+                            location.clone(),
+                            slot_name.clone(),
+                            Connection::Structure(Structure {
+                                a_ref: candidate,
+                                c: Default::default(),
+                            }),
+                        ));
+                    }
+                }
+                ui.separator();
+                ui.label(format!("Or expose it as a slot of {} named:", aref));
+                ui.horizontal(|ui| {
+                    ui.text_edit_singleline(exposed_slot_name);
+                    if ui.button("Expose").clicked() {
+                        context.should(EditorAction::ConfirmConnectSlot(
+                            aref.clone(),
+                            location.clone(),
+                            slot_name.clone(),
+                            Connection::Slot(sn(&*exposed_slot_name)),
+                        ));
+                    }
+                });
+                ui.separator();
+                if ui.button("Cancel").clicked() {
+                    context.should(EditorAction::GoToListing);
+                }
+            }
+            EditorState::SwappingArtifact(ref aref, ref location, ref current_aref) => {
+                ui.label(format!(
+                    "You are replacing {} at {} in {}",
+                    current_aref, location, aref
+                ));
+                match model.main_slot_kind_of(current_aref) {
+                    Ok(slot_kind) => {
+                        ui.label(format!("Artifacts of kind {}:", slot_kind));
+                        for candidate in artifacts_of_kind(context, model, &slot_kind, aref) {
+                            if candidate == *current_aref {
+                                continue;
+                            }
+                            if ui.button(format!("{}", candidate)).clicked() {
+                                context.should(EditorAction::ConfirmSwapArtifact(
+                                    aref.clone(),
+                                    location.clone(),
+                                    candidate,
+                                ));
+                            }
+                        }
+                    }
+                    Err(_) => {
+                        ui.colored_label(
+                            Color32::RED,
+                            format!("Cannot get the kind of {}", current_aref),
+                        );
+                    }
+                }
+                ui.separator();
+                if ui.button("Cancel").clicked() {
+                    context.should(EditorAction::GoToListing);
+                }
+            }
         };
 
         ui.separator();
-        if context.model_is_valid {
+        ui.horizontal(|ui| {
+            if ui
+                .add(Button::new("undo").small().enabled(context.can_undo))
+                .clicked()
+            {
+                context.should(EditorAction::Undo);
+            }
+            if ui
+                .add(Button::new("redo").small().enabled(context.can_redo))
+                .clicked()
+            {
+                context.should(EditorAction::Redo);
+            }
+        });
+        if context.model_is_connected {
             ui.label("🌑 Model is valid");
+        } else if context.model_is_valid {
+            ui.colored_label(
+                Color32::YELLOW,
+                "🌓 Model is valid but has disconnected slots",
+            );
         } else {
             ui.colored_label(Color32::RED, "🌕 Model is not valid");
         }
@@ -164,12 +370,31 @@ impl EditorState {
         }
     }
 
+    fn list_kinds(&mut self, context: &mut EditorContext, model: &dyn Model, ui: &mut Ui) {
+        ui.collapsing("[K] Kinds", |ui| {
+            for (kind, superkind) in model.kinds().declarations() {
+                ui.horizontal(|ui| {
+                    ui.colored_label(Color32::LIGHT_BLUE, format!("{}", kind));
+                    ui.label("is a");
+                    ui.colored_label(Color32::LIGHT_BLUE, format!("{}", superkind));
+                    if ui.small_button("delete").clicked() {
+                        context.should(EditorAction::UndeclareSubkind(kind, superkind));
+                    }
+                });
+            }
+            if ui.small_button("declare subkind").clicked() {
+                context.should(EditorAction::OpenDeclareSubkindPrompt);
+            }
+        });
+    }
+
     fn list_item(
         &mut self,
         context: &mut EditorContext,
         model: &dyn Model,
         ui: &mut Ui,
         aref: &ArtifactReference,
+        reasons: &[MatchReason],
     ) {
         let artifact = model.get_artifact(aref).unwrap();
         let prefix = match artifact {
@@ -177,11 +402,31 @@ impl EditorState {
             Artifact::Structure(_) => "[S]",
         };
 
+        let diagnostics = context.diagnostics.get(aref).cloned().unwrap_or_default();
+        let recursive = diagnostics
+            .iter()
+            .any(|d| matches!(d, Diagnostic::RecursionDetected(_, _)));
+
         ui.collapsing(format!("{} {}", prefix, aref), |ui| {
+            for diagnostic in diagnostics.iter() {
+                let color = if diagnostic.is_error() {
+                    Color32::RED
+                } else {
+                    Color32::YELLOW
+                };
+                ui.colored_label(color, format!("{}", diagnostic));
+            }
+            CollapsingHeader::new("dependencies")
+                .id_source((aref, "dependencies"))
+                .show(ui, |ui| self.detail_dependencies(context, ui, aref));
             ui.horizontal(|ui| {
                 ui.vertical(|ui| match artifact {
                     Artifact::Block(b) => self.detail_block(context, model, ui, aref, b),
-                    Artifact::Structure(s) => self.detail_structure(context, model, ui, s),
+                    // Looking into the slots of a recursive structure would never end
+                    Artifact::Structure(_) if recursive => (),
+                    Artifact::Structure(s) => {
+                        self.detail_structure(context, model, ui, aref, &Location::root(), s)
+                    }
                 });
                 ui.with_layout(Layout::top_down(Align::Max), |ui| {
                     self.actions_artifact(context, model, ui, aref);
@@ -194,6 +439,27 @@ impl EditorState {
                 })
             })
         });
+        for reason in reasons {
+            ui.colored_label(Color32::GRAY, format!("    {}", reason));
+        }
+    }
+
+    fn detail_dependencies(
+        &mut self,
+        context: &mut EditorContext,
+        ui: &mut Ui,
+        aref: &ArtifactReference,
+    ) {
+        let graph = &context.graph;
+        if graph.in_cycle(aref) {
+            ui.colored_label(Color32::RED, "Depends on itself");
+        }
+        ui.label(format!("Uses: {}", listing(&graph.uses(aref))));
+        ui.label(format!("Used by: {}", listing(&graph.used_by(aref))));
+        let affected = graph.all_used_by(aref);
+        if affected.len() > graph.used_by(aref).len() {
+            ui.label(format!("Changing it affects: {}", listing(&affected)));
+        }
     }
 
     fn actions_artifact(
@@ -206,6 +472,9 @@ impl EditorState {
         if ui.small_button("delete").clicked() {
             context.should(EditorAction::SafelyRemoveArtifact(aref.clone()))
         }
+        if ui.small_button("new structure").clicked() {
+            context.should(EditorAction::OpenCreateStructurePrompt(aref.clone()))
+        }
     }
 
     fn actions_block(
@@ -249,7 +518,7 @@ impl EditorState {
                 ui.label(format!("{}:", slot_name));
                 ui.colored_label(Color32::YELLOW, format!("{}", slot_kind));
                 if ui.small_button("rename").clicked() {
-                    context.should(EditorAction::OpenRenameBlockSlotPrompt(
+                    context.should(EditorAction::OpenRenameSlotPrompt(
                         aref.clone(),
                         slot_name.clone(),
                     ));
@@ -269,58 +538,150 @@ impl EditorState {
         context: &mut EditorContext,
         model: &dyn Model,
         ui: &mut Ui,
+        aref: &ArtifactReference,
+        location: &Location,
         structure: &Structure,
     ) {
-        if context.model_is_valid {
-            let main_slot_kind = model.main_slot_kind_of(&structure.a_ref).unwrap();
-            ui.colored_label(Color32::LIGHT_BLUE, format!("{}", main_slot_kind));
+        if location.0.is_empty() {
+            if let Ok(exposed_slots) = model.slots_of(aref) {
+                let mut exposed_slots: Vec<_> = exposed_slots.into_iter().collect();
+                exposed_slots.sort();
+                for (slot_name, slot_kind) in exposed_slots {
+                    ui.horizontal(|ui| {
+                        ui.label(format!("{}:", slot_name));
+                        ui.colored_label(Color32::YELLOW, format!("{}", slot_kind));
+                        if ui.small_button("rename").clicked() {
+                            context.should(EditorAction::OpenRenameSlotPrompt(
+                                aref.clone(),
+                                slot_name.clone(),
+                            ));
+                        }
+                    });
+                }
+                ui.separator();
+            }
         }
-        ui.label(format!("{}", structure.a_ref));
+
+        ui.horizontal(|ui| {
+            if let Ok(main_slot_kind) = model.main_slot_kind_of(&structure.a_ref) {
+                ui.colored_label(Color32::LIGHT_BLUE, format!("{}", main_slot_kind));
+            }
+            ui.label(format!("{}", structure.a_ref));
+            if ui.small_button("swap").clicked() {
+                context.should(EditorAction::OpenSwapArtifactPrompt(
+                    aref.clone(),
+                    location.clone(),
+                    structure.a_ref.clone(),
+                ));
+            }
+        });
         let available_slots = match model.slots_of(&structure.a_ref) {
             Err(_) => {
-                ui.colored_label(Color32::RED, format!("Cannot get slots of {}", structure.a_ref));
+                ui.colored_label(
+                    Color32::RED,
+                    format!("Cannot get slots of {}", structure.a_ref),
+                );
                 return;
             }
             Ok(hm) => hm,
         };
+        let mut available_slots: Vec<_> = available_slots.into_iter().collect();
+        available_slots.sort();
 
         for (slot_name, slot_kind) in available_slots {
-            if let Some(c) = structure.c.get(&slot_name) {
-                match c {
-                    Connection::Slot(s) => {
-                        if context.model_is_valid {
-                            let slots = model.slots_of(&structure.a_ref).unwrap();
-                            let (_, slot_kind) =
-                                slots.into_iter().find(|(sn, _)| *sn == slot_name).unwrap();
-                            ui.horizontal(|ui| {
-                                ui.label(format!("{} <- {}:", slot_name, s));
-                                ui.colored_label(Color32::YELLOW, format!("{}", slot_kind));
-                            });
-                        } else {
-                            ui.label(format!("{} <- {}", slot_name, s));
+            match structure.c.get(&slot_name) {
+                Some(Connection::Slot(s)) => {
+                    ui.horizontal(|ui| {
+                        ui.label(format!("{} <- {}:", slot_name, s));
+                        ui.colored_label(Color32::YELLOW, format!("{}", slot_kind));
+                        if ui.small_button("disconnect").clicked() {
+                            context.should(EditorAction::DisconnectSlot(
+                                aref.clone(),
+                                location.clone(),
+                                slot_name.clone(),
+                            ));
                         }
-                    }
-                    Connection::Structure(s) => {
-                        ui.horizontal(|ui| {
-                            ui.label(format!("{} <-", slot_name));
-                            ui.separator();
-                            ui.vertical(|ui| {
-                                self.detail_structure(context, model, ui, s);
-                            });
+                    });
+                }
+                Some(Connection::Structure(s)) => {
+                    let sublocation = location.child(slot_name.clone());
+                    ui.horizontal(|ui| {
+                        ui.label(format!("{} <-", slot_name));
+                        if ui.small_button("disconnect").clicked() {
+                            context.should(EditorAction::DisconnectSlot(
+                                aref.clone(),
+                                location.clone(),
+                                slot_name.clone(),
+                            ));
+                        }
+                    });
+                    CollapsingHeader::new(format!("{}", s.a_ref))
+                        .id_source((aref, &sublocation))
+                        .default_open(true)
+                        .show(ui, |ui| {
+                            self.detail_structure(context, model, ui, aref, &sublocation, s);
                         });
-                    }
-                };
-            } else {
-                ui.horizontal(|ui| {
-                    ui.label(format!("{}:", slot_name));
-                    ui.colored_label(Color32::RED, format!("{}", slot_kind));
-                    ui.colored_label(Color32::BLACK, "(disconnected)");
-                });
-            }
+                }
+                None => {
+                    ui.horizontal(|ui| {
+                        ui.label(format!("{}:", slot_name));
+                        ui.colored_label(Color32::RED, format!("{}", slot_kind));
+                        ui.colored_label(Color32::BLACK, "(disconnected)");
+                        if ui.small_button("connect").clicked() {
+                            context.should(EditorAction::OpenConnectSlotPrompt(
+                                aref.clone(),
+                                location.clone(),
+                                slot_name.clone(),
+                                slot_kind.clone(),
+                            ));
+                        }
+                    });
+                }
+            };
         }
     }
 }
 
+fn listing(arefs: &[ArtifactReference]) -> String {
+    if arefs.is_empty() {
+        "nothing".into()
+    } else {
+        arefs
+            .iter()
+            .map(|aref| aref.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Artifacts that can be connected to a slot of `slot_kind` (or any of its subkinds) in `aref`
+/// without making it depend on itself.
+fn artifacts_of_kind(
+    context: &EditorContext,
+    model: &dyn Model,
+    slot_kind: &SlotKind,
+    aref: &ArtifactReference,
+) -> Vec<ArtifactReference> {
+    // Dependents cannot be computed when there is recursion.
+    let dependents = if context.model_is_valid {
+        model.dependents(aref)
+    } else {
+        vec![]
+    };
+    let mut candidates: Vec<_> = model
+        .list_artifacts()
+        .into_iter()
+        .filter(|candidate| candidate != aref && !dependents.contains(candidate))
+        .filter(|candidate| {
+            model
+                .main_slot_kind_of(candidate)
+                .map_or(false, |kind| model.accepts_kind(slot_kind, &kind))
+        })
+        .collect();
+    candidates.sort();
+    candidates
+}
+
 impl ModelEditor {
     pub fn title(&mut self) -> &str {
         match self.state {
@@ -328,24 +689,34 @@ impl ModelEditor {
             EditorState::AddingABlock(_, _) => "Adding a new block",
             EditorState::ChangingBlockKind(_, _) => "Changing block kind",
             EditorState::AddingSlotToBlock(_, _, _) => "Adding slot to a block",
-            EditorState::RenamingBlockSlot(_, _, _) => "Renaming block slot",
+            EditorState::RenamingSlot(_, _, _) => "Renaming slot",
+            EditorState::CreatingStructure(_, _) => "Creating a new structure",
+            EditorState::ConnectingSlot(_, _, _, _, _) => "Connecting a slot",
+            EditorState::SwappingArtifact(_, _, _) => "Swapping an artifact",
+            EditorState::DeclaringSubkind(_, _) => "Declaring a subkind",
         }
     }
 
-    pub fn show(&mut self, model: &dyn Model, ui: &mut Ui) {
+    pub fn show(&mut self, model: &CurrentModel, ui: &mut Ui) {
         let Self {
             ref mut state,
             ref mut context,
         } = self;
+        context.can_undo = model.can_undo();
+        context.can_redo = model.can_redo();
+        context.refresh(model, model.revision());
         state.show(context, model, ui);
     }
 
-    pub fn fullfill_actions(&mut self, model: &mut dyn Model) {
+    pub fn fullfill_actions(&mut self, model: &mut CurrentModel) {
+        for error in model.take_errors() {
+            self.context.error(error);
+        }
         for action in self.context.pending_actions.drain(..).collect::<Vec<_>>() {
             self.fullfill_action(model, action);
         }
     }
-    pub fn fullfill_action(&mut self, model: &mut dyn Model, action: EditorAction) {
+    pub fn fullfill_action(&mut self, model: &mut CurrentModel, action: EditorAction) {
         match action {
             EditorAction::GoToListing => {
                 self.state = EditorState::Listing;
@@ -355,108 +726,66 @@ impl ModelEditor {
                     EditorState::AddingABlock("my_new_block".into(), "NewBlockKind".into());
             }
             EditorAction::ConfirmAddBlock(ref aref, ref slot_kind) => {
-                if model.exists_artifact(&ar(aref)) {
-                    self.context.error(format!("Block {} already exists", aref));
-                    return;
-                }
-                model.set_artifact(
-                    ar(aref),
-                    Artifact::Block(Block {
-                        main_slot_kind: sk(slot_kind),
-                        slots: HashMap::default(),
-                    }),
-                );
-                self.context
-                    .info(format!("Added block {} with kind {}", aref, slot_kind));
-                self.state = EditorState::Listing;
+                match model.execute(ModelCommand::AddBlock(ar(aref), sk(slot_kind))) {
+                    Ok(()) => {
+                        self.context
+                            .info(format!("Added block {} with kind {}", aref, slot_kind));
+                        self.state = EditorState::Listing;
+                    }
+                    Err(err) => self.context.error(err.to_string()),
+                }
+            }
+            EditorAction::SafelyRemoveArtifact(aref) => {
+                match model.execute(ModelCommand::RemoveArtifact(aref.clone())) {
+                    Ok(()) => self.context.info(format!("Removed {}", aref)),
+                    Err(err) => self.context.error(err.to_string()),
+                }
             }
-            EditorAction::SafelyRemoveArtifact(aref) => match model.safely_remove_artifact(&aref) {
-                Ok(()) => self.context.info(format!("Removed {}", aref)),
-                Err(err) => self.context.error(err),
-            },
             EditorAction::OpenChangeBlockKindPrompt(aref) => {
                 self.state = EditorState::ChangingBlockKind(aref, "NewBlockKind".into());
             }
             EditorAction::ConfirmChangeBlockKind(aref, new_block_kind) => {
-                // TODO: Getting the artifact to modify it and send it again modified is a bad
-                // smell. This could be directly an action of the model. But at the same time,
-                // changing a block kind might not be a good use case.
-                let block = match model.get_artifact(&aref) {
-                    None => {
-                        self.context
-                            .error(format!("Artifact {} does not exists", aref));
-                        return;
-                    }
-                    Some(Artifact::Structure(_)) => {
+                match model.execute(ModelCommand::ChangeBlockKind(
+                    aref.clone(),
+                    new_block_kind.clone(),
+                )) {
+                    Ok(()) => {
                         self.context
-                            .error(format!("Artifact {} is not a block", aref));
-                        return;
+                            .info(format!("Changed {} kind to {}", aref, new_block_kind));
+                        self.state = EditorState::Listing;
                     }
-                    Some(Artifact::Block(block)) => block.clone(),
-                };
-                model.set_artifact(
-                    aref.clone(),
-                    Artifact::Block(Block {
-                        main_slot_kind: new_block_kind.clone(),
-                        ..block.clone()
-                    }),
-                );
-
-                if !model.is_all_valid() {
-                    self.context
-                        .error(format!("Changing block kind would invalidate model"));
-                    model.set_artifact(aref.clone(), Artifact::Block(block));
-                    return;
+                    Err(err) => self.context.error(err.to_string()),
                 }
-
-                self.context
-                    .info(format!("Changed {} kind to {}", aref, new_block_kind));
-                self.state = EditorState::Listing;
             }
             EditorAction::OpenAddSlotToBlockPrompt(aref) => {
                 self.state =
                     EditorState::AddingSlotToBlock(aref, "slot_name".into(), "SlotKind".into());
             }
             EditorAction::ConfirmAddSlotToBlock(aref, slot_name, slot_kind) => {
-                // TODO: Getting the artifact to modify it and send it again modified is a bad
-                // smell. This could be directly an action of the model. Plus, this is already
-                // repeated code.
-                let mut block = match model.get_artifact(&aref) {
-                    None => {
-                        self.context
-                            .error(format!("Artifact {} does not exists", aref));
-                        return;
-                    }
-                    Some(Artifact::Structure(_)) => {
-                        self.context
-                            .error(format!("Artifact {} is not a block", aref));
-                        return;
+                match model.execute(ModelCommand::AddSlotToBlock(
+                    aref.clone(),
+                    slot_name.clone(),
+                    slot_kind.clone(),
+                )) {
+                    Ok(()) => {
+                        self.context.info(format!(
+                            "Added slot {} of kind {} to {}",
+                            &slot_name, &slot_kind, &aref
+                        ));
+                        self.state = EditorState::Listing;
                     }
-                    Some(Artifact::Block(block)) => block.clone(),
-                };
-
-                if block.slots.contains_key(&slot_name) {
-                    self.context
-                        .error(format!("Slot {} already exists in {}", &slot_name, &aref));
-                    return;
+                    Err(err) => self.context.error(err.to_string()),
                 }
-
-                self.context.info(format!(
-                    "Added slot {} of kind {} to {}",
-                    &slot_name, &slot_kind, &aref
-                ));
-                block.slots.insert(slot_name, slot_kind);
-                model.set_artifact(aref.clone(), Artifact::Block(block));
-                // Yes, this operation most probably invalidates the model.
-
-                self.state = EditorState::Listing;
             }
-            EditorAction::OpenRenameBlockSlotPrompt(aref, slot_name) => {
-                self.state =
-                    EditorState::RenamingBlockSlot(aref, slot_name, "new_slot_name".into());
+            EditorAction::OpenRenameSlotPrompt(aref, slot_name) => {
+                self.state = EditorState::RenamingSlot(aref, slot_name, "new_slot_name".into());
             }
-            EditorAction::ConfirmRenameBlockSlot(aref, slot_name, slot_new_name) => {
-                match model.rename_slot(&aref, &slot_name, slot_new_name.clone()) {
+            EditorAction::ConfirmRenameSlot(aref, slot_name, slot_new_name) => {
+                match model.execute(ModelCommand::RenameSlot(
+                    aref.clone(),
+                    slot_name.clone(),
+                    slot_new_name.clone(),
+                )) {
                     Ok(()) => {
                         self.context.info(format!(
                             "Renamed {} to {} in {}",
@@ -464,36 +793,160 @@ impl ModelEditor {
                         ));
                         self.state = EditorState::Listing;
                     }
-                    Err(err) => self.context.error(err),
+                    Err(err) => self.context.error(err.to_string()),
                 }
             }
             EditorAction::RemoveBlockSlot(aref, slot_name) => {
-                match model.safely_remove_block_slot(&aref, &slot_name) {
+                match model.execute(ModelCommand::RemoveBlockSlot(
+                    aref.clone(),
+                    slot_name.clone(),
+                )) {
                     Ok(()) => {
                         self.context
                             .info(format!("Removed {} from {}", slot_name, aref));
                         self.state = EditorState::Listing;
                     }
-                    Err(err) => self.context.error(err),
+                    Err(err) => self.context.error(err.to_string()),
+                }
+            }
+            EditorAction::OpenCreateStructurePrompt(base_aref) => {
+                self.state = EditorState::CreatingStructure(base_aref, "my_new_structure".into());
+            }
+            EditorAction::ConfirmCreateStructure(base_aref, aref) => {
+                let structure = Artifact::Structure(Structure {
+                    a_ref: base_aref.clone(),
+                    c: Default::default(),
+                });
+                match model.execute(ModelCommand::AddArtifact(aref.clone(), structure)) {
+                    Ok(()) => {
+                        self.context
+                            .info(format!("Created structure {} of {}", aref, base_aref));
+                        self.state = EditorState::Listing;
+                    }
+                    Err(err) => self.context.error(err.to_string()),
+                }
+            }
+            EditorAction::OpenConnectSlotPrompt(aref, location, slot_name, slot_kind) => {
+                let exposed_slot_name = slot_name.as_str().into();
+                self.state = EditorState::ConnectingSlot(
+                    aref,
+                    location,
+                    slot_name,
+                    slot_kind,
+                    exposed_slot_name,
+                );
+            }
+            EditorAction::ConfirmConnectSlot(aref, location, slot_name, connection) => {
+                match model.execute(ModelCommand::Connect(
+                    aref.clone(),
+                    location.clone(),
+                    slot_name.clone(),
+                    connection,
+                )) {
+                    Ok(()) => {
+                        self.context.info(format!(
+                            "Connected {} at {} in {}",
+                            slot_name, location, aref
+                        ));
+                        self.state = EditorState::Listing;
+                    }
+                    Err(err) => self.context.error(err.to_string()),
+                }
+            }
+            EditorAction::DisconnectSlot(aref, location, slot_name) => {
+                match model.execute(ModelCommand::Disconnect(
+                    aref.clone(),
+                    location.clone(),
+                    slot_name.clone(),
+                )) {
+                    Ok(()) => self.context.info(format!(
+                        "Disconnected {} at {} in {}",
+                        slot_name, location, aref
+                    )),
+                    Err(err) => self.context.error(err.to_string()),
+                }
+            }
+            EditorAction::OpenSwapArtifactPrompt(aref, location, current_aref) => {
+                self.state = EditorState::SwappingArtifact(aref, location, current_aref);
+            }
+            EditorAction::ConfirmSwapArtifact(aref, location, new_aref) => {
+                match model.execute(ModelCommand::Swap(
+                    aref.clone(),
+                    location.clone(),
+                    new_aref.clone(),
+                )) {
+                    Ok(()) => {
+                        self.context.info(format!(
+                            "Swapped in {} at {} in {}",
+                            new_aref, location, aref
+                        ));
+                        self.state = EditorState::Listing;
+                    }
+                    Err(err) => self.context.error(err.to_string()),
+                }
+            }
+            EditorAction::OpenDeclareSubkindPrompt => {
+                self.state = EditorState::DeclaringSubkind("SubKind".into(), "SuperKind".into());
+            }
+            EditorAction::ConfirmDeclareSubkind(kind, superkind) => {
+                match model.execute(ModelCommand::DeclareSubkind(
+                    kind.clone(),
+                    superkind.clone(),
+                )) {
+                    Ok(()) => {
+                        self.context
+                            .info(format!("Declared {} as a kind of {}", kind, superkind));
+                        self.state = EditorState::Listing;
+                    }
+                    Err(err) => self.context.error(err.to_string()),
+                }
+            }
+            EditorAction::UndeclareSubkind(kind, superkind) => {
+                match model.execute(ModelCommand::UndeclareSubkind(
+                    kind.clone(),
+                    superkind.clone(),
+                )) {
+                    Ok(()) => self
+                        .context
+                        .info(format!("{} is not a kind of {} anymore", kind, superkind)),
+                    Err(err) => self.context.error(err.to_string()),
+                }
+            }
+            EditorAction::Undo => match model.undo() {
+                Ok(()) => self.context.info("Undone last change".into()),
+                Err(err) => self.context.error(err.to_string()),
+            },
+            EditorAction::Redo => match model.redo() {
+                Ok(()) => self.context.info("Redone last undone change".into()),
+                Err(err) => self.context.error(err.to_string()),
+            },
+            EditorAction::SaveModel => {
+                let path = default_model_path();
+                match save_model(&*model, path) {
+                    Ok(()) => self.context.info(format!("Saved to {}", path.display())),
+                    Err(err) => self.context.error(err.to_string()),
+                }
+            }
+            EditorAction::ExportGraph => {
+                let path = default_graph_path();
+                match std::fs::write(path, DependencyGraph::of(&*model).to_dot()) {
+                    Ok(()) => self
+                        .context
+                        .info(format!("Exported graph to {}", path.display())),
+                    Err(err) => self.context.error(err.to_string()),
+                }
+            }
+            EditorAction::LoadModel => {
+                let path = default_model_path();
+                match load_model(path) {
+                    Ok(loaded) => {
+                        model.replace(Box::new(loaded));
+                        self.context.info(format!("Loaded {}", path.display()));
+                        self.state = EditorState::Listing;
+                    }
+                    Err(err) => self.context.error(err.to_string()),
                 }
             }
         }
     }
 }
-
-/*
- * TODO:
- *
- * - see disonnected slots
- *
- * - create a structure starting from another artifact
- *
- * - rename a slot of a structure
- * - connect an artifact to a slot
- * - disconnect an artifact from a slot
- *
- *
- * - be able to (safely) edit a structure
- *     - change artifact reference
- *     - connect artifact in slot
- */
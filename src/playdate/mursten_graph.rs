@@ -0,0 +1,352 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+    path::Path,
+};
+
+use super::mursten::*;
+
+pub fn default_graph_path() -> &'static Path {
+    Path::new("model.dot")
+}
+
+/// Which artifacts each artifact uses, as a directed graph going from an artifact to the ones it
+/// depends on. It can be built from invalid models: references to unexistent artifacts are kept
+/// and recursion loops are reported as cycles instead of being followed forever.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct DependencyGraph {
+    blocks: BTreeSet<ArtifactReference>,
+    structures: BTreeSet<ArtifactReference>,
+    uses: BTreeMap<ArtifactReference, BTreeSet<ArtifactReference>>,
+    used_by: BTreeMap<ArtifactReference, BTreeSet<ArtifactReference>>,
+    cycles: Vec<Vec<ArtifactReference>>,
+}
+
+impl DependencyGraph {
+    pub fn of(model: &dyn Model) -> Self {
+        let mut graph = Self::default();
+        for aref in model.list_artifacts() {
+            match model.get_artifact(&aref) {
+                Some(Artifact::Block(_)) => graph.blocks.insert(aref.clone()),
+                Some(Artifact::Structure(_)) => graph.structures.insert(aref.clone()),
+                None => continue,
+            };
+            for dependency in model.direct_dependencies(&aref) {
+                graph
+                    .used_by
+                    .entry(dependency.clone())
+                    .or_default()
+                    .insert(aref.clone());
+                graph
+                    .uses
+                    .entry(aref.clone())
+                    .or_default()
+                    .insert(dependency);
+            }
+        }
+        graph.cycles = graph.find_cycles();
+        graph
+    }
+
+    /// Every artifact in the model, sorted.
+    pub fn artifacts(&self) -> Vec<ArtifactReference> {
+        self.blocks.union(&self.structures).cloned().collect()
+    }
+
+    /// Artifacts used by others that are not in the model.
+    pub fn missing(&self) -> Vec<ArtifactReference> {
+        self.used_by
+            .keys()
+            .filter(|aref| !self.contains(aref))
+            .cloned()
+            .collect()
+    }
+
+    pub fn contains(&self, aref: &ArtifactReference) -> bool {
+        self.blocks.contains(aref) || self.structures.contains(aref)
+    }
+
+    /// Every edge of the graph, going from an artifact to one it uses.
+    pub fn edges(&self) -> Vec<(ArtifactReference, ArtifactReference)> {
+        self.uses
+            .iter()
+            .flat_map(|(aref, dependencies)| {
+                dependencies
+                    .iter()
+                    .map(move |dependency| (aref.clone(), dependency.clone()))
+            })
+            .collect()
+    }
+
+    pub fn uses(&self, aref: &ArtifactReference) -> Vec<ArtifactReference> {
+        self.uses
+            .get(aref)
+            .map_or(vec![], |uses| uses.iter().cloned().collect())
+    }
+
+    pub fn used_by(&self, aref: &ArtifactReference) -> Vec<ArtifactReference> {
+        self.used_by
+            .get(aref)
+            .map_or(vec![], |used_by| used_by.iter().cloned().collect())
+    }
+
+    /// Everything `aref` uses, directly or through other artifacts.
+    pub fn all_uses(&self, aref: &ArtifactReference) -> Vec<ArtifactReference> {
+        Self::reachable(&self.uses, aref)
+    }
+
+    /// Everything that would be affected by a change in `aref`.
+    pub fn all_used_by(&self, aref: &ArtifactReference) -> Vec<ArtifactReference> {
+        Self::reachable(&self.used_by, aref)
+    }
+
+    /// Groups of artifacts that depend on each other, each one sorted.
+    pub fn cycles(&self) -> &[Vec<ArtifactReference>] {
+        &self.cycles
+    }
+
+    pub fn is_acyclic(&self) -> bool {
+        self.cycles.is_empty()
+    }
+
+    pub fn in_cycle(&self, aref: &ArtifactReference) -> bool {
+        self.cycle_of(aref).is_some()
+    }
+
+    fn cycle_of(&self, aref: &ArtifactReference) -> Option<usize> {
+        self.cycles.iter().position(|cycle| cycle.contains(aref))
+    }
+
+    fn edge_in_cycle(&self, from: &ArtifactReference, to: &ArtifactReference) -> bool {
+        match (self.cycle_of(from), self.cycle_of(to)) {
+            (Some(a), Some(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    fn reachable(
+        edges: &BTreeMap<ArtifactReference, BTreeSet<ArtifactReference>>,
+        aref: &ArtifactReference,
+    ) -> Vec<ArtifactReference> {
+        let mut seen = BTreeSet::new();
+        let mut to_see = vec![aref];
+        while let Some(current) = to_see.pop() {
+            for next in edges.get(current).into_iter().flatten() {
+                if seen.insert(next.clone()) {
+                    to_see.push(next);
+                }
+            }
+        }
+        // Only an artifact in a cycle can reach itself, and it is not interesting to show it.
+        seen.remove(aref);
+        seen.into_iter().collect()
+    }
+
+    /// Strongly connected components with more than one artifact, or with an artifact that uses
+    /// itself, found with Tarjan's algorithm.
+    fn find_cycles(&self) -> Vec<Vec<ArtifactReference>> {
+        struct Tarjan<'a> {
+            graph: &'a DependencyGraph,
+            index: BTreeMap<&'a ArtifactReference, usize>,
+            lowlink: BTreeMap<&'a ArtifactReference, usize>,
+            stack: Vec<&'a ArtifactReference>,
+            cycles: Vec<Vec<ArtifactReference>>,
+        }
+
+        impl<'a> Tarjan<'a> {
+            fn visit(&mut self, aref: &'a ArtifactReference) {
+                let index = self.index.len();
+                self.index.insert(aref, index);
+                self.lowlink.insert(aref, index);
+                self.stack.push(aref);
+
+                for next in self.graph.uses.get(aref).into_iter().flatten() {
+                    if !self.index.contains_key(next) {
+                        self.visit(next);
+                        let lowlink = self.lowlink[aref].min(self.lowlink[next]);
+                        self.lowlink.insert(aref, lowlink);
+                    } else if self.stack.contains(&next) {
+                        let lowlink = self.lowlink[aref].min(self.index[next]);
+                        self.lowlink.insert(aref, lowlink);
+                    }
+                }
+
+                if self.lowlink[aref] == self.index[aref] {
+                    let mut component = vec![];
+                    loop {
+                        let member = self.stack.pop().unwrap();
+                        component.push(member.clone());
+                        if member == aref {
+                            break;
+                        }
+                    }
+                    let uses_itself = self.graph.uses(aref).contains(aref);
+                    if component.len() > 1 || uses_itself {
+                        component.sort();
+                        self.cycles.push(component);
+                    }
+                }
+            }
+        }
+
+        let mut tarjan = Tarjan {
+            graph: self,
+            index: BTreeMap::new(),
+            lowlink: BTreeMap::new(),
+            stack: vec![],
+            cycles: vec![],
+        };
+        for aref in self.uses.keys() {
+            if !tarjan.index.contains_key(aref) {
+                tarjan.visit(aref);
+            }
+        }
+        let mut cycles = tarjan.cycles;
+        cycles.sort();
+        cycles
+    }
+
+    /// Graphviz description of the graph. Blocks are drawn as boxes, structures as ellipses,
+    /// missing artifacts dashed and cycles in red.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::new();
+        writeln!(dot, "digraph mursten {{").unwrap();
+        let nodes = self
+            .blocks
+            .iter()
+            .map(|aref| (aref, "shape=box"))
+            .chain(self.structures.iter().map(|aref| (aref, "shape=ellipse")))
+            .collect::<BTreeMap<_, _>>();
+        for (aref, shape) in nodes {
+            let color = if self.in_cycle(aref) {
+                ", color=red"
+            } else {
+                ""
+            };
+            writeln!(dot, "    {} [{}{}];", quoted(aref), shape, color).unwrap();
+        }
+        for aref in self.missing() {
+            writeln!(dot, "    {} [style=dashed];", quoted(&aref)).unwrap();
+        }
+        for (from, to) in self.edges() {
+            let color = if self.edge_in_cycle(&from, &to) {
+                " [color=red]"
+            } else {
+                ""
+            };
+            writeln!(dot, "    {} -> {}{};", quoted(&from), quoted(&to), color).unwrap();
+        }
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+}
+
+fn quoted(aref: &ArtifactReference) -> String {
+    format!(
+        "\"{}\"",
+        aref.as_str().replace('\\', "\\\\").replace('"', "\\\"")
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::mursten::example::*;
+    use super::*;
+
+    #[test]
+    fn graph_of_a_valid_model() {
+        let graph = DependencyGraph::of(&and_one_more_is_five_model());
+        assert!(graph.is_acyclic());
+        assert!(graph.missing().is_empty());
+        assert_eq!(
+            graph.artifacts(),
+            vec![
+                ar("number_4"),
+                ar("number_5"),
+                ar("plus_2"),
+                ar("successor"),
+                ar("zero")
+            ]
+        );
+        assert_eq!(graph.uses(&ar("number_4")), vec![ar("plus_2"), ar("zero")]);
+        assert_eq!(
+            graph.used_by(&ar("successor")),
+            vec![ar("number_5"), ar("plus_2")]
+        );
+        assert_eq!(
+            graph.all_used_by(&ar("successor")),
+            vec![ar("number_4"), ar("number_5"), ar("plus_2")]
+        );
+        assert_eq!(
+            graph.all_uses(&ar("number_5")),
+            vec![ar("number_4"), ar("plus_2"), ar("successor"), ar("zero")]
+        );
+        assert!(graph.uses(&ar("zero")).is_empty());
+    }
+
+    #[test]
+    fn cycles_and_missing_artifacts_are_flagged() {
+        let mut model = peano_model();
+        let structure = |a_ref: &str| {
+            Artifact::Structure(Structure {
+                a_ref: ar(a_ref),
+                c: hashmap! {},
+            })
+        };
+        model.set_artifact(ar("a"), structure("b"));
+        model.set_artifact(ar("b"), structure("c"));
+        model.set_artifact(ar("c"), structure("a"));
+        model.set_artifact(ar("d"), structure("a"));
+        model.set_artifact(ar("e"), structure("e"));
+        model.set_artifact(ar("f"), structure("nothing"));
+
+        let graph = DependencyGraph::of(&model);
+        assert_eq!(
+            graph.cycles(),
+            &[vec![ar("a"), ar("b"), ar("c")], vec![ar("e")]][..]
+        );
+        assert!(graph.in_cycle(&ar("b")));
+        assert!(!graph.in_cycle(&ar("d")));
+        assert_eq!(graph.missing(), vec![ar("nothing")]);
+        assert_eq!(graph.all_used_by(&ar("a")), vec![ar("b"), ar("c"), ar("d")]);
+        assert_eq!(graph.all_uses(&ar("d")), vec![ar("a"), ar("b"), ar("c")]);
+    }
+
+    #[test]
+    fn exports_to_dot() {
+        let mut model = two_and_two_is_four_model();
+        model.set_artifact(
+            ar("loop \"1\""),
+            Artifact::Structure(Structure {
+                a_ref: ar("loop \"1\""),
+                c: hashmap! {},
+            }),
+        );
+        model.set_artifact(
+            ar("broken"),
+            Artifact::Structure(Structure {
+                a_ref: ar("nothing"),
+                c: hashmap! {},
+            }),
+        );
+        assert_eq!(
+            DependencyGraph::of(&model).to_dot(),
+            concat!(
+                "digraph mursten {\n",
+                "    \"broken\" [shape=ellipse];\n",
+                "    \"loop \\\"1\\\"\" [shape=ellipse, color=red];\n",
+                "    \"number_4\" [shape=ellipse];\n",
+                "    \"plus_2\" [shape=ellipse];\n",
+                "    \"successor\" [shape=box];\n",
+                "    \"zero\" [shape=box];\n",
+                "    \"nothing\" [style=dashed];\n",
+                "    \"broken\" -> \"nothing\";\n",
+                "    \"loop \\\"1\\\"\" -> \"loop \\\"1\\\"\" [color=red];\n",
+                "    \"number_4\" -> \"plus_2\";\n",
+                "    \"number_4\" -> \"zero\";\n",
+                "    \"plus_2\" -> \"successor\";\n",
+                "}\n",
+            )
+        );
+    }
+}
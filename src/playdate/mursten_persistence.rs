@@ -0,0 +1,530 @@
+//! Saving and loading of whole mursten models, either as JSON or as a compact text format that
+//! looks like this:
+//!
+//! ```text
+//! block successor: Natural {
+//!     "x": Natural,
+//! }
+//!
+//! structure plus_2 = successor! {
+//!     "x" => successor! {
+//!         "x" => "x",
+//!     },
+//! }
+//! ```
+//!
+//! Structure connections are either another structure (`artifact! { ... }`) or the name of the
+//! slot they expose (`"x"`). Disconnected slots are just not listed.
+//!
+//! Declared subkinds go before the artifacts, one per line, like `kind PositiveNatural: Natural`.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::{Display, Write},
+    path::{Path, PathBuf},
+};
+
+use super::lexer::{is_bare_name, quoted, Located, ParseError, Token, Tokens};
+use super::mursten::*;
+
+pub fn default_model_path() -> &'static Path {
+    Path::new("model.mursten")
+}
+
+pub fn default_project_path() -> &'static Path {
+    Path::new("mursten_project")
+}
+
+#[derive(Debug)]
+pub enum PersistenceError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Parse(ParseError),
+    ArtifactFile(PathBuf, String),
+}
+
+impl Display for PersistenceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PersistenceError::Io(err) => write!(f, "I/O error: {}", err),
+            PersistenceError::Json(err) => write!(f, "Invalid JSON model: {}", err),
+            PersistenceError::Parse(err) => write!(f, "Invalid model: {}", err),
+            PersistenceError::ArtifactFile(path, reason) => {
+                write!(f, "Invalid artifact file {}: {}", path.display(), reason)
+            }
+        }
+    }
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension().map_or(false, |ext| ext == "json")
+}
+
+/// Writes the model to `path`, as JSON if the file has a `.json` extension or in the text format
+/// otherwise.
+pub fn save_model(model: &dyn Model, path: &Path) -> Result<(), PersistenceError> {
+    let contents = if is_json(path) {
+        to_json(model)
+    } else {
+        to_text(model)
+    };
+    std::fs::write(path, contents).map_err(PersistenceError::Io)
+}
+
+pub fn load_model(path: &Path) -> Result<InMemoryModel, PersistenceError> {
+    let contents = std::fs::read_to_string(path).map_err(PersistenceError::Io)?;
+    if is_json(path) {
+        from_json(&contents).map_err(PersistenceError::Json)
+    } else {
+        from_text(&contents).map_err(PersistenceError::Parse)
+    }
+}
+
+fn sorted_artifacts(model: &dyn Model) -> BTreeMap<ArtifactReference, &Artifact> {
+    model
+        .list_artifacts()
+        .into_iter()
+        .filter_map(|aref| model.get_artifact(&aref).map(|artifact| (aref, artifact)))
+        .collect()
+}
+
+#[derive(serde::Serialize)]
+struct JsonModel<'a> {
+    kinds: &'a KindLattice,
+    artifacts: BTreeMap<ArtifactReference, &'a Artifact>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum ParsedJsonModel {
+    Full {
+        #[serde(default)]
+        kinds: KindLattice,
+        artifacts: HashMap<ArtifactReference, Artifact>,
+    },
+    // Models saved before kinds could be declared are only a map of artifacts.
+    OnlyArtifacts(HashMap<ArtifactReference, Artifact>),
+}
+
+pub fn to_json(model: &dyn Model) -> String {
+    let json_model = JsonModel {
+        kinds: model.kinds(),
+        artifacts: sorted_artifacts(model),
+    };
+    serde_json::to_string_pretty(&json_model).expect("Models are always serializable")
+}
+
+pub fn from_json(json: &str) -> Result<InMemoryModel, serde_json::Error> {
+    let (kinds, artifacts) = match serde_json::from_str(json)? {
+        ParsedJsonModel::Full { kinds, artifacts } => (kinds, artifacts),
+        ParsedJsonModel::OnlyArtifacts(artifacts) => (KindLattice::default(), artifacts),
+    };
+    let mut model = InMemoryModel::default();
+    model.set_kinds(kinds);
+    for (aref, artifact) in artifacts {
+        model.set_artifact(aref, artifact);
+    }
+    Ok(model)
+}
+
+pub fn to_text(model: &dyn Model) -> String {
+    let mut texts: Vec<_> = sorted_artifacts(model)
+        .into_iter()
+        .map(|(aref, artifact)| artifact_to_text(&aref, artifact))
+        .collect();
+    if !model.kinds().is_empty() {
+        texts.insert(0, kinds_to_text(model.kinds()));
+    }
+    texts.join("\n")
+}
+
+pub fn kinds_to_text(kinds: &KindLattice) -> String {
+    let mut text = String::new();
+    for (kind, superkind) in kinds.declarations() {
+        writeln!(
+            text,
+            "kind {}: {}",
+            name(kind.as_str()),
+            name(superkind.as_str())
+        )
+        .unwrap();
+    }
+    text
+}
+
+pub fn artifact_to_text(aref: &ArtifactReference, artifact: &Artifact) -> String {
+    let mut text = String::new();
+    match artifact {
+        Artifact::Block(block) => {
+            write!(
+                text,
+                "block {}: {} {{",
+                name(aref.as_str()),
+                name(block.main_slot_kind.as_str())
+            )
+            .unwrap();
+            if !block.slots.is_empty() {
+                text.push('\n');
+                let slots: BTreeMap<_, _> = block.slots.iter().collect();
+                for (slot_name, slot_kind) in slots {
+                    writeln!(
+                        text,
+                        "    {}: {},",
+                        quoted(slot_name.as_str()),
+                        name(slot_kind.as_str())
+                    )
+                    .unwrap();
+                }
+            }
+            text.push_str("}\n");
+        }
+        Artifact::Structure(structure) => {
+            write!(text, "structure {} = ", name(aref.as_str())).unwrap();
+            write_structure(&mut text, structure, 0);
+            text.push('\n');
+        }
+    }
+    text
+}
+
+fn write_structure(text: &mut String, structure: &Structure, depth: usize) {
+    write!(text, "{}! {{", name(structure.a_ref.as_str())).unwrap();
+    if structure.c.is_empty() {
+        text.push('}');
+        return;
+    }
+    text.push('\n');
+    let indentation = "    ".repeat(depth + 1);
+    let connections: BTreeMap<_, _> = structure.c.iter().collect();
+    for (slot_name, connection) in connections {
+        write!(text, "{}{} => ", indentation, quoted(slot_name.as_str())).unwrap();
+        match connection {
+            Connection::Slot(exposed_slot_name) => {
+                text.push_str(&quoted(exposed_slot_name.as_str()))
+            }
+            Connection::Structure(substruct) => write_structure(text, substruct, depth + 1),
+        }
+        text.push_str(",\n");
+    }
+    write!(text, "{}}}", "    ".repeat(depth)).unwrap();
+}
+
+fn name(name: &str) -> String {
+    if is_bare_name(name, false) {
+        name.into()
+    } else {
+        quoted(name)
+    }
+}
+
+pub fn from_text(text: &str) -> Result<InMemoryModel, ParseError> {
+    let mut parser = Parser {
+        tokens: Tokens::new(text, false)?,
+    };
+    parser.model()
+}
+
+struct Parser {
+    tokens: Tokens,
+}
+
+impl Parser {
+    fn name(&mut self, what: &str) -> Result<Located<String>, ParseError> {
+        let token = self.tokens.next();
+        match token.value {
+            Token::Name(ref name) | Token::Quoted(ref name) => Ok(token.map(name.clone())),
+            ref other => Err(token.error(format!("Expected {}, found {}", what, other))),
+        }
+    }
+
+    fn quoted(&mut self, what: &str) -> Result<Located<String>, ParseError> {
+        let token = self.tokens.next();
+        match token.value {
+            Token::Quoted(ref string) => Ok(token.map(string.clone())),
+            ref other => Err(token.error(format!(
+                "Expected {} between double quotes, found {}",
+                what, other
+            ))),
+        }
+    }
+
+    fn model(&mut self) -> Result<InMemoryModel, ParseError> {
+        let mut model = InMemoryModel::default();
+        loop {
+            let keyword = self.tokens.next();
+            let (aref, artifact) = match keyword.value {
+                Token::End => return Ok(model),
+                Token::Name(ref k) if k == "kind" => {
+                    let kind = self.name("a slot kind")?;
+                    self.tokens.expect(Token::Colon)?;
+                    let superkind = self.name("a slot kind")?;
+                    let mut kinds = model.kinds().clone();
+                    if !kinds.declare(sk(&*kind.value), sk(&*superkind.value)) {
+                        return Err(kind.error(format!(
+                            "{} cannot be a subkind of {} because it would be a subkind of itself",
+                            kind.value, superkind.value
+                        )));
+                    }
+                    model.set_kinds(kinds);
+                    continue;
+                }
+                Token::Name(ref k) if k == "block" => {
+                    let aref = self.name("an artifact name")?;
+                    (aref, Artifact::Block(self.block()?))
+                }
+                Token::Name(ref k) if k == "structure" => {
+                    let aref = self.name("an artifact name")?;
+                    self.tokens.expect(Token::Equals)?;
+                    (aref, Artifact::Structure(self.structure()?))
+                }
+                ref other => {
+                    return Err(keyword.error(format!(
+                        "Expected `kind`, `block` or `structure`, found {}",
+                        other
+                    )))
+                }
+            };
+            if model.exists_artifact(&ar(&*aref.value)) {
+                return Err(aref.error(format!("Artifact {} is defined twice", aref.value)));
+            }
+            model.set_artifact(ar(aref.value), artifact);
+        }
+    }
+
+    fn block(&mut self) -> Result<Block, ParseError> {
+        self.tokens.expect(Token::Colon)?;
+        let main_slot_kind = sk(self.name("a slot kind")?.value);
+        self.tokens.expect(Token::OpenBrace)?;
+        let mut slots = HashMap::new();
+        while self.tokens.peek().value != Token::CloseBrace {
+            let slot_name = self.quoted("a slot name")?;
+            if slots.contains_key(&sn(&*slot_name.value)) {
+                return Err(slot_name.error(format!("Slot {} is defined twice", slot_name.value)));
+            }
+            self.tokens.expect(Token::Colon)?;
+            let slot_kind = self.name("a slot kind")?;
+            slots.insert(sn(slot_name.value), sk(slot_kind.value));
+            if self.tokens.end_of_entry()? {
+                break;
+            }
+        }
+        self.tokens.expect(Token::CloseBrace)?;
+        Ok(Block {
+            main_slot_kind,
+            slots,
+        })
+    }
+
+    fn structure(&mut self) -> Result<Structure, ParseError> {
+        let a_ref = ar(self.name("an artifact name")?.value);
+        self.tokens.expect(Token::Bang)?;
+        self.tokens.expect(Token::OpenBrace)?;
+        let mut c = HashMap::new();
+        let mut connected = HashSet::new();
+        while self.tokens.peek().value != Token::CloseBrace {
+            let slot_name = self.quoted("a slot name")?;
+            if !connected.insert(slot_name.value.clone()) {
+                return Err(slot_name.error(format!("Slot {} is connected twice", slot_name.value)));
+            }
+            self.tokens.expect(Token::Arrow)?;
+            let connection = if let Token::Quoted(_) = self.tokens.peek().value {
+                Connection::Slot(sn(self.quoted("a slot name")?.value))
+            } else {
+                Connection::Structure(self.structure()?)
+            };
+            c.insert(sn(slot_name.value), connection);
+            if self.tokens.end_of_entry()? {
+                break;
+            }
+        }
+        self.tokens.expect(Token::CloseBrace)?;
+        Ok(Structure { a_ref, c })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::mursten::example::*;
+    use super::*;
+
+    fn snapshot(model: &dyn Model) -> Vec<(ArtifactReference, Artifact)> {
+        sorted_artifacts(model)
+            .into_iter()
+            .map(|(aref, artifact)| (aref, artifact.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let model = and_one_more_is_five_model();
+        let json = to_json(&model);
+        assert_eq!(snapshot(&from_json(&json).unwrap()), snapshot(&model));
+        assert_eq!(to_json(&from_json(&json).unwrap()), json);
+    }
+
+    #[test]
+    fn text_round_trip() {
+        let model = and_one_more_is_five_model();
+        let text = to_text(&model);
+        let parsed = from_text(&text).unwrap();
+        assert_eq!(snapshot(&parsed), snapshot(&model));
+        assert_eq!(to_text(&parsed), text);
+    }
+
+    #[test]
+    fn text_round_trip_with_names_that_need_quotes() {
+        let mut model = empty_test_model();
+        model.set_artifact(
+            ar("my block"),
+            Artifact::Block(Block {
+                main_slot_kind: sk("Some-Kind"),
+                slots: hashmap! {
+                    sn("with \"quotes\"") => sk("Kind"),
+                },
+            }),
+        );
+        model.set_artifact(
+            ar("structure"),
+            Artifact::Structure(Structure {
+                a_ref: ar("my block"),
+                c: hashmap! {
+                    sn("with \"quotes\"") => Connection::Slot(sn("plain")),
+                },
+            }),
+        );
+        let text = to_text(&model);
+        assert_eq!(snapshot(&from_text(&text).unwrap()), snapshot(&model));
+    }
+
+    #[test]
+    fn text_round_trip_with_control_characters_in_names() {
+        let mut model = empty_test_model();
+        model.set_artifact(
+            ar("carriage\rreturn"),
+            Artifact::Block(Block {
+                main_slot_kind: sk("it's"),
+                slots: hashmap! {
+                    sn("bell\u{7}, escape\u{1b} and nul\u{0}") => sk("tab\tkind"),
+                    sn("don't") => sk("Kind"),
+                },
+            }),
+        );
+        model.set_artifact(
+            ar("line\nbreak"),
+            Artifact::Structure(Structure {
+                a_ref: ar("carriage\rreturn"),
+                c: hashmap! {
+                    sn("bell\u{7}, escape\u{1b} and nul\u{0}") => Connection::Slot(sn("del\u{7f}")),
+                    sn("don't") => Connection::Slot(sn("won't\r\n")),
+                },
+            }),
+        );
+        let text = to_text(&model);
+        let parsed = from_text(&text).unwrap();
+        assert_eq!(snapshot(&parsed), snapshot(&model));
+        assert_eq!(to_text(&parsed), text);
+    }
+
+    #[test]
+    fn writes_text_format() {
+        let text = to_text(&two_and_two_is_four_model());
+        assert_eq!(
+            text,
+            r#"structure number_4 = plus_2! {
+    "x" => plus_2! {
+        "x" => zero! {},
+    },
+}
+
+structure plus_2 = successor! {
+    "x" => successor! {
+        "x" => "x",
+    },
+}
+
+block successor: Natural {
+    "x": Natural,
+}
+
+block zero: Natural {}
+"#
+        );
+    }
+
+    #[test]
+    fn declared_kinds_are_saved() {
+        let mut model = peano_model();
+        let mut kinds = KindLattice::default();
+        kinds.declare(sk("PositiveNatural"), sk("Natural"));
+        kinds.declare(sk("Natural"), sk("Number"));
+        model.set_kinds(kinds.clone());
+
+        let text = to_text(&model);
+        assert!(text.starts_with(
+            "kind Natural: Number\nkind PositiveNatural: Natural\n\nblock successor: Natural {"
+        ));
+        assert_eq!(from_text(&text).unwrap().kinds(), &kinds);
+        assert_eq!(from_json(&to_json(&model)).unwrap().kinds(), &kinds);
+
+        let err = from_text("kind A: B\nkind B: A\n").unwrap_err();
+        assert_eq!((err.line, err.column), (2, 6));
+    }
+
+    #[test]
+    fn loads_json_without_kinds() {
+        let model =
+            from_json(r#"{ "zero": { "Block": { "main_slot_kind": "Natural", "slots": {} } } }"#)
+                .unwrap();
+        assert!(model.kinds().is_empty());
+        assert_eq!(model.main_slot_kind_of(&ar("zero")).unwrap(), sk("Natural"));
+    }
+
+    #[test]
+    fn parses_comments_and_trailing_commas() {
+        let model = from_text(
+            r#"
+            // Peano numbers
+            block zero: Natural {}
+            block successor: Natural { "x": Natural }
+            structure one = successor! { "x" => zero! {}, }
+            "#,
+        )
+        .unwrap();
+        model.validate_model().unwrap();
+        assert_eq!(peano_eval(&ar("one"), &model), Some(1));
+    }
+
+    #[test]
+    fn parse_errors_point_at_the_offending_line() {
+        let err = from_text("block zero: Natural {}\nblock one Natural {}\n").unwrap_err();
+        assert_eq!(
+            err,
+            ParseError {
+                line: 2,
+                column: 11,
+                message: "Expected `:`, found `Natural`".into()
+            }
+        );
+
+        let err = from_text("block zero: Natural {}\n\nblock zero: Natural {}").unwrap_err();
+        assert_eq!((err.line, err.column), (3, 7));
+        assert_eq!(err.message, "Artifact zero is defined twice");
+
+        let err =
+            from_text("structure a = b! {\n  \"x\" => c! {\n    x => \"y\"\n  }\n}").unwrap_err();
+        assert_eq!((err.line, err.column), (3, 5));
+        assert_eq!(
+            err.to_string(),
+            "line 3, column 5: Expected a slot name between double quotes, found `x`"
+        );
+
+        let err = from_text("block a: A {\n  \"unterminated: A\n}").unwrap_err();
+        assert_eq!((err.line, err.column), (2, 3));
+
+        let err = from_text("block a: A {").unwrap_err();
+        assert_eq!(
+            err.message,
+            "Expected a slot name between double quotes, found end of file"
+        );
+    }
+}
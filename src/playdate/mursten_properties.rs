@@ -0,0 +1,550 @@
+//! Randomized checks of the invariants every model should keep, no matter how it was built or
+//! refactored. Each case is generated from a seed, so a failure can be reproduced by running the
+//! same seed again, and it is shrunk to the smallest model and list of steps that still fail
+//! before being reported.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    panic::{catch_unwind, AssertUnwindSafe},
+};
+
+use super::mursten::*;
+use super::mursten_graph::*;
+use super::mursten_persistence::*;
+
+const CASES: u64 = 200;
+const KINDS: [&str; 3] = ["A", "B", "C"];
+const SLOT_NAMES: [&str; 4] = ["x", "y", "z", "w"];
+
+/// Small xorshift generator, good enough to explore models and always the same for a seed.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+
+    fn pick<T: Clone>(&mut self, items: &[T]) -> T {
+        items[self.below(items.len())].clone()
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Step {
+    RenameSlot(ArtifactReference, SlotName, SlotName),
+    RemoveBlockSlot(ArtifactReference, SlotName),
+    RemoveArtifact(ArtifactReference),
+    AddSlot(ArtifactReference, SlotName, SlotKind),
+}
+
+impl Step {
+    fn random(rng: &mut Rng, model: &dyn Model) -> Self {
+        let mut arefs = model.list_artifacts();
+        arefs.sort();
+        arefs.push(ar("nothing"));
+        let aref = rng.pick(&arefs);
+        let mut slot_names: Vec<_> = model
+            .slots_of(&aref)
+            .map(|slots| slots.into_iter().map(|(slot_name, _)| slot_name).collect())
+            .unwrap_or_default();
+        slot_names.sort();
+        slot_names.push(sn(rng.pick(&SLOT_NAMES)));
+        let slot_name = rng.pick(&slot_names);
+        match rng.below(4) {
+            0 => Step::RenameSlot(aref, slot_name, sn(rng.pick(&SLOT_NAMES))),
+            1 => Step::RemoveBlockSlot(aref, slot_name),
+            2 => Step::RemoveArtifact(aref),
+            _ => Step::AddSlot(aref, sn(rng.pick(&SLOT_NAMES)), sk(rng.pick(&KINDS))),
+        }
+    }
+
+    /// Applies the step if the model allows it. Being refused is fine, what matters is what the
+    /// model looks like afterwards.
+    fn apply(&self, model: &mut dyn Model) {
+        match self {
+            Step::RenameSlot(aref, old_slot_name, new_slot_name) => {
+                let _ = model.rename_slot(aref, old_slot_name, new_slot_name.clone());
+            }
+            Step::RemoveBlockSlot(aref, slot_name) => {
+                let _ = model.safely_remove_block_slot(aref, slot_name);
+            }
+            Step::RemoveArtifact(aref) => {
+                let _ = model.safely_remove_artifact(aref);
+            }
+            Step::AddSlot(aref, slot_name, slot_kind) => {
+                if let Some(Artifact::Block(block)) = model.get_artifact(aref) {
+                    let mut block = block.clone();
+                    if !block.slots.contains_key(slot_name) {
+                        block.slots.insert(slot_name.clone(), slot_kind.clone());
+                        model.set_artifact(aref.clone(), Artifact::Block(block));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Case {
+    model: InMemoryModel,
+    steps: Vec<Step>,
+}
+
+impl Case {
+    fn of(model: InMemoryModel) -> Self {
+        Case {
+            model,
+            steps: vec![],
+        }
+    }
+
+    fn with_steps(rng: &mut Rng, model: InMemoryModel) -> Self {
+        let mut current = model.clone();
+        let steps = (0..rng.below(12))
+            .map(|_| {
+                let step = Step::random(rng, &current);
+                step.apply(&mut current);
+                step
+            })
+            .collect();
+        Case { model, steps }
+    }
+
+    /// Slightly smaller versions of this case, to look for a minimal failing one.
+    fn shrunk(&self) -> Vec<Case> {
+        let mut cases = vec![];
+        for i in 0..self.steps.len() {
+            let mut case = self.clone();
+            case.steps.remove(i);
+            cases.push(case);
+        }
+        let mut arefs = self.model.list_artifacts();
+        arefs.sort();
+        for aref in arefs.iter() {
+            let mut case = self.clone();
+            case.model.remove_artifact(aref);
+            cases.push(case);
+        }
+        for aref in arefs.iter() {
+            match self.model.get_artifact(aref).unwrap() {
+                Artifact::Block(block) => {
+                    for slot_name in block.slots.keys() {
+                        let mut block = block.clone();
+                        block.slots.remove(slot_name);
+                        let mut case = self.clone();
+                        case.model
+                            .set_artifact(aref.clone(), Artifact::Block(block));
+                        cases.push(case);
+                    }
+                }
+                Artifact::Structure(structure) => {
+                    for (location, slot_name) in connections(structure) {
+                        let mut structure = structure.clone();
+                        structure.disconnect(&location, &slot_name).unwrap();
+                        let mut case = self.clone();
+                        case.model
+                            .set_artifact(aref.clone(), Artifact::Structure(structure));
+                        cases.push(case);
+                    }
+                }
+            }
+        }
+        if !self.model.kinds().is_empty() {
+            let mut case = self.clone();
+            case.model.set_kinds(KindLattice::default());
+            cases.push(case);
+        }
+        cases
+    }
+}
+
+/// Every connected slot in `structure`, deepest first.
+fn connections(structure: &Structure) -> Vec<(Location, SlotName)> {
+    fn visit(structure: &Structure, location: Location, found: &mut Vec<(Location, SlotName)>) {
+        let c: BTreeMap<_, _> = structure.c.iter().collect();
+        for (slot_name, connection) in c {
+            if let Connection::Structure(substruct) = connection {
+                visit(substruct, location.child(slot_name.clone()), found);
+            }
+            found.push((location.clone(), slot_name.clone()));
+        }
+    }
+    let mut found = vec![];
+    visit(structure, Location::root(), &mut found);
+    found
+}
+
+/// Builds a model adding artifacts one by one. Valid models only use artifacts already added
+/// and connect slots to artifacts of the right kind, while invalid ones can reference anything.
+fn random_model(rng: &mut Rng, valid: bool) -> InMemoryModel {
+    let mut model = InMemoryModel::default();
+    if rng.chance(50) {
+        let mut kinds = model.kinds().clone();
+        kinds.declare(sk("B"), sk("A"));
+        model.set_kinds(kinds);
+    }
+    let size = 1 + rng.below(8);
+    let names: Vec<_> = (0..size).map(|i| ar(format!("a{}", i))).collect();
+    for (i, aref) in names.iter().enumerate() {
+        let artifact = if i == 0 || rng.chance(40) {
+            Artifact::Block(Block {
+                main_slot_kind: sk(rng.pick(&KINDS)),
+                slots: (0..rng.below(3))
+                    .map(|_| (sn(rng.pick(&SLOT_NAMES)), sk(rng.pick(&KINDS))))
+                    .collect(),
+            })
+        } else {
+            let target = if valid {
+                rng.pick(&names[..i])
+            } else {
+                let mut targets = names.clone();
+                targets.push(ar("nothing"));
+                rng.pick(&targets)
+            };
+            let mut exposed = 0;
+            Artifact::Structure(random_structure(
+                rng,
+                &model,
+                target,
+                valid,
+                2,
+                &mut exposed,
+            ))
+        };
+        model.set_artifact(aref.clone(), artifact);
+    }
+    model
+}
+
+fn random_structure(
+    rng: &mut Rng,
+    model: &dyn Model,
+    a_ref: ArtifactReference,
+    valid: bool,
+    depth: usize,
+    exposed: &mut usize,
+) -> Structure {
+    let mut slots: Vec<_> = match model.slots_of(&a_ref) {
+        Ok(slots) => slots.into_iter().collect(),
+        Err(_) => (0..rng.below(3))
+            .map(|_| (sn(rng.pick(&SLOT_NAMES)), sk(rng.pick(&KINDS))))
+            .collect(),
+    };
+    slots.sort();
+    let mut arefs = model.list_artifacts();
+    arefs.sort();
+    let mut c = hashmap! {};
+    for (slot_name, slot_kind) in slots {
+        let candidates: Vec<_> = arefs
+            .iter()
+            .filter(|aref| {
+                !valid
+                    || model
+                        .main_slot_kind_of(aref)
+                        .map_or(false, |kind| model.accepts_kind(&slot_kind, &kind))
+            })
+            .cloned()
+            .collect();
+        let connection = match rng.below(3) {
+            0 => continue,
+            1 if depth > 0 && !candidates.is_empty() => {
+                let aref = rng.pick(&candidates);
+                Connection::Structure(random_structure(
+                    rng,
+                    model,
+                    aref,
+                    valid,
+                    depth - 1,
+                    exposed,
+                ))
+            }
+            _ => {
+                *exposed += 1;
+                Connection::Slot(sn(format!("e{}", exposed)))
+            }
+        };
+        c.insert(slot_name, connection);
+    }
+    Structure { a_ref, c }
+}
+
+/// Adds a loop of `length` structures, each one using the next either directly or through one
+/// of the slots of a block, and returns the artifacts in the loop.
+fn add_recursion_loop(
+    rng: &mut Rng,
+    model: &mut InMemoryModel,
+    length: usize,
+) -> Vec<ArtifactReference> {
+    model.set_artifact(
+        ar("holder"),
+        Artifact::Block(Block {
+            main_slot_kind: sk("A"),
+            slots: hashmap! { sn("x") => sk("A") },
+        }),
+    );
+    let members: Vec<_> = (0..length).map(|i| ar(format!("loop{}", i))).collect();
+    for (i, aref) in members.iter().enumerate() {
+        let next = members[(i + 1) % length].clone();
+        let structure = if rng.chance(50) {
+            Structure {
+                a_ref: next,
+                c: hashmap! {},
+            }
+        } else {
+            Structure {
+                a_ref: ar("holder"),
+                c: hashmap! {
+                    sn("x") => Connection::Structure(Structure { a_ref: next, c: hashmap! {} }),
+                },
+            }
+        };
+        model.set_artifact(aref.clone(), Artifact::Structure(structure));
+    }
+    members
+}
+
+/// Checks `property` on cases generated from many seeds, panicking with the smallest failing
+/// case found.
+fn check<G, P>(generate: G, property: P)
+where
+    G: Fn(&mut Rng) -> Case,
+    P: Fn(&Case) -> Result<(), String>,
+{
+    let run = |case: &Case| {
+        catch_unwind(AssertUnwindSafe(|| property(case)))
+            .unwrap_or_else(|_| Err("Panicked".to_string()))
+    };
+    for seed in 0..CASES {
+        let case = generate(&mut Rng::new(seed));
+        if let Err(err) = run(&case) {
+            let (case, err) = shrink(case, err, &run);
+            panic!(
+                "Failed with seed {}: {}\nModel:\n{}\nSteps: {:#?}",
+                seed,
+                err,
+                to_text(&case.model),
+                case.steps
+            );
+        }
+    }
+}
+
+fn shrink<R>(mut case: Case, mut err: String, run: &R) -> (Case, String)
+where
+    R: Fn(&Case) -> Result<(), String>,
+{
+    'shrinking: loop {
+        for smaller in case.shrunk() {
+            if let Err(smaller_err) = run(&smaller) {
+                case = smaller;
+                err = smaller_err;
+                continue 'shrinking;
+            }
+        }
+        return (case, err);
+    }
+}
+
+fn ensure<T: Debug + PartialEq>(what: &str, found: T, expected: T) -> Result<(), String> {
+    if found == expected {
+        Ok(())
+    } else {
+        Err(format!(
+            "{}: expected {:?} but found {:?}",
+            what, expected, found
+        ))
+    }
+}
+
+fn all_artifacts(model: &dyn Model) -> Vec<ArtifactReference> {
+    let mut arefs = model.list_artifacts();
+    arefs.sort();
+    arefs
+}
+
+/// Dependencies and dependents must describe the same graph from both ends. The transitive ones
+/// are only checked in valid models, where they cannot loop forever.
+fn dependencies_are_inverse_of_dependents(model: &dyn Model) -> Result<(), String> {
+    let arefs = all_artifacts(model);
+    let valid = model.is_all_valid();
+    for a in arefs.iter() {
+        for b in arefs.iter() {
+            ensure(
+                &format!("{} directly uses {}", a, b),
+                model.direct_dependencies(a).contains(b),
+                model.direct_dependents(b).contains(a),
+            )?;
+            if valid {
+                ensure(
+                    &format!("{} uses {}", a, b),
+                    model.dependencies(a).contains(b),
+                    model.dependents(b).contains(a),
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Recursion is found exactly when the artifact can reach a cycle in the dependency graph.
+fn recursion_matches_the_graph(model: &dyn Model) -> Result<(), String> {
+    let graph = DependencyGraph::of(model);
+    for aref in all_artifacts(model) {
+        let reaches_cycle = graph.in_cycle(&aref)
+            || graph
+                .all_uses(&aref)
+                .iter()
+                .any(|used| graph.in_cycle(used));
+        ensure(
+            &format!("Recursion found from {}", aref),
+            model.find_recursion(&aref).is_some(),
+            reaches_cycle,
+        )?;
+        if reaches_cycle {
+            ensure(
+                &format!("{} is valid", aref),
+                model.validate(&aref).is_ok(),
+                false,
+            )?;
+        }
+    }
+    if model.is_all_valid() {
+        ensure("Valid model has cycles", graph.is_acyclic(), true)?;
+        ensure("Valid model misses artifacts", graph.missing(), vec![])?;
+    }
+    Ok(())
+}
+
+#[test]
+fn generated_valid_models_are_valid() {
+    check(
+        |rng| Case::of(random_model(rng, true)),
+        |case| match case.model.validate_model() {
+            Ok(()) => Ok(()),
+            Err(err) => Err(format!("{:?}", err)),
+        },
+    );
+}
+
+#[test]
+fn safe_refactors_keep_valid_models_valid() {
+    check(
+        |rng| {
+            let model = random_model(rng, true);
+            Case::with_steps(rng, model)
+        },
+        |case| {
+            let mut model = case.model.clone();
+            if !model.is_all_valid() {
+                return Ok(());
+            }
+            for (i, step) in case.steps.iter().enumerate() {
+                step.apply(&mut model);
+                if let Err(err) = model.validate_model() {
+                    return Err(format!("Invalid after step {}: {:?}", i, err));
+                }
+                dependencies_are_inverse_of_dependents(&model)?;
+            }
+            Ok(())
+        },
+    );
+}
+
+#[test]
+fn dependencies_and_dependents_are_consistent() {
+    check(
+        |rng| {
+            let valid = rng.chance(50);
+            Case::of(random_model(rng, valid))
+        },
+        |case| dependencies_are_inverse_of_dependents(&case.model),
+    );
+}
+
+#[test]
+fn recursion_of_any_length_is_detected() {
+    check(
+        |rng| {
+            let mut model = random_model(rng, true);
+            let length = 1 + rng.below(8);
+            add_recursion_loop(rng, &mut model, length);
+            Case::of(model)
+        },
+        |case| {
+            let model = &case.model;
+            let graph = DependencyGraph::of(model);
+            if graph.is_acyclic() {
+                return Ok(());
+            }
+            ensure("Model with a loop is valid", model.is_all_valid(), false)?;
+            for aref in graph.cycles().iter().flatten() {
+                let recursion = model
+                    .diagnose(aref)
+                    .iter()
+                    .any(|d| matches!(d, Diagnostic::RecursionDetected(_, _)));
+                ensure(&format!("Recursion detected in {}", aref), recursion, true)?;
+                ensure(
+                    &format!("Expanding {}", aref),
+                    matches!(
+                        model.expand(aref),
+                        Err(ExpansionError::RecursionDetected(_, _))
+                    ),
+                    true,
+                )?;
+            }
+            recursion_matches_the_graph(model)
+        },
+    );
+}
+
+#[test]
+fn invalid_models_can_be_inspected() {
+    check(
+        |rng| Case::of(random_model(rng, false)),
+        |case| {
+            let model = &case.model;
+            for aref in all_artifacts(model) {
+                model.diagnose(&aref);
+                let _ = model.slots_of(&aref);
+                let _ = model.main_slot_kind_of(&aref);
+                let _ = model.expand(&aref);
+            }
+            recursion_matches_the_graph(model)
+        },
+    );
+}
+
+#[test]
+fn failures_shrink_to_a_minimal_case() {
+    let mut rng = Rng::new(0);
+    let mut model = random_model(&mut rng, true);
+    add_recursion_loop(&mut rng, &mut model, 3);
+    let case = Case::with_steps(&mut rng, model);
+    let (minimal, _) = shrink(case, String::new(), &|case: &Case| {
+        if DependencyGraph::of(&case.model).is_acyclic() {
+            Ok(())
+        } else {
+            Err("Has a loop".to_string())
+        }
+    });
+    assert!(minimal.steps.is_empty());
+    let cycles = DependencyGraph::of(&minimal.model).cycles().to_vec();
+    assert_eq!(cycles.len(), 1);
+    assert_eq!(all_artifacts(&minimal.model), cycles[0]);
+}
@@ -0,0 +1,426 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Display,
+};
+
+use super::mursten::*;
+
+/// A filter over the artifacts of a model. It can also be written as text, as a list of terms
+/// that must all match:
+///
+/// - `kind:K`: artifacts whose main slot kind is `K` or one of its subkinds.
+/// - `slot:S`: artifacts with a slot named `S`.
+/// - `slot-kind:K`: artifacts with a slot of kind `K` or one of its subkinds.
+/// - `uses:A`: artifacts that depend on `A`, directly or through other artifacts.
+/// - `unused`: artifacts that no other artifact uses.
+/// - `invalid`: artifacts with errors.
+/// - `disconnected`: structures with disconnected slots.
+/// - `-term`: artifacts that do not match `term`.
+/// - Anything else: artifacts whose reference contains that text.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Query {
+    All(Vec<Query>),
+    Not(Box<Query>),
+    Named(String),
+    OfKind(SlotKind),
+    WithSlot(SlotName),
+    WithSlotOfKind(SlotKind),
+    DependsOn(ArtifactReference),
+    Unused,
+    Invalid,
+    Disconnected,
+}
+
+/// Why an artifact matched a query.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum MatchReason {
+    Named(String),
+    OfKind(SlotKind),
+    HasSlot(SlotName, SlotKind),
+    DependsOn(ArtifactReference, Vec<ArtifactReference>),
+    Unused,
+    Diagnosed(Diagnostic),
+    DoesNotMatch(Query),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct QueryMatch {
+    pub aref: ArtifactReference,
+    pub reasons: Vec<MatchReason>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct QueryParseError(pub String);
+
+impl Display for QueryParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Query {
+    pub fn parse(text: &str) -> Result<Self, QueryParseError> {
+        let terms = text
+            .split_whitespace()
+            .map(Self::parse_term)
+            .collect::<Result<Vec<_>, _>>()?;
+        if terms.len() == 1 {
+            Ok(terms.into_iter().next().unwrap())
+        } else {
+            Ok(Query::All(terms))
+        }
+    }
+
+    fn parse_term(term: &str) -> Result<Self, QueryParseError> {
+        if let Some(negated) = term.strip_prefix('-') {
+            return Ok(Query::Not(Box::new(Self::parse_term(negated)?)));
+        }
+        let (key, value) = match term.find(':') {
+            Some(colon) => (&term[..colon], &term[colon + 1..]),
+            None => {
+                return Ok(match term {
+                    "unused" => Query::Unused,
+                    "invalid" => Query::Invalid,
+                    "disconnected" => Query::Disconnected,
+                    "" => {
+                        return Err(QueryParseError(
+                            "Expected something to search after `-`".into(),
+                        ))
+                    }
+                    name => Query::Named(name.into()),
+                })
+            }
+        };
+        if value.is_empty() {
+            return Err(QueryParseError(format!(
+                "Expected a value after `{}:`",
+                key
+            )));
+        }
+        match key {
+            "kind" => Ok(Query::OfKind(sk(value))),
+            "slot" => Ok(Query::WithSlot(sn(value))),
+            "slot-kind" => Ok(Query::WithSlotOfKind(sk(value))),
+            "uses" => Ok(Query::DependsOn(ar(value))),
+            _ => Err(QueryParseError(format!(
+                "Unknown filter `{}`, expected `kind`, `slot`, `slot-kind` or `uses`",
+                key
+            ))),
+        }
+    }
+
+    /// The reasons why `aref` matches this query, or `None` if it does not. Works with invalid
+    /// models, although artifacts in a recursion loop do not expose any slots.
+    pub fn reasons<M: Model + ?Sized>(
+        &self,
+        model: &M,
+        aref: &ArtifactReference,
+    ) -> Option<Vec<MatchReason>> {
+        match self {
+            Query::All(queries) => {
+                let mut reasons = vec![];
+                for query in queries {
+                    reasons.extend(query.reasons(model, aref)?);
+                }
+                Some(reasons)
+            }
+            Query::Not(query) => match query.reasons(model, aref) {
+                Some(_) => None,
+                None => Some(vec![MatchReason::DoesNotMatch(*query.clone())]),
+            },
+            Query::Named(text) => non_empty(if aref.as_str().contains(text.as_str()) {
+                vec![MatchReason::Named(text.clone())]
+            } else {
+                vec![]
+            }),
+            Query::OfKind(expected) => {
+                let found = model.main_slot_kind_of(aref).ok()?;
+                if model.accepts_kind(expected, &found) {
+                    Some(vec![MatchReason::OfKind(found)])
+                } else {
+                    None
+                }
+            }
+            Query::WithSlot(slot_name) => {
+                let slots = slots_of(model, aref)?;
+                let slot_kind = slots.get(slot_name)?;
+                Some(vec![MatchReason::HasSlot(
+                    slot_name.clone(),
+                    slot_kind.clone(),
+                )])
+            }
+            Query::WithSlotOfKind(expected) => non_empty(
+                slots_of(model, aref)?
+                    .into_iter()
+                    .filter(|(_, slot_kind)| model.accepts_kind(expected, slot_kind))
+                    .map(|(slot_name, slot_kind)| MatchReason::HasSlot(slot_name, slot_kind))
+                    .collect(),
+            ),
+            Query::DependsOn(dependency) => {
+                let through = path_to(model, aref, dependency)?;
+                Some(vec![MatchReason::DependsOn(dependency.clone(), through)])
+            }
+            Query::Unused => {
+                if model.direct_dependents(aref).is_empty() {
+                    Some(vec![MatchReason::Unused])
+                } else {
+                    None
+                }
+            }
+            Query::Invalid => non_empty(
+                model
+                    .diagnose(aref)
+                    .into_iter()
+                    .filter(Diagnostic::is_error)
+                    .map(MatchReason::Diagnosed)
+                    .collect(),
+            ),
+            Query::Disconnected => non_empty(
+                model
+                    .diagnose(aref)
+                    .into_iter()
+                    .filter(|diagnostic| !diagnostic.is_error())
+                    .map(MatchReason::Diagnosed)
+                    .collect(),
+            ),
+        }
+    }
+}
+
+fn non_empty(reasons: Vec<MatchReason>) -> Option<Vec<MatchReason>> {
+    if reasons.is_empty() {
+        None
+    } else {
+        Some(reasons)
+    }
+}
+
+/// Slots of `aref` sorted by name, if they can be known without looping forever.
+fn slots_of<M: Model + ?Sized>(
+    model: &M,
+    aref: &ArtifactReference,
+) -> Option<BTreeMap<SlotName, SlotKind>> {
+    if model.find_recursion(aref).is_some() {
+        return None;
+    }
+    model
+        .slots_of(aref)
+        .ok()
+        .map(|slots| slots.into_iter().collect())
+}
+
+/// The artifacts to go through to get from `from` to `to`, following the shortest path of
+/// dependencies, or `None` if `from` does not depend on `to`.
+fn path_to<M: Model + ?Sized>(
+    model: &M,
+    from: &ArtifactReference,
+    to: &ArtifactReference,
+) -> Option<Vec<ArtifactReference>> {
+    let mut previous: BTreeMap<ArtifactReference, ArtifactReference> = BTreeMap::new();
+    let mut to_see = VecDeque::new();
+    to_see.push_back(from.clone());
+    while let Some(current) = to_see.pop_front() {
+        if !model.exists_artifact(&current) {
+            continue;
+        }
+        let mut dependencies = model.direct_dependencies(&current);
+        dependencies.sort();
+        for dependency in dependencies {
+            if dependency == *from || previous.contains_key(&dependency) {
+                continue;
+            }
+            previous.insert(dependency.clone(), current.clone());
+            if dependency == *to {
+                let mut through = vec![];
+                let mut step = &previous[to];
+                while step != from {
+                    through.push(step.clone());
+                    step = &previous[step];
+                }
+                through.reverse();
+                return Some(through);
+            }
+            to_see.push_back(dependency);
+        }
+    }
+    None
+}
+
+/// Artifacts of `model` matching `query`, sorted by reference, with the reasons why they matched.
+/// It is what `Model::query` does, for any backend.
+pub fn search<M: Model + ?Sized>(model: &M, query: &Query) -> Vec<QueryMatch> {
+    let mut found: Vec<_> = model
+        .list_artifacts()
+        .into_iter()
+        .filter_map(|aref| {
+            let reasons = query.reasons(model, &aref)?;
+            Some(QueryMatch { aref, reasons })
+        })
+        .collect();
+    found.sort_by(|a, b| a.aref.cmp(&b.aref));
+    found
+}
+
+impl Display for Query {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Query::All(queries) => {
+                let terms: Vec<_> = queries.iter().map(Query::to_string).collect();
+                f.write_str(&terms.join(" "))
+            }
+            Query::Not(query) => write!(f, "-{}", query),
+            Query::Named(text) => f.write_str(text),
+            Query::OfKind(slot_kind) => write!(f, "kind:{}", slot_kind.as_str()),
+            Query::WithSlot(slot_name) => write!(f, "slot:{}", slot_name.as_str()),
+            Query::WithSlotOfKind(slot_kind) => write!(f, "slot-kind:{}", slot_kind.as_str()),
+            Query::DependsOn(aref) => write!(f, "uses:{}", aref.as_str()),
+            Query::Unused => f.write_str("unused"),
+            Query::Invalid => f.write_str("invalid"),
+            Query::Disconnected => f.write_str("disconnected"),
+        }
+    }
+}
+
+impl Display for MatchReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MatchReason::Named(text) => write!(f, "Its reference contains \"{}\"", text),
+            MatchReason::OfKind(slot_kind) => write!(f, "It is a {}", slot_kind),
+            MatchReason::HasSlot(slot_name, slot_kind) => {
+                write!(f, "It has a {} slot of kind {}", slot_name, slot_kind)
+            }
+            MatchReason::DependsOn(aref, through) if through.is_empty() => {
+                write!(f, "It uses {}", aref)
+            }
+            MatchReason::DependsOn(aref, through) => {
+                let through: Vec<_> = through.iter().map(ToString::to_string).collect();
+                write!(f, "It uses {} through {}", aref, through.join(" -> "))
+            }
+            MatchReason::Unused => f.write_str("No other artifact uses it"),
+            MatchReason::Diagnosed(diagnostic) => write!(f, "{}", diagnostic),
+            MatchReason::DoesNotMatch(query) => write!(f, "It does not match `{}`", query),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::mursten::example::*;
+    use super::*;
+
+    fn matching(model: &dyn Model, query: &str) -> Vec<ArtifactReference> {
+        model
+            .query(&Query::parse(query).unwrap())
+            .into_iter()
+            .map(|found| found.aref)
+            .collect()
+    }
+
+    #[test]
+    fn parses_queries_from_text() {
+        assert_eq!(
+            Query::parse("kind:Number -uses:zero four").unwrap(),
+            Query::All(vec![
+                Query::OfKind(sk("Number")),
+                Query::Not(Box::new(Query::DependsOn(ar("zero")))),
+                Query::Named("four".into()),
+            ])
+        );
+        assert_eq!(Query::parse("  invalid ").unwrap(), Query::Invalid);
+        assert_eq!(Query::parse("").unwrap(), Query::All(vec![]));
+        let query = "slot:n slot-kind:Number unused -disconnected";
+        assert_eq!(Query::parse(query).unwrap().to_string(), query);
+        assert_eq!(
+            Query::parse("color:red").unwrap_err().to_string(),
+            "Unknown filter `color`, expected `kind`, `slot`, `slot-kind` or `uses`"
+        );
+        assert_eq!(
+            Query::parse("kind:").unwrap_err().to_string(),
+            "Expected a value after `kind:`"
+        );
+    }
+
+    #[test]
+    fn finds_artifacts_by_kind_slots_and_dependencies() {
+        let model = and_one_more_is_five_model();
+        assert_eq!(
+            matching(&model, ""),
+            vec![
+                ar("number_4"),
+                ar("number_5"),
+                ar("plus_2"),
+                ar("successor"),
+                ar("zero")
+            ]
+        );
+        assert_eq!(
+            matching(&model, "number"),
+            vec![ar("number_4"), ar("number_5")]
+        );
+        assert_eq!(
+            matching(&model, "slot:x"),
+            vec![ar("plus_2"), ar("successor")]
+        );
+        assert_eq!(
+            matching(&model, "uses:successor -plus"),
+            vec![ar("number_4"), ar("number_5")]
+        );
+        assert_eq!(matching(&model, "unused"), vec![ar("number_5")]);
+        assert!(matching(&model, "invalid").is_empty());
+        assert!(matching(&model, "kind:Nothing").is_empty());
+
+        let found = model.query(&Query::parse("uses:zero slot-kind:Natural").unwrap());
+        assert_eq!(found.len(), 0);
+        let found = model.query(&Query::parse("uses:successor plus").unwrap());
+        assert_eq!(
+            found,
+            vec![QueryMatch {
+                aref: ar("plus_2"),
+                reasons: vec![
+                    MatchReason::DependsOn(ar("successor"), vec![]),
+                    MatchReason::Named("plus".into()),
+                ]
+            }]
+        );
+        let found = model.query(&Query::parse("uses:zero number_5").unwrap());
+        assert_eq!(
+            found[0].reasons[0].to_string(),
+            "It uses zero through number_4"
+        );
+    }
+
+    #[test]
+    fn finds_problems_in_invalid_models() {
+        let mut model = and_one_more_is_five_model();
+        let number_4 = match model.get_artifact(&ar("number_4")) {
+            Some(Artifact::Structure(structure)) => structure.clone(),
+            _ => unreachable!(),
+        };
+        let mut broken = number_4.clone();
+        broken.c.clear();
+        model.set_artifact(ar("incomplete"), Artifact::Structure(broken));
+        model.set_artifact(
+            ar("loop"),
+            Artifact::Structure(Structure {
+                a_ref: ar("loop"),
+                c: hashmap! {},
+            }),
+        );
+
+        assert_eq!(matching(&model, "invalid"), vec![ar("loop")]);
+        assert_eq!(matching(&model, "disconnected"), vec![ar("incomplete")]);
+        assert_eq!(
+            matching(&model, "unused -invalid"),
+            vec![ar("incomplete"), ar("number_5")]
+        );
+        assert_eq!(
+            matching(&model, "slot-kind:Natural"),
+            vec![ar("plus_2"), ar("successor")]
+        );
+        let found = model.query(&Query::Disconnected);
+        assert_eq!(
+            found[0].reasons[0].to_string(),
+            "At /'x: [Natural] is disconnected"
+        );
+    }
+}
@@ -1,36 +1,44 @@
 use bevy::prelude::*;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 
 pub type LinkId = usize;
 pub type SlotName = char;
 
-#[derive(Debug, Default, Eq, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize)]
-pub struct SlotId(LinkId, SlotName);
+#[derive(
+    Debug, Default, Eq, PartialEq, Hash, Clone, Copy, serde::Serialize, serde::Deserialize,
+)]
+pub struct SlotId(pub LinkId, pub SlotName);
 
 pub type Variable = String;
 pub type Effector = String;
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Definition {
     pub last_link_id: LinkId,
     pub links: HashMap<LinkId, Link>,
     pub joints: Vec<(SlotId, Joint, SlotId)>,
-    // variable_constrains: HashMap<String, VariableConstraint>,
+    #[serde(default)]
+    pub variable_constraints: HashMap<Variable, VariableConstraint>,
+    #[serde(default)]
+    pub effectors: HashMap<Effector, SlotId>,
 }
 
-#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Default, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Slot {
     pub position: Vec3,
     pub orientation: Quat,
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 // should be private
 pub struct Link {
+    /// Name of the link type in the library the slots are a copy of, if any.
+    #[serde(default)]
+    pub link_type: Option<String>,
     pub slots: HashMap<SlotName, Slot>,
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum Change {
     Add {
         link: Link,
@@ -57,9 +65,24 @@ pub enum Change {
         from: SlotId,
         to: SlotId,
     },
+    /// Puts back links taken out by a cut, keeping their ids.
+    Graft {
+        links: HashMap<LinkId, Link>,
+        joints: Vec<(SlotId, Joint, SlotId)>,
+    },
+    Constrain {
+        variable: Variable,
+        with: VariableConstraint,
+    },
+    /// Attaches an effector to a slot, or detaches it when `at` is `None`.
+    PlaceEffector {
+        effector: Effector,
+        at: Option<SlotId>,
+    },
+    Batch(Vec<Change>),
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum Joint {
     Fixed,
     TwistingJoint(FloatValue),
@@ -67,23 +90,163 @@ pub enum Joint {
     // LinearJoint(FloatValue),
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum FloatValue {
     Variable(Variable),
     Constant(f32),
 }
 
-#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Default, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct VariableConstraint {
     pub min: Option<f32>,
     pub max: Option<f32>,
 }
 
+impl VariableConstraint {
+    pub fn contains(&self, value: f32) -> bool {
+        self.min.map_or(true, |min| min <= value) && self.max.map_or(true, |max| value <= max)
+    }
+
+    pub fn clamp(&self, value: f32) -> f32 {
+        let value = self.min.map_or(value, |min| value.max(min));
+        self.max.map_or(value, |max| value.min(max))
+    }
+
+    /// Value a variable takes when nothing else is said about it: zero, or the closest value to
+    /// it in range.
+    pub fn default_value(&self) -> f32 {
+        self.clamp(0.0)
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum ChangeError {
     UnexistingLink(LinkId),
     UnexistingSlot(SlotId),
     SlotOccupied(SlotId),
+    SlotFree(SlotId),
+    SlotInUse(SlotId),
+    LinkAlreadyExists(LinkId),
+    CutsLinkZero(SlotId),
+    MoveIntoItself(SlotId),
+    SpliceOutsideCut(SlotId),
+    DetachedLink(LinkId),
+    ClosedLoop(LinkId),
+    UnexistingVariable(Variable),
+    EmptyRange(Variable),
+    DetachedEffector(Effector),
+}
+
+/// Something wrong with the values a pose gives to the variables of a skeleton.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PoseProblem {
+    Missing(Variable),
+    Unknown(Variable),
+    OutOfRange {
+        variable: Variable,
+        value: f32,
+        constraint: VariableConstraint,
+    },
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum PoseError {
+    UnboundVariable(Variable),
+    UnexistingLink(LinkId),
+    UnexistingSlot(SlotId),
+    UnreachableLink(LinkId),
+    UnexistingEffector(Effector),
+}
+
+/// Result of inverse kinematics: the pose found and how far from its target the furthest
+/// effector is left.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IkSolution {
+    pub pose: Pose,
+    pub error: f32,
+}
+
+const IK_ITERATIONS: usize = 50;
+const IK_TOLERANCE: f32 = 1e-3;
+
+impl PoseProblem {
+    pub fn variable(&self) -> &Variable {
+        match self {
+            PoseProblem::Missing(variable)
+            | PoseProblem::Unknown(variable)
+            | PoseProblem::OutOfRange { variable, .. } => variable,
+        }
+    }
+}
+
+impl Slot {
+    /// Where the slot is in the space of its link.
+    pub fn transform(&self) -> Transform {
+        Transform {
+            translation: self.position,
+            rotation: self.orientation,
+            ..Transform::identity()
+        }
+    }
+}
+
+impl Joint {
+    /// How the joint moves the child slot relative to the parent slot. A twisting joint rotates
+    /// around the axis going out of the slot (`UP` in slot space), and a rotational one bends
+    /// around the `LEFT` axis of the slot. Angles are in radians.
+    pub fn transform(&self, pose: &Pose) -> Result<Transform, PoseError> {
+        match self {
+            Joint::Fixed => Ok(Transform::identity()),
+            Joint::TwistingJoint(angle) => Ok(Transform::from_rotation(Quat::from_axis_angle(
+                UP,
+                angle.resolve(pose)?,
+            ))),
+            Joint::RotationalJoin(angle) => Ok(Transform::from_rotation(Quat::from_axis_angle(
+                LEFT,
+                angle.resolve(pose)?,
+            ))),
+        }
+    }
+
+    pub fn variables(&self) -> Vec<Variable> {
+        match self {
+            Joint::Fixed => vec![],
+            Joint::TwistingJoint(value) | Joint::RotationalJoin(value) => {
+                value.variable().into_iter().cloned().collect()
+            }
+        }
+    }
+}
+
+impl FloatValue {
+    pub fn resolve(&self, pose: &Pose) -> Result<f32, PoseError> {
+        match self {
+            FloatValue::Constant(value) => Ok(*value),
+            FloatValue::Variable(variable) => pose
+                .valuation
+                .get(variable)
+                .cloned()
+                .ok_or_else(|| PoseError::UnboundVariable(variable.clone())),
+        }
+    }
+
+    pub fn variable(&self) -> Option<&Variable> {
+        match self {
+            FloatValue::Variable(variable) => Some(variable),
+            FloatValue::Constant(_) => None,
+        }
+    }
+}
+
+/// Inverse of a transform made only of a translation and a rotation, like the ones of slots and
+/// joints.
+fn inverse(transform: &Transform) -> Transform {
+    let rotation = transform.rotation.conjugate();
+    Transform {
+        translation: -(rotation * transform.translation),
+        rotation,
+        ..Transform::identity()
+    }
 }
 
 impl Definition {
@@ -94,10 +257,50 @@ impl Definition {
                 0 => link0,
             },
             joints: vec![],
+            variable_constraints: HashMap::new(),
+            effectors: HashMap::new(),
         }
     }
 
-    pub fn apply(&mut self, change: Change) -> Result<(), ChangeError> {
+    /// Applies `change` if it leaves a valid skeleton, where every link hangs from link 0, and
+    /// returns the change that reverts it. Constraints of variables no joint uses anymore are
+    /// dropped, and put back by the inverse.
+    pub fn apply(&mut self, change: Change) -> Result<Change, ChangeError> {
+        let mut next = self.clone();
+        let inverse = next.apply_unchecked(change)?;
+        next.check_tree()?;
+        let restore = next.prune_constraints();
+        *self = next;
+        if restore.is_empty() {
+            Ok(inverse)
+        } else {
+            Ok(Change::Batch(
+                std::iter::once(inverse).chain(restore).collect(),
+            ))
+        }
+    }
+
+    /// Drops the constraints of variables that no joint uses, returning the changes that put
+    /// them back.
+    fn prune_constraints(&mut self) -> Vec<Change> {
+        let variables = self.variables();
+        let mut orphaned: Vec<_> = self
+            .variable_constraints
+            .keys()
+            .filter(|variable| !variables.contains(*variable))
+            .cloned()
+            .collect();
+        orphaned.sort();
+        orphaned
+            .into_iter()
+            .map(|variable| {
+                let with = self.variable_constraints.remove(&variable).unwrap();
+                Change::Constrain { variable, with }
+            })
+            .collect()
+    }
+
+    fn apply_unchecked(&mut self, change: Change) -> Result<Change, ChangeError> {
         match change {
             Change::Add {
                 link,
@@ -105,88 +308,371 @@ impl Definition {
                 joint,
                 local_slot_name,
             } => {
-                if !self.links.contains_key(&to_parent_slot.0) {
-                    return Err(ChangeError::UnexistingLink(to_parent_slot.0));
-                }
-                if !self
-                    .links
-                    .get(&to_parent_slot.0)
-                    .unwrap()
-                    .slots
-                    .contains_key(&to_parent_slot.1)
-                {
-                    return Err(ChangeError::UnexistingSlot(to_parent_slot));
-                }
-                if self
-                    .joints
-                    .iter()
-                    .any(|(a, _j, b)| to_parent_slot == *a || to_parent_slot == *b)
-                {
-                    return Err(ChangeError::SlotOccupied(to_parent_slot));
+                self.check_slot(to_parent_slot)?;
+                self.check_free(to_parent_slot)?;
+                let id = self.last_link_id + 1;
+                if !link.slots.contains_key(&local_slot_name) {
+                    return Err(ChangeError::UnexistingSlot(SlotId(id, local_slot_name)));
                 }
 
-                self.last_link_id += 1;
-                let id = self.last_link_id;
+                self.last_link_id = id;
                 self.links.insert(id, link);
                 self.joints
                     .push((to_parent_slot, joint, SlotId(id, local_slot_name)));
+                Ok(Change::Cut {
+                    from: to_parent_slot,
+                })
+            }
+            Change::ReplaceLink { at, with } => {
+                if !self.links.contains_key(&at) {
+                    return Err(ChangeError::UnexistingLink(at));
+                }
+                for slot_id in self.used_slots(at) {
+                    if !with.slots.contains_key(&slot_id.1) {
+                        return Err(ChangeError::SlotInUse(slot_id));
+                    }
+                }
+                let old = self.links.insert(at, with).unwrap();
+                Ok(Change::ReplaceLink { at, with: old })
+            }
+            Change::ReplaceJoint { at, with } => {
+                self.check_slot(at)?;
+                let index = self.joint_at(at).ok_or(ChangeError::SlotFree(at))?;
+                let old = std::mem::replace(&mut self.joints[index].1, with);
+                Ok(Change::ReplaceJoint { at, with: old })
+            }
+            Change::Cut { from } => {
+                let child = self.attached_at(from)?;
+                let (links, joints) = self.remove_links(&self.subtree(child));
+                Ok(Change::Graft { links, joints })
             }
-            _ => panic!(),
-        }
-        // Change::Delete(LinkId::Id(id)) => {
-        //     self.entries.remove(&id);
-        //     for (_, entry) in self.entries.iter_mut() {
-        //         if entry.attached_to == Some(id) {
-        //             entry.attached_to = None;
-        //         }
-        //     }
-        // }
-        // Change::AddToRoot(part) => {
-        //     let id = self.last_link_id;
-        //     self.last_link_id += 1;
-        //     self.entries.insert(
-        //         id,
-        //         PartEntry {
-        //             part,
-        //             attached_to: None,
-        //         },
-        //     );
-        // }
-        // Change::AddToPart(pid, part) => {
-        //     let id = self.last_link_id;
-        //     self.last_link_id += 1;
-        //     self.entries.insert(
-        //         id,
-        //         PartEntry {
-        //             part,
-        //             attached_to: Some(pid),
-        //         },
-        //     );
-        // }
-        // Change::Replace(id, part) => {
-        //     self.entries
-        //         .iter_mut()
-        //         .find(|(eid, _)| **eid == id)
-        //         .unwrap()
-        //         .1
-        //         .part = part.clone();
-        // }
-        // }
-        Ok(())
+            Change::MoveLink { at, to } => {
+                let child = self.attached_at(at)?;
+                self.check_slot(to)?;
+                if self.subtree(child).contains(&to.0) {
+                    return Err(ChangeError::MoveIntoItself(to));
+                }
+                self.check_free(to)?;
+                let index = self.joint_at(at).unwrap();
+                self.joints[index].0 = to;
+                Ok(Change::MoveLink { at: to, to: at })
+            }
+            Change::CutAndSplice { from, to } => {
+                let child = self.attached_at(from)?;
+                let cut = self.subtree(child);
+                self.check_slot(to)?;
+                if !cut.contains(&to.0) {
+                    return Err(ChangeError::SpliceOutsideCut(to));
+                }
+                let spliced = match self.attached_at(to) {
+                    Err(ChangeError::CutsLinkZero(_)) => {
+                        return Err(ChangeError::SpliceOutsideCut(to))
+                    }
+                    result => result?,
+                };
+                let (_, joint, spliced_slot) = self.joints[self.joint_at(to).unwrap()].clone();
+                let kept = self.subtree(spliced);
+
+                let removed: Vec<_> = cut
+                    .iter()
+                    .filter(|link_id| !kept.contains(link_id))
+                    .cloned()
+                    .collect();
+                let inverse = Change::Batch(vec![
+                    Change::Cut { from },
+                    Change::Graft {
+                        links: cut
+                            .iter()
+                            .map(|link_id| (*link_id, self.links[link_id].clone()))
+                            .collect(),
+                        joints: self
+                            .joints
+                            .iter()
+                            .filter(|(a, _, b)| cut.contains(&a.0) || cut.contains(&b.0))
+                            .cloned()
+                            .collect(),
+                    },
+                ]);
+                self.remove_links(&removed);
+                self.joints.push((from, joint, spliced_slot));
+                Ok(inverse)
+            }
+            Change::Graft { links, joints } => {
+                for link_id in links.keys() {
+                    if self.links.contains_key(link_id) {
+                        return Err(ChangeError::LinkAlreadyExists(*link_id));
+                    }
+                }
+                let mut cuts = vec![];
+                for (parent_slot, _, _) in joints.iter() {
+                    if self.links.contains_key(&parent_slot.0) {
+                        cuts.push(Change::Cut { from: *parent_slot });
+                    }
+                }
+                self.last_link_id = links.keys().cloned().fold(self.last_link_id, LinkId::max);
+                self.links.extend(links);
+                for (parent_slot, joint, child_slot) in joints {
+                    for slot_id in [parent_slot, child_slot].iter() {
+                        self.check_slot(*slot_id)?;
+                        self.check_free(*slot_id)?;
+                    }
+                    self.joints.push((parent_slot, joint, child_slot));
+                }
+                if cuts.len() == 1 {
+                    Ok(cuts.remove(0))
+                } else {
+                    Ok(Change::Batch(cuts))
+                }
+            }
+            Change::Constrain { variable, with } => {
+                if !self.variables().contains(&variable) {
+                    return Err(ChangeError::UnexistingVariable(variable));
+                }
+                if let (Some(min), Some(max)) = (with.min, with.max) {
+                    if min > max {
+                        return Err(ChangeError::EmptyRange(variable));
+                    }
+                }
+                // Unconstrained variables are not stored
+                let old = if with == VariableConstraint::default() {
+                    self.variable_constraints.remove(&variable)
+                } else {
+                    self.variable_constraints.insert(variable.clone(), with)
+                }
+                .unwrap_or_default();
+                Ok(Change::Constrain {
+                    variable,
+                    with: old,
+                })
+            }
+            Change::PlaceEffector { effector, at } => {
+                let old = match at {
+                    Some(slot_id) => {
+                        self.check_slot(slot_id)?;
+                        self.effectors.insert(effector.clone(), slot_id)
+                    }
+                    None => self.effectors.remove(&effector),
+                };
+                Ok(Change::PlaceEffector { effector, at: old })
+            }
+            Change::Batch(changes) => {
+                let mut inverses = vec![];
+                for change in changes {
+                    inverses.push(self.apply_unchecked(change)?);
+                }
+                inverses.reverse();
+                Ok(Change::Batch(inverses))
+            }
+        }
+    }
+
+    fn check_slot(&self, slot_id: SlotId) -> Result<(), ChangeError> {
+        let link = self
+            .links
+            .get(&slot_id.0)
+            .ok_or(ChangeError::UnexistingLink(slot_id.0))?;
+        if link.slots.contains_key(&slot_id.1) {
+            Ok(())
+        } else {
+            Err(ChangeError::UnexistingSlot(slot_id))
+        }
+    }
+
+    fn check_free(&self, slot_id: SlotId) -> Result<(), ChangeError> {
+        match self.joint_at(slot_id) {
+            Some(_) => Err(ChangeError::SlotOccupied(slot_id)),
+            None => Ok(()),
+        }
+    }
+
+    /// Every link but link 0 must hang from exactly one parent, and be reached from link 0.
+    /// Joints must connect existing slots, each slot holding at most one joint, and effectors
+    /// must stay on existing slots. Changes keep this true, but definitions read from files need
+    /// to be checked.
+    pub fn check_tree(&self) -> Result<(), ChangeError> {
+        let mut used = HashSet::new();
+        for (parent_slot, _, child_slot) in self.joints.iter() {
+            for slot_id in vec![*parent_slot, *child_slot] {
+                self.check_slot(slot_id)?;
+                if !used.insert(slot_id) {
+                    return Err(ChangeError::SlotOccupied(slot_id));
+                }
+            }
+        }
+        let mut effectors: Vec<_> = self.effectors.keys().collect();
+        effectors.sort();
+        for effector in effectors {
+            if self.check_slot(self.effectors[effector]).is_err() {
+                return Err(ChangeError::DetachedEffector(effector.clone()));
+            }
+        }
+        for link_id in self.links() {
+            let parents = self
+                .joints
+                .iter()
+                .filter(|(_, _, child_slot)| child_slot.0 == link_id)
+                .count();
+            match (link_id, parents) {
+                (0, 0) => (),
+                (0, _) => return Err(ChangeError::ClosedLoop(link_id)),
+                (_, 0) => return Err(ChangeError::DetachedLink(link_id)),
+                (_, 1) => (),
+                _ => return Err(ChangeError::ClosedLoop(link_id)),
+            }
+        }
+        let reached = self.subtree(0);
+        match self
+            .links()
+            .into_iter()
+            .find(|link_id| !reached.contains(link_id))
+        {
+            Some(link_id) => Err(ChangeError::DetachedLink(link_id)),
+            None => Ok(()),
+        }
+    }
+
+    fn joint_at(&self, slot_id: SlotId) -> Option<usize> {
+        self.joints
+            .iter()
+            .position(|(a, _, b)| *a == slot_id || *b == slot_id)
+    }
+
+    /// The link hanging from `slot_id`, which must be a parent slot.
+    fn attached_at(&self, slot_id: SlotId) -> Result<LinkId, ChangeError> {
+        self.check_slot(slot_id)?;
+        let index = self
+            .joint_at(slot_id)
+            .ok_or(ChangeError::SlotFree(slot_id))?;
+        let (parent_slot, _, child_slot) = &self.joints[index];
+        if *parent_slot == slot_id {
+            Ok(child_slot.0)
+        } else {
+            Err(ChangeError::CutsLinkZero(slot_id))
+        }
+    }
+
+    /// Slots of `link_id` with a joint.
+    fn used_slots(&self, link_id: LinkId) -> Vec<SlotId> {
+        self.joints
+            .iter()
+            .flat_map(|(a, _, b)| vec![*a, *b])
+            .filter(|slot_id| slot_id.0 == link_id)
+            .collect()
+    }
+
+    /// `link_id` and every link hanging from it, sorted.
+    fn subtree(&self, link_id: LinkId) -> Vec<LinkId> {
+        let mut found = vec![];
+        let mut to_visit = vec![link_id];
+        while let Some(link_id) = to_visit.pop() {
+            if found.contains(&link_id) || !self.links.contains_key(&link_id) {
+                continue;
+            }
+            found.push(link_id);
+            for (parent_slot, _, child_slot) in self.joints.iter() {
+                if parent_slot.0 == link_id {
+                    to_visit.push(child_slot.0);
+                }
+            }
+        }
+        found.sort();
+        found
+    }
+
+    /// Takes `link_ids` out of the skeleton, together with every joint touching them.
+    fn remove_links(
+        &mut self,
+        link_ids: &[LinkId],
+    ) -> (HashMap<LinkId, Link>, Vec<(SlotId, Joint, SlotId)>) {
+        let links = link_ids
+            .iter()
+            .filter_map(|link_id| Some((*link_id, self.links.remove(link_id)?)))
+            .collect();
+        let (joints, kept) = std::mem::take(&mut self.joints)
+            .into_iter()
+            .partition(|(a, _, b)| link_ids.contains(&a.0) || link_ids.contains(&b.0));
+        self.joints = kept;
+        (links, joints)
     }
 
     pub fn variables(&self) -> HashSet<Variable> {
-        todo!()
-        // let mut set = HashSet::default();
-        // for entry in self.entries.values() {
-        //     match entry.part {
-        //         Part::Rotation(FloatValue::Variable(Id var)) => {
-        //             set.insert(var.clone());
-        //         }
-        //         _ => {}
-        //     }
-        // }
-        // set
+        self.joints
+            .iter()
+            .flat_map(|(_, joint, _)| joint.variables())
+            .collect()
+    }
+
+    pub fn constraint(&self, variable: &str) -> VariableConstraint {
+        self.variable_constraints
+            .get(variable)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// The pose with every variable at zero, where the skeleton has all its joints straight, or as
+    /// close to it as the constraints allow.
+    pub fn rest_pose(&self) -> Pose {
+        Pose {
+            valuation: self
+                .variables()
+                .into_iter()
+                .map(|variable| {
+                    let value = self.constraint(&variable).default_value();
+                    (variable, value)
+                })
+                .collect(),
+        }
+    }
+
+    /// Every problem of `pose`, sorted by variable.
+    pub fn validate_pose(&self, pose: &Pose) -> Result<(), Vec<PoseProblem>> {
+        let variables = self.variables();
+        let mut problems = vec![];
+        for variable in variables.iter() {
+            match pose.valuation.get(variable) {
+                None => problems.push(PoseProblem::Missing(variable.clone())),
+                Some(value) => {
+                    let constraint = self.constraint(variable);
+                    if !constraint.contains(*value) {
+                        problems.push(PoseProblem::OutOfRange {
+                            variable: variable.clone(),
+                            value: *value,
+                            constraint,
+                        });
+                    }
+                }
+            }
+        }
+        for variable in pose.valuation.keys() {
+            if !variables.contains(variable) {
+                problems.push(PoseProblem::Unknown(variable.clone()));
+            }
+        }
+        problems.sort_by(|a, b| a.variable().cmp(b.variable()));
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    /// The closest valid pose to `pose`: unknown variables are dropped, missing ones take their
+    /// default value and the rest are clamped to their range.
+    pub fn clamp_pose(&self, pose: &Pose) -> Pose {
+        Pose {
+            valuation: self
+                .variables()
+                .into_iter()
+                .map(|variable| {
+                    let constraint = self.constraint(&variable);
+                    let value = match pose.valuation.get(&variable) {
+                        Some(value) => constraint.clamp(*value),
+                        None => constraint.default_value(),
+                    };
+                    (variable, value)
+                })
+                .collect(),
+        }
     }
 
     pub fn has_link(&self, link_id: LinkId) -> bool {
@@ -198,35 +684,201 @@ impl Definition {
         l.sort();
         l
     }
-    pub fn get_link_transform(&self, link_id: LinkId) -> Transform {
-        self.get_link_transform_from(0, link_id)
+
+    /// Transform of a link relative to link 0 in the rest pose.
+    pub fn get_link_transform(&self, link_id: LinkId) -> Result<Transform, PoseError> {
+        self.get_link_transform_from(0, link_id, &self.rest_pose())
     }
-    fn get_link_transform_from(&self, origin_link_id: LinkId, target_link_id: LinkId) -> Transform {
-        if origin_link_id == target_link_id {
-            Transform::identity()
-        } else {
-            let translation_to_next = {
-                let origin_link = self.links[&origin_link_id].clone();
-                // Get shortest path P to target T from origin O
-                // Get next link N in P
-                // Get the pair of slots (with the joint) that connect the two links
-                // Calculate the transform
-                //   (now can just use the slot name to see if is 'l' or 'r')
-                if true {
-                    todo!("Implement what's above")
+
+    /// Transform of `target_link_id` in the space of `origin_link_id` for the given pose.
+    pub fn get_link_transform_from(
+        &self,
+        origin_link_id: LinkId,
+        target_link_id: LinkId,
+        pose: &Pose,
+    ) -> Result<Transform, PoseError> {
+        self.transforms_from(origin_link_id, pose)?
+            .remove(&target_link_id)
+            .ok_or_else(|| {
+                if self.links.contains_key(&target_link_id) {
+                    PoseError::UnreachableLink(target_link_id)
                 } else {
-                    Transform::from_translation(2.0 * UP)
+                    PoseError::UnexistingLink(target_link_id)
                 }
-            };
+            })
+    }
+
+    /// Forward kinematics: the transform of every link relative to link 0 for the given pose.
+    pub fn forward_kinematics(&self, pose: &Pose) -> Result<HashMap<LinkId, Transform>, PoseError> {
+        self.transforms_from(0, pose)
+    }
+
+    /// Transform of every link relative to the link it hangs from, for the given pose. Link 0
+    /// gets the identity. Composing them from link 0 down gives the `forward_kinematics`.
+    pub fn local_transforms(&self, pose: &Pose) -> Result<HashMap<LinkId, Transform>, PoseError> {
+        let mut transforms = hashmap! { 0 => Transform::identity() };
+        for (parent_slot, joint, child_slot) in self.joints.iter() {
+            let transform = self.get_slot(parent_slot)?.transform()
+                * joint.transform(pose)?
+                * inverse(&self.get_slot(child_slot)?.transform());
+            transforms.insert(child_slot.0, transform);
+        }
+        Ok(transforms)
+    }
 
-            // TODO: change this implementation to be an iteration over the links in P instead of
-            // a recursion
-            translation_to_next * self.get_link_transform_from(origin_link_id + 1, target_link_id)
+    /// Walks the joints breadth first from `origin_link_id`, composing the transforms of the
+    /// slots and joints found on the way. Joints can be crossed in both directions, so any link
+    /// can be the origin. Links that cannot be reached are left out.
+    fn transforms_from(
+        &self,
+        origin_link_id: LinkId,
+        pose: &Pose,
+    ) -> Result<HashMap<LinkId, Transform>, PoseError> {
+        if !self.links.contains_key(&origin_link_id) {
+            return Err(PoseError::UnexistingLink(origin_link_id));
         }
+        let mut transforms = hashmap! { origin_link_id => Transform::identity() };
+        let mut to_visit = VecDeque::new();
+        to_visit.push_back(origin_link_id);
+        while let Some(link_id) = to_visit.pop_front() {
+            let link_transform = transforms[&link_id];
+            for (parent_slot, joint, child_slot) in self.joints.iter() {
+                let (from, to, joint_transform) = if parent_slot.0 == link_id {
+                    (parent_slot, child_slot, joint.transform(pose)?)
+                } else if child_slot.0 == link_id {
+                    (child_slot, parent_slot, inverse(&joint.transform(pose)?))
+                } else {
+                    continue;
+                };
+                if transforms.contains_key(&to.0) {
+                    continue;
+                }
+                let transform = link_transform
+                    * self.get_slot(from)?.transform()
+                    * joint_transform
+                    * inverse(&self.get_slot(to)?.transform());
+                transforms.insert(to.0, transform);
+                to_visit.push_back(to.0);
+            }
+        }
+        Ok(transforms)
+    }
+
+    fn get_slot(&self, slot_id: &SlotId) -> Result<&Slot, PoseError> {
+        self.links
+            .get(&slot_id.0)
+            .ok_or(PoseError::UnexistingLink(slot_id.0))?
+            .slots
+            .get(&slot_id.1)
+            .ok_or(PoseError::UnexistingSlot(*slot_id))
+    }
+
+    /// Position of an effector relative to link 0 for the given pose.
+    pub fn effector_position(&self, effector: &str, pose: &Pose) -> Result<Vec3, PoseError> {
+        let slot_id = self
+            .effectors
+            .get(effector)
+            .ok_or_else(|| PoseError::UnexistingEffector(effector.to_string()))?;
+        let transforms = self.forward_kinematics(pose)?;
+        self.slot_position(&transforms, slot_id)
+    }
+
+    fn slot_position(
+        &self,
+        transforms: &HashMap<LinkId, Transform>,
+        slot_id: &SlotId,
+    ) -> Result<Vec3, PoseError> {
+        let link_transform = transforms
+            .get(&slot_id.0)
+            .ok_or(PoseError::UnreachableLink(slot_id.0))?;
+        Ok(link_transform.mul_vec3(self.get_slot(slot_id)?.position))
+    }
+
+    /// Inverse kinematics with cyclic coordinate descent: starting from `start`, turns one joint
+    /// at a time, from the effector towards link 0, to bring each effector as close as possible
+    /// to its target. Targets are relative to link 0, and only joints driven by a variable move,
+    /// always within the constraints of the variable. Effectors out of reach get as close as they
+    /// can, so the solution tells how far they are left.
+    pub fn solve_ik(
+        &self,
+        targets: &HashMap<Effector, Vec3>,
+        start: &Pose,
+    ) -> Result<IkSolution, PoseError> {
+        let mut effectors: Vec<_> = targets.keys().collect();
+        effectors.sort();
+        let mut chains = vec![];
+        for effector in effectors {
+            let slot_id = *self
+                .effectors
+                .get(effector)
+                .ok_or_else(|| PoseError::UnexistingEffector(effector.clone()))?;
+            chains.push((slot_id, targets[effector], self.chain_to(slot_id.0)));
+        }
+
+        let mut pose = self.clamp_pose(start);
+        let mut error = self.ik_error(&chains, &pose)?;
+        for _ in 0..IK_ITERATIONS {
+            if error < IK_TOLERANCE {
+                break;
+            }
+            for (slot_id, target, chain) in chains.iter() {
+                for (parent_slot, joint, _) in chain.iter() {
+                    let (axis, angle) = match joint {
+                        Joint::TwistingJoint(FloatValue::Variable(variable)) => (UP, variable),
+                        Joint::RotationalJoin(FloatValue::Variable(variable)) => (LEFT, variable),
+                        _ => continue,
+                    };
+                    let transforms = self.forward_kinematics(&pose)?;
+                    let frame =
+                        transforms[&parent_slot.0] * self.get_slot(parent_slot)?.transform();
+                    let axis = frame.rotation * axis;
+                    let pivot = frame.translation;
+                    let reject = |v: Vec3| v - axis * v.dot(axis);
+                    let current = reject(self.slot_position(&transforms, slot_id)? - pivot);
+                    let wanted = reject(*target - pivot);
+                    if current.length() < IK_TOLERANCE || wanted.length() < IK_TOLERANCE {
+                        continue;
+                    }
+                    let turn = axis.dot(current.cross(wanted)).atan2(current.dot(wanted));
+                    let value = pose.valuation[angle] + turn;
+                    let value = self.constraint(angle).clamp(value);
+                    pose.valuation.insert(angle.clone(), value);
+                }
+            }
+            error = self.ik_error(&chains, &pose)?;
+        }
+        Ok(IkSolution { pose, error })
+    }
+
+    fn ik_error(
+        &self,
+        chains: &[(SlotId, Vec3, Vec<(SlotId, Joint, SlotId)>)],
+        pose: &Pose,
+    ) -> Result<f32, PoseError> {
+        let transforms = self.forward_kinematics(pose)?;
+        let mut error: f32 = 0.0;
+        for (slot_id, target, _) in chains {
+            error = error.max(self.slot_position(&transforms, slot_id)?.distance(*target));
+        }
+        Ok(error)
+    }
+
+    /// Joints between `link_id` and link 0, starting by the one nearest to `link_id`.
+    fn chain_to(&self, mut link_id: LinkId) -> Vec<(SlotId, Joint, SlotId)> {
+        let mut chain = vec![];
+        while let Some(joint) = self
+            .joints
+            .iter()
+            .find(|(_, _, child_slot)| child_slot.0 == link_id)
+        {
+            chain.push(joint.clone());
+            link_id = joint.0 .0;
+        }
+        chain
     }
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Default, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Pose {
     pub valuation: HashMap<Variable, f32>,
 }
@@ -238,11 +890,19 @@ const LEFT: Vec3 = Vec3::X;
 const FORWARD: Vec3 = Vec3::Y;
 const UP: Vec3 = Vec3::Z;
 
-mod link {
+pub(crate) mod link {
     use super::*;
 
+    /// Every preset, by the name of its link type in the default library.
+    pub const PRESETS: &[(&str, fn() -> Link)] = &[
+        ("arm_base", arm_base),
+        ("l_link", l_link),
+        ("t_link", t_link),
+    ];
+
     pub fn arm_base() -> Link {
         Link {
+            link_type: Some("arm_base".into()),
             slots: hashmap! {
                 'n' => Slot {
                     position: 1.0 * UP ,
@@ -254,6 +914,7 @@ mod link {
 
     pub fn l_link() -> Link {
         Link {
+            link_type: Some("l_link".into()),
             slots: hashmap! {
                 'p' => Slot {
                     position: -1.0 * UP,
@@ -269,6 +930,7 @@ mod link {
 
     pub fn t_link() -> Link {
         Link {
+            link_type: Some("t_link".into()),
             slots: hashmap! {
                 'p' => Slot {
                     position: -1.0 * UP,
@@ -287,9 +949,63 @@ mod link {
     }
 }
 
+/// Skeletons and poses shared by the tests of the skeleton modules.
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::*;
+
+    /// Arm made of a base, a forearm hanging from it through `elbow`, and a hand fixed to the
+    /// forearm.
+    pub fn arm(elbow: Joint) -> Definition {
+        let mut s = Definition::new(link::arm_base());
+        s.apply(Change::Add {
+            link: link::l_link(),
+            to_parent_slot: SlotId(0, 'n'),
+            joint: elbow,
+            local_slot_name: 'p',
+        })
+        .expect("Failed to add forearm");
+        s.apply(Change::Add {
+            link: link::l_link(),
+            to_parent_slot: SlotId(1, 'n'),
+            joint: Joint::Fixed,
+            local_slot_name: 'p',
+        })
+        .expect("Failed to add hand");
+        s
+    }
+
+    /// A rotational joint driven by the variable `elbow`.
+    pub fn elbow() -> Joint {
+        Joint::RotationalJoin(FloatValue::Variable("elbow".into()))
+    }
+
+    pub fn pose(valuation: &[(&str, f32)]) -> Pose {
+        Pose {
+            valuation: valuation
+                .iter()
+                .map(|(variable, value)| (variable.to_string(), *value))
+                .collect(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use super::fixtures::*;
     use crate::playdate::skeleton::*;
+    use std::f32::consts::{FRAC_PI_2, PI};
+
+    fn assert_near(found: Transform, expected: Transform) {
+        assert!(
+            found
+                .compute_matrix()
+                .abs_diff_eq(expected.compute_matrix(), 1e-5),
+            "Expected {:?} but found {:?}",
+            expected,
+            found
+        );
+    }
 
     #[test]
     fn assert_link0_transform_is_identity() {
@@ -297,11 +1013,11 @@ mod test {
         assert!(s.has_link(0));
         assert_eq!(s.links(), vec![0]);
 
-        let t = s.get_link_transform(0);
+        let t = s.get_link_transform(0).unwrap();
         assert_eq!(t, Transform::identity())
     }
 
-    #[test] #[ignore]
+    #[test]
     fn can_add_link_with_fixed_joint() {
         let mut s = Definition::new(link::arm_base());
         s.apply(Change::Add {
@@ -314,7 +1030,7 @@ mod test {
         assert!(s.has_link(1));
         assert_eq!(s.links(), vec![0, 1]);
 
-        let t = s.get_link_transform(1);
+        let t = s.get_link_transform(1).unwrap();
         assert_eq!(t, Transform::from_translation(2.0 * UP))
     }
 
@@ -338,7 +1054,7 @@ mod test {
         assert_eq!(res, Err(ChangeError::UnexistingSlot(SlotId(0, 'q'))));
     }
 
-    #[test] #[ignore]
+    #[test]
     fn add_multiple_links_with_fixed_joint() {
         let mut s = Definition::new(link::arm_base());
         s.apply(Change::Add {
@@ -360,7 +1076,7 @@ mod test {
         assert!(s.has_link(2));
         assert_eq!(s.links(), vec![0, 1, 2]);
 
-        let t = s.get_link_transform(2);
+        let t = s.get_link_transform(2).unwrap();
         assert_eq!(t, Transform::from_translation(4.0 * UP));
     }
 
@@ -414,23 +1130,652 @@ mod test {
 
         assert_eq!(s.links(), vec![0, 1, 2, 3]);
 
-        let t = s.get_link_transform(2);
+        let t = s.get_link_transform(2).unwrap();
         assert_eq!(t, Transform::from_translation(2.0 * UP + 2.0 * LEFT));
 
-        let t = s.get_link_transform(3);
+        let t = s.get_link_transform(3).unwrap();
         assert_eq!(t, Transform::from_translation(2.0 * UP - 2.0 * LEFT));
     }
 
-    // # TODO
-    // ## Fixed Joint
-    // - using_a_t_link_
-    // ## Twisting Joint
-    // - ...
-    // ## Rotational Joint
-    // - ...
-    // - ...
-    // ## Replace link and joint
-    // ## Cut
-    // ## Move link
-    // -
+    #[test]
+    fn t_link_branches_hang_from_its_side_slots() {
+        let s = spinning_torso();
+
+        // The side slots of the preset are not rotated, so branches keep pointing up
+        assert_near(
+            s.get_link_transform(2).unwrap(),
+            Transform::from_translation(4.0 * UP + 1.0 * LEFT),
+        );
+        assert_near(
+            s.get_link_transform(3).unwrap(),
+            Transform::from_translation(4.0 * UP - 1.0 * LEFT),
+        );
+    }
+
+    #[test]
+    fn rotational_joints_bend_the_rest_of_the_chain() {
+        let s = arm(Joint::RotationalJoin(FloatValue::Variable("elbow".into())));
+        assert_eq!(s.variables(), maplit::hashset! { "elbow".to_string() });
+
+        let t = s.get_link_transform(2).unwrap();
+        assert_near(t, Transform::from_translation(4.0 * UP));
+
+        let transforms = s
+            .forward_kinematics(&pose(&[("elbow", FRAC_PI_2)]))
+            .unwrap();
+        assert_eq!(transforms.len(), 3);
+        assert_near(transforms[&0], Transform::identity());
+        let bent = Quat::from_axis_angle(LEFT, FRAC_PI_2);
+        assert_near(
+            transforms[&1],
+            Transform {
+                translation: 1.0 * UP - 1.0 * FORWARD,
+                rotation: bent,
+                ..Transform::identity()
+            },
+        );
+        assert_near(
+            transforms[&2],
+            Transform {
+                translation: 1.0 * UP - 3.0 * FORWARD,
+                rotation: bent,
+                ..Transform::identity()
+            },
+        );
+    }
+
+    #[test]
+    fn twisting_joints_turn_around_the_slot_axis() {
+        let mut s = Definition::new(link::arm_base());
+        s.apply(Change::Add {
+            link: link::t_link(),
+            to_parent_slot: SlotId(0, 'n'),
+            joint: Joint::TwistingJoint(FloatValue::Constant(PI)),
+            local_slot_name: 'p',
+        })
+        .expect("Failed to add t_link");
+        s.apply(Change::Add {
+            link: link::l_link(),
+            to_parent_slot: SlotId(1, 'l'),
+            joint: Joint::Fixed,
+            local_slot_name: 'p',
+        })
+        .expect("Failed to add l_link");
+
+        // Half a turn moves the left branch to the right
+        let t = s.get_link_transform(2).unwrap();
+        assert_near(
+            t,
+            Transform {
+                translation: 4.0 * UP - 1.0 * LEFT,
+                rotation: Quat::from_rotation_z(PI),
+                ..Transform::identity()
+            },
+        );
+    }
+
+    #[test]
+    fn transforms_can_be_relative_to_any_link() {
+        let s = arm(Joint::TwistingJoint(FloatValue::Variable("wrist".into())));
+        let p = pose(&[("wrist", 1.0)]);
+        let hand = s.get_link_transform_from(0, 2, &p).unwrap();
+        let base = s.get_link_transform_from(2, 0, &p).unwrap();
+        assert_near(hand * base, Transform::identity());
+        assert_near(
+            s.get_link_transform_from(1, 2, &p).unwrap(),
+            Transform::from_translation(2.0 * UP),
+        );
+    }
+
+    #[test]
+    fn posing_needs_every_variable_and_existing_links() {
+        let s = arm(Joint::RotationalJoin(FloatValue::Variable("elbow".into())));
+        assert_eq!(
+            s.forward_kinematics(&Pose::default()),
+            Err(PoseError::UnboundVariable("elbow".into()))
+        );
+        assert_eq!(
+            s.get_link_transform_from(0, 7, &s.rest_pose()),
+            Err(PoseError::UnexistingLink(7))
+        );
+        assert_eq!(
+            s.get_link_transform_from(7, 0, &s.rest_pose()),
+            Err(PoseError::UnexistingLink(7))
+        );
+    }
+
+    /// Base with a t_link (1), two l_links on its branches (2 on the left and 3 on the right)
+    /// and another l_link (4) on top of the left one.
+    fn spinning_torso() -> Definition {
+        let mut s = Definition::new(link::arm_base());
+        for (link, to_parent_slot) in vec![
+            (link::t_link(), SlotId(0, 'n')),
+            (link::l_link(), SlotId(1, 'l')),
+            (link::l_link(), SlotId(1, 'r')),
+            (link::l_link(), SlotId(2, 'n')),
+        ] {
+            s.apply(Change::Add {
+                link,
+                to_parent_slot,
+                joint: Joint::Fixed,
+                local_slot_name: 'p',
+            })
+            .expect("Failed to build spinning torso");
+        }
+        s
+    }
+
+    fn assert_same_skeleton(found: &Definition, expected: &Definition) {
+        assert_eq!(found.links, expected.links);
+        assert_eq!(found.variable_constraints, expected.variable_constraints);
+        assert_eq!(found.effectors, expected.effectors);
+        assert_eq!(found.joints.len(), expected.joints.len());
+        for joint in expected.joints.iter() {
+            assert!(found.joints.contains(joint), "Missing joint {:?}", joint);
+        }
+    }
+
+    #[test]
+    fn every_change_can_be_undone_and_redone() {
+        let original = spinning_torso();
+        let changes = vec![
+            Change::Add {
+                link: link::l_link(),
+                to_parent_slot: SlotId(3, 'n'),
+                joint: Joint::TwistingJoint(FloatValue::Constant(1.0)),
+                local_slot_name: 'n',
+            },
+            Change::ReplaceLink {
+                at: 4,
+                with: link::t_link(),
+            },
+            Change::ReplaceJoint {
+                at: SlotId(2, 'p'),
+                with: Joint::RotationalJoin(FloatValue::Variable("shoulder".into())),
+            },
+            Change::Cut {
+                from: SlotId(1, 'l'),
+            },
+            Change::MoveLink {
+                at: SlotId(1, 'l'),
+                to: SlotId(3, 'n'),
+            },
+            Change::CutAndSplice {
+                from: SlotId(0, 'n'),
+                to: SlotId(1, 'l'),
+            },
+            Change::Batch(vec![
+                Change::Cut {
+                    from: SlotId(1, 'l'),
+                },
+                Change::Cut {
+                    from: SlotId(1, 'r'),
+                },
+            ]),
+            Change::Batch(vec![
+                Change::ReplaceJoint {
+                    at: SlotId(1, 'p'),
+                    with: Joint::TwistingJoint(FloatValue::Variable("waist".into())),
+                },
+                Change::Constrain {
+                    variable: "waist".into(),
+                    with: VariableConstraint {
+                        min: Some(-1.0),
+                        max: Some(1.0),
+                    },
+                },
+            ]),
+            Change::PlaceEffector {
+                effector: "left hand".into(),
+                at: Some(SlotId(4, 'n')),
+            },
+        ];
+        for change in changes {
+            let mut s = original.clone();
+            let inverse = s
+                .apply(change.clone())
+                .unwrap_or_else(|err| panic!("Failed to apply {:?}: {:?}", change, err));
+            let changed = s.clone();
+            let redo = s
+                .apply(inverse)
+                .unwrap_or_else(|err| panic!("Failed to undo {:?}: {:?}", change, err));
+            assert_same_skeleton(&s, &original);
+            s.apply(redo)
+                .unwrap_or_else(|err| panic!("Failed to redo {:?}: {:?}", change, err));
+            assert_same_skeleton(&s, &changed);
+        }
+    }
+
+    #[test]
+    fn cut_removes_everything_hanging_from_the_slot() {
+        let mut s = spinning_torso();
+        s.apply(Change::Cut {
+            from: SlotId(1, 'l'),
+        })
+        .expect("Failed to cut left branch");
+        assert_eq!(s.links(), vec![0, 1, 3]);
+
+        // Ids are never reused
+        s.apply(Change::Add {
+            link: link::l_link(),
+            to_parent_slot: SlotId(1, 'l'),
+            joint: Joint::Fixed,
+            local_slot_name: 'p',
+        })
+        .expect("Failed to add a new left branch");
+        assert_eq!(s.links(), vec![0, 1, 3, 5]);
+    }
+
+    #[test]
+    fn move_link_takes_everything_hanging_from_it() {
+        let mut s = spinning_torso();
+        s.apply(Change::MoveLink {
+            at: SlotId(1, 'l'),
+            to: SlotId(3, 'n'),
+        })
+        .expect("Failed to move left branch");
+        assert_eq!(s.links(), vec![0, 1, 2, 3, 4]);
+        assert_near(
+            s.get_link_transform(2).unwrap(),
+            Transform::from_translation(6.0 * UP - 1.0 * LEFT),
+        );
+        assert_eq!(s.variables(), HashSet::new());
+    }
+
+    #[test]
+    fn cut_and_splice_removes_links_in_the_middle() {
+        let mut s = spinning_torso();
+        s.apply(Change::CutAndSplice {
+            from: SlotId(0, 'n'),
+            to: SlotId(1, 'l'),
+        })
+        .expect("Failed to splice left branch");
+        assert_eq!(s.links(), vec![0, 2, 4]);
+        assert_near(
+            s.get_link_transform(2).unwrap(),
+            Transform::from_translation(2.0 * UP),
+        );
+        assert_near(
+            s.get_link_transform(4).unwrap(),
+            Transform::from_translation(4.0 * UP),
+        );
+    }
+
+    #[test]
+    fn invalid_changes_fail_and_leave_the_skeleton_untouched() {
+        let original = spinning_torso();
+        let failures = vec![
+            (
+                Change::Add {
+                    link: link::l_link(),
+                    to_parent_slot: SlotId(3, 'n'),
+                    joint: Joint::Fixed,
+                    local_slot_name: 'q',
+                },
+                ChangeError::UnexistingSlot(SlotId(5, 'q')),
+            ),
+            (
+                Change::ReplaceLink {
+                    at: 1,
+                    with: link::l_link(),
+                },
+                ChangeError::SlotInUse(SlotId(1, 'l')),
+            ),
+            (
+                Change::ReplaceLink {
+                    at: 7,
+                    with: link::l_link(),
+                },
+                ChangeError::UnexistingLink(7),
+            ),
+            (
+                Change::ReplaceJoint {
+                    at: SlotId(3, 'n'),
+                    with: Joint::Fixed,
+                },
+                ChangeError::SlotFree(SlotId(3, 'n')),
+            ),
+            (
+                Change::Cut {
+                    from: SlotId(1, 'p'),
+                },
+                ChangeError::CutsLinkZero(SlotId(1, 'p')),
+            ),
+            (
+                Change::Cut {
+                    from: SlotId(3, 'n'),
+                },
+                ChangeError::SlotFree(SlotId(3, 'n')),
+            ),
+            (
+                Change::MoveLink {
+                    at: SlotId(1, 'l'),
+                    to: SlotId(4, 'n'),
+                },
+                ChangeError::MoveIntoItself(SlotId(4, 'n')),
+            ),
+            (
+                Change::MoveLink {
+                    at: SlotId(1, 'l'),
+                    to: SlotId(1, 'r'),
+                },
+                ChangeError::SlotOccupied(SlotId(1, 'r')),
+            ),
+            (
+                Change::CutAndSplice {
+                    from: SlotId(1, 'l'),
+                    to: SlotId(3, 'n'),
+                },
+                ChangeError::SpliceOutsideCut(SlotId(3, 'n')),
+            ),
+            (
+                Change::CutAndSplice {
+                    from: SlotId(0, 'n'),
+                    to: SlotId(2, 'p'),
+                },
+                ChangeError::SpliceOutsideCut(SlotId(2, 'p')),
+            ),
+            (
+                Change::Graft {
+                    links: hashmap! { 1 => link::l_link() },
+                    joints: vec![],
+                },
+                ChangeError::LinkAlreadyExists(1),
+            ),
+            (
+                Change::Graft {
+                    links: hashmap! { 9 => link::l_link() },
+                    joints: vec![],
+                },
+                ChangeError::DetachedLink(9),
+            ),
+            (
+                Change::Graft {
+                    links: hashmap! { 9 => link::l_link() },
+                    joints: vec![
+                        (SlotId(3, 'n'), Joint::Fixed, SlotId(9, 'p')),
+                        (SlotId(9, 'n'), Joint::Fixed, SlotId(0, 'x')),
+                    ],
+                },
+                ChangeError::UnexistingSlot(SlotId(0, 'x')),
+            ),
+            (
+                Change::Batch(vec![
+                    Change::Cut {
+                        from: SlotId(1, 'l'),
+                    },
+                    Change::Cut {
+                        from: SlotId(1, 'l'),
+                    },
+                ]),
+                ChangeError::SlotFree(SlotId(1, 'l')),
+            ),
+            (
+                Change::Constrain {
+                    variable: "waist".into(),
+                    with: VariableConstraint::default(),
+                },
+                ChangeError::UnexistingVariable("waist".into()),
+            ),
+            (
+                Change::PlaceEffector {
+                    effector: "left hand".into(),
+                    at: Some(SlotId(4, 'q')),
+                },
+                ChangeError::UnexistingSlot(SlotId(4, 'q')),
+            ),
+        ];
+        for (change, error) in failures {
+            let mut s = original.clone();
+            assert_eq!(s.apply(change), Err(error));
+            assert_same_skeleton(&s, &original);
+            assert_eq!(s.last_link_id, original.last_link_id);
+        }
+    }
+
+    #[test]
+    fn variables_come_from_every_joint() {
+        let mut s = arm(Joint::RotationalJoin(FloatValue::Variable("elbow".into())));
+        s.apply(Change::ReplaceJoint {
+            at: SlotId(1, 'n'),
+            with: Joint::TwistingJoint(FloatValue::Variable("wrist".into())),
+        })
+        .expect("Failed to make the wrist twist");
+        s.apply(Change::Add {
+            link: link::l_link(),
+            to_parent_slot: SlotId(2, 'n'),
+            joint: Joint::TwistingJoint(FloatValue::Constant(1.0)),
+            local_slot_name: 'p',
+        })
+        .expect("Failed to add a finger");
+        assert_eq!(
+            s.variables(),
+            maplit::hashset! { "elbow".to_string(), "wrist".to_string() }
+        );
+    }
+
+    #[test]
+    fn constraints_go_away_with_the_last_joint_using_them() {
+        let mut s = arm(Joint::RotationalJoin(FloatValue::Variable("elbow".into())));
+        s.apply(Change::ReplaceJoint {
+            at: SlotId(1, 'n'),
+            with: Joint::RotationalJoin(FloatValue::Variable("elbow".into())),
+        })
+        .unwrap();
+        let stiff = VariableConstraint {
+            min: Some(0.0),
+            max: Some(1.0),
+        };
+        s.apply(Change::Constrain {
+            variable: "elbow".into(),
+            with: stiff.clone(),
+        })
+        .unwrap();
+        let constrained = s.clone();
+
+        // Another joint still uses it
+        s.apply(Change::ReplaceJoint {
+            at: SlotId(0, 'n'),
+            with: Joint::Fixed,
+        })
+        .unwrap();
+        assert_eq!(s.constraint("elbow"), stiff);
+
+        let inverse = s
+            .apply(Change::Cut {
+                from: SlotId(1, 'n'),
+            })
+            .unwrap();
+        assert!(s.variable_constraints.is_empty());
+        s.apply(inverse).unwrap();
+        assert_eq!(s.constraint("elbow"), stiff);
+
+        let mut s = constrained;
+        let original = s.clone();
+        let inverse = s
+            .apply(Change::Batch(vec![
+                Change::ReplaceJoint {
+                    at: SlotId(0, 'n'),
+                    with: Joint::Fixed,
+                },
+                Change::ReplaceJoint {
+                    at: SlotId(1, 'n'),
+                    with: Joint::TwistingJoint(FloatValue::Constant(0.5)),
+                },
+            ]))
+            .unwrap();
+        assert!(s.variable_constraints.is_empty());
+        s.apply(inverse).unwrap();
+        assert_eq!(s, original);
+    }
+
+    #[test]
+    fn poses_are_checked_against_constraints() {
+        let mut s = arm(Joint::RotationalJoin(FloatValue::Variable("elbow".into())));
+        s.apply(Change::ReplaceJoint {
+            at: SlotId(1, 'n'),
+            with: Joint::TwistingJoint(FloatValue::Variable("wrist".into())),
+        })
+        .expect("Failed to make the wrist twist");
+        s.apply(Change::Constrain {
+            variable: "elbow".into(),
+            with: VariableConstraint {
+                min: Some(0.5),
+                max: Some(2.0),
+            },
+        })
+        .expect("Failed to constrain the elbow");
+        assert_eq!(
+            s.apply(Change::Constrain {
+                variable: "wrist".into(),
+                with: VariableConstraint {
+                    min: Some(1.0),
+                    max: Some(-1.0),
+                },
+            }),
+            Err(ChangeError::EmptyRange("wrist".into()))
+        );
+
+        assert_eq!(s.rest_pose(), pose(&[("elbow", 0.5), ("wrist", 0.0)]));
+        assert_eq!(s.validate_pose(&s.rest_pose()), Ok(()));
+
+        let bad_pose = pose(&[("elbow", 3.0), ("knee", 1.0)]);
+        assert_eq!(
+            s.validate_pose(&bad_pose),
+            Err(vec![
+                PoseProblem::OutOfRange {
+                    variable: "elbow".into(),
+                    value: 3.0,
+                    constraint: VariableConstraint {
+                        min: Some(0.5),
+                        max: Some(2.0),
+                    },
+                },
+                PoseProblem::Unknown("knee".into()),
+                PoseProblem::Missing("wrist".into()),
+            ])
+        );
+        assert_eq!(
+            s.clamp_pose(&bad_pose),
+            pose(&[("elbow", 2.0), ("wrist", 0.0)])
+        );
+    }
+
+    /// Arm bending at the elbow and the wrist, with an effector at the tip of the hand.
+    fn reaching_arm() -> Definition {
+        let mut s = arm(Joint::RotationalJoin(FloatValue::Variable("elbow".into())));
+        s.apply(Change::Batch(vec![
+            Change::ReplaceJoint {
+                at: SlotId(1, 'n'),
+                with: Joint::RotationalJoin(FloatValue::Variable("wrist".into())),
+            },
+            Change::PlaceEffector {
+                effector: "hand".into(),
+                at: Some(SlotId(2, 'n')),
+            },
+        ]))
+        .expect("Failed to make the arm reach");
+        s
+    }
+
+    #[test]
+    fn effectors_follow_their_slot() {
+        let s = reaching_arm();
+        let hand = s.effector_position("hand", &s.rest_pose()).unwrap();
+        assert!(hand.abs_diff_eq(5.0 * UP, 1e-5), "Hand at {:?}", hand);
+
+        let hand = s
+            .effector_position("hand", &pose(&[("elbow", FRAC_PI_2), ("wrist", 0.0)]))
+            .unwrap();
+        assert!(
+            hand.abs_diff_eq(1.0 * UP - 4.0 * FORWARD, 1e-5),
+            "Hand at {:?}",
+            hand
+        );
+        assert_eq!(
+            s.effector_position("foot", &s.rest_pose()),
+            Err(PoseError::UnexistingEffector("foot".into()))
+        );
+    }
+
+    #[test]
+    fn effectors_cannot_be_left_on_removed_links() {
+        let mut s = reaching_arm();
+        assert_eq!(
+            s.apply(Change::Cut {
+                from: SlotId(1, 'n'),
+            }),
+            Err(ChangeError::DetachedEffector("hand".into()))
+        );
+        s.apply(Change::Batch(vec![
+            Change::PlaceEffector {
+                effector: "hand".into(),
+                at: None,
+            },
+            Change::Cut {
+                from: SlotId(1, 'n'),
+            },
+        ]))
+        .expect("Failed to cut the hand without its effector");
+        assert!(s.effectors.is_empty());
+    }
+
+    #[test]
+    fn inverse_kinematics_reaches_targets_in_range() {
+        let s = reaching_arm();
+        let target = 3.0 * UP - 2.0 * FORWARD;
+        let solution = s
+            .solve_ik(&hashmap! { "hand".to_string() => target }, &s.rest_pose())
+            .unwrap();
+        assert!(solution.error < 1e-2, "Missed by {}", solution.error);
+        assert_eq!(s.validate_pose(&solution.pose), Ok(()));
+        let hand = s.effector_position("hand", &solution.pose).unwrap();
+        assert!(hand.abs_diff_eq(target, 1e-2), "Hand at {:?}", hand);
+    }
+
+    #[test]
+    fn inverse_kinematics_respects_constraints() {
+        let mut s = reaching_arm();
+        let stiff = VariableConstraint {
+            min: Some(0.0),
+            max: Some(0.5),
+        };
+        for variable in vec!["elbow", "wrist"] {
+            s.apply(Change::Constrain {
+                variable: variable.into(),
+                with: stiff.clone(),
+            })
+            .expect("Failed to constrain the arm");
+        }
+        let solution = s
+            .solve_ik(
+                &hashmap! { "hand".to_string() => 1.0 * UP - 4.0 * FORWARD },
+                &s.rest_pose(),
+            )
+            .unwrap();
+        assert!(solution.error > 1.0, "Reached an impossible target");
+        assert_eq!(s.validate_pose(&solution.pose), Ok(()));
+        assert_eq!(solution.pose, pose(&[("elbow", 0.5), ("wrist", 0.5)]));
+
+        assert_eq!(
+            s.solve_ik(
+                &hashmap! { "foot".to_string() => Vec3::ZERO },
+                &s.rest_pose()
+            ),
+            Err(PoseError::UnexistingEffector("foot".into()))
+        );
+    }
+
+    #[test]
+    fn local_transforms_compose_into_forward_kinematics() {
+        let s = spinning_torso();
+        let p = s.rest_pose();
+        let local = s.local_transforms(&p).unwrap();
+        let global = s.forward_kinematics(&p).unwrap();
+        assert_eq!(local.len(), 5);
+        assert_near(local[&0], Transform::identity());
+        assert_near(local[&1], Transform::from_translation(2.0 * UP));
+        assert_near(local[&1] * local[&2] * local[&4], global[&4]);
+        assert_near(local[&1] * local[&3], global[&3]);
+    }
 }
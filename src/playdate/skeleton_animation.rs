@@ -0,0 +1,320 @@
+use std::collections::{HashMap, HashSet};
+
+use super::skeleton::{Pose, Variable};
+
+/// How a variable goes from the value of a keyframe to the value of the next one.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Interpolation {
+    /// Keeps the value of the previous keyframe until the next one is reached.
+    Step,
+    Linear,
+    /// Starts and ends slowly, like a smoothstep.
+    EaseInOut,
+}
+
+/// What happens when playback gets past the last keyframe.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum PlaybackMode {
+    /// Stays in the last keyframe.
+    Once,
+    /// Starts again from the first keyframe.
+    Loop,
+    /// Plays backwards to the first keyframe, and then forward again.
+    PingPong,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Keyframe {
+    /// Seconds since the start of the clip.
+    pub time: f32,
+    /// Values of the variables at that time. Variables left out are interpolated between the
+    /// surrounding keyframes that have them.
+    pub pose: Pose,
+}
+
+/// An animation for a skeleton, as a sequence of poses in time.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Clip {
+    /// Sorted by time, with no two keyframes at the same time.
+    keyframes: Vec<Keyframe>,
+    /// Variables that are not here are interpolated linearly.
+    #[serde(default)]
+    pub interpolations: HashMap<Variable, Interpolation>,
+    pub mode: PlaybackMode,
+}
+
+impl Default for Clip {
+    fn default() -> Self {
+        Self {
+            keyframes: vec![],
+            interpolations: HashMap::new(),
+            mode: PlaybackMode::Loop,
+        }
+    }
+}
+
+impl Clip {
+    pub fn keyframes(&self) -> &[Keyframe] {
+        &self.keyframes
+    }
+
+    /// Time of the last keyframe.
+    pub fn duration(&self) -> f32 {
+        self.keyframes.last().map_or(0.0, |keyframe| keyframe.time)
+    }
+
+    /// Adds a keyframe, replacing the one at the same time if there is one.
+    pub fn insert_keyframe(&mut self, time: f32, pose: Pose) {
+        let time = time.max(0.0);
+        match self
+            .keyframes
+            .binary_search_by(|keyframe| keyframe.time.partial_cmp(&time).unwrap())
+        {
+            Ok(index) => self.keyframes[index].pose = pose,
+            Err(index) => self.keyframes.insert(index, Keyframe { time, pose }),
+        }
+    }
+
+    /// Index of the keyframe closest to `time`, if it is less than `tolerance` seconds away.
+    pub fn keyframe_near(&self, time: f32, tolerance: f32) -> Option<usize> {
+        self.keyframes
+            .iter()
+            .enumerate()
+            .map(|(index, keyframe)| (index, (keyframe.time - time).abs()))
+            .filter(|(_, distance)| *distance <= tolerance)
+            .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+            .map(|(index, _)| index)
+    }
+
+    pub fn remove_keyframe(&mut self, index: usize) -> Option<Keyframe> {
+        if index < self.keyframes.len() {
+            Some(self.keyframes.remove(index))
+        } else {
+            None
+        }
+    }
+
+    pub fn interpolation(&self, variable: &str) -> Interpolation {
+        self.interpolations
+            .get(variable)
+            .cloned()
+            .unwrap_or(Interpolation::Linear)
+    }
+
+    /// Every variable given a value by some keyframe.
+    pub fn variables(&self) -> HashSet<Variable> {
+        self.keyframes
+            .iter()
+            .flat_map(|keyframe| keyframe.pose.valuation.keys().cloned())
+            .collect()
+    }
+
+    /// Time inside the clip after playing it for `time` seconds, following the playback mode.
+    pub fn local_time(&self, time: f32) -> f32 {
+        let duration = self.duration();
+        if duration <= 0.0 {
+            return 0.0;
+        }
+        match self.mode {
+            PlaybackMode::Once => time.max(0.0).min(duration),
+            PlaybackMode::Loop => time.rem_euclid(duration),
+            PlaybackMode::PingPong => {
+                let time = time.rem_euclid(2.0 * duration);
+                if time > duration {
+                    2.0 * duration - time
+                } else {
+                    time
+                }
+            }
+        }
+    }
+
+    /// Pose of the clip after playing it for `time` seconds. Before the first keyframe that has a
+    /// variable, and after the last one, the variable keeps the value of that keyframe.
+    pub fn sample(&self, time: f32) -> Pose {
+        let time = self.local_time(time);
+        Pose {
+            valuation: self
+                .variables()
+                .into_iter()
+                .filter_map(|variable| {
+                    let value = self.sample_variable(&variable, time)?;
+                    Some((variable, value))
+                })
+                .collect(),
+        }
+    }
+
+    fn sample_variable(&self, variable: &str, time: f32) -> Option<f32> {
+        let mut previous: Option<(f32, f32)> = None;
+        for keyframe in self.keyframes.iter() {
+            let value = match keyframe.pose.valuation.get(variable) {
+                Some(value) => *value,
+                None => continue,
+            };
+            if keyframe.time >= time {
+                return Some(match previous {
+                    Some((previous_time, previous_value)) if keyframe.time > time => {
+                        let t = (time - previous_time) / (keyframe.time - previous_time);
+                        self.interpolation(variable)
+                            .between(previous_value, value, t)
+                    }
+                    _ => value,
+                });
+            }
+            previous = Some((keyframe.time, value));
+        }
+        previous.map(|(_, value)| value)
+    }
+}
+
+impl Interpolation {
+    /// Value at `t`, from 0 to 1, of the way from `a` to `b`.
+    pub fn between(self, a: f32, b: f32, t: f32) -> f32 {
+        let t = match self {
+            Interpolation::Step => 0.0,
+            Interpolation::Linear => t,
+            Interpolation::EaseInOut => t * t * (3.0 - 2.0 * t),
+        };
+        a + (b - a) * t
+    }
+}
+
+/// Where a clip is being played.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Playback {
+    pub time: f32,
+    pub speed: f32,
+    pub playing: bool,
+}
+
+impl Default for Playback {
+    fn default() -> Self {
+        Self {
+            time: 0.0,
+            speed: 1.0,
+            playing: true,
+        }
+    }
+}
+
+impl Playback {
+    /// Moves `delta` seconds forward in `clip`. Clips played once stop at their end, the others
+    /// keep their time within a cycle.
+    pub fn advance(&mut self, clip: &Clip, delta: f32) {
+        if !self.playing {
+            return;
+        }
+        self.time += delta * self.speed;
+        let duration = clip.duration();
+        match clip.mode {
+            PlaybackMode::Once => {
+                if self.time >= duration {
+                    self.time = duration;
+                    self.playing = false;
+                }
+            }
+            PlaybackMode::Loop | PlaybackMode::PingPong if duration > 0.0 => {
+                self.time = self.time.rem_euclid(2.0 * duration);
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::skeleton::fixtures::pose;
+    use super::*;
+
+    /// Elbow going from 0 to 2 and back in two seconds, with the wrist only keyed at the middle.
+    fn wave(mode: PlaybackMode) -> Clip {
+        let mut clip = Clip {
+            mode,
+            ..Clip::default()
+        };
+        clip.insert_keyframe(2.0, pose(&[("elbow", 0.0)]));
+        clip.insert_keyframe(0.0, pose(&[("elbow", 0.0)]));
+        clip.insert_keyframe(1.0, pose(&[("elbow", 2.0), ("wrist", 1.0)]));
+        clip
+    }
+
+    #[test]
+    fn keyframes_are_kept_sorted() {
+        let mut clip = wave(PlaybackMode::Once);
+        let times: Vec<_> = clip.keyframes().iter().map(|k| k.time).collect();
+        assert_eq!(times, vec![0.0, 1.0, 2.0]);
+        assert_eq!(clip.duration(), 2.0);
+
+        clip.insert_keyframe(1.0, pose(&[("elbow", 3.0)]));
+        assert_eq!(clip.keyframes().len(), 3);
+        assert_eq!(clip.keyframes()[1].pose, pose(&[("elbow", 3.0)]));
+
+        assert_eq!(clip.keyframe_near(1.04, 0.05), Some(1));
+        assert_eq!(clip.keyframe_near(1.6, 0.5), Some(2));
+        assert_eq!(clip.keyframe_near(1.5, 0.1), None);
+
+        assert!(clip.remove_keyframe(2).is_some());
+        assert!(clip.remove_keyframe(2).is_none());
+        assert_eq!(clip.duration(), 1.0);
+    }
+
+    #[test]
+    fn variables_are_interpolated_between_their_keyframes() {
+        let mut clip = wave(PlaybackMode::Once);
+        assert_eq!(clip.sample(0.5), pose(&[("elbow", 1.0), ("wrist", 1.0)]));
+        assert_eq!(clip.sample(1.5), pose(&[("elbow", 1.0), ("wrist", 1.0)]));
+
+        clip.interpolations
+            .insert("elbow".into(), Interpolation::Step);
+        assert_eq!(clip.sample(0.5).valuation["elbow"], 0.0);
+        assert_eq!(clip.sample(1.0).valuation["elbow"], 2.0);
+
+        clip.interpolations
+            .insert("elbow".into(), Interpolation::EaseInOut);
+        assert_eq!(clip.sample(0.5).valuation["elbow"], 1.0);
+        assert!(clip.sample(0.25).valuation["elbow"] < 0.5);
+        assert!(clip.sample(0.75).valuation["elbow"] > 1.5);
+    }
+
+    #[test]
+    fn playback_modes_decide_what_comes_after_the_end() {
+        assert_eq!(wave(PlaybackMode::Once).local_time(2.5), 2.0);
+        assert_eq!(wave(PlaybackMode::Once).local_time(-1.0), 0.0);
+        assert_eq!(wave(PlaybackMode::Loop).local_time(2.5), 0.5);
+        assert_eq!(wave(PlaybackMode::PingPong).local_time(2.5), 1.5);
+        assert_eq!(wave(PlaybackMode::PingPong).local_time(4.5), 0.5);
+        assert_eq!(Clip::default().local_time(3.0), 0.0);
+        assert_eq!(Clip::default().sample(3.0), Pose::default());
+    }
+
+    #[test]
+    fn playback_stops_at_the_end_of_clips_played_once() {
+        let clip = wave(PlaybackMode::Once);
+        let mut playback = Playback::default();
+        playback.advance(&clip, 1.5);
+        assert_eq!(playback.time, 1.5);
+        playback.advance(&clip, 1.5);
+        assert_eq!(playback.time, 2.0);
+        assert!(!playback.playing);
+
+        let clip = wave(PlaybackMode::Loop);
+        let mut playback = Playback {
+            speed: 2.0,
+            ..Playback::default()
+        };
+        playback.advance(&clip, 2.5);
+        assert_eq!(playback.time, 1.0);
+        assert!(playback.playing);
+        assert_eq!(clip.local_time(playback.time), 1.0);
+    }
+
+    #[test]
+    fn clips_survive_serialization() {
+        let mut clip = wave(PlaybackMode::PingPong);
+        clip.interpolations
+            .insert("wrist".into(), Interpolation::EaseInOut);
+        let json = serde_json::to_string(&clip).unwrap();
+        assert_eq!(serde_json::from_str::<Clip>(&json).unwrap(), clip);
+    }
+}
@@ -1,17 +1,57 @@
+use super::skeleton::{Change, ChangeError, Definition, FloatValue, Joint, LinkId, Slot, SlotId};
+use super::skeleton_library::{LinkLibrary, LinkType};
+use super::SkeletonDatabase;
 use crate::root_ui::*;
 use bevy::prelude::*;
-use super::SkeletonDatabase;
-use super::skeleton;
-
-/*
 
 pub struct SkeletonEditor {
     skeleton_name: String,
+    /// Changes that revert the ones done in the editor, the last one first.
+    undo: Vec<Change>,
+    /// Slot of the subtree being moved, waiting for the user to pick where.
+    moving: Option<SlotId>,
+    /// Joint whose value is being dragged or typed, with the changes that revert what was done
+    /// to it so far. They are undone at once, when the edit ends.
+    pending: Option<(SlotId, Vec<Change>)>,
+    /// The skeleton as the editor left it. When it is changed somewhere else, the undo history
+    /// no longer applies to it.
+    seen: Option<Definition>,
+    error: Option<ChangeError>,
 }
 
 impl SkeletonEditor {
     pub fn for_skeleton(skeleton_name: String) -> Self {
-        Self { skeleton_name }
+        Self {
+            skeleton_name,
+            undo: vec![],
+            moving: None,
+            pending: None,
+            seen: None,
+            error: None,
+        }
+    }
+
+    /// Ends the edit going on, if any, so it is undone on its own.
+    fn finish_edit(&mut self) {
+        if let Some((_, mut inverses)) = self.pending.take() {
+            inverses.reverse();
+            self.undo.push(Change::Batch(inverses));
+        }
+    }
+
+    /// Keeps `inverse` to undo the change it reverts. Changes to the joint being edited are
+    /// undone along with the rest of the edit.
+    fn record(&mut self, inverse: Change, joint: Option<SlotId>) {
+        match &mut self.pending {
+            Some((at, inverses)) if Some(*at) == joint => inverses.push(inverse),
+            _ => {
+                self.finish_edit();
+                match joint {
+                    Some(joint) => self.pending = Some((joint, vec![inverse])),
+                    None => self.undo.push(inverse),
+                }
+            }
+        }
     }
 
     pub fn render_editors(
@@ -20,47 +60,77 @@ impl SkeletonEditor {
         mut editors: Query<(Entity, &mut SkeletonEditor)>,
         mut skeleton_db: ResMut<SkeletonDatabase>,
     ) {
+        let SkeletonDatabase {
+            skeletons, links, ..
+        } = &mut *skeleton_db;
         for (editor_eid, mut editor) in editors.iter_mut() {
+            let md = match skeletons.get_mut(&editor.skeleton_name) {
+                Some(md) => md,
+                None => {
+                    // The skeleton was deleted or renamed
+                    commands.entity(editor_eid).despawn_recursive();
+                    continue;
+                }
+            };
+            if editor.seen.as_ref() != Some(&*md) {
+                // Changed by a file, an import or another editor
+                editor.undo.clear();
+                editor.pending = None;
+                editor.seen = Some(md.clone());
+            }
             let mut open = true;
             egui::Window::new(format!("Skeleton Editor: {}", editor.skeleton_name))
                 .open(&mut open)
                 .show(egui_context.ctx(), |ui| {
-                    let md = skeleton_db.skeletons.get_mut(&editor.skeleton_name).unwrap();
-
                     let mut changes = vec![];
+                    let mut editing = None;
+                    let mut undo = false;
 
-                    let mut roots: Vec<_> = md
-                        .entries
-                        .iter()
-                        .filter(|(_, entry)| entry.attached_to == None)
-                        .collect();
-                    roots.sort_by_key(|(eid, _)| *eid);
-                    for (id, entry) in roots {
-                        edit_part(ui, *id, &entry.part, &md, &mut changes);
+                    ui.horizontal(|ui| {
+                        let can_undo = !editor.undo.is_empty() || editor.pending.is_some();
+                        if ui
+                            .add(egui::Button::new("Undo").enabled(can_undo))
+                            .clicked()
+                        {
+                            undo = true;
+                        }
+                        if let Some(moving) = editor.moving {
+                            ui.label(format!("Moving link at {:?}, pick a free slot", moving));
+                            if ui.small_button("cancel").clicked() {
+                                editor.moving = None;
+                            }
+                        }
+                    });
+                    if let Some(err) = &editor.error {
+                        ui.colored_label(egui::Color32::RED, format!("{:?}", err));
                     }
+                    ui.separator();
 
-                    egui::ComboBox::from_id_source(-1)
-                        .selected_text("Add new part to root")
-                        .show_ui(ui, |ui| {
-                            if ui.button("Translation").clicked() {
-                                changes.push(skeleton::Change::AddToRoot(skeleton::Part::Translation(
-                                    0., 0., 0.,
-                                )));
-                            }
-                            if ui.button("Rotation").clicked() {
-                                changes.push(skeleton::Change::AddToRoot(skeleton::Part::Rotation(
-                                    skeleton::FloatValue::Constant(0.),
-                                )));
-                            }
-                            if ui.button("EndEffector").clicked() {
-                                changes.push(skeleton::Change::AddToRoot(skeleton::Part::EndEffector(
-                                    "EFFECTOR NAME".into(),
-                                )));
-                            }
-                        });
+                    edit_link(ui, 0, md, links, &mut editor, &mut changes, &mut editing);
+                    let changed = undo || !changes.is_empty();
 
-                    for change in changes {
-                        md.apply(change).expect("Invalid part id");
+                    if undo {
+                        editor.finish_edit();
+                        let change = editor.undo.pop().unwrap();
+                        match md.apply(change) {
+                            Ok(_) => editor.error = None,
+                            Err(err) => editor.error = Some(err),
+                        }
+                    }
+                    for (change, joint) in changes {
+                        match md.apply(change) {
+                            Ok(inverse) => {
+                                editor.record(inverse, joint);
+                                editor.error = None;
+                            }
+                            Err(err) => editor.error = Some(err),
+                        }
+                    }
+                    if editor.pending.as_ref().map(|(at, _)| *at) != editing {
+                        editor.finish_edit();
+                    }
+                    if changed {
+                        editor.seen = Some(md.clone());
                     }
                 });
             if !open {
@@ -70,128 +140,324 @@ impl SkeletonEditor {
     }
 }
 
-fn part_name(part: &skeleton::Part) -> String {
-    match part {
-        skeleton::Part::Translation(x, y, z) => format!("Translation  ({}, {}, {})", x, y, z),
-        skeleton::Part::Rotation(value) => match value {
-            skeleton::FloatValue::Constant(c) => format!("Rotation by {} deg", c),
-            skeleton::FloatValue::Variable(name) => format!("Rotation by {} deg", name),
-        },
-        skeleton::Part::EndEffector(name) => format!("EndEffector {}", name),
+fn link_name(md: &Definition, link_id: LinkId) -> String {
+    match &md.links[&link_id].link_type {
+        Some(name) => format!("{} {}", name, link_id),
+        None => format!("link {}", link_id),
     }
 }
 
-fn edit_part(
+/// Shows `link_id` and everything hanging from it: every slot of the link with either the joint
+/// and link attached to it, or what can be done with it if it is free. Changes to joints come
+/// with the slot of the joint, and `editing` is set to the joint whose value is still being
+/// edited.
+fn edit_link(
     ui: &mut egui::Ui,
-    id: usize,
-    part: &skeleton::Part,
-    md: &skeleton::Definition,
-    changes: &mut Vec<skeleton::Change>,
+    link_id: LinkId,
+    md: &Definition,
+    links: &LinkLibrary,
+    editor: &mut SkeletonEditor,
+    changes: &mut Vec<(Change, Option<SlotId>)>,
+    editing: &mut Option<SlotId>,
 ) {
+    ui.label(format!("▶ {}", link_name(md, link_id)));
+    let mut slot_names: Vec<_> = md.links[&link_id].slots.keys().cloned().collect();
+    slot_names.sort();
     ui.horizontal(|ui| {
-        ui.label("▶");
-        match part {
-            skeleton::Part::Translation(x, y, z) => {
-                let mut p = (*x, *y, *z);
-                ui.label("Translate by (");
-                ui.add(egui::DragValue::new(&mut p.0).speed(0.1));
-                ui.add(egui::DragValue::new(&mut p.1).speed(0.1));
-                ui.add(egui::DragValue::new(&mut p.2).speed(0.1));
-                ui.label(")");
-
-                if p != (*x, *y, *z) {
-                    changes.push(skeleton::Change::Replace(
-                        id,
-                        skeleton::Part::Translation(p.0, p.1, p.2),
-                    ));
-                }
-            }
-
-            skeleton::Part::Rotation(value) => {
-                let mut new_value = None;
-                match value {
-                    skeleton::FloatValue::Constant(c) => {
-                        ui.label("Rotate by constant");
-                        let mut nc = *c;
-                        if ui.drag_angle(&mut nc).changed() {
-                            new_value = Some(skeleton::FloatValue::Constant(nc));
-                        }
-                        ui.label("degrees");
-                        ui.separator();
-                        if ui.small_button("make var").clicked() {
-                            new_value = Some(skeleton::FloatValue::Variable("VAR_NAME".into()));
-                        }
+        ui.label("   ");
+        ui.vertical(|ui| {
+            for slot_name in slot_names {
+                let slot_id = SlotId(link_id, slot_name);
+                let joint = md
+                    .joints
+                    .iter()
+                    .find(|(a, _, b)| *a == slot_id || *b == slot_id);
+                match joint {
+                    // The slot the link hangs from
+                    Some((_, _, child_slot)) if *child_slot == slot_id => {}
+                    Some((_, joint, child_slot)) => {
+                        ui.horizontal(|ui| {
+                            ui.label(format!("'{}' =>", slot_name));
+                            let (new_joint, still_editing) = edit_joint(ui, slot_id, joint);
+                            if let Some(with) = new_joint {
+                                changes
+                                    .push((replace_joint(md, slot_id, joint, with), Some(slot_id)));
+                            }
+                            if still_editing {
+                                *editing = Some(slot_id);
+                            }
+                            if ui.small_button("cut").clicked() {
+                                changes.push((Change::Cut { from: slot_id }, None));
+                            }
+                            if ui.small_button("move").clicked() {
+                                editor.moving = Some(slot_id);
+                            }
+                        });
+                        edit_link(ui, child_slot.0, md, links, editor, changes, editing);
                     }
-                    skeleton::FloatValue::Variable(name) => {
-                        ui.label("Rotate by var");
-                        let mut nname = name.clone();
-                        if ui.text_edit_singleline(&mut nname).changed() {
-                            new_value = Some(skeleton::FloatValue::Variable(nname));
-                        }
-                        ui.label("degrees");
-                        ui.separator();
-                        if ui.small_button("make const").clicked() {
-                            new_value = Some(skeleton::FloatValue::Constant(0.));
-                        }
+                    None => {
+                        ui.horizontal(|ui| {
+                            ui.label(format!("'{}' free", slot_name));
+                            egui::ComboBox::from_id_source(("add link", slot_id))
+                                .selected_text("add link")
+                                .show_ui(ui, |ui| {
+                                    for name in links.names() {
+                                        if ui.button(name.as_str()).clicked() {
+                                            let link = links.link(name).unwrap();
+                                            // Hang it from 'p' when it has one, like the
+                                            // schemas do. Links without slots cannot be added.
+                                            let local_slot_name = Some('p')
+                                                .filter(|p| link.slots.contains_key(p))
+                                                .or_else(|| link.slots.keys().min().cloned())
+                                                .unwrap_or('p');
+                                            let add = Change::Add {
+                                                link,
+                                                to_parent_slot: slot_id,
+                                                joint: Joint::Fixed,
+                                                local_slot_name,
+                                            };
+                                            changes.push((add, None));
+                                        }
+                                    }
+                                });
+                            if let Some(moving) = editor.moving {
+                                if ui.small_button("move here").clicked() {
+                                    let move_link = Change::MoveLink {
+                                        at: moving,
+                                        to: slot_id,
+                                    };
+                                    changes.push((move_link, None));
+                                    editor.moving = None;
+                                }
+                            }
+                        });
                     }
                 }
+            }
+        });
+    });
+}
 
-                if let Some(value) = new_value {
-                    changes.push(skeleton::Change::Replace(id, skeleton::Part::Rotation(value)));
-                }
+/// Replaces the joint at `at`. When that renames its variable, the constraint goes along with
+/// the name, unless the new name already had its own. The old name loses its constraint when no
+/// other joint uses it.
+fn replace_joint(md: &Definition, at: SlotId, joint: &Joint, with: Joint) -> Change {
+    let replace = Change::ReplaceJoint {
+        at,
+        with: with.clone(),
+    };
+    match (joint.variables().pop(), with.variables().pop()) {
+        (Some(old), Some(new)) if old != new && !md.variables().contains(&new) => {
+            Change::Batch(vec![
+                replace,
+                Change::Constrain {
+                    variable: new,
+                    with: md.constraint(&old),
+                },
+            ])
+        }
+        _ => replace,
+    }
+}
+
+/// Edits the type of a joint and the value driving it. Returns the new joint if it changed, and
+/// whether its value is still being dragged or typed.
+fn edit_joint(ui: &mut egui::Ui, slot_id: SlotId, joint: &Joint) -> (Option<Joint>, bool) {
+    let kind = match joint {
+        Joint::Fixed => "Fixed",
+        Joint::TwistingJoint(_) => "Twisting",
+        Joint::RotationalJoin(_) => "Rotational",
+    };
+    let value = match joint {
+        Joint::Fixed => FloatValue::Constant(0.0),
+        Joint::TwistingJoint(value) | Joint::RotationalJoin(value) => value.clone(),
+    };
+    let mut new_joint = None;
+    egui::ComboBox::from_id_source(("joint", slot_id))
+        .selected_text(kind)
+        .show_ui(ui, |ui| {
+            if ui.selectable_label(kind == "Fixed", "Fixed").clicked() {
+                new_joint = Some(Joint::Fixed);
             }
-            skeleton::Part::EndEffector(name) => {
-                let mut nname = name.clone();
-                ui.label("End effector named");
-                if ui.text_edit_singleline(&mut nname).changed() {
-                    changes.push(skeleton::Change::Replace(id, skeleton::Part::EndEffector(nname)));
-                }
+            if ui
+                .selectable_label(kind == "Twisting", "Twisting")
+                .clicked()
+            {
+                new_joint = Some(Joint::TwistingJoint(value.clone()));
+            }
+            if ui
+                .selectable_label(kind == "Rotational", "Rotational")
+                .clicked()
+            {
+                new_joint = Some(Joint::RotationalJoin(value.clone()));
             }
+        });
+    let (new_value, editing) = match joint {
+        Joint::Fixed => (None, false),
+        Joint::TwistingJoint(value) | Joint::RotationalJoin(value) => edit_float_value(ui, value),
+    };
+    if let Some(new_value) = new_value {
+        new_joint = Some(match joint {
+            Joint::TwistingJoint(_) => Joint::TwistingJoint(new_value),
+            _ => Joint::RotationalJoin(new_value),
+        });
+    }
+    (new_joint.filter(|new_joint| new_joint != joint), editing)
+}
+
+fn edit_float_value(ui: &mut egui::Ui, value: &FloatValue) -> (Option<FloatValue>, bool) {
+    match value {
+        FloatValue::Constant(c) => {
+            let mut nc = *c;
+            let mut new_value = None;
+            let response = ui.drag_angle(&mut nc);
+            if response.changed() {
+                new_value = Some(FloatValue::Constant(nc));
+            }
+            let editing = response.dragged() || response.has_focus();
+            if ui.small_button("make variable").clicked() {
+                new_value = Some(FloatValue::Variable("VARIABLE NAME".into()));
+            }
+            (new_value, editing)
         }
-        ui.separator();
-        if ui.small_button("delete").clicked() {
-            changes.push(skeleton::Change::Delete(id));
+        FloatValue::Variable(name) => {
+            let mut new_name = name.clone();
+            let mut new_value = None;
+            ui.label("variable");
+            let response = ui.text_edit_singleline(&mut new_name);
+            if response.changed() && !new_name.is_empty() {
+                new_value = Some(FloatValue::Variable(new_name));
+            }
+            let editing = response.has_focus();
+            if ui.small_button("make constant").clicked() {
+                new_value = Some(FloatValue::Constant(0.0));
+            }
+            (new_value, editing)
         }
-        egui::ComboBox::from_id_source(id)
-            .selected_text("add child")
-            .show_ui(ui, |ui| {
-                if ui.button("Translation").clicked() {
-                    changes.push(skeleton::Change::AddToPart(
-                        id,
-                        skeleton::Part::Translation(0., 0., 0.),
-                    ));
-                }
-                if ui.button("Rotation").clicked() {
-                    changes.push(skeleton::Change::AddToPart(
-                        id,
-                        skeleton::Part::Rotation(skeleton::FloatValue::Constant(0.)),
-                    ));
-                }
-                if ui.button("EndEffector").clicked() {
-                    changes.push(skeleton::Change::AddToPart(
-                        id,
-                        skeleton::Part::EndEffector("EFFECTOR NAME".into()),
-                    ));
-                }
-            });
-    });
+    }
+}
+
+/// Edits a copy of a link type, which replaces the one in the library when applied.
+pub struct LinkTypeEditor {
+    link_type_name: String,
+    draft: LinkType,
+    new_slot_name: String,
+}
 
-    let mut children: Vec<_> = md
-        .entries
-        .iter()
-        .filter(|(_, entry)| entry.attached_to == Some(id))
-        .collect();
-    children.sort_by_key(|(eid, _)| *eid);
-    if !children.is_empty() {
-        ui.horizontal(|ui| {
-            ui.label("   ");
-            ui.vertical(|ui| {
-                for (child_id, entry) in children {
-                    edit_part(ui, *child_id, &entry.part, &md, changes);
+impl LinkTypeEditor {
+    pub fn for_link_type(link_type_name: String, draft: LinkType) -> Self {
+        Self {
+            link_type_name,
+            draft,
+            new_slot_name: String::new(),
+        }
+    }
+
+    pub fn render_editors(
+        mut commands: Commands,
+        egui_context: ResMut<EguiContext>,
+        mut editors: Query<(Entity, &mut LinkTypeEditor)>,
+        mut skeleton_db: ResMut<SkeletonDatabase>,
+    ) {
+        for (editor_eid, mut editor) in editors.iter_mut() {
+            let saved = match skeleton_db.links.get(&editor.link_type_name) {
+                Some(link_type) => link_type.clone(),
+                None => {
+                    // The link type was deleted from the library
+                    commands.entity(editor_eid).despawn_recursive();
+                    continue;
                 }
-            });
-        });
+            };
+            let mut open = true;
+            let mut apply = false;
+            egui::Window::new(format!("Link Type Editor: {}", editor.link_type_name))
+                .open(&mut open)
+                .show(egui_context.ctx(), |ui| {
+                    let editor = &mut *editor;
+                    let mut slot_names: Vec<_> = editor.draft.slots.keys().cloned().collect();
+                    slot_names.sort();
+                    let mut slot_to_remove = None;
+                    for slot_name in slot_names {
+                        ui.horizontal(|ui| {
+                            ui.label(format!("'{}'", slot_name));
+                            edit_slot(ui, editor.draft.slots.get_mut(&slot_name).unwrap());
+                            if ui.small_button("remove").clicked() {
+                                slot_to_remove = Some(slot_name);
+                            }
+                        });
+                    }
+                    if let Some(slot_name) = slot_to_remove {
+                        editor.draft.slots.remove(&slot_name);
+                    }
+
+                    ui.horizontal(|ui| {
+                        ui.text_edit_singleline(&mut editor.new_slot_name);
+                        let mut chars = editor.new_slot_name.chars();
+                        let new_slot_name = match (chars.next(), chars.next()) {
+                            (Some(c), None) if !editor.draft.slots.contains_key(&c) => Some(c),
+                            _ => None,
+                        };
+                        if ui
+                            .add(egui::Button::new("Add slot").enabled(new_slot_name.is_some()))
+                            .clicked()
+                        {
+                            editor
+                                .draft
+                                .slots
+                                .insert(new_slot_name.unwrap(), Slot::default());
+                            editor.new_slot_name.clear();
+                        }
+                    });
+                    ui.separator();
+
+                    let changed = editor.draft != saved;
+                    ui.horizontal(|ui| {
+                        if ui
+                            .add(egui::Button::new("Apply").enabled(changed))
+                            .clicked()
+                        {
+                            apply = true;
+                        }
+                        if ui
+                            .add(egui::Button::new("Revert").enabled(changed))
+                            .clicked()
+                        {
+                            editor.draft = saved.clone();
+                        }
+                    });
+                    ui.label("Applying updates every skeleton using this link type");
+                });
+            if apply {
+                skeleton_db.update_link_type(editor.link_type_name.clone(), editor.draft.clone());
+            }
+            if !open {
+                commands.entity(editor_eid).despawn_recursive();
+            }
+        }
     }
 }
 
-*/
+/// Edits the position of a slot and its orientation, as an angle around an axis.
+fn edit_slot(ui: &mut egui::Ui, slot: &mut Slot) {
+    ui.label("position");
+    ui.add(egui::DragValue::new(&mut slot.position.x).speed(0.1));
+    ui.add(egui::DragValue::new(&mut slot.position.y).speed(0.1));
+    ui.add(egui::DragValue::new(&mut slot.position.z).speed(0.1));
+
+    let (mut axis, mut angle) = slot.orientation.to_axis_angle();
+    ui.label("rotation");
+    let mut changed = ui.drag_angle(&mut angle).changed();
+    ui.label("around");
+    changed |= ui
+        .add(egui::DragValue::new(&mut axis.x).speed(0.05))
+        .changed();
+    changed |= ui
+        .add(egui::DragValue::new(&mut axis.y).speed(0.05))
+        .changed();
+    changed |= ui
+        .add(egui::DragValue::new(&mut axis.z).speed(0.05))
+        .changed();
+    if changed && axis.length_squared() > 0.0 {
+        slot.orientation = Quat::from_axis_angle(axis.normalize(), angle);
+    }
+}
@@ -1,45 +1,313 @@
-/*
-use bevy::prelude::*;
-use bevy::utils::HashMap;
-use super::skeleton;
+use bevy::{ecs::system::Command, prelude::*};
+use std::collections::{HashMap, HashSet};
 
-pub struct Root {
-    pub definition: Entity,
-    pub parts: HashMap<skeleton::LinkRef, Entity>,
-    pose: skeleton::Pose,
+use super::skeleton::{self, LinkId};
+use super::skeleton_animation::{Clip, Playback};
+
+/// Root of a skeleton instance: there is an entity for every link of the definition, parented
+/// to the entity of the link it hangs from, with link 0 parented to the root.
+#[derive(Debug, Clone)]
+pub struct SkeletonInstance {
+    pub definition: skeleton::Definition,
+    pub links: HashMap<LinkId, Entity>,
+    pub pose: skeleton::Pose,
+}
+
+/// Marks the entity of a link inside a skeleton instance.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct LinkInstance {
+    pub root: Entity,
+    pub link: LinkId,
 }
 
+impl SkeletonInstance {
+    /// Moves the entity of every link to where the current pose puts it.
+    fn apply_pose(&self, transforms: &mut Query<&mut Transform>) {
+        let local = match self.definition.local_transforms(&self.pose) {
+            Ok(local) => local,
+            Err(err) => {
+                error!("Could not pose skeleton instance: {:?}", err);
+                return;
+            }
+        };
+        for (link_id, entity) in self.links.iter() {
+            if let (Some(transform), Ok(mut current)) =
+                (local.get(link_id), transforms.get_mut(*entity))
+            {
+                *current = *transform;
+            }
+        }
+    }
+}
+
+/// Spawns the links of `definition` below `target`, posed with `pose`. Variables missing from
+/// the pose take their default value.
 pub struct CreateInstance {
-    definition: Entity,
-    target: Entity,
-    pose: skeleton::Pose,
+    pub definition: skeleton::Definition,
+    pub target: Entity,
+    pub pose: skeleton::Pose,
 }
 
-impl bevy::ecs::system::Command for CreateInstance {
+impl Command for CreateInstance {
     fn write(self: Box<Self>, world: &mut World) {
-        let definition = world
-            .entity(self.definition)
-            .get::<skeleton::Definition>()
-            .expect("Definition not found when creating instance")
-            .clone();
-
-        let mut parts = HashMap::default();
-        for (LinkRef, _) in definition.entries.iter() {
-            parts.insert(*part_id, world.spawn().id());
-        }
-        for (part_id, part_entry) in definition.entries.iter() {
-            match part_entry.part {
-                skeleton::Part::Translation(x, y, z) => {}
-                skeleton::Part::Rotation() => {}
+        let pose = self.definition.clamp_pose(&self.pose);
+        let local = match self.definition.local_transforms(&pose) {
+            Ok(local) => local,
+            Err(err) => {
+                error!("Could not create skeleton instance: {:?}", err);
+                return;
+            }
+        };
+
+        // Instancing again replaces the links of the previous instance
+        let previous = world.entity_mut(self.target).remove::<SkeletonInstance>();
+        if let Some(previous) = previous {
+            let old_links: HashSet<Entity> = previous.links.values().cloned().collect();
+            if let Some(children) = world.get::<Children>(self.target) {
+                let others: Vec<Entity> = children
+                    .iter()
+                    .cloned()
+                    .filter(|child| !old_links.contains(child))
+                    .collect();
+                world
+                    .entity_mut(self.target)
+                    .insert(Children::with(&others));
+            }
+            for entity in old_links {
+                world.despawn(entity);
             }
         }
 
-        let root = world.entity_mut(self.target);
+        let mut links = HashMap::new();
+        for link_id in self.definition.links() {
+            let entity = world
+                .spawn()
+                .insert_bundle((
+                    local.get(&link_id).cloned().unwrap_or_default(),
+                    GlobalTransform::identity(),
+                    LinkInstance {
+                        root: self.target,
+                        link: link_id,
+                    },
+                ))
+                .id();
+            links.insert(link_id, entity);
+        }
+        world.entity_mut(self.target).push_children(&[links[&0]]);
+        for (parent_slot, _, child_slot) in self.definition.joints.iter() {
+            let child = links[&child_slot.0];
+            world
+                .entity_mut(links[&parent_slot.0])
+                .push_children(&[child]);
+        }
+
+        world.entity_mut(self.target).insert(SkeletonInstance {
+            definition: self.definition,
+            links,
+            pose,
+        });
     }
 }
 
+/// Changes the pose of the skeleton instance in `target`. Poses that are not valid for the
+/// skeleton are clamped to the closest valid one.
 pub struct UpdatePose {
-    target: Entity,
-    new_pose: skeleton::Pose,
+    pub target: Entity,
+    pub new_pose: skeleton::Pose,
+}
+
+impl Command for UpdatePose {
+    fn write(self: Box<Self>, world: &mut World) {
+        let mut instance = match world.get_mut::<SkeletonInstance>(self.target) {
+            Some(instance) => instance,
+            None => {
+                warn!("No skeleton instance to pose in {:?}", self.target);
+                return;
+            }
+        };
+        if let Err(problems) = instance.definition.validate_pose(&self.new_pose) {
+            warn!("Clamping invalid pose: {:?}", problems);
+        }
+        let pose = instance.definition.clamp_pose(&self.new_pose);
+        if instance.pose != pose {
+            instance.pose = pose;
+        }
+    }
+}
+
+/// Plays a clip on the skeleton instance of the same entity.
+#[derive(Debug, Clone, Default)]
+pub struct AnimationPlayer {
+    pub clip: Clip,
+    pub playback: Playback,
+}
+
+/// Advances the clips being played and poses their skeleton instances accordingly.
+pub fn play_animations(
+    time: Res<Time>,
+    mut players: Query<(&mut AnimationPlayer, &mut SkeletonInstance)>,
+) {
+    for (mut player, mut instance) in players.iter_mut() {
+        if !player.playback.playing {
+            continue;
+        }
+        let AnimationPlayer { clip, playback } = &mut *player;
+        playback.advance(clip, time.delta_seconds());
+        let pose = instance.definition.clamp_pose(&clip.sample(playback.time));
+        if instance.pose != pose {
+            instance.pose = pose;
+        }
+    }
+}
+
+/// Moves the links of the skeleton instances whose pose changed.
+pub fn update_poses(
+    instances: Query<&SkeletonInstance, Changed<SkeletonInstance>>,
+    mut transforms: Query<&mut Transform>,
+) {
+    for instance in instances.iter() {
+        instance.apply_pose(&mut transforms);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::playdate::skeleton::fixtures::{arm, elbow};
+    use crate::playdate::skeleton::*;
+
+    fn posed_world(elbow: f32) -> (World, Entity) {
+        let mut world = World::default();
+        let target = world.spawn().id();
+        let mut pose = Pose::default();
+        pose.valuation.insert("elbow".into(), elbow);
+        Box::new(CreateInstance {
+            definition: arm(elbow()),
+            target,
+            pose,
+        })
+        .write(&mut world);
+        (world, target)
+    }
+
+    #[test]
+    fn create_instance_spawns_a_hierarchy_of_links() {
+        let (world, target) = posed_world(0.0);
+        let instance = world.get::<SkeletonInstance>(target).unwrap();
+        assert_eq!(instance.links.len(), 3);
+        let parent_of = |link_id| world.get::<Parent>(instance.links[&link_id]).map(|p| p.0);
+        assert_eq!(parent_of(0), Some(target));
+        assert_eq!(parent_of(1), Some(instance.links[&0]));
+        assert_eq!(parent_of(2), Some(instance.links[&1]));
+        assert_eq!(
+            world.get::<LinkInstance>(instance.links[&2]),
+            Some(&LinkInstance {
+                root: target,
+                link: 2
+            })
+        );
+        assert_eq!(
+            world.get::<Transform>(instance.links[&1]),
+            Some(&Transform::from_translation(2.0 * Vec3::Z))
+        );
+    }
+
+    #[test]
+    fn instancing_again_replaces_the_previous_links() {
+        let (mut world, target) = posed_world(0.0);
+        let other_child = world.spawn().id();
+        world.entity_mut(target).push_children(&[other_child]);
+        let old_links: Vec<Entity> = world
+            .get::<SkeletonInstance>(target)
+            .unwrap()
+            .links
+            .values()
+            .cloned()
+            .collect();
+
+        Box::new(CreateInstance {
+            definition: arm(elbow()),
+            target,
+            pose: Pose::default(),
+        })
+        .write(&mut world);
+
+        for entity in old_links {
+            assert!(world.get_entity(entity).is_none());
+        }
+        let instance = world.get::<SkeletonInstance>(target).unwrap();
+        let children: Vec<Entity> = world.get::<Children>(target).unwrap().to_vec();
+        assert_eq!(children, vec![other_child, instance.links[&0]]);
+    }
+
+    #[test]
+    fn update_pose_moves_the_links() {
+        let (mut world, target) = posed_world(0.0);
+        let mut stage = SystemStage::parallel();
+        stage.add_system(update_poses.system());
+        stage.run(&mut world);
+
+        let mut pose = Pose::default();
+        pose.valuation.insert("elbow".into(), std::f32::consts::PI);
+        Box::new(UpdatePose {
+            target,
+            new_pose: pose.clone(),
+        })
+        .write(&mut world);
+        stage.run(&mut world);
+
+        let instance = world.get::<SkeletonInstance>(target).unwrap();
+        assert_eq!(instance.pose, pose);
+        let forearm = world.get::<Transform>(instance.links[&1]).unwrap();
+        // Bent backwards, the forearm goes down through the base
+        assert!(
+            forearm.translation.abs_diff_eq(Vec3::ZERO, 1e-5),
+            "Forearm at {:?}",
+            forearm
+        );
+    }
+
+    #[test]
+    fn animation_players_pose_their_instance() {
+        let (mut world, target) = posed_world(0.0);
+        world.insert_resource(Time::default());
+        let mut bent = Pose::default();
+        bent.valuation.insert("elbow".into(), std::f32::consts::PI);
+        let mut clip = Clip::default();
+        clip.insert_keyframe(0.0, bent.clone());
+        world.entity_mut(target).insert(AnimationPlayer {
+            clip,
+            playback: Playback {
+                playing: false,
+                ..Playback::default()
+            },
+        });
+        let mut play = SystemStage::parallel();
+        play.add_system(play_animations.system());
+        let mut pose = SystemStage::parallel();
+        pose.add_system(update_poses.system());
+        play.run(&mut world);
+        pose.run(&mut world);
+
+        // Stopped players leave the pose alone
+        let instance = world.get::<SkeletonInstance>(target).unwrap();
+        assert_eq!(instance.pose.valuation["elbow"], 0.0);
+
+        world
+            .get_mut::<AnimationPlayer>(target)
+            .unwrap()
+            .playback
+            .playing = true;
+        play.run(&mut world);
+        pose.run(&mut world);
+
+        let instance = world.get::<SkeletonInstance>(target).unwrap();
+        assert_eq!(instance.pose, bent);
+        let forearm = world.get::<Transform>(instance.links[&1]).unwrap();
+        assert!(
+            forearm.translation.abs_diff_eq(Vec3::ZERO, 1e-5),
+            "Forearm at {:?}",
+            forearm
+        );
+    }
 }
-*/
@@ -0,0 +1,235 @@
+//! The link library: the link types skeletons are built from, each one with its named slots.
+//! Links refer to their type by name and keep a copy of its slots, which `LinkLibrary::update`
+//! brings up to date when the type changes. Libraries are stored as JSON:
+//!
+//! ```text
+//! { "version": 1, "link_types": { "l_link": { "slots": { "p": { ... }, "n": { ... } } } } }
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+use super::skeleton::*;
+use super::skeleton_persistence::{read, write_safely, DatabaseError};
+
+pub const FORMAT_VERSION: u64 = 1;
+
+/// The library used with the skeletons file in `database_path`, which lives next to it.
+pub fn library_path(database_path: &Path) -> PathBuf {
+    database_path.with_file_name("links.json")
+}
+
+#[derive(Debug, Default, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct LinkType {
+    pub slots: HashMap<SlotName, Slot>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct LinkLibrary {
+    link_types: HashMap<String, LinkType>,
+}
+
+/// Same as `LinkLibrary`, with the link types sorted by name.
+#[derive(serde::Serialize)]
+struct JsonFile<'a> {
+    version: u64,
+    link_types: BTreeMap<&'a String, &'a LinkType>,
+}
+
+/// A library with the presets.
+impl Default for LinkLibrary {
+    fn default() -> Self {
+        Self {
+            link_types: link::PRESETS
+                .iter()
+                .map(|(name, preset)| {
+                    (
+                        name.to_string(),
+                        LinkType {
+                            slots: preset().slots,
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+impl LinkLibrary {
+    /// Names of the link types, sorted.
+    pub fn names(&self) -> Vec<&String> {
+        let mut names: Vec<_> = self.link_types.keys().collect();
+        names.sort();
+        names
+    }
+
+    pub fn get(&self, name: &str) -> Option<&LinkType> {
+        self.link_types.get(name)
+    }
+
+    /// A new link of the type `name`.
+    pub fn link(&self, name: &str) -> Option<Link> {
+        Some(Link {
+            link_type: Some(name.into()),
+            slots: self.get(name)?.slots.clone(),
+        })
+    }
+
+    /// Adds a link type, or replaces the one with the same name. Skeletons using it are not
+    /// changed until they are updated.
+    pub fn insert(&mut self, name: String, link_type: LinkType) -> Option<LinkType> {
+        self.link_types.insert(name, link_type)
+    }
+
+    /// Removes a link type. Links of that type keep the slots they had.
+    pub fn remove(&mut self, name: &str) -> Option<LinkType> {
+        self.link_types.remove(name)
+    }
+
+    /// Copies the slots of the link types into the links of `definition` that use them, and
+    /// returns the change that reverts it. Links without a type take the one with their same
+    /// slots, if there is one. If a link type lost a slot that a link uses, nothing is changed.
+    pub fn update(&self, definition: &mut Definition) -> Result<Change, ChangeError> {
+        let mut changes = vec![];
+        for link_id in definition.links() {
+            let link = &definition.links[&link_id];
+            let updated = match &link.link_type {
+                Some(name) => self.link(name),
+                None => self
+                    .names()
+                    .into_iter()
+                    .find(|name| self.link_types[*name].slots == link.slots)
+                    .and_then(|name| self.link(name)),
+            };
+            match updated {
+                Some(updated) if updated != *link => changes.push(Change::ReplaceLink {
+                    at: link_id,
+                    with: updated,
+                }),
+                _ => (),
+            }
+        }
+        definition.apply(Change::Batch(changes))
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(&JsonFile {
+            version: FORMAT_VERSION,
+            link_types: self.link_types.iter().collect(),
+        })
+        .expect("Link types can always be serialized")
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, DatabaseError> {
+        let value: serde_json::Value = serde_json::from_str(json).map_err(DatabaseError::Json)?;
+        match value.get("version").and_then(|version| version.as_u64()) {
+            Some(FORMAT_VERSION) => serde_json::from_value(value).map_err(DatabaseError::Json),
+            Some(version) => Err(DatabaseError::UnsupportedVersion(version, FORMAT_VERSION)),
+            None => Err(DatabaseError::Json(serde::de::Error::custom(
+                "Link library must have a numeric version",
+            ))),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, DatabaseError> {
+        Self::from_json(&read(path)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), DatabaseError> {
+        write_safely(path, &self.to_json())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::skeleton::fixtures::{arm, elbow};
+    use super::*;
+    use bevy::prelude::*;
+
+    #[test]
+    fn the_default_library_has_the_presets() {
+        let library = LinkLibrary::default();
+        assert_eq!(library.names(), vec!["arm_base", "l_link", "t_link"]);
+        assert_eq!(library.link("t_link"), Some(link::t_link()));
+        assert_eq!(library.link("leg"), None);
+    }
+
+    #[test]
+    fn changes_to_link_types_reach_the_skeletons_using_them() {
+        let mut library = LinkLibrary::default();
+        let mut long_link = library.get("l_link").unwrap().clone();
+        long_link.slots.get_mut(&'n').unwrap().position = 3.0 * Vec3::Z;
+        library.insert("l_link".into(), long_link);
+
+        let mut s = arm(elbow());
+        let original = s.clone();
+        let inverse = library.update(&mut s).unwrap();
+        assert_eq!(s.links[&0], original.links[&0]);
+        for link_id in vec![1, 2] {
+            assert_eq!(s.links[&link_id].slots[&'n'].position, 3.0 * Vec3::Z);
+        }
+        let local = s.local_transforms(&s.clamp_pose(&Pose::default())).unwrap();
+        assert_eq!(local[&2].translation, 4.0 * Vec3::Z);
+
+        s.apply(inverse).unwrap();
+        assert_eq!(s, original);
+    }
+
+    #[test]
+    fn link_types_cannot_lose_slots_in_use() {
+        let mut library = LinkLibrary::default();
+        let mut short_link = library.get("l_link").unwrap().clone();
+        short_link.slots.remove(&'n');
+        library.insert("l_link".into(), short_link);
+
+        let mut s = arm(elbow());
+        let original = s.clone();
+        assert_eq!(
+            library.update(&mut s),
+            Err(ChangeError::SlotInUse(SlotId(1, 'n')))
+        );
+        assert_eq!(s, original);
+
+        // Links of types no longer in the library are left alone
+        library.remove("l_link");
+        library.update(&mut s).unwrap();
+        assert_eq!(s, original);
+    }
+
+    #[test]
+    fn links_without_a_type_take_the_one_with_their_slots() {
+        let library = LinkLibrary::default();
+        let mut s = arm(elbow());
+        s.links.get_mut(&1).unwrap().link_type = None;
+        let custom = s.links.get_mut(&2).unwrap();
+        custom.link_type = None;
+        custom.slots.insert('x', Slot::default());
+        let custom = custom.clone();
+
+        library.update(&mut s).unwrap();
+        assert_eq!(s.links[&1], link::l_link());
+        assert_eq!(s.links[&2], custom);
+    }
+
+    #[test]
+    fn libraries_survive_serialization() {
+        let mut library = LinkLibrary::default();
+        library.insert(
+            "hand".into(),
+            LinkType {
+                slots: vec![('p', Slot::default())].into_iter().collect(),
+            },
+        );
+        assert_eq!(LinkLibrary::from_json(&library.to_json()).unwrap(), library);
+
+        let newer = library
+            .to_json()
+            .replacen("\"version\": 1", "\"version\": 2", 1);
+        match LinkLibrary::from_json(&newer) {
+            Err(DatabaseError::UnsupportedVersion(2, FORMAT_VERSION)) => (),
+            other => panic!("Expected an unsupported version, found {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,529 @@
+//! Saving and loading of the skeleton database. Files are JSON with a format version:
+//!
+//! ```text
+//! { "version": 2, "skeletons": { ... }, "clips": { ... } }
+//! ```
+//!
+//! Older files are migrated when loaded:
+//! - Version 1 is a bare map from names to skeletons made of links and joints, with the clips
+//!   in an `animations.json` file next to it.
+//! - Version 0 is a bare map from names to skeletons made of `entries`, each one a part
+//!   (a translation, a rotation or an end effector) attached to another entry.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::{Quat, Vec3};
+
+use super::skeleton::*;
+use super::skeleton_animation::Clip;
+
+pub const FORMAT_VERSION: u64 = 2;
+
+/// How many files `RecentFiles` remembers.
+const MAX_RECENT_FILES: usize = 8;
+
+pub fn default_database_path() -> &'static Path {
+    Path::new("skeletons.json")
+}
+
+pub fn recent_files_path() -> &'static Path {
+    Path::new("recent_skeleton_files.json")
+}
+
+#[derive(Debug)]
+pub enum DatabaseError {
+    Io(PathBuf, std::io::Error),
+    Json(serde_json::Error),
+    /// The version of a file, and the latest one supported for that kind of file.
+    UnsupportedVersion(u64, u64),
+    Migration(String, String),
+    /// A skeleton whose links do not make a tree.
+    InvalidSkeleton(String, ChangeError),
+}
+
+impl Display for DatabaseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DatabaseError::Io(path, err) => write!(f, "I/O error on {}: {}", path.display(), err),
+            DatabaseError::Json(err) => write!(f, "Invalid skeletons file: {}", err),
+            DatabaseError::UnsupportedVersion(version, supported) => write!(
+                f,
+                "File has version {}, but only up to {} is supported",
+                version, supported
+            ),
+            DatabaseError::Migration(name, reason) => {
+                write!(f, "Could not migrate skeleton {}: {}", name, reason)
+            }
+            DatabaseError::InvalidSkeleton(name, err) => {
+                write!(f, "Skeleton {} is not a valid tree: {:?}", name, err)
+            }
+        }
+    }
+}
+
+/// Everything stored in a skeletons file.
+#[derive(Debug, Default, Clone, PartialEq, serde::Deserialize)]
+pub struct SkeletonFile {
+    pub skeletons: HashMap<String, Definition>,
+    /// Animation clips of each skeleton, by name.
+    #[serde(default)]
+    pub clips: HashMap<String, HashMap<String, Clip>>,
+}
+
+/// Same as `SkeletonFile`, with skeletons and clips sorted by name.
+#[derive(serde::Serialize)]
+struct JsonFile<'a> {
+    version: u64,
+    skeletons: BTreeMap<&'a String, &'a Definition>,
+    clips: BTreeMap<&'a String, BTreeMap<&'a String, &'a Clip>>,
+}
+
+pub fn to_json(file: &SkeletonFile) -> String {
+    serde_json::to_string_pretty(&JsonFile {
+        version: FORMAT_VERSION,
+        skeletons: file.skeletons.iter().collect(),
+        clips: file
+            .clips
+            .iter()
+            .map(|(name, clips)| (name, clips.iter().collect()))
+            .collect(),
+    })
+    .expect("Skeletons can always be serialized")
+}
+
+/// Parses a skeletons file of any version, migrating it to the current one. Files of version 1
+/// come without clips.
+pub fn from_json(json: &str) -> Result<SkeletonFile, DatabaseError> {
+    Ok(parse(json)?.0)
+}
+
+/// Parses a skeletons file, returning the version it had together with its migrated contents.
+/// Every skeleton in it is checked to be a tree.
+fn parse(json: &str) -> Result<(SkeletonFile, u64), DatabaseError> {
+    let value: serde_json::Value = serde_json::from_str(json).map_err(DatabaseError::Json)?;
+    // Older files are bare maps of skeletons, which may well have one called "version"
+    let envelope_version = value
+        .get("version")
+        .and_then(|version| version.as_u64())
+        .filter(|_| value.get("skeletons").is_some());
+    let version = match envelope_version {
+        Some(version) => version,
+        None if value.as_object().map_or(false, |skeletons| {
+            skeletons
+                .values()
+                .any(|skeleton| skeleton.get("entries").is_some())
+        }) =>
+        {
+            0
+        }
+        None => 1,
+    };
+    let file = match version {
+        0 => {
+            let legacy: HashMap<String, LegacyDefinition> =
+                serde_json::from_value(value).map_err(DatabaseError::Json)?;
+            let mut skeletons = HashMap::new();
+            for (name, definition) in legacy {
+                let definition = migrate_legacy(definition)
+                    .map_err(|reason| DatabaseError::Migration(name.clone(), reason))?;
+                skeletons.insert(name, definition);
+            }
+            SkeletonFile {
+                skeletons,
+                clips: HashMap::new(),
+            }
+        }
+        1 => SkeletonFile {
+            skeletons: serde_json::from_value(value).map_err(DatabaseError::Json)?,
+            clips: HashMap::new(),
+        },
+        FORMAT_VERSION => serde_json::from_value(value).map_err(DatabaseError::Json)?,
+        version => return Err(DatabaseError::UnsupportedVersion(version, FORMAT_VERSION)),
+    };
+    let mut names: Vec<_> = file.skeletons.keys().collect();
+    names.sort();
+    for name in names {
+        file.skeletons[name]
+            .check_tree()
+            .map_err(|err| DatabaseError::InvalidSkeleton(name.clone(), err))?;
+    }
+    Ok((file, version))
+}
+
+/// Loads a skeletons file. For files of version 1, clips are read from the `animations.json`
+/// next to it, if there is one.
+pub fn load(path: &Path) -> Result<SkeletonFile, DatabaseError> {
+    let (mut file, version) = parse(&read(path)?)?;
+    let legacy_clips = path.with_file_name("animations.json");
+    if version == 1 && legacy_clips.exists() {
+        file.clips = serde_json::from_str(&read(&legacy_clips)?).map_err(DatabaseError::Json)?;
+    }
+    Ok(file)
+}
+
+pub fn save(file: &SkeletonFile, path: &Path) -> Result<(), DatabaseError> {
+    write_safely(path, &to_json(file))
+}
+
+/// Writes a file without ever leaving it half written: the contents go to a temporary file first,
+/// which then replaces the old one. The old one is kept with `.bak` added to its name.
+pub fn write_safely(path: &Path, contents: &str) -> Result<(), DatabaseError> {
+    let io_error = |path: &Path| {
+        let path = path.to_path_buf();
+        move |err| DatabaseError::Io(path, err)
+    };
+    let temporary = with_suffix(path, ".tmp");
+    std::fs::write(&temporary, contents).map_err(io_error(&temporary))?;
+    if path.exists() {
+        let backup = with_suffix(path, ".bak");
+        std::fs::copy(path, &backup).map_err(io_error(&backup))?;
+    }
+    std::fs::rename(&temporary, path).map_err(io_error(path))
+}
+
+/// `path` with `suffix` added to the end of its file name, after any extension.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    name.into()
+}
+
+pub fn read(path: &Path) -> Result<String, DatabaseError> {
+    std::fs::read_to_string(path).map_err(|err| DatabaseError::Io(path.to_path_buf(), err))
+}
+
+/// Skeletons files opened lately, most recent first.
+#[derive(Debug, Default, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RecentFiles {
+    paths: Vec<PathBuf>,
+}
+
+impl RecentFiles {
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    pub fn push(&mut self, path: &Path) {
+        self.paths.retain(|recent| recent != path);
+        self.paths.insert(0, path.to_path_buf());
+        self.paths.truncate(MAX_RECENT_FILES);
+    }
+
+    pub fn load(path: &Path) -> Result<Self, DatabaseError> {
+        serde_json::from_str(&read(path)?).map_err(DatabaseError::Json)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), DatabaseError> {
+        let json = serde_json::to_string_pretty(self).expect("Paths can always be serialized");
+        std::fs::write(path, json).map_err(|err| DatabaseError::Io(path.to_path_buf(), err))
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct LegacyDefinition {
+    entries: HashMap<usize, LegacyEntry>,
+}
+
+#[derive(serde::Deserialize)]
+struct LegacyEntry {
+    part: LegacyPart,
+    attached_to: Option<usize>,
+}
+
+#[derive(serde::Deserialize)]
+enum LegacyPart {
+    Translation(f32, f32, f32),
+    /// Around the axis of the slot, in radians.
+    Rotation(FloatValue),
+    EndEffector(Effector),
+}
+
+/// Turns every entry into a link hanging from the link of the entry it was attached to, with a
+/// slot for each entry attached to it. Translations put those slots away from the link origin,
+/// rotations become twisting joints and end effectors become effectors. Entries that were not
+/// attached to anything hang from a new link 0.
+fn migrate_legacy(legacy: LegacyDefinition) -> Result<Definition, String> {
+    let children_of = |parent: Option<usize>| {
+        let mut children: Vec<_> = legacy
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.attached_to == parent)
+            .map(|(id, _)| *id)
+            .collect();
+        children.sort();
+        children
+    };
+    let slots_for = |children: &[usize], position: Vec3| -> Result<Link, String> {
+        let mut slot_names = ('a'..='z').chain('A'..='Z').filter(|c| *c != 'p');
+        let mut link = Link {
+            link_type: None,
+            slots: HashMap::new(),
+        };
+        for child in children {
+            let slot_name = slot_names
+                .next()
+                .ok_or_else(|| format!("Too many entries attached to the parent of {}", child))?;
+            link.slots.insert(
+                slot_name,
+                Slot {
+                    position,
+                    orientation: Quat::default(),
+                },
+            );
+        }
+        Ok(link)
+    };
+
+    let roots = children_of(None);
+    let mut definition = Definition::new(slots_for(&roots, Vec3::ZERO)?);
+    let mut migrated = 0;
+    // Entries waiting for their link, with the slot where it must hang
+    let mut pending: Vec<_> = slot_ids(&definition, 0, &roots);
+    while let Some((id, parent_slot)) = pending.pop() {
+        let entry = &legacy.entries[&id];
+        let children = children_of(Some(id));
+        let (position, joint) = match &entry.part {
+            LegacyPart::Translation(x, y, z) => (Vec3::new(*x, *y, *z), Joint::Fixed),
+            LegacyPart::Rotation(angle) => (Vec3::ZERO, Joint::TwistingJoint(angle.clone())),
+            LegacyPart::EndEffector(_) => (Vec3::ZERO, Joint::Fixed),
+        };
+        let mut link = slots_for(&children, position)?;
+        link.slots.insert('p', Slot::default());
+        definition
+            .apply(Change::Add {
+                link,
+                to_parent_slot: parent_slot,
+                joint,
+                local_slot_name: 'p',
+            })
+            .map_err(|err| format!("Entry {} cannot be added: {:?}", id, err))?;
+        let link_id = definition.last_link_id;
+        if let LegacyPart::EndEffector(effector) = &entry.part {
+            definition
+                .apply(Change::PlaceEffector {
+                    effector: effector.clone(),
+                    at: Some(SlotId(link_id, 'p')),
+                })
+                .map_err(|err| format!("Effector {} cannot be placed: {:?}", effector, err))?;
+        }
+        migrated += 1;
+        pending.extend(slot_ids(&definition, link_id, &children));
+    }
+
+    if migrated < legacy.entries.len() {
+        Err("Some entries are attached in a loop or to entries that do not exist".into())
+    } else {
+        Ok(definition)
+    }
+}
+
+/// Pairs `children` with the slots made for them by `slots_for` in `link_id`, in reverse order so
+/// they are popped in order.
+fn slot_ids(definition: &Definition, link_id: LinkId, children: &[usize]) -> Vec<(usize, SlotId)> {
+    let mut slot_names: Vec<_> = definition.links[&link_id]
+        .slots
+        .keys()
+        .filter(|slot_name| **slot_name != 'p')
+        .cloned()
+        .collect();
+    slot_names.sort_by_key(|slot_name| (slot_name.is_uppercase(), *slot_name));
+    children
+        .iter()
+        .zip(slot_names)
+        .map(|(child, slot_name)| (*child, SlotId(link_id, slot_name)))
+        .rev()
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::skeleton::fixtures::{arm, elbow, pose};
+    use super::*;
+
+    fn example_file() -> SkeletonFile {
+        let mut clip = Clip::default();
+        clip.insert_keyframe(1.0, pose(&[("elbow", 1.0)]));
+        SkeletonFile {
+            skeletons: maplit::hashmap! { "arm".to_string() => arm(elbow()) },
+            clips: maplit::hashmap! {
+                "arm".to_string() => maplit::hashmap! { "wave".to_string() => clip },
+            },
+        }
+    }
+
+    /// A directory of its own for a test, empty.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "skeleton_persistence_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let file = example_file();
+        let json = to_json(&file);
+        assert!(json.contains("\"version\": 2"));
+        assert_eq!(from_json(&json).unwrap(), file);
+    }
+
+    #[test]
+    fn version_1_files_are_bare_skeletons() {
+        let file = example_file();
+        let json = serde_json::to_string(&file.skeletons).unwrap();
+        let migrated = from_json(&json).unwrap();
+        assert_eq!(migrated.skeletons, file.skeletons);
+        assert!(migrated.clips.is_empty());
+
+        let dir = test_dir("version_1");
+        std::fs::write(dir.join("skeletons.json"), json).unwrap();
+        std::fs::write(
+            dir.join("animations.json"),
+            serde_json::to_string(&file.clips).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(load(&dir.join("skeletons.json")).unwrap(), file);
+    }
+
+    #[test]
+    fn version_1_files_can_have_a_skeleton_called_version() {
+        let mut file = example_file();
+        let arm = file.skeletons["arm"].clone();
+        file.skeletons.insert("version".into(), arm.clone());
+        file.skeletons.insert("skeletons".into(), arm);
+        let json = serde_json::to_string(&file.skeletons).unwrap();
+        assert_eq!(from_json(&json).unwrap().skeletons, file.skeletons);
+    }
+
+    #[test]
+    fn skeletons_that_are_not_trees_are_rejected() {
+        let mut file = example_file();
+        let arm = file.skeletons.get_mut("arm").unwrap();
+        arm.joints.clear();
+        match from_json(&to_json(&file)) {
+            Err(DatabaseError::InvalidSkeleton(name, ChangeError::DetachedLink(1))) => {
+                assert_eq!(name, "arm")
+            }
+            other => panic!("Expected an invalid skeleton, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn joints_must_connect_existing_and_distinct_slots() {
+        let mut file = example_file();
+        let arm = file.skeletons.get_mut("arm").unwrap();
+        arm.joints[0].2 = SlotId(1, 'x');
+        match from_json(&to_json(&file)) {
+            Err(DatabaseError::InvalidSkeleton(_, ChangeError::UnexistingSlot(slot_id))) => {
+                assert_eq!(slot_id, SlotId(1, 'x'))
+            }
+            other => panic!("Expected an invalid skeleton, found {:?}", other),
+        }
+
+        let mut file = example_file();
+        let arm = file.skeletons.get_mut("arm").unwrap();
+        arm.joints[1].0 = arm.joints[0].2;
+        match from_json(&to_json(&file)) {
+            Err(DatabaseError::InvalidSkeleton(_, ChangeError::SlotOccupied(slot_id))) => {
+                assert_eq!(slot_id, SlotId(1, 'p'))
+            }
+            other => panic!("Expected an invalid skeleton, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn version_0_entries_become_links() {
+        let json = r#"{
+            "lever": { "entries": {
+                "0": { "part": { "Rotation": { "Variable": "base" } }, "attached_to": null },
+                "1": { "part": { "Translation": [1.0, 0.0, 0.0] }, "attached_to": 0 },
+                "2": { "part": { "EndEffector": "tip" }, "attached_to": 1 },
+                "3": { "part": { "Translation": [1.0, 0.0, 0.0] }, "attached_to": null }
+            } }
+        }"#;
+        let lever = from_json(json).unwrap().skeletons.remove("lever").unwrap();
+        assert_eq!(lever.links().len(), 5);
+        assert_eq!(lever.variables(), maplit::hashset! { "base".to_string() });
+        assert_eq!(lever.effectors.len(), 1);
+        let pose = Pose {
+            valuation: maplit::hashmap! { "base".to_string() => 1.0 },
+        };
+        let tip = lever.effector_position("tip", &pose).unwrap();
+        // The translation turns with the rotation it is attached to
+        let expected = Vec3::new(1f32.cos(), 1f32.sin(), 0.0);
+        assert!(tip.abs_diff_eq(expected, 1e-5), "Tip at {:?}", tip);
+
+        let looped = r#"{ "bad": { "entries": {
+            "0": { "part": { "Translation": [0.0, 0.0, 1.0] }, "attached_to": 1 },
+            "1": { "part": { "Translation": [0.0, 0.0, 1.0] }, "attached_to": 0 }
+        } } }"#;
+        match from_json(looped) {
+            Err(DatabaseError::Migration(name, _)) => assert_eq!(name, "bad"),
+            other => panic!("Expected a migration error, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn newer_versions_are_rejected() {
+        match from_json(r#"{ "version": 3, "skeletons": {} }"#) {
+            Err(DatabaseError::UnsupportedVersion(3, FORMAT_VERSION)) => (),
+            other => panic!("Expected an unsupported version, found {:?}", other),
+        }
+        assert!(matches!(from_json("{"), Err(DatabaseError::Json(_))));
+    }
+
+    #[test]
+    fn saving_keeps_a_backup_of_the_previous_file() {
+        let dir = test_dir("backup");
+        let path = dir.join("skeletons.json");
+        let first = example_file();
+        save(&first, &path).unwrap();
+        assert!(!dir.join("skeletons.json.bak").exists());
+
+        let second = SkeletonFile::default();
+        save(&second, &path).unwrap();
+        assert_eq!(load(&path).unwrap(), second);
+        assert_eq!(load(&dir.join("skeletons.json.bak")).unwrap(), first);
+        assert!(!dir.join("skeletons.json.tmp").exists());
+
+        let schema = dir.join("arm.schema");
+        write_safely(&schema, "arm_base! {}\n").unwrap();
+        write_safely(&schema, "l_link! {}\n").unwrap();
+        assert_eq!(read(&dir.join("arm.schema.bak")).unwrap(), "arm_base! {}\n");
+        assert!(!dir.join("arm.json.bak").exists());
+        assert!(!dir.join("arm.schema.tmp").exists());
+
+        match load(&dir.join("missing.json")) {
+            Err(DatabaseError::Io(path, _)) => assert_eq!(path, dir.join("missing.json")),
+            other => panic!("Expected an I/O error, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn recent_files_are_unique_and_limited() {
+        let mut recent = RecentFiles::default();
+        for i in 0..10 {
+            recent.push(Path::new(&format!("{}.json", i)));
+        }
+        recent.push(Path::new("5.json"));
+        assert_eq!(recent.paths().len(), MAX_RECENT_FILES);
+        assert_eq!(recent.paths()[0], Path::new("5.json"));
+        assert_eq!(recent.paths()[1], Path::new("9.json"));
+        assert_eq!(
+            recent
+                .paths()
+                .iter()
+                .filter(|path| **path == Path::new("5.json"))
+                .count(),
+            1
+        );
+    }
+}
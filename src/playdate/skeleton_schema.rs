@@ -0,0 +1,585 @@
+//! Skeleton schemas: a text format for skeleton definitions built from the link types of a
+//! library, that looks like this:
+//!
+//! ```text
+//! arm_base! {
+//!     "n" => twist(waist) t_link! {
+//!         "l" => rotate(left_shoulder) l_link! {},
+//!         "r" => rotate(-0.5) l_link!("n") {},
+//!     },
+//! }
+//! ```
+//!
+//! Each link lists what is connected to its slots. Joints are fixed unless annotated with
+//! `twist(...)` or `rotate(...)`, that take either a variable or a constant angle. Links hang
+//! from their `"p"` slot unless another one is given after the `!`. Slots can be marked as free
+//! with `()`, which is the same as not listing them.
+//!
+//! Schemas only describe links and joints. Variable constraints and effectors are not written,
+//! and `keep_constraints_and_effectors` carries them over when a schema replaces a skeleton.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::{Display, Write},
+};
+
+use super::lexer::{is_bare_name, quoted, Located, ParseError, Token, Tokens};
+use super::skeleton::*;
+use super::skeleton_library::LinkLibrary;
+
+/// Slot links hang from when the schema does not say otherwise.
+const DEFAULT_CHILD_SLOT: SlotName = 'p';
+
+/// What keeps a skeleton from being written as a schema.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SchemaError {
+    /// A link that does not match a link type of the library, so it has no name in schemas.
+    UnknownLink(LinkId),
+    /// A joint at the slot with an infinite or NaN angle, that would not read back as a number.
+    NotFinite(SlotId),
+}
+
+impl Display for SchemaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SchemaError::UnknownLink(link_id) => write!(
+                f,
+                "Link {} is not in the link library and cannot be written in a schema",
+                link_id
+            ),
+            SchemaError::NotFinite(slot_id) => write!(
+                f,
+                "The joint at {:?} has an angle that is not a finite number",
+                slot_id
+            ),
+        }
+    }
+}
+
+pub fn to_schema(definition: &Definition, library: &LinkLibrary) -> Result<String, SchemaError> {
+    let mut text = String::new();
+    write_link(&mut text, definition, library, 0, DEFAULT_CHILD_SLOT, 0)?;
+    text.push('\n');
+    Ok(text)
+}
+
+fn write_link(
+    text: &mut String,
+    definition: &Definition,
+    library: &LinkLibrary,
+    link_id: LinkId,
+    child_slot: SlotName,
+    depth: usize,
+) -> Result<(), SchemaError> {
+    let link = &definition.links[&link_id];
+    let name = link
+        .link_type
+        .as_ref()
+        .filter(|name| library.link(name).as_ref() == Some(link))
+        .ok_or(SchemaError::UnknownLink(link_id))?;
+    write!(text, "{}!", name).unwrap();
+    if child_slot != DEFAULT_CHILD_SLOT {
+        write!(text, "({})", quoted_slot(child_slot)).unwrap();
+    }
+    let connections: BTreeMap<_, _> = definition
+        .joints
+        .iter()
+        .filter(|(parent_slot, _, _)| parent_slot.0 == link_id)
+        .map(|(parent_slot, joint, child_slot)| (parent_slot.1, (joint, child_slot)))
+        .collect();
+    if connections.is_empty() {
+        text.push_str(" {}");
+        return Ok(());
+    }
+    text.push_str(" {\n");
+    let indentation = "    ".repeat(depth + 1);
+    for (slot_name, (joint, child_slot)) in connections {
+        write!(text, "{}{} => ", indentation, quoted_slot(slot_name)).unwrap();
+        let angle = match joint {
+            Joint::Fixed => None,
+            Joint::TwistingJoint(value) => Some(("twist", value)),
+            Joint::RotationalJoin(value) => Some(("rotate", value)),
+        };
+        if let Some((keyword, value)) = angle {
+            let value =
+                float_value(value).ok_or(SchemaError::NotFinite(SlotId(link_id, slot_name)))?;
+            write!(text, "{}({}) ", keyword, value).unwrap();
+        }
+        write_link(
+            text,
+            definition,
+            library,
+            child_slot.0,
+            child_slot.1,
+            depth + 1,
+        )?;
+        text.push_str(",\n");
+    }
+    write!(text, "{}}}", "    ".repeat(depth)).unwrap();
+    Ok(())
+}
+
+/// How `value` is written, unless it is a constant that cannot be read back.
+fn float_value(value: &FloatValue) -> Option<String> {
+    match value {
+        FloatValue::Constant(constant) if constant.is_finite() => Some(format!("{:?}", constant)),
+        FloatValue::Constant(_) => None,
+        FloatValue::Variable(variable) if is_bare_name(variable, true) => Some(variable.clone()),
+        FloatValue::Variable(variable) => Some(quoted(variable)),
+    }
+}
+
+fn quoted_slot(slot_name: SlotName) -> String {
+    quoted(&slot_name.to_string())
+}
+
+/// Builds the skeleton described by a schema, adding its links in the order they are written.
+pub fn from_schema(text: &str, library: &LinkLibrary) -> Result<Definition, ParseError> {
+    let mut parser = Parser {
+        tokens: Tokens::new(text, true)?,
+        library,
+    };
+    let (name, link0, child_slot) = parser.link()?;
+    if let Some(child_slot) = child_slot {
+        return Err(child_slot.error("The first link does not hang from any slot"));
+    }
+    let mut definition = Definition::new(link0);
+    parser.connections(&mut definition, 0, &name.value)?;
+    parser.tokens.expect(Token::End)?;
+    Ok(definition)
+}
+
+/// Copies into `definition` the variable constraints and effectors of `previous`, which schemas
+/// leave out. Returns what does not fit `definition`: constraints of variables it does not have,
+/// as `UnexistingVariable`, and effectors on slots it does not have, as `DetachedEffector`.
+pub fn keep_constraints_and_effectors(
+    previous: &Definition,
+    definition: &mut Definition,
+) -> Vec<ChangeError> {
+    let mut dropped = vec![];
+    let mut variables: Vec<_> = previous.variable_constraints.keys().collect();
+    variables.sort();
+    for variable in variables {
+        let change = Change::Constrain {
+            variable: variable.clone(),
+            with: previous.variable_constraints[variable].clone(),
+        };
+        if let Err(err) = definition.apply(change) {
+            dropped.push(err);
+        }
+    }
+    let mut effectors: Vec<_> = previous.effectors.keys().collect();
+    effectors.sort();
+    for effector in effectors {
+        let change = Change::PlaceEffector {
+            effector: effector.clone(),
+            at: Some(previous.effectors[effector]),
+        };
+        if definition.apply(change).is_err() {
+            dropped.push(ChangeError::DetachedEffector(effector.clone()));
+        }
+    }
+    dropped
+}
+
+struct Parser<'a> {
+    tokens: Tokens,
+    library: &'a LinkLibrary,
+}
+
+impl<'a> Parser<'a> {
+    fn slot_name(&mut self) -> Result<Located<SlotName>, ParseError> {
+        let token = self.tokens.next();
+        match token.value {
+            Token::Quoted(ref string) => {
+                let mut chars = string.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Ok(token.map(c)),
+                    _ => Err(token.error(format!(
+                        "Slot names are a single character, found {}",
+                        quoted(string)
+                    ))),
+                }
+            }
+            ref other => Err(token.error(format!(
+                "Expected a slot name between double quotes, found {}",
+                other
+            ))),
+        }
+    }
+
+    /// A link type with the slot it hangs from, if given: `t_link!` or `l_link!("n")`.
+    fn link(&mut self) -> Result<(Located<String>, Link, Option<Located<SlotName>>), ParseError> {
+        let token = self.tokens.next();
+        let name = match token.value {
+            Token::Name(ref name) => token.map(name.clone()),
+            ref other => return Err(token.error(format!("Expected a link, found {}", other))),
+        };
+        let link = self
+            .library
+            .link(&name.value)
+            .ok_or_else(|| name.error(format!("Unknown link {}", name.value)))?;
+        self.tokens.expect(Token::Bang)?;
+        let child_slot = if self.tokens.peek().value == Token::OpenParen {
+            self.tokens.next();
+            let child_slot = self.slot_name()?;
+            self.tokens.expect(Token::CloseParen)?;
+            Some(child_slot)
+        } else {
+            None
+        };
+        Ok((name, link, child_slot))
+    }
+
+    /// An optional joint annotation in front of a link.
+    fn joint(&mut self) -> Result<Joint, ParseError> {
+        if *self.tokens.peek_after() == Token::Bang {
+            return Ok(Joint::Fixed);
+        }
+        let token = self.tokens.next();
+        match token.value {
+            Token::Name(ref k) if k == "fixed" => Ok(Joint::Fixed),
+            Token::Name(ref k) if k == "twist" => Ok(Joint::TwistingJoint(self.angle()?)),
+            Token::Name(ref k) if k == "rotate" => Ok(Joint::RotationalJoin(self.angle()?)),
+            ref other => Err(token.error(format!(
+                "Expected `fixed`, `twist`, `rotate` or a link, found {}",
+                other
+            ))),
+        }
+    }
+
+    fn angle(&mut self) -> Result<FloatValue, ParseError> {
+        self.tokens.expect(Token::OpenParen)?;
+        let token = self.tokens.next();
+        let value = match token.value {
+            Token::Name(ref variable) | Token::Quoted(ref variable) => {
+                FloatValue::Variable(variable.clone())
+            }
+            Token::Number(constant) => FloatValue::Constant(constant),
+            ref other => {
+                return Err(token.error(format!("Expected a variable or a number, found {}", other)))
+            }
+        };
+        self.tokens.expect(Token::CloseParen)?;
+        Ok(value)
+    }
+
+    /// The braces after a link, adding what is connected to its slots to `definition`.
+    fn connections(
+        &mut self,
+        definition: &mut Definition,
+        link_id: LinkId,
+        link_name: &str,
+    ) -> Result<(), ParseError> {
+        self.tokens.expect(Token::OpenBrace)?;
+        let mut connected = HashSet::new();
+        while self.tokens.peek().value != Token::CloseBrace {
+            let slot_name = self.slot_name()?;
+            if !definition.links[&link_id]
+                .slots
+                .contains_key(&slot_name.value)
+            {
+                return Err(slot_name.error(format!(
+                    "{} has no slot {}",
+                    link_name,
+                    quoted_slot(slot_name.value)
+                )));
+            }
+            if !connected.insert(slot_name.value) {
+                return Err(slot_name.error(format!(
+                    "Slot {} is connected twice",
+                    quoted_slot(slot_name.value)
+                )));
+            }
+            self.tokens.expect(Token::Arrow)?;
+
+            if self.tokens.peek().value == Token::OpenParen {
+                self.tokens.next();
+                self.tokens.expect(Token::CloseParen)?;
+            } else {
+                let joint = self.joint()?;
+                let (name, link, child_slot) = self.link()?;
+                let child_slot = child_slot.unwrap_or_else(|| name.map(DEFAULT_CHILD_SLOT));
+                if !link.slots.contains_key(&child_slot.value) {
+                    return Err(child_slot.error(format!(
+                        "{} has no slot {}",
+                        name.value,
+                        quoted_slot(child_slot.value)
+                    )));
+                }
+                definition
+                    .apply(Change::Add {
+                        link,
+                        to_parent_slot: SlotId(link_id, slot_name.value),
+                        joint,
+                        local_slot_name: child_slot.value,
+                    })
+                    .map_err(|err| name.error(format!("Cannot add {}: {:?}", name.value, err)))?;
+                self.connections(definition, definition.last_link_id, &name.value)?;
+            }
+
+            if self.tokens.end_of_entry()? {
+                break;
+            }
+        }
+        self.tokens.expect(Token::CloseBrace)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::skeleton::fixtures::{arm, elbow};
+    use super::*;
+
+    const SPINNING_TORSO: &str = r#"arm_base! {
+    "n" => t_link! {
+        "l" => l_link! {},
+        "r" => l_link! {}
+    }
+}"#;
+
+    fn parse(text: &str) -> Result<Definition, ParseError> {
+        from_schema(text, &LinkLibrary::default())
+    }
+
+    fn print(definition: &Definition) -> Result<String, SchemaError> {
+        to_schema(definition, &LinkLibrary::default())
+    }
+
+    fn link_names(definition: &Definition) -> Vec<&str> {
+        definition
+            .links()
+            .iter()
+            .map(|link_id| definition.links[link_id].link_type.as_deref().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn parses_the_examples_in_the_notes() {
+        let lever = parse(
+            r#"arm_base! {
+    "n" => l_link! {
+        "n" => ()
+    }
+}"#,
+        )
+        .unwrap();
+        assert_eq!(link_names(&lever), vec!["arm_base", "l_link"]);
+        assert_eq!(
+            lever.joints,
+            vec![(SlotId(0, 'n'), Joint::Fixed, SlotId(1, 'p'))]
+        );
+
+        let torso = parse(SPINNING_TORSO).unwrap();
+        assert_eq!(
+            link_names(&torso),
+            vec!["arm_base", "t_link", "l_link", "l_link"]
+        );
+        assert_eq!(
+            torso.joints,
+            vec![
+                (SlotId(0, 'n'), Joint::Fixed, SlotId(1, 'p')),
+                (SlotId(1, 'l'), Joint::Fixed, SlotId(2, 'p')),
+                (SlotId(1, 'r'), Joint::Fixed, SlotId(3, 'p')),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_joint_annotations() {
+        let s = parse(
+            r#"
+            // Joints can take variables or constants
+            arm_base! {
+                "n" => twist(waist) t_link! {
+                    "l" => rotate(-0.5) l_link!("n") {},
+                    "r" => fixed l_link! { "n" => rotate("left hand") l_link! {} },
+                },
+            }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            s.joints,
+            vec![
+                (
+                    SlotId(0, 'n'),
+                    Joint::TwistingJoint(FloatValue::Variable("waist".into())),
+                    SlotId(1, 'p')
+                ),
+                (
+                    SlotId(1, 'l'),
+                    Joint::RotationalJoin(FloatValue::Constant(-0.5)),
+                    SlotId(2, 'n')
+                ),
+                (SlotId(1, 'r'), Joint::Fixed, SlotId(3, 'p')),
+                (
+                    SlotId(3, 'n'),
+                    Joint::RotationalJoin(FloatValue::Variable("left hand".into())),
+                    SlotId(4, 'p')
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn schema_round_trip() {
+        let text = r#"arm_base! {
+    "n" => twist(waist) t_link! {
+        "l" => rotate(-0.5) l_link!("n") {},
+        "r" => l_link! {
+            "n" => rotate("left hand") l_link! {},
+        },
+    },
+}
+"#;
+        let s = parse(text).unwrap();
+        assert_eq!(print(&s).unwrap(), text);
+        assert_eq!(parse(&print(&s).unwrap()).unwrap(), s);
+
+        let torso = parse(SPINNING_TORSO).unwrap();
+        assert_eq!(parse(&print(&torso).unwrap()).unwrap(), torso);
+        assert_eq!(
+            print(&Definition::new(link::l_link())).unwrap(),
+            "l_link! {}\n"
+        );
+    }
+
+    #[test]
+    fn links_that_are_not_presets_cannot_be_written() {
+        let mut s = parse(SPINNING_TORSO).unwrap();
+        s.links.get_mut(&2).unwrap().slots.remove(&'n');
+        assert_eq!(print(&s), Err(SchemaError::UnknownLink(2)));
+    }
+
+    #[test]
+    fn variables_with_any_name_survive_a_round_trip() {
+        let mut s = Definition::new(link::arm_base());
+        s.apply(Change::Add {
+            link: link::l_link(),
+            to_parent_slot: SlotId(0, 'n'),
+            joint: Joint::RotationalJoin(FloatValue::Variable("it's \"bent\"\r\u{1b}".into())),
+            local_slot_name: 'p',
+        })
+        .unwrap();
+        assert_eq!(parse(&print(&s).unwrap()).unwrap(), s);
+    }
+
+    #[test]
+    fn angles_that_are_not_numbers_cannot_be_written() {
+        let mut s = parse(SPINNING_TORSO).unwrap();
+        for angle in vec![f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            s.apply(Change::ReplaceJoint {
+                at: SlotId(1, 'l'),
+                with: Joint::TwistingJoint(FloatValue::Constant(angle)),
+            })
+            .unwrap();
+            assert_eq!(print(&s), Err(SchemaError::NotFinite(SlotId(1, 'l'))));
+        }
+    }
+
+    #[test]
+    fn replaced_skeletons_keep_the_constraints_and_effectors_that_fit() {
+        let mut previous = arm(elbow());
+        let constraint = VariableConstraint {
+            min: Some(0.0),
+            max: Some(1.0),
+        };
+        previous
+            .apply(Change::Constrain {
+                variable: "elbow".into(),
+                with: constraint.clone(),
+            })
+            .unwrap();
+        for (effector, slot_id) in vec![("wrist", SlotId(1, 'n')), ("hand", SlotId(2, 'n'))] {
+            previous
+                .apply(Change::PlaceEffector {
+                    effector: effector.into(),
+                    at: Some(slot_id),
+                })
+                .unwrap();
+        }
+
+        let mut s = parse(&print(&previous).unwrap()).unwrap();
+        assert!(s.variable_constraints.is_empty() && s.effectors.is_empty());
+        assert_eq!(keep_constraints_and_effectors(&previous, &mut s), vec![]);
+        assert_eq!(s, previous);
+
+        let mut shorter = parse(r#"arm_base! { "n" => l_link! {} }"#).unwrap();
+        assert_eq!(
+            keep_constraints_and_effectors(&previous, &mut shorter),
+            vec![
+                ChangeError::UnexistingVariable("elbow".into()),
+                ChangeError::DetachedEffector("hand".into()),
+            ]
+        );
+        assert!(shorter.variable_constraints.is_empty());
+        assert_eq!(shorter.effectors["wrist"], SlotId(1, 'n'));
+    }
+
+    #[test]
+    fn schemas_use_the_link_types_of_the_library() {
+        let mut library = LinkLibrary::default();
+        let mut hand = library.get("l_link").unwrap().clone();
+        hand.slots.remove(&'n');
+        library.insert("hand".into(), hand);
+
+        let text = "arm_base! {\n    \"n\" => hand! {},\n}\n";
+        let s = from_schema(text, &library).unwrap();
+        assert_eq!(s.links[&1].link_type.as_deref(), Some("hand"));
+        assert_eq!(to_schema(&s, &library).unwrap(), text);
+        assert_eq!(print(&s), Err(SchemaError::UnknownLink(1)));
+    }
+
+    #[test]
+    fn parse_errors_point_at_the_offending_line() {
+        let error = |text| {
+            let err = parse(text).unwrap_err();
+            (err.line, err.column, err.message)
+        };
+        assert_eq!(
+            error("arm_base! {\n    \"n\" => leg! {}\n}"),
+            (2, 12, "Unknown link leg".into())
+        );
+        assert_eq!(
+            error("arm_base! {\n    \"x\" => l_link! {}\n}"),
+            (2, 5, "arm_base has no slot \"x\"".into())
+        );
+        assert_eq!(
+            error("arm_base! {\n  \"n\" => l_link!(\"q\") {}\n}"),
+            (2, 18, "l_link has no slot \"q\"".into())
+        );
+        assert_eq!(
+            error("arm_base! {\n  \"n\" => (),\n  \"n\" => ()\n}"),
+            (3, 3, "Slot \"n\" is connected twice".into())
+        );
+        assert_eq!(
+            error("arm_base! {\n  \"nn\" => ()\n}"),
+            (
+                2,
+                3,
+                "Slot names are a single character, found \"nn\"".into()
+            )
+        );
+        assert_eq!(
+            error("arm_base! {\n  \"n\" => bend(1) l_link! {}\n}"),
+            (
+                2,
+                10,
+                "Expected `fixed`, `twist`, `rotate` or a link, found `bend`".into()
+            )
+        );
+        assert_eq!(
+            error("arm_base! {\n  \"n\" l_link! {}\n}"),
+            (2, 7, "Expected `=>`, found `l_link`".into())
+        );
+        assert_eq!(
+            error("arm_base!(\"n\") {}"),
+            (1, 11, "The first link does not hang from any slot".into())
+        );
+        assert_eq!(
+            error("arm_base! {}\nl_link! {}"),
+            (2, 1, "Expected end of file, found `l_link`".into())
+        );
+    }
+}